@@ -1,19 +1,29 @@
-use std::{collections::HashMap, error::Error, path::PathBuf, sync::Arc};
+use std::{collections::HashMap, io, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::{Duration, Instant}};
 
 use base64::Engine;
 use brain::managers::storage_manager::StorageManager;
-use tokio::sync::Mutex;
-use tonic::{transport::Server, Request, Response, Status};
-use tracing::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncRead, sync::{mpsc::{self, error::TrySendError}, oneshot, Mutex, RwLock, Semaphore, SemaphorePermit}, task::JoinHandle};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tokio_util::io::StreamReader;
+use tonic::{transport::{Channel, Endpoint, Server}, Request, Response, Status, Streaming};
+use tracing::{error, info, info_span, warn, Instrument, Span};
 use common::brain_service::{self, MessageType};
 
 
 use brain_service::{
+    brain_service_client::BrainServiceClient,
     brain_service_server::{BrainService, BrainServiceServer},
-    ComponentRegistration, ComponentStatus, ComponentType, MessageRouteRequest,
-    MessageRouteResponse, RegistrationResponse, SystemStatusRequest, SystemStatusResponse,
-    UnregistrationRequest, UnregistrationResponse, ComponentInfo, SystemHealth,
+    file_ref::File, message_route_event::Event, storage_command::Operation, ComponentRegistration, ComponentStatus, ComponentType,
+    HeartbeatRequest, HeartbeatResponse, MessageRouteEvent, MessageRouteRequest, MessageRouteResponse, ProgressUpdate,
+    RegistrationResponse, SystemStatusRequest, SystemStatusResponse, UnregistrationRequest, UnregistrationResponse, ComponentInfo,
+    SystemHealth, StorageCommand, FileRef, ListFiles, UploadFile, DownloadFile, DownloadRange, UpdateFile, DeleteFile, GetFileInfo,
+    GetProgress, ExistsBatch, VerifyChecksums, EncryptionAudit, CompressionStats, UploadPart,
 };
+use prost::Message;
+use storage_engine::storage::disk::{ChecksumStatus, DiskStorage};
+use storage_engine::storage::progress::{ProgressStats, ProgressTracker};
+use storage_engine::FileId;
 use uuid::Uuid;
 
 #[derive(Clone)]
@@ -23,28 +33,478 @@ struct RegisteredComponent {
     ip_address: String,
     port: i32,
     status: ComponentStatus,
+    // Refreshed whenever the component registers, routes a message or sends a heartbeat
+    last_seen: Instant,
+}
+
+impl RegisteredComponent {
+    /// Records that the component was heard from, which brings it back from unresponsive.
+    /// Returns whether it was unresponsive.
+    fn mark_seen(&mut self) -> bool {
+        self.last_seen = Instant::now();
+        let revived = self.status == ComponentStatus::Unresponsive;
+        if revived {
+            self.status = ComponentStatus::Running;
+        }
+        revived
+    }
+}
+
+/// A registered component as saved in the registry file.
+#[derive(Serialize, Deserialize)]
+struct SavedComponent {
+    id: String,
+    component_type: i32,
+    ip_address: String,
+    port: i32,
+    status: i32,
+}
+
+/// Contents of the registry file.
+#[derive(Serialize, Deserialize, Default)]
+struct SavedRegistry {
+    system_id: String,
+    components: Vec<SavedComponent>,
 }
 
 // Brain service state
-#[derive(Default)]
 struct BrainServiceState {
     system_id: String,
     components: HashMap<String, RegisteredComponent>,
+    // Saved to after every change, so registrations survive a restart
+    registry_path: PathBuf,
+}
+
+impl BrainServiceState {
+    /// Reloads the registry saved at `registry_path`, starting empty if the file is
+    /// missing or unreadable. Components count as seen now, so ones that don't come
+    /// back after the restart expire like any other.
+    async fn load(registry_path: &Path) -> Self {
+        let saved = match tokio::fs::read_to_string(registry_path).await {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable registry {}: {}", registry_path.display(), e);
+                SavedRegistry::default()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => SavedRegistry::default(),
+            Err(e) => {
+                warn!("Cannot read registry {}: {}", registry_path.display(), e);
+                SavedRegistry::default()
+            }
+        };
+
+        let now = Instant::now();
+        let components: HashMap<_, _> = saved
+            .components
+            .into_iter()
+            .filter_map(|component| {
+                Some((component.id.clone(), RegisteredComponent {
+                    component_type: ComponentType::try_from(component.component_type).ok()?,
+                    status: ComponentStatus::try_from(component.status).ok()?,
+                    id: component.id,
+                    ip_address: component.ip_address,
+                    port: component.port,
+                    last_seen: now,
+                }))
+            })
+            .collect();
+        if !components.is_empty() {
+            info!("Restored {} registered components from {}", components.len(), registry_path.display());
+        }
+
+        Self {
+            system_id: saved.system_id,
+            components,
+            registry_path: registry_path.to_path_buf(),
+        }
+    }
+
+    /// Writes the registry to its file. Failures are only logged: the registration
+    /// itself still holds until the brain restarts.
+    async fn save(&self) {
+        let saved = SavedRegistry {
+            system_id: self.system_id.clone(),
+            components: self
+                .components
+                .values()
+                .map(|component| SavedComponent {
+                    id: component.id.clone(),
+                    component_type: component.component_type as i32,
+                    ip_address: component.ip_address.clone(),
+                    port: component.port,
+                    status: component.status as i32,
+                })
+                .collect(),
+        };
+        let content = match serde_json::to_string_pretty(&saved) {
+            Ok(content) => content,
+            Err(e) => {
+                error!("Cannot serialize registry: {}", e);
+                return;
+            }
+        };
+
+        // Renamed into place so a crash mid-write can't truncate the registry
+        let mut tmp_path = self.registry_path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let written = match tokio::fs::write(&tmp_path, content).await {
+            Ok(()) => tokio::fs::rename(&tmp_path, &self.registry_path).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            error!("Cannot save registry to {}: {}", self.registry_path.display(), e);
+        }
+    }
+}
+
+const STORAGE_PATH: &str = "./storage";
+const REGISTRY_PATH: &str = "./registry.json";
+// Seconds between attempts to open storage while degraded, unless STORAGE_RETRY_SECS is set
+const DEFAULT_STORAGE_RETRY_SECS: u64 = 5;
+
+// Storage commands processed at once and waiting to be, unless STORAGE_WORKERS / STORAGE_QUEUE_CAPACITY are set
+const DEFAULT_STORAGE_WORKERS: usize = 4;
+const DEFAULT_STORAGE_QUEUE_CAPACITY: usize = 32;
+
+// How often components are checked for missed heartbeats
+const LIVENESS_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Longest a forwarded message may wait for the destination component to answer
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Progress updates not yet sent to a client following a storage command
+const PROGRESS_EVENT_BUFFER: usize = 16;
+
+type StorageSlot = Arc<RwLock<Option<Arc<StorageManager>>>>;
+type EventSender = mpsc::Sender<Result<MessageRouteEvent, Status>>;
+type ConnectionCache = Arc<Mutex<HashMap<String, BrainServiceClient<Channel>>>>;
+
+fn env_parse<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok().and_then(|value| value.trim().parse().ok())
+}
+
+/// Decides overall system health from the registered components. Read from the
+/// environment so deployments can tune it without a rebuild.
+struct HealthPolicy {
+    // Fewer registered components than this is Critical
+    min_components: usize,
+    // Fewer running, recently seen components than this is Degraded
+    min_healthy: usize,
+    // Each of these types needs at least one running, recently seen component
+    required_types: Vec<ComponentType>,
+    // Components not seen for this long no longer count as healthy and are marked unresponsive
+    stale_after: Duration,
+    // Components not seen for this long are unregistered
+    remove_after: Duration,
+}
+
+impl Default for HealthPolicy {
+    fn default() -> Self {
+        Self {
+            min_components: 1,
+            min_healthy: 3,
+            required_types: Vec::new(),
+            stale_after: Duration::from_secs(60),
+            remove_after: Duration::from_secs(300),
+        }
+    }
+}
+
+impl HealthPolicy {
+    /// Reads HEALTH_MIN_COMPONENTS, HEALTH_MIN_HEALTHY, HEALTH_STALE_SECS,
+    /// HEALTH_REMOVE_SECS and HEALTH_REQUIRED_TYPES (comma-separated, e.g.
+    /// `SERVER,CLI`), keeping the default for anything unset or unparseable.
+    fn from_env() -> Self {
+        let defaults = Self::default();
+        let required_types = std::env::var("HEALTH_REQUIRED_TYPES")
+            .map(|types| {
+                types
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .filter_map(|name| {
+                        let parsed = ComponentType::from_str_name(&name.to_uppercase());
+                        if parsed.is_none() {
+                            warn!("Ignoring unknown component type in HEALTH_REQUIRED_TYPES: {}", name);
+                        }
+                        parsed
+                    })
+                    .collect()
+            })
+            .unwrap_or(defaults.required_types);
+
+        Self {
+            min_components: env_parse("HEALTH_MIN_COMPONENTS").unwrap_or(defaults.min_components),
+            min_healthy: env_parse("HEALTH_MIN_HEALTHY").unwrap_or(defaults.min_healthy),
+            required_types,
+            stale_after: env_parse("HEALTH_STALE_SECS").map(Duration::from_secs).unwrap_or(defaults.stale_after),
+            remove_after: env_parse("HEALTH_REMOVE_SECS").map(Duration::from_secs).unwrap_or(defaults.remove_after),
+        }
+    }
+
+    fn is_healthy(&self, component: &RegisteredComponent, now: Instant) -> bool {
+        component.status == ComponentStatus::Running
+            && now.duration_since(component.last_seen) <= self.stale_after
+    }
+
+    fn classify<'a>(
+        &self,
+        components: impl IntoIterator<Item = &'a RegisteredComponent>,
+        storage_available: bool,
+    ) -> SystemHealth {
+        let now = Instant::now();
+        let components: Vec<_> = components.into_iter().collect();
+        if components.len() < self.min_components {
+            return SystemHealth::Critical;
+        }
+
+        let healthy: Vec<_> = components
+            .into_iter()
+            .filter(|component| self.is_healthy(component, now))
+            .collect();
+        let missing_type = self
+            .required_types
+            .iter()
+            .any(|required| !healthy.iter().any(|component| component.component_type == *required));
+
+        if healthy.len() < self.min_healthy || missing_type || !storage_available {
+            SystemHealth::Degraded
+        } else {
+            SystemHealth::Healthy
+        }
+    }
 }
 
 // #[derive(Default)]
 struct BrainServiceImpl {
     state: Arc<Mutex<BrainServiceState>>,
-    storage: Arc<StorageManager>,
+    // Empty while the storage backend can't be opened; storage commands answer unavailable
+    storage: StorageSlot,
+    health_policy: HealthPolicy,
+    storage_jobs: mpsc::Sender<StorageJob>,
+    // Streamed uploads don't go through the storage queue, so they get as many slots as there are workers
+    stream_uploads: Semaphore,
+    // Channels to components messages were forwarded to, by component id
+    connections: ConnectionCache,
+}
+
+/// Runs storage commands against whichever backend is currently open.
+#[derive(Clone)]
+struct StorageHandler {
+    storage: StorageSlot,
+}
+
+// A storage command waiting for a worker, with the channel its answer goes back on
+struct StorageJob {
+    message: MessageRouteRequest,
+    span: Span,
+    reply: oneshot::Sender<Result<MessageRouteResponse, Status>>,
+    // Set when the client asked for progress updates
+    progress: Option<EventSender>,
+}
+
+/// Starts `workers` tasks taking storage commands off a queue that holds at most
+/// `capacity` waiting commands, and returns the sending side of the queue.
+fn spawn_storage_workers(handler: StorageHandler, workers: usize, capacity: usize) -> mpsc::Sender<StorageJob> {
+    let (sender, receiver) = mpsc::channel::<StorageJob>(capacity);
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    for _ in 0..workers {
+        let handler = handler.clone();
+        let receiver = Arc::clone(&receiver);
+        tokio::spawn(async move {
+            loop {
+                let Some(job) = receiver.lock().await.recv().await else {
+                    return;
+                };
+                // Run on its own task so a panicking command fails that request, not the worker
+                let handler = handler.clone();
+                let message = job.message;
+                let progress = job.progress;
+                let task = tokio::spawn(async move { handler.handle_storage_message(&message, progress.as_ref()).await }.instrument(job.span));
+                let response = match task.await {
+                    Ok(response) => response,
+                    Err(e) => {
+                        error!("Storage command failed: {}", e);
+                        Err(Status::internal("Storage command failed"))
+                    }
+                };
+                // The caller may have given up on the answer
+                let _ = job.reply.send(response);
+            }
+        });
+    }
+
+    sender
 }
 
 impl BrainServiceImpl {
-    async fn new() -> Result<Self, Box<dyn Error>> {
-        let storage_manager = StorageManager::new("./storage").await?;
-        Ok(Self {
-            state: Arc::new(Mutex::new(BrainServiceState::default())),
-            storage: Arc::new(storage_manager),
-        })
+    /// Starts degraded if storage can't be opened, serving registration and status
+    /// while retrying in the background until the backend comes up. Components
+    /// registered before a restart are reloaded from `registry_path`.
+    async fn new(storage_path: &str, registry_path: &Path) -> Self {
+        let storage: StorageSlot = Arc::new(RwLock::new(None));
+        match StorageManager::new(storage_path).await {
+            Ok(storage_manager) => *storage.write().await = Some(Arc::new(storage_manager)),
+            Err(e) => {
+                warn!("Storage backend unavailable, starting in degraded mode: {}", e);
+                tokio::spawn(reconnect_storage(Arc::clone(&storage), storage_path.to_string(), storage_retry_interval()));
+            }
+        }
+
+        let workers = env_parse("STORAGE_WORKERS").unwrap_or(DEFAULT_STORAGE_WORKERS).max(1);
+        let capacity = env_parse("STORAGE_QUEUE_CAPACITY").unwrap_or(DEFAULT_STORAGE_QUEUE_CAPACITY).max(1);
+        let handler = StorageHandler { storage: Arc::clone(&storage) };
+
+        let state = Arc::new(Mutex::new(BrainServiceState::load(registry_path).await));
+        let connections: ConnectionCache = Arc::new(Mutex::new(HashMap::new()));
+        let health_policy = HealthPolicy::from_env();
+        tokio::spawn(expire_components(
+            Arc::clone(&state),
+            Arc::clone(&connections),
+            health_policy.stale_after,
+            health_policy.remove_after,
+        ));
+
+        Self {
+            state,
+            storage,
+            health_policy,
+            storage_jobs: spawn_storage_workers(handler, workers, capacity),
+            stream_uploads: Semaphore::new(workers),
+            connections,
+        }
+    }
+
+    /// Delivers a message to another component's own `RouteMessage` endpoint and returns
+    /// its answer. Connections are opened on first use and reused after that.
+    async fn forward_message(&self, destination: RegisteredComponent, message: MessageRouteRequest) -> Result<MessageRouteResponse, Status> {
+        if destination.port <= 0 {
+            return Err(Status::failed_precondition(format!("Component {} does not accept messages", destination.id)));
+        }
+
+        let mut client = {
+            let mut connections = self.connections.lock().await;
+            match connections.get(&destination.id) {
+                Some(client) => client.clone(),
+                None => {
+                    // IPv6 addresses need brackets in a URI
+                    let host = if destination.ip_address.contains(':') {
+                        format!("[{}]", destination.ip_address)
+                    } else {
+                        destination.ip_address.clone()
+                    };
+                    let endpoint = Endpoint::from_shared(format!("http://{}:{}", host, destination.port))
+                        .map_err(|e| Status::invalid_argument(format!("Component {} has an invalid address: {}", destination.id, e)))?
+                        .timeout(FORWARD_TIMEOUT);
+                    let client = BrainServiceClient::new(endpoint.connect_lazy());
+                    connections.insert(destination.id.clone(), client.clone());
+                    client
+                }
+            }
+        };
+
+        match client.route_message(Request::new(message)).await {
+            Ok(response) => Ok(response.into_inner()),
+            Err(e) => {
+                // Dial again next time in case the component moved or restarted
+                self.connections.lock().await.remove(&destination.id);
+                Err(Status::unavailable(format!("Could not deliver message to {}: {}", destination.id, e.message())))
+            }
+        }
+    }
+
+    /// Queues a storage command for the worker pool and waits for its answer. Fails
+    /// with `resource_exhausted` instead of waiting when the queue is full.
+    async fn dispatch_storage_message(&self, message: MessageRouteRequest, span: Span) -> Result<MessageRouteResponse, Status> {
+        let response = self.queue_storage_message(message, span, None)?;
+        response.await.map_err(|_| Status::internal("Storage command was dropped"))?
+    }
+
+    // `Status` is tonic's error type, large or not
+    #[allow(clippy::result_large_err)]
+    fn queue_storage_message(
+        &self,
+        message: MessageRouteRequest,
+        span: Span,
+        progress: Option<EventSender>,
+    ) -> Result<oneshot::Receiver<Result<MessageRouteResponse, Status>>, Status> {
+        let (reply, response) = oneshot::channel();
+        self.storage_jobs
+            .try_send(StorageJob { message, span, reply, progress })
+            .map_err(|e| match e {
+                TrySendError::Full(_) => Status::resource_exhausted("Storage queue is full, try again later"),
+                TrySendError::Closed(_) => Status::unavailable("Storage workers have stopped"),
+            })?;
+        Ok(response)
+    }
+
+    /// Claims a slot for a streamed upload, held until the upload finishes. Fails with
+    /// `resource_exhausted` instead of waiting when every slot is taken.
+    #[allow(clippy::result_large_err)]
+    fn claim_upload_slot(&self) -> Result<SemaphorePermit<'_>, Status> {
+        self.stream_uploads
+            .try_acquire()
+            .map_err(|_| Status::resource_exhausted("Too many uploads in progress, try again later"))
+    }
+}
+
+fn storage_retry_interval() -> Duration {
+    let secs = std::env::var("STORAGE_RETRY_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(DEFAULT_STORAGE_RETRY_SECS);
+    Duration::from_secs(secs)
+}
+
+async fn reconnect_storage(storage: StorageSlot, storage_path: String, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        match StorageManager::new(&storage_path).await {
+            Ok(storage_manager) => {
+                *storage.write().await = Some(Arc::new(storage_manager));
+                info!("Storage backend available, leaving degraded mode");
+                return;
+            }
+            Err(e) => warn!("Storage backend still unavailable: {}", e),
+        }
+    }
+}
+
+/// Marks components that stopped sending heartbeats as unresponsive once they've been
+/// silent for `stale_after`, and unregisters them after `remove_after`.
+async fn expire_components(state: Arc<Mutex<BrainServiceState>>, connections: ConnectionCache, stale_after: Duration, remove_after: Duration) {
+    let mut ticker = tokio::time::interval(LIVENESS_CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let now = Instant::now();
+        let mut removed = Vec::new();
+        let mut changed = false;
+        let mut state = state.lock().await;
+        state.components.retain(|id, component| {
+            let silent = now.duration_since(component.last_seen);
+            if silent > remove_after {
+                warn!("Unregistering component {}, not seen for {:?}", id, silent);
+                removed.push(id.clone());
+                return false;
+            }
+            if silent > stale_after && component.status == ComponentStatus::Running {
+                warn!("Component {} missed its heartbeats, marking it unresponsive", id);
+                component.status = ComponentStatus::Unresponsive;
+                changed = true;
+            }
+            true
+        });
+        if changed || !removed.is_empty() {
+            state.save().await;
+        }
+        drop(state);
+
+        if !removed.is_empty() {
+            let mut connections = connections.lock().await;
+            for id in &removed {
+                connections.remove(id);
+            }
+        }
     }
 }
 
@@ -61,28 +521,35 @@ impl BrainService for BrainServiceImpl {
             state.system_id = Uuid::new_v4().to_string();
         }
 
-        if state.components.contains_key(&registration.component_id) {
-            return Err(Status::already_exists("component already exists"));
-        }
-
         let component_type = ComponentType::try_from(registration.component_type).map_err(|_| Status::invalid_argument("Invalid component type"))?;
 
         let new_component = RegisteredComponent {
             id: registration.component_id.clone(),
-            component_type: component_type,
+            component_type,
             ip_address: registration.ip_address,
             port: registration.port,
             status: ComponentStatus::Running,
+            last_seen: Instant::now(),
         };
 
-        state
+        // A component restarting under the same id refreshes its existing entry
+        let previous = state
             .components
             .insert(registration.component_id.clone(), new_component);
-
-        info!(
-            "Registered component: {} (Type: {:?})",
-            registration.component_id, registration.component_type
-        );
+        if previous.is_some() {
+            // It may be listening somewhere else now
+            self.connections.lock().await.remove(&registration.component_id);
+            info!(
+                "Re-registered component: {} (Type: {:?})",
+                registration.component_id, registration.component_type
+            );
+        } else {
+            info!(
+                "Registered component: {} (Type: {:?})",
+                registration.component_id, registration.component_type
+            );
+        }
+        state.save().await;
 
         Ok(Response::new(RegistrationResponse {
             success: true,
@@ -101,6 +568,8 @@ impl BrainService for BrainServiceImpl {
         // Remove the component
         match state.components.remove(&unregistration.component_id) {
             Some(_) => {
+                state.save().await;
+                self.connections.lock().await.remove(&unregistration.component_id);
                 info!(
                     "Unregistered component: {}", 
                     unregistration.component_id
@@ -120,43 +589,168 @@ impl BrainService for BrainServiceImpl {
         }
     }
 
+    async fn heartbeat(
+        &self,
+        request: Request<HeartbeatRequest>,
+    ) -> Result<Response<HeartbeatResponse>, Status> {
+        let heartbeat = request.into_inner();
+        let mut state = self.state.lock().await;
+
+        // Components dropped for missing heartbeats learn here that they need to register again
+        match state.components.get_mut(&heartbeat.component_id) {
+            Some(component) => {
+                if component.mark_seen() {
+                    info!("Component {} is sending heartbeats again", heartbeat.component_id);
+                    state.save().await;
+                }
+                Ok(Response::new(HeartbeatResponse {
+                    success: true,
+                    error_message: String::new(),
+                }))
+            }
+            None => Err(Status::not_found("Component not registered")),
+        }
+    }
+
     async fn route_message(
         &self,
         request: Request<MessageRouteRequest>,
     ) -> Result<Response<MessageRouteResponse>, Status> {
-        let message = request.into_inner();
-        let state = self.state.lock().await;
+        let mut message = request.into_inner();
+        if message.request_id.is_empty() {
+            message.request_id = Uuid::new_v4().to_string();
+        }
+        let span = info_span!("route_message", request_id = %message.request_id);
+        let mut state = self.state.lock().await;
 
-        info!(message.destination_component);
+        info!(parent: &span, source = %message.source_component, destination = %message.destination_component, "Received message");
 
         // Validate source and destination components
-        if !state.components.contains_key(&message.source_component) {
-            return Err(Status::not_found("Source component not registered"));
+        let revived = match state.components.get_mut(&message.source_component) {
+            Some(source) => source.mark_seen(),
+            None => return Err(Status::not_found("Source component not registered")),
+        };
+        if revived {
+            state.save().await;
         }
 
-        if message.destination_component == "brain" {
-            if message.message_type == MessageType::StorageRequest as i32 {
-                let storage_response = self.handle_storage_message(&message).await?;
-                return Ok(Response::new(storage_response));
-            }
+        if message.destination_component == "brain" && message.message_type == MessageType::StorageRequest as i32 {
+            // Storage commands don't touch component state, so other requests needn't wait behind them
+            drop(state);
+            let storage_response = self.dispatch_storage_message(message, span).await?;
+            return Ok(Response::new(storage_response));
         }
 
-        if !state.components.contains_key(&message.destination_component) | (message.destination_component != "brain") {
-            return Err(Status::not_found("Destination component not registered"));
+        // Messages for the brain that aren't storage commands have nothing to act on
+        if message.destination_component == "brain" {
+            return Ok(Response::new(MessageRouteResponse {
+                success: true,
+                error_message: String::new(),
+            }));
         }
 
-        // In a real implementation, this would actually route the message
-        // Here we're just simulating the routing
+        let destination = state
+            .components
+            .get(&message.destination_component)
+            .cloned()
+            .ok_or_else(|| Status::not_found("Destination component not registered"))?;
+        drop(state);
+
         info!(
+            parent: &span,
             "Routing message from {} to {}", 
             message.source_component, 
             message.destination_component
         );
+        let response = self.forward_message(destination, message).instrument(span).await?;
+        Ok(Response::new(response))
+    }
 
-        Ok(Response::new(MessageRouteResponse {
-            success: true,
-            error_message: String::new(),
-        }))
+    type RouteMessageWithProgressStream = ReceiverStream<Result<MessageRouteEvent, Status>>;
+
+    /// Runs a storage command like `route_message`, sending progress updates while uploads
+    /// and downloads run. The command's response is always the last event.
+    async fn route_message_with_progress(
+        &self,
+        request: Request<MessageRouteRequest>,
+    ) -> Result<Response<Self::RouteMessageWithProgressStream>, Status> {
+        let mut message = request.into_inner();
+        if message.request_id.is_empty() {
+            message.request_id = Uuid::new_v4().to_string();
+        }
+        let span = info_span!("route_message", request_id = %message.request_id);
+
+        {
+            let mut state = self.state.lock().await;
+            let revived = match state.components.get_mut(&message.source_component) {
+                Some(source) => source.mark_seen(),
+                None => return Err(Status::not_found("Source component not registered")),
+            };
+            if revived {
+                state.save().await;
+            }
+        }
+        if message.destination_component != "brain" || message.message_type != MessageType::StorageRequest as i32 {
+            return Err(Status::invalid_argument("Only storage commands for the brain report progress"));
+        }
+        info!(parent: &span, source = %message.source_component, "Received message with progress");
+
+        let (events, stream) = mpsc::channel(PROGRESS_EVENT_BUFFER);
+        let response = self.queue_storage_message(message, span, Some(events.clone()))?;
+        tokio::spawn(async move {
+            let event = match response.await {
+                Ok(result) => result.map(|response| MessageRouteEvent { event: Some(Event::Response(response)) }),
+                Err(_) => Err(Status::internal("Storage command was dropped")),
+            };
+            // The client may have stopped listening
+            let _ = events.send(event).await;
+        });
+
+        Ok(Response::new(ReceiverStream::new(stream)))
+    }
+
+    async fn stream_upload(
+        &self,
+        request: Request<Streaming<UploadPart>>,
+    ) -> Result<Response<MessageRouteResponse>, Status> {
+        let _slot = self.claim_upload_slot()?;
+        let mut parts = request.into_inner();
+        let first = parts.message().await?.ok_or_else(|| Status::invalid_argument("Upload stream is empty"))?;
+        let request_id = if first.request_id.is_empty() { Uuid::new_v4().to_string() } else { first.request_id.clone() };
+        let span = info_span!("stream_upload", request_id = %request_id);
+
+        {
+            let mut state = self.state.lock().await;
+            let revived = match state.components.get_mut(&first.source_component) {
+                Some(source) => source.mark_seen(),
+                None => return Err(Status::not_found("Source component not registered")),
+            };
+            if revived {
+                state.save().await;
+            }
+        }
+        validate_file_name(&first.name).map_err(Status::invalid_argument)?;
+        let storage = self
+            .storage
+            .read()
+            .await
+            .clone()
+            .ok_or_else(|| Status::unavailable("Storage backend is unavailable, try again later"))?;
+        info!(parent: &span, source = %first.source_component, size = first.size, "Receiving streamed upload");
+
+        let name = first.name.clone();
+        let reader = upload_reader(first, parts);
+        let response = match storage.upload_stream(&name, reader).instrument(span).await {
+            Ok(metadata) => MessageRouteResponse {
+                success: true,
+                error_message: format!("File uploaded successfully. File ID: {}", metadata.id),
+            },
+            Err(e) => MessageRouteResponse {
+                success: false,
+                error_message: format!("Upload failed: {}", e),
+            },
+        };
+        Ok(Response::new(response))
     }
 
     async fn get_system_status(
@@ -177,12 +771,8 @@ impl BrainService for BrainServiceImpl {
             })
             .collect();
 
-        // Determine overall system health
-        let overall_health = match registered_components.len() {
-            0 => SystemHealth::Critical,
-            1..=2 => SystemHealth::Degraded,
-            _ => SystemHealth::Healthy,
-        };
+        let storage_available = self.storage.read().await.is_some();
+        let overall_health = self.health_policy.classify(state.components.values(), storage_available);
 
         Ok(Response::new(SystemStatusResponse {
             system_id: state.system_id.clone(),
@@ -192,24 +782,30 @@ impl BrainService for BrainServiceImpl {
     }
 }
 
-impl  BrainServiceImpl {
-    async fn handle_storage_message(&self, message: &MessageRouteRequest,) -> Result<MessageRouteResponse, Status> {
-        let payload = &message.payload;
-        let command = String::from_utf8(payload.to_vec()).map_err(|_| Status::invalid_argument("Invalid payload"))?;
-        let parts: Vec<&str> = command.splitn(3, ' ').collect();
-        let operation = parts[0];
-        println!("{}", operation);
+impl StorageHandler {
+    async fn storage(&self) -> Result<Arc<StorageManager>, Status> {
+        self.storage
+            .read()
+            .await
+            .clone()
+            .ok_or_else(|| Status::unavailable("Storage backend is unavailable, try again later"))
+    }
+
+    async fn handle_storage_message(&self, message: &MessageRouteRequest, progress: Option<&EventSender>) -> Result<MessageRouteResponse, Status> {
+        let command = StorageCommand::decode(message.payload.as_slice()).map_err(|_| Status::invalid_argument("Invalid payload"))?;
+        let operation = command.operation.ok_or_else(|| Status::invalid_argument("Missing storage operation"))?;
+        let storage = self.storage().await?;
 
         let mut response = MessageRouteResponse{
             success: true,
             error_message: String::new(),
         };
 
-        match (operation, parts.get(1).copied(), parts.get(2).copied()) {
-            ("list", None, None) => {
-                match self.storage.list_files().await {
+        match operation {
+            Operation::List(ListFiles {}) => {
+                match storage.list_files().await {
                     Ok(files) => {
-                        let file_list: Vec<String> = files.iter().map(|f| format!("{}: {}", f.id, f.name)).collect();
+                        let file_list: Vec<String> = files.iter().map(|f| format!("{}: {} ({} bytes)", f.id, f.name, f.original_len())).collect();
                         response.error_message = file_list.join("\n");
                     }
                     Err(e) => {
@@ -218,108 +814,347 @@ impl  BrainServiceImpl {
                     }
                 }
             }
-            ("upload", Some(file_name), Some(data)) => {
-                match base64::prelude::BASE64_STANDARD.decode(data) {
-                    Ok(file_content) => {
-                        match self.storage.upload_file(file_name, &file_content).await {
-                            Ok(file_id) => {
-                                response.error_message = format!("File uploaded successfully. File ID: {}", file_id.id);
-                            }
-                            Err(e) => {
-                                response.success = false;
-                                response.error_message = format!("Upload failed: {}", e);
-                            }
-                        }
+            Operation::EncryptionAudit(EncryptionAudit {}) => {
+                match storage.encryption_audit().await {
+                    Ok(audit) => {
+                        let mut lines = vec![
+                            format!("Encrypted: {}", audit.encrypted),
+                            format!("Plaintext: {}", audit.plaintext),
+                        ];
+                        lines.extend(audit.plaintext_ids.iter().map(|id| format!("  {}", id)));
+                        response.error_message = lines.join("\n");
                     }
-                    Err(_) => {
+                    Err(e) => {
                         response.success = false;
-                        response.error_message = "Invalid base64 content".to_string();
+                        response.error_message = format!("Encryption audit failed: {}", e);
                     }
                 }
             }
-            ("download", Some(param_type), Some(param)) => {
-                match param_type {
-                    "id" => {
-                        match self.storage.download_file(&Uuid::parse_str(param).unwrap()).await {
-                            Ok(file_contents) => {
-                                response.error_message = base64::prelude::BASE64_STANDARD.encode(&file_contents);
-                            }
-                            Err(e) => {
-                                response.success = false;
-                                response.error_message = format!("Download failed: {}", e);
-                            }
-                        }
+            Operation::Stats(CompressionStats {}) => {
+                match storage.compression_report().await {
+                    Ok(report) => {
+                        let mut by_type: Vec<_> = report
+                            .by_type
+                            .iter()
+                            .map(|(file_type, stats)| {
+                                format!("  {:?}: {} files, average ratio {:.2}", file_type, stats.files, stats.average_ratio)
+                            })
+                            .collect();
+                        by_type.sort();
+
+                        let mut lines = vec!["Compression by file type:".to_string()];
+                        lines.extend(by_type);
+                        response.error_message = lines.join("\n");
                     }
-                    "name" => {
-                        let index_path = PathBuf::from("./storage/name_to_id.json");
-                        if !index_path.exists() {
-                            return Err(Status::not_found("index file not found"));
-                        }
-                        
-                        let content = tokio::fs::read_to_string(&index_path).await?;
-                        let index: HashMap<String, Uuid> = serde_json::from_str(&content).map_err(|e:  serde_json::Error| Status::not_found(format!("failed to parse index {}", e))).unwrap();
-                        let id = index.get(param).cloned().ok_or_else(|| Status::not_found(format!("file {} not found", param)))?;
-
-                        match self.storage.download_file(&id).await {
-                            Ok(file_contents) => {
-                                response.error_message = base64::prelude::BASE64_STANDARD.encode(&file_contents);
-                            }
-                            Err(e) => {
-                                response.success = false;
-                                response.error_message = format!("Download failed: {}", e);
-                            }
-                        }
+                    Err(e) => {
+                        response.success = false;
+                        response.error_message = format!("Stats failed: {}", e);
                     }
-                    _ => {
+                }
+            }
+            Operation::ExistsBatch(ExistsBatch { by_checksum, keys }) => {
+                let found = if by_checksum {
+                    storage.exists_many_by_checksum(&keys).await.map(|found| {
+                        keys.iter().map(|checksum| found.get(checksum).copied().unwrap_or(false)).collect::<Vec<_>>()
+                    })
+                } else {
+                    let ids = keys
+                        .iter()
+                        .map(|key| key.parse::<FileId>())
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|e| Status::invalid_argument(format!("invalid file id: {}", e)))?;
+                    storage.exists_many(&ids).await.map(|found| {
+                        ids.iter().map(|id| found.get(id).copied().unwrap_or(false)).collect::<Vec<_>>()
+                    })
+                };
+
+                match found {
+                    Ok(found) => {
+                        let lines: Vec<String> = keys.iter().zip(found).map(|(key, exists)| format!("{}: {}", key, exists)).collect();
+                        response.error_message = lines.join("\n");
+                    }
+                    Err(e) => {
                         response.success = false;
-                        response.error_message = "Invalid download identifier type".to_string();
+                        response.error_message = format!("Existence check failed: {}", e);
                     }
                 }
             }
-            ("delete", Some(param_type), Some(param)) => {
-                match param_type {
-                    "id" => {
-                        match self.storage.delete_file(&Uuid::parse_str(param).unwrap()).await {
-                            Ok(_) => {
-                                response.error_message = format!("File with ID {} deleted", param);
-                            }
-                            Err(e) => {
-                                response.success = false;
-                                response.error_message = format!("Delete failed: {}", e);
-                            }
-                        }
+            Operation::VerifyChecksums(VerifyChecksums { files }) => {
+                let expected: Vec<(String, String)> = files.into_iter().map(|file| (file.name, file.checksum)).collect();
+
+                match storage.verify_checksums(&expected).await {
+                    Ok(statuses) => {
+                        let lines: Vec<String> = expected
+                            .iter()
+                            .zip(statuses)
+                            .map(|((name, _), status)| {
+                                let status = match status {
+                                    ChecksumStatus::Match => "match",
+                                    ChecksumStatus::Differs => "differs",
+                                    ChecksumStatus::Missing => "missing",
+                                };
+                                format!("{}: {}", name, status)
+                            })
+                            .collect();
+                        response.error_message = lines.join("\n");
+                    }
+                    Err(e) => {
+                        response.success = false;
+                        response.error_message = format!("Checksum verification failed: {}", e);
+                    }
+                }
+            }
+            Operation::Upload(UploadFile { name, data }) => {
+                validate_file_name(&name).map_err(Status::invalid_argument)?;
+                match upload_with_progress(&storage, &name, &data, progress).await {
+                    Ok(file_id) => {
+                        response.error_message = format!("File uploaded successfully. File ID: {}", file_id.id);
+                    }
+                    Err(e) => {
+                        response.success = false;
+                        response.error_message = format!("Upload failed: {}", e);
+                    }
+                }
+            }
+            Operation::Update(UpdateFile { file, data }) => {
+                let id = self.resolve_file_id(file.as_ref()).await?;
+
+                match storage.update_file(&id, &data).await {
+                    Ok(metadata) => {
+                        response.error_message = format!("File updated successfully. File ID: {}", metadata.id);
+                    }
+                    Err(e) => {
+                        response.success = false;
+                        response.error_message = format!("Update failed: {}", e);
+                    }
+                }
+            }
+            Operation::Range(DownloadRange { file, start, end }) => {
+                let id = self.resolve_file_id(file.as_ref()).await?;
+
+                match storage.download_range(&id, start, end).await {
+                    Ok(file_contents) => {
+                        response.error_message = base64::prelude::BASE64_STANDARD.encode(&file_contents);
                     }
-                    "name" => {
-                        let index_path = PathBuf::from("./storage/name_to_id.json");
-                        if !index_path.exists() {
-                            return Err(Status::not_found("index file not found"));
+                    Err(e) => {
+                        response.success = false;
+                        response.error_message = format!("Range download failed: {}", e);
+                    }
+                }
+            }
+            Operation::Download(DownloadFile { file, with_checksum }) => {
+                let id = self.resolve_file_id(file.as_ref()).await?;
+
+                let downloaded = if with_checksum {
+                    download_with_checksum(&storage, &id, progress).await.map(|(checksum, contents)| (Some(checksum), contents))
+                } else {
+                    download_with_progress(&storage, &id, progress).await.map(|contents| (None, contents))
+                };
+                match downloaded {
+                    Ok((Some(checksum), file_contents)) => {
+                        response.error_message = format!("{} {}", checksum, base64::prelude::BASE64_STANDARD.encode(&file_contents));
+                    }
+                    Ok((None, file_contents)) => {
+                        response.error_message = base64::prelude::BASE64_STANDARD.encode(&file_contents);
+                    }
+                    Err(e) => {
+                        response.success = false;
+                        response.error_message = format!("Download failed: {}", e);
+                    }
+                }
+            }
+            Operation::Progress(GetProgress { operation_id }) => {
+                let operation_id = Uuid::parse_str(&operation_id).map_err(|_| Status::invalid_argument(format!("invalid operation id {}", operation_id)))?;
+
+                match storage.get_progress(&operation_id).await {
+                    Some(stats) => {
+                        response.error_message = format!(
+                            "{} {} {:.0} {}",
+                            stats.processed_bytes,
+                            stats.total_bytes,
+                            stats.current_speed,
+                            stats.estimated_time_remaining.as_secs()
+                        );
+                    }
+                    None => {
+                        response.success = false;
+                        response.error_message = format!("Operation {} not found", operation_id);
+                    }
+                }
+            }
+            Operation::Info(GetFileInfo { file }) => {
+                let id = self.resolve_file_id(file.as_ref()).await?;
+
+                match storage.get_metadata(&id).await {
+                    Ok(metadata) => {
+                        let mut lines = vec![
+                            format!("ID: {}", metadata.id),
+                            format!("Name: {}", metadata.name),
+                            format!("Size: {} bytes", metadata.original_len()),
+                            format!("Stored-Size: {} bytes", metadata.size),
+                            format!("Type: {:?}", metadata.file_type),
+                            format!("Content-Type: {}", metadata.file_type.mime_type()),
+                            format!("Created: {}", metadata.created_at),
+                            format!("Modified: {}", metadata.modified_at),
+                            format!("Chunks: {}", metadata.chunk_ids.len()),
+                            format!("Checksum: {}", metadata.checksum),
+                        ];
+                        if !metadata.content_checksum.is_empty() {
+                            lines.push(format!("Content-Checksum: {}", metadata.content_checksum));
                         }
-                        
-                        let content = tokio::fs::read_to_string(&index_path).await?;
-                        let index: HashMap<String, Uuid> = serde_json::from_str(&content).map_err(|e:  serde_json::Error| Status::not_found(format!("failed to parse index {}", e))).unwrap();
-                        let id = index.get(param).cloned().ok_or_else(|| Status::not_found(format!("file {} not found", param)))?;
-
-                        match self.storage.delete_file(&id).await {
-                            Ok(_) => {
-                                response.error_message = format!("File with ID {} deleted", id);
-                            }
-                            Err(e) => {
-                                response.success = false;
-                                response.error_message = format!("Delete failed: {}", e);
-                            }
+                        if let Some(algorithm) = metadata.compression_algorithm {
+                            lines.push(format!("Compression: {:?}", algorithm));
                         }
+                        if let Some(level) = metadata.compression_level {
+                            lines.push(format!("Compression-Level: {}", level));
+                        }
+                        response.error_message = lines.join("\n");
                     }
-                    _ => {
+                    Err(e) => {
                         response.success = false;
-                        response.error_message = "Invalid download identifier type".to_string();
+                        response.error_message = format!("Info failed: {}", e);
+                    }
+                }
+            }
+            Operation::Delete(DeleteFile { file }) => {
+                let id = self.resolve_file_id(file.as_ref()).await?;
+
+                match storage.delete_file(&id).await {
+                    Ok(_) => {
+                        response.error_message = format!("File with ID {} deleted", id);
+                    }
+                    Err(e) => {
+                        response.success = false;
+                        response.error_message = format!("Delete failed: {}", e);
                     }
                 }
             }
-            _ => return Err(Status::invalid_argument("Invalid storage operation")),
         }
 
         Ok(response)
     }
+
+    async fn resolve_file_id(&self, file: Option<&FileRef>) -> Result<FileId, Status> {
+        match file.and_then(|file| file.file.as_ref()) {
+            Some(File::Id(id)) => id.parse::<FileId>().map_err(|_| Status::invalid_argument(format!("invalid file id {}", id))),
+            Some(File::Name(name)) => {
+                validate_file_name(name).map_err(Status::invalid_argument)?;
+                let id = self.storage().await?.lookup_name(name).await.map_err(|e| Status::internal(format!("name lookup failed: {}", e)))?;
+                id.ok_or_else(|| Status::not_found(format!("file {} not found", name)))
+            }
+            None => Err(Status::invalid_argument("Missing file identifier")),
+        }
+    }
+}
+
+/// Reads the data of a streamed upload, starting with the first part's. Fails at the end
+/// if the parts didn't add up to the size the first one announced, so an upload cut off
+/// partway is never stored as if it were whole.
+fn upload_reader<S>(first: UploadPart, rest: S) -> impl AsyncRead + Unpin
+where
+    S: Stream<Item = Result<UploadPart, Status>> + Unpin,
+{
+    let size = first.size;
+    let received = Arc::new(AtomicU64::new(0));
+    let counted = Arc::clone(&received);
+    let data = tokio_stream::once(Ok(first.data))
+        .chain(rest.map(|part| part.map(|part| part.data).map_err(|status| io::Error::other(status.message().to_string()))))
+        .map(move |data| {
+            let data = data?;
+            counted.fetch_add(data.len() as u64, Ordering::Relaxed);
+            Ok(io::Cursor::new(data))
+        });
+    let check = tokio_stream::iter([()]).filter_map(move |()| {
+        let received = received.load(Ordering::Relaxed);
+        (received != size).then(|| Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("upload sent {} of {} bytes", received, size))))
+    });
+    StreamReader::new(data.chain(check))
+}
+
+async fn upload_with_progress(
+    storage: &StorageManager,
+    file_name: &str,
+    data: &[u8],
+    progress: Option<&EventSender>,
+) -> storage_engine::Result<storage_engine::FileMetadata> {
+    let Some(events) = progress else {
+        return storage.upload_file(file_name, data).await;
+    };
+
+    let operation_id = storage.progress_tracker().start_operation(data.len() as u64).await;
+    let forwarder = forward_progress(storage.progress_tracker(), &operation_id, events).await;
+    let result = storage.upload_file_tracked(file_name, data, &operation_id).await;
+    // Let the last update out before the response is sent
+    if let Some(forwarder) = forwarder {
+        let _ = forwarder.await;
+    }
+    result
+}
+
+async fn download_with_progress(storage: &StorageManager, id: &FileId, progress: Option<&EventSender>) -> storage_engine::Result<Vec<u8>> {
+    let Some(events) = progress else {
+        return storage.download_file(id).await;
+    };
+
+    let size = storage.get_metadata(id).await?.original_len();
+    let operation_id = storage.progress_tracker().start_operation(size).await;
+    let forwarder = forward_progress(storage.progress_tracker(), &operation_id, events).await;
+    let result = storage.download_file_tracked(id, &operation_id).await;
+    if let Some(forwarder) = forwarder {
+        let _ = forwarder.await;
+    }
+    result
+}
+
+/// Downloads a file along with the checksum recorded when it was uploaded, so the client
+/// can tell whether the contents changed anywhere between upload and delivery. Files
+/// stored before content checksums were recorded fall back to a hash of what was read.
+async fn download_with_checksum(storage: &StorageManager, id: &FileId, progress: Option<&EventSender>) -> storage_engine::Result<(String, Vec<u8>)> {
+    let recorded = storage.get_metadata(id).await?.content_checksum;
+    let contents = download_with_progress(storage, id, progress).await?;
+    let checksum = if recorded.is_empty() { DiskStorage::calculate_checksum(&contents) } else { recorded };
+    Ok((checksum, contents))
+}
+
+/// Sends each update of the operation to the client until the operation completes.
+async fn forward_progress(tracker: &ProgressTracker, operation_id: &Uuid, events: &EventSender) -> Option<JoinHandle<()>> {
+    let mut updates = tracker.subscribe(operation_id).await?;
+    let events = events.clone();
+    Some(tokio::spawn(async move {
+        while updates.changed().await.is_ok() {
+            let update = progress_update(&updates.borrow_and_update());
+            if events.send(Ok(MessageRouteEvent { event: Some(Event::Progress(update)) })).await.is_err() {
+                return;
+            }
+        }
+    }))
+}
+
+fn progress_update(stats: &ProgressStats) -> ProgressUpdate {
+    ProgressUpdate {
+        processed_bytes: stats.processed_bytes,
+        total_bytes: stats.total_bytes,
+        current_speed: stats.current_speed,
+        percent_complete: stats.percent_complete,
+        seconds_remaining: stats.estimated_time_remaining.as_secs(),
+    }
+}
+
+/// Rejects names that would corrupt logs or the name index, or escape the store.
+fn validate_file_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(format!("Invalid file name {:?}", name));
+    }
+    if name.len() > 255 {
+        return Err("File name exceeds 255 bytes".to_string());
+    }
+    if let Some(c) = name.chars().find(|c| c.is_control()) {
+        return Err(format!("File name contains control character {:?}", c));
+    }
+    if name.contains(['/', '\\']) {
+        return Err("File name must not contain path separators".to_string());
+    }
+    Ok(())
 }
 
 #[tokio::main]
@@ -328,7 +1163,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let addr = "[::1]:2207".parse().unwrap();
 
-    let brain_service = BrainServiceImpl::new().await?;
+    let brain_service = BrainServiceImpl::new(STORAGE_PATH, Path::new(REGISTRY_PATH)).await;
     info!("Brain service starting on {}", addr);
     let reflection = tonic_reflection::server::Builder::configure().register_encoded_file_descriptor_set(brain_service::FILE_DESCRIPTOR_SET).build_v1()?;
     Server::builder()
@@ -338,3 +1173,548 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::brain_service::{storage_command, ExpectedChecksum};
+
+    async fn storage_handler(dir: &Path) -> StorageHandler {
+        let manager = StorageManager::new(dir.to_str().unwrap()).await.unwrap();
+        StorageHandler { storage: Arc::new(RwLock::new(Some(Arc::new(manager)))) }
+    }
+
+    fn storage_request(operation: storage_command::Operation) -> MessageRouteRequest {
+        MessageRouteRequest {
+            source_component: "cli".to_string(),
+            destination_component: "brain".to_string(),
+            payload: StorageCommand::from(operation).encode_to_vec(),
+            message_type: MessageType::StorageRequest as i32,
+            request_id: String::new(),
+        }
+    }
+
+    async fn upload(handler: &StorageHandler, name: &str, data: &[u8]) -> FileId {
+        let request = storage_request(Operation::Upload(UploadFile { name: name.to_string(), data: data.to_vec() }));
+        let response = handler.handle_storage_message(&request, None).await.unwrap();
+        assert!(response.success, "{}", response.error_message);
+        response.error_message.rsplit(' ').next().unwrap().parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn checked_download_sends_the_checksum_recorded_at_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let handler = storage_handler(dir.path()).await;
+        let id = upload(&handler, "report.txt", b"quarterly numbers").await;
+
+        // Stands in for contents that changed after upload without the read noticing
+        let metadata_path = dir.path().join("metadata").join(format!("{}.json", id));
+        let mut metadata: storage_engine::FileMetadata = serde_json::from_str(&std::fs::read_to_string(&metadata_path).unwrap()).unwrap();
+        let recorded = DiskStorage::calculate_checksum(b"what was uploaded");
+        metadata.content_checksum = recorded.clone();
+        std::fs::write(&metadata_path, serde_json::to_string(&metadata).unwrap()).unwrap();
+
+        let request = storage_request(Operation::Download(DownloadFile {
+            file: Some(FileRef::id(id.to_string())),
+            with_checksum: true,
+        }));
+        let response = handler.handle_storage_message(&request, None).await.unwrap();
+        let (checksum, encoded) = response.error_message.split_once(' ').unwrap();
+        assert_eq!(checksum, recorded);
+        assert_eq!(base64::prelude::BASE64_STANDARD.decode(encoded).unwrap(), b"quarterly numbers");
+    }
+
+    #[tokio::test]
+    async fn file_names_with_spaces_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let handler = storage_handler(dir.path()).await;
+        let id = upload(&handler, "my report.txt", b"quarterly numbers").await;
+
+        let storage = handler.storage().await.unwrap();
+        assert_eq!(storage.get_metadata(&id).await.unwrap().name, "my report.txt");
+        assert_eq!(storage.lookup_name("my report.txt").await.unwrap(), Some(id));
+
+        let request = storage_request(Operation::Download(DownloadFile {
+            file: Some(FileRef::name("my report.txt".to_string())),
+            with_checksum: false,
+        }));
+        let response = handler.handle_storage_message(&request, None).await.unwrap();
+        assert!(response.success, "{}", response.error_message);
+        assert_eq!(base64::prelude::BASE64_STANDARD.decode(response.error_message).unwrap(), b"quarterly numbers");
+    }
+
+    fn upload_parts(name: &str, data: &[u8], size: u64) -> (UploadPart, impl Stream<Item = Result<UploadPart, Status>> + Unpin) {
+        let first = UploadPart { source_component: "api_server".to_string(), name: name.to_string(), size, ..Default::default() };
+        let rest: Vec<_> = data.chunks(4).map(|data| UploadPart { data: data.to_vec(), ..Default::default() }).collect();
+        (first, tokio_stream::iter(rest).map(Ok))
+    }
+
+    #[tokio::test]
+    async fn streamed_upload_is_stored_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = StorageManager::new(dir.path().to_str().unwrap()).await.unwrap();
+        let data = b"streamed straight from the temp file".to_vec();
+
+        let (first, rest) = upload_parts("big.bin", &data, data.len() as u64);
+        let metadata = manager.upload_stream("big.bin", upload_reader(first, rest)).await.unwrap();
+        assert_eq!(manager.download_file(&metadata.id).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn cut_off_upload_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = StorageManager::new(dir.path().to_str().unwrap()).await.unwrap();
+        let data = b"only part of it arrived".to_vec();
+
+        let (first, rest) = upload_parts("short.bin", &data, data.len() as u64 + 10);
+        assert!(manager.upload_stream("short.bin", upload_reader(first, rest)).await.is_err());
+        assert_eq!(manager.lookup_name("short.bin").await.unwrap(), None);
+    }
+
+    async fn brain(dir: &Path) -> BrainServiceImpl {
+        BrainServiceImpl::new(dir.join("storage").to_str().unwrap(), &dir.join("registry.json")).await
+    }
+
+    async fn register(brain: &BrainServiceImpl, id: &str, port: i32) {
+        let registration = ComponentRegistration {
+            component_id: id.to_string(),
+            component_type: ComponentType::Server as i32,
+            ip_address: "127.0.0.1".to_string(),
+            port,
+        };
+        assert!(brain.register_component(Request::new(registration)).await.unwrap().into_inner().success);
+    }
+
+    fn message(source: &str, destination: &str, payload: &[u8]) -> MessageRouteRequest {
+        MessageRouteRequest {
+            source_component: source.to_string(),
+            destination_component: destination.to_string(),
+            payload: payload.to_vec(),
+            message_type: MessageType::ServerCommand as i32,
+            request_id: String::new(),
+        }
+    }
+
+    /// Stands in for a component with its own endpoint, keeping every message routed to it.
+    #[derive(Clone, Default)]
+    struct Recorder {
+        received: Arc<Mutex<Vec<MessageRouteRequest>>>,
+    }
+
+    impl Recorder {
+        /// Serves on an unused local port and returns it.
+        async fn serve(&self) -> i32 {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let incoming = tokio_stream::wrappers::TcpListenerStream::new(listener);
+            tokio::spawn(Server::builder().add_service(BrainServiceServer::new(self.clone())).serve_with_incoming(incoming));
+            port as i32
+        }
+    }
+
+    #[tonic::async_trait]
+    impl BrainService for Recorder {
+        async fn register_component(&self, _request: Request<ComponentRegistration>) -> Result<Response<RegistrationResponse>, Status> {
+            Err(Status::unimplemented("recorder"))
+        }
+
+        async fn unregister_component(&self, _request: Request<UnregistrationRequest>) -> Result<Response<UnregistrationResponse>, Status> {
+            Err(Status::unimplemented("recorder"))
+        }
+
+        async fn heartbeat(&self, _request: Request<HeartbeatRequest>) -> Result<Response<HeartbeatResponse>, Status> {
+            Err(Status::unimplemented("recorder"))
+        }
+
+        async fn route_message(&self, request: Request<MessageRouteRequest>) -> Result<Response<MessageRouteResponse>, Status> {
+            self.received.lock().await.push(request.into_inner());
+            Ok(Response::new(MessageRouteResponse { success: true, error_message: "received".to_string() }))
+        }
+
+        type RouteMessageWithProgressStream = ReceiverStream<Result<MessageRouteEvent, Status>>;
+
+        async fn route_message_with_progress(&self, _request: Request<MessageRouteRequest>) -> Result<Response<Self::RouteMessageWithProgressStream>, Status> {
+            Err(Status::unimplemented("recorder"))
+        }
+
+        async fn stream_upload(&self, _request: Request<Streaming<UploadPart>>) -> Result<Response<MessageRouteResponse>, Status> {
+            Err(Status::unimplemented("recorder"))
+        }
+
+        async fn get_system_status(&self, _request: Request<SystemStatusRequest>) -> Result<Response<SystemStatusResponse>, Status> {
+            Err(Status::unimplemented("recorder"))
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_are_forwarded_to_the_destination_component() {
+        let dir = tempfile::tempdir().unwrap();
+        let brain = brain(dir.path()).await;
+        let recorder = Recorder::default();
+        register(&brain, "sender", 0).await;
+        register(&brain, "receiver", recorder.serve().await).await;
+
+        let response = brain.route_message(Request::new(message("sender", "receiver", b"hello"))).await.unwrap().into_inner();
+        assert!(response.success);
+        assert_eq!(response.error_message, "received");
+
+        let received = recorder.received.lock().await;
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].source_component, "sender");
+        assert_eq!(received[0].payload, b"hello");
+    }
+
+    #[tokio::test]
+    async fn components_without_an_endpoint_are_not_sent_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let brain = brain(dir.path()).await;
+        register(&brain, "cli", 0).await;
+        register(&brain, "api_server", 0).await;
+
+        let status = brain.route_message(Request::new(message("cli", "api_server", b"hello"))).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    }
+
+    #[tokio::test]
+    async fn info_reports_the_uploaded_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let handler = storage_handler(dir.path()).await;
+        let data = b"quarterly numbers\n".repeat(500);
+        let id = upload(&handler, "report.txt", &data).await;
+
+        let request = storage_request(Operation::Info(GetFileInfo { file: Some(FileRef::name("report.txt".to_string())) }));
+        let response = handler.handle_storage_message(&request, None).await.unwrap();
+        assert!(response.success, "{}", response.error_message);
+        let lines: Vec<&str> = response.error_message.lines().collect();
+        assert!(lines.contains(&format!("ID: {}", id).as_str()));
+        assert!(lines.contains(&format!("Size: {} bytes", data.len()).as_str()));
+    }
+
+    #[tokio::test]
+    async fn registering_an_existing_id_updates_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let brain = brain(dir.path()).await;
+        register(&brain, "api_server", 8000).await;
+        register(&brain, "api_server", 9000).await;
+
+        let state = brain.state.lock().await;
+        assert_eq!(state.components.len(), 1);
+        assert_eq!(state.components["api_server"].port, 9000);
+    }
+
+    #[tokio::test]
+    async fn progress_follows_an_operation_until_it_completes() {
+        let dir = tempfile::tempdir().unwrap();
+        let handler = storage_handler(dir.path()).await;
+        let tracker = handler.storage().await.unwrap().progress_tracker().clone();
+        let operation_id = tracker.start_operation(100).await;
+        let request = storage_request(Operation::Progress(GetProgress { operation_id: operation_id.to_string() }));
+
+        let mut reported = Vec::new();
+        for processed in [25, 60, 100] {
+            tracker.update_progress(&operation_id, processed).await;
+            let response = handler.handle_storage_message(&request, None).await.unwrap();
+            assert!(response.success, "{}", response.error_message);
+            let fields: Vec<u64> = response.error_message.split_whitespace().map(|f| f.parse().unwrap()).collect();
+            assert_eq!(fields[1], 100);
+            reported.push(fields[0]);
+        }
+        assert_eq!(reported, [25, 60, 100]);
+
+        // The CLI takes a forgotten operation as a finished one
+        tracker.complete_operation(&operation_id).await;
+        assert!(!handler.handle_storage_message(&request, None).await.unwrap().success);
+    }
+
+    #[tokio::test]
+    async fn upload_with_control_characters_in_its_name_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let handler = storage_handler(dir.path()).await;
+        let request = storage_request(Operation::Upload(UploadFile { name: "report\u{1b}[2J\n.txt".to_string(), data: b"numbers".to_vec() }));
+
+        let status = handler.handle_storage_message(&request, None).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(handler.storage().await.unwrap().list_files().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn file_names_with_path_separators_are_rejected() {
+        assert!(validate_file_name("../etc/passwd").is_err());
+        assert!(validate_file_name("dir\\file.txt").is_err());
+        assert!(validate_file_name("..").is_err());
+        assert!(validate_file_name("").is_err());
+        assert!(validate_file_name("my report (final).txt").is_ok());
+    }
+
+    /// Collects formatted log lines for tests that check what gets logged.
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn request_id_is_logged_by_the_brain_and_the_storage_engine() {
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt().with_ansi(false).with_writer(move || writer.clone()).finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let dir = tempfile::tempdir().unwrap();
+        let brain = brain(dir.path()).await;
+        register(&brain, "cli", 0).await;
+        let mut request = storage_request(Operation::Upload(UploadFile { name: "report.txt".to_string(), data: b"numbers".to_vec() }));
+        request.request_id = "req-7f3a".to_string();
+        assert!(brain.route_message(Request::new(request)).await.unwrap().into_inner().success);
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let tagged: Vec<&str> = logs.lines().filter(|line| line.contains("request_id=req-7f3a")).collect();
+        assert!(tagged.iter().any(|line| line.contains("Received message")), "{}", logs);
+        assert!(tagged.iter().any(|line| line.contains("store_file")), "{}", logs);
+    }
+
+    #[tokio::test]
+    async fn exists_batch_answers_one_line_per_key_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let handler = storage_handler(dir.path()).await;
+        let id = upload(&handler, "kept.txt", b"still here").await;
+        let missing = FileId::new();
+
+        let request = storage_request(Operation::ExistsBatch(ExistsBatch {
+            by_checksum: false,
+            keys: vec![missing.to_string(), id.to_string()],
+        }));
+        let response = handler.handle_storage_message(&request, None).await.unwrap();
+        assert!(response.success, "{}", response.error_message);
+        assert_eq!(response.error_message, format!("{}: false\n{}: true", missing, id));
+
+        let checksum = DiskStorage::calculate_checksum(b"still here");
+        let request = storage_request(Operation::ExistsBatch(ExistsBatch { by_checksum: true, keys: vec![checksum.clone()] }));
+        let response = handler.handle_storage_message(&request, None).await.unwrap();
+        assert_eq!(response.error_message, format!("{}: true", checksum));
+    }
+
+    #[tokio::test]
+    async fn exists_batch_rejects_malformed_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let handler = storage_handler(dir.path()).await;
+
+        let request = storage_request(Operation::ExistsBatch(ExistsBatch { by_checksum: false, keys: vec!["not-an-id".to_string()] }));
+        let status = handler.handle_storage_message(&request, None).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn storage_commands_are_unavailable_until_the_backend_comes_up() {
+        let dir = tempfile::tempdir().unwrap();
+        // A file where the storage directory should be keeps the backend from opening
+        let storage_path = dir.path().join("storage");
+        std::fs::write(&storage_path, b"not a directory").unwrap();
+        let brain = BrainServiceImpl::new(storage_path.to_str().unwrap(), &dir.path().join("registry.json")).await;
+
+        // Registration and status keep working while degraded
+        register(&brain, "cli", 0).await;
+        let status = brain.get_system_status(Request::new(SystemStatusRequest {})).await.unwrap().into_inner();
+        assert_eq!(status.registered_components.len(), 1);
+
+        let list = || storage_request(Operation::List(ListFiles {}));
+        let status = brain.route_message(Request::new(list())).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+
+        std::fs::remove_file(&storage_path).unwrap();
+        reconnect_storage(Arc::clone(&brain.storage), storage_path.to_str().unwrap().to_string(), Duration::from_millis(10)).await;
+        let response = brain.route_message(Request::new(list())).await.unwrap().into_inner();
+        assert!(response.success, "{}", response.error_message);
+    }
+
+    fn component(id: &str, component_type: ComponentType, silent_for: Duration) -> RegisteredComponent {
+        RegisteredComponent {
+            id: id.to_string(),
+            component_type,
+            ip_address: "127.0.0.1".to_string(),
+            port: 0,
+            status: ComponentStatus::Running,
+            last_seen: Instant::now() - silent_for,
+        }
+    }
+
+    #[test]
+    fn stale_components_leave_the_system_degraded() {
+        let policy = HealthPolicy::default();
+        let fresh: Vec<_> = (0..3).map(|i| component(&format!("server-{}", i), ComponentType::Server, Duration::ZERO)).collect();
+        let stale: Vec<_> = (0..3).map(|i| component(&format!("server-{}", i), ComponentType::Server, Duration::from_secs(120))).collect();
+
+        assert_eq!(policy.classify(&fresh, true), SystemHealth::Healthy);
+        assert_eq!(policy.classify(&stale, true), SystemHealth::Degraded);
+        assert_eq!(policy.classify(&fresh, false), SystemHealth::Degraded);
+        assert_eq!(policy.classify(&[], true), SystemHealth::Critical);
+    }
+
+    #[test]
+    fn required_types_must_have_a_healthy_component() {
+        let policy = HealthPolicy { min_healthy: 1, required_types: vec![ComponentType::Cli], ..HealthPolicy::default() };
+        let servers = [component("server-1", ComponentType::Server, Duration::ZERO)];
+        let mut with_cli = servers.to_vec();
+        with_cli.push(component("cli-1", ComponentType::Cli, Duration::ZERO));
+
+        assert_eq!(policy.classify(&servers, true), SystemHealth::Degraded);
+        assert_eq!(policy.classify(&with_cli, true), SystemHealth::Healthy);
+    }
+
+    #[tokio::test]
+    async fn verify_checksums_answers_one_line_per_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let handler = storage_handler(dir.path()).await;
+        upload(&handler, "same.txt", b"unchanged").await;
+        upload(&handler, "edited.txt", b"edited remotely").await;
+
+        let expected = |name: &str, data: &[u8]| ExpectedChecksum { name: name.to_string(), checksum: DiskStorage::calculate_checksum(data) };
+        let request = storage_request(Operation::VerifyChecksums(VerifyChecksums {
+            files: vec![expected("same.txt", b"unchanged"), expected("edited.txt", b"edited locally"), expected("gone.txt", b"never stored")],
+        }));
+        let response = handler.handle_storage_message(&request, None).await.unwrap();
+        assert!(response.success, "{}", response.error_message);
+        assert_eq!(response.error_message, "same.txt: match\nedited.txt: differs\ngone.txt: missing");
+    }
+
+    #[tokio::test]
+    async fn a_full_storage_queue_rejects_instead_of_buffering() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut brain = brain(dir.path()).await;
+        brain.storage_jobs = spawn_storage_workers(StorageHandler { storage: Arc::clone(&brain.storage) }, 1, 1);
+        brain.stream_uploads = Semaphore::new(1);
+
+        // Holding the storage slot keeps the only worker busy with the first command
+        let busy = brain.storage.write().await;
+        let mut accepted = Vec::new();
+        let mut uploads = Vec::new();
+        let mut rejected = 0;
+        for _ in 0..10 {
+            match brain.queue_storage_message(storage_request(Operation::List(ListFiles {})), Span::none(), None) {
+                Ok(response) => accepted.push(response),
+                Err(status) => {
+                    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+                    rejected += 1;
+                }
+            }
+            match brain.claim_upload_slot() {
+                Ok(slot) => uploads.push(slot),
+                Err(status) => {
+                    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+                    rejected += 1;
+                }
+            }
+        }
+        // One command being worked on and one waiting in the queue at most, and one upload
+        assert!(accepted.len() <= 2 && uploads.len() == 1 && rejected >= 17, "{} accepted", accepted.len());
+
+        drop(busy);
+        for response in accepted {
+            assert!(response.await.unwrap().unwrap().success);
+        }
+        drop(uploads);
+        assert!(brain.claim_upload_slot().is_ok());
+    }
+
+    async fn backdate(brain: &BrainServiceImpl, id: &str, silent_for: Duration) {
+        brain.state.lock().await.components.get_mut(id).unwrap().last_seen = Instant::now() - silent_for;
+    }
+
+    async fn status_of(brain: &BrainServiceImpl, id: &str) -> Option<i32> {
+        let status = brain.get_system_status(Request::new(SystemStatusRequest {})).await.unwrap().into_inner();
+        status.registered_components.iter().find(|component| component.component_id == id).map(|component| component.status)
+    }
+
+    #[tokio::test]
+    async fn lapsed_heartbeats_mark_components_unresponsive_then_remove_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let brain = brain(dir.path()).await;
+        register(&brain, "api_server", 0).await;
+        // The first check runs as soon as the task starts
+        let expire = |stale_after, remove_after| {
+            tokio::spawn(expire_components(Arc::clone(&brain.state), Arc::clone(&brain.connections), stale_after, remove_after))
+        };
+
+        backdate(&brain, "api_server", Duration::from_secs(45)).await;
+        let task = expire(Duration::from_secs(30), Duration::from_secs(60));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        task.abort();
+        assert_eq!(status_of(&brain, "api_server").await, Some(ComponentStatus::Unresponsive as i32));
+
+        let heartbeat = HeartbeatRequest { component_id: "api_server".to_string() };
+        assert!(brain.heartbeat(Request::new(heartbeat)).await.unwrap().into_inner().success);
+        assert_eq!(status_of(&brain, "api_server").await, Some(ComponentStatus::Running as i32));
+
+        backdate(&brain, "api_server", Duration::from_secs(120)).await;
+        let task = expire(Duration::from_secs(30), Duration::from_secs(60));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        task.abort();
+        assert_eq!(status_of(&brain, "api_server").await, None);
+        let heartbeat = HeartbeatRequest { component_id: "api_server".to_string() };
+        assert_eq!(brain.heartbeat(Request::new(heartbeat)).await.unwrap_err().code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn registrations_and_system_id_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let system_id = {
+            let brain = brain(dir.path()).await;
+            register(&brain, "api_server", 8000).await;
+            register(&brain, "cli", 0).await;
+            brain.get_system_status(Request::new(SystemStatusRequest {})).await.unwrap().into_inner().system_id
+        };
+
+        let restarted = brain(dir.path()).await;
+        let status = restarted.get_system_status(Request::new(SystemStatusRequest {})).await.unwrap().into_inner();
+        assert_eq!(status.system_id, system_id);
+        let mut ids: Vec<String> = status.registered_components.iter().map(|component| component.component_id.clone()).collect();
+        ids.sort();
+        assert_eq!(ids, ["api_server", "cli"]);
+        assert_eq!(restarted.state.lock().await.components["api_server"].port, 8000);
+    }
+
+    #[tokio::test]
+    async fn a_corrupt_registry_starts_fresh() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("registry.json"), b"{\"system_id\": ").unwrap();
+
+        let brain = brain(dir.path()).await;
+        assert!(brain.state.lock().await.components.is_empty());
+        register(&brain, "cli", 0).await;
+        assert_eq!(brain.state.lock().await.components.len(), 1);
+    }
+
+    #[test]
+    fn storage_commands_round_trip_through_their_encoding() {
+        let operations = [
+            Operation::Upload(UploadFile { name: "my report (final).txt".to_string(), data: b"numbers".to_vec() }),
+            Operation::Download(DownloadFile { file: Some(FileRef::name("my report (final).txt")), with_checksum: true }),
+            Operation::Delete(DeleteFile { file: Some(FileRef::id(FileId::new().to_string())) }),
+            Operation::List(ListFiles {}),
+        ];
+        for operation in operations {
+            let command = StorageCommand::from(operation);
+            assert_eq!(StorageCommand::decode(command.encode_to_vec().as_slice()).unwrap(), command);
+        }
+    }
+
+    #[tokio::test]
+    async fn space_separated_string_commands_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let handler = storage_handler(dir.path()).await;
+        let mut request = storage_request(Operation::List(ListFiles {}));
+        request.payload = b"upload my report.txt bnVtYmVycw==".to_vec();
+
+        let status = handler.handle_storage_message(&request, None).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(handler.storage().await.unwrap().list_files().await.unwrap().is_empty());
+    }
+}
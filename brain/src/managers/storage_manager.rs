@@ -1,46 +1,230 @@
-use storage_engine::storage::disk::{DiskStorage, StorageBackend};
-use storage_engine::FileMetadata;
+use storage_engine::storage::disk::{ChecksumStatus, CompactionReport, CompressionReport, DiskStorage, EncryptionAudit, StorageBackend};
+use storage_engine::storage::progress::{ProgressStats, ProgressTracker};
+use storage_engine::{AppError, FileId, FileMetadata, StorageError};
 use storage_engine::Result;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::io::AsyncRead;
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
 
 #[derive(Clone)]
+/// Reads and streamed uploads share the storage lock; other uploads, updates and deletes
+/// take it exclusively.
 pub struct StorageManager {
-    inner: Arc<Mutex<DiskStorage>>
+    inner: Arc<RwLock<DiskStorage>>,
+    storage_path: PathBuf,
+    // Shared with the storage, so progress can be followed while an upload holds the lock
+    progress: ProgressTracker,
 }
 
 impl StorageManager {
     pub async fn new(storage_path: &str) -> Result<Self> {
-        let storage = DiskStorage::new(storage_path)
+        // Resolve the path up front so the store doesn't depend on the working directory
+        tokio::fs::create_dir_all(storage_path).await.map_err(|e| {
+            AppError::Storage(StorageError::Storage(format!("Cannot create storage directory {}: {}", storage_path, e)))
+        })?;
+        let storage_path = tokio::fs::canonicalize(storage_path).await.map_err(|e| {
+            AppError::Storage(StorageError::Storage(format!("Cannot resolve storage directory {}: {}", storage_path, e)))
+        })?;
+        info!("Using storage directory {}", storage_path.display());
+
+        let storage = DiskStorage::new(&storage_path)
         .await?
-        .with_encryption([0u8; 32])
         .with_cache(100)
         .with_compression(true);
+        let storage = match std::env::var("STORAGE_PASSPHRASE") {
+            Ok(passphrase) if !passphrase.is_empty() => storage.with_passphrase(&passphrase)?,
+            _ => {
+                // Kept so stores created before passphrases existed stay readable
+                warn!("STORAGE_PASSPHRASE is not set, falling back to the insecure default key");
+                storage.with_encryption([0u8; 32])
+            }
+        };
+
+        let progress = storage.progress_tracker();
+        Ok(Self { inner: Arc::new(RwLock::new(storage)), storage_path, progress })
+    }
 
-        Ok(Self { inner: Arc::new(Mutex::new(storage)) })
+    pub fn storage_path(&self) -> &Path {
+        &self.storage_path
     }
 
-    pub fn get_arc_mutex(&self) -> Arc<Mutex<DiskStorage>> {
+    pub fn get_arc_rwlock(&self) -> Arc<RwLock<DiskStorage>> {
         Arc::clone(&self.inner)
     }
 
     pub async fn upload_file(&self, filename: &str, data: &[u8]) -> Result<FileMetadata> {
-        let storage = self.inner.lock().await;
+        let storage = self.inner.write().await;
         storage.store_file(filename, data).await
     }
 
-    pub async fn download_file(&self, file_id: &uuid::Uuid) -> Result<Vec<u8>> {
-        let storage = self.inner.lock().await;
+    /// Uploads like `upload_file`, advancing `operation_id`, started on `progress_tracker`.
+    pub async fn upload_file_tracked(&self, filename: &str, data: &[u8], operation_id: &uuid::Uuid) -> Result<FileMetadata> {
+        let storage = self.inner.write().await;
+        storage.store_file_tracked(filename, data, operation_id).await
+    }
+
+    /// Uploads a file read from `reader`. The data may trickle in over the network for a
+    /// long time, so this shares the lock with reads instead of blocking them;
+    /// `DiskStorage` keeps concurrent chunk writes safe on its own.
+    pub async fn upload_stream<R: AsyncRead + Unpin>(&self, filename: &str, reader: R) -> Result<FileMetadata> {
+        let storage = self.inner.read().await;
+        storage.store_file_stream(filename, reader).await
+    }
+
+    pub async fn update_file(&self, file_id: &FileId, data: &[u8]) -> Result<FileMetadata> {
+        let storage = self.inner.write().await;
+        storage.update_file(file_id, data).await
+    }
+
+    pub async fn lookup_name(&self, name: &str) -> Result<Option<FileId>> {
+        let storage = self.inner.read().await;
+        storage.lookup_name(name).await
+    }
+
+    pub async fn download_file(&self, file_id: &FileId) -> Result<Vec<u8>> {
+        let storage = self.inner.read().await;
         storage.get_file(file_id).await
     }
 
+    /// Downloads like `download_file`, advancing `operation_id`, started on `progress_tracker`.
+    pub async fn download_file_tracked(&self, file_id: &FileId, operation_id: &uuid::Uuid) -> Result<Vec<u8>> {
+        let storage = self.inner.read().await;
+        storage.get_file_tracked(file_id, operation_id).await
+    }
+
+    pub async fn download_range(&self, file_id: &FileId, start: u64, end: u64) -> Result<Vec<u8>> {
+        let storage = self.inner.read().await;
+        storage.get_file_range(file_id, start, end).await
+    }
+
     pub async fn list_files(&self) -> Result<Vec<FileMetadata>> {
-        let storage = self.inner.lock().await;
+        let storage = self.inner.read().await;
         storage.list_files().await
     }
 
-    pub async fn delete_file(&self, file_id: &uuid::Uuid) -> Result<()> {
-        let storage = self.inner.lock().await;
+    pub async fn get_metadata(&self, file_id: &FileId) -> Result<FileMetadata> {
+        let storage = self.inner.read().await;
+        storage.get_metadata(file_id).await
+    }
+
+    pub async fn exists_many(&self, file_ids: &[FileId]) -> Result<HashMap<FileId, bool>> {
+        let storage = self.inner.read().await;
+        storage.exists_many(file_ids).await
+    }
+
+    pub async fn exists_many_by_checksum(&self, checksums: &[String]) -> Result<HashMap<String, bool>> {
+        let storage = self.inner.read().await;
+        storage.exists_many_by_checksum(checksums).await
+    }
+
+    pub async fn verify_checksums(&self, expected: &[(String, String)]) -> Result<Vec<ChecksumStatus>> {
+        let storage = self.inner.read().await;
+        storage.verify_checksums(expected).await
+    }
+
+    pub async fn get_progress(&self, operation_id: &uuid::Uuid) -> Option<ProgressStats> {
+        self.progress.get_progress(operation_id).await
+    }
+
+    pub fn progress_tracker(&self) -> &ProgressTracker {
+        &self.progress
+    }
+
+    pub async fn encryption_audit(&self) -> Result<EncryptionAudit> {
+        let storage = self.inner.read().await;
+        storage.encryption_audit().await
+    }
+
+    pub async fn compression_report(&self) -> Result<CompressionReport> {
+        let storage = self.inner.read().await;
+        storage.compression_report().await
+    }
+
+    pub async fn compact(&self) -> Result<CompactionReport> {
+        let storage = self.inner.write().await;
+        storage.compact().await
+    }
+
+    /// Runs `compact` every `interval` until the returned task is aborted.
+    pub fn spawn_compaction(&self, interval: Duration) -> JoinHandle<()> {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // The first tick completes immediately; skip it so startup isn't slowed down
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                match manager.compact().await {
+                    Ok(report) => info!(
+                        "Compaction rewrote {} files, removed {} chunks and {} index entries",
+                        report.files_compacted, report.chunks_removed, report.index_entries_removed
+                    ),
+                    Err(e) => warn!("Compaction failed: {}", e),
+                }
+            }
+        })
+    }
+
+    pub async fn delete_file(&self, file_id: &FileId) -> Result<()> {
+        let storage = self.inner.write().await;
         storage.delete_file(file_id).await
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn storage_path_is_resolved_to_a_canonical_absolute_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join("..").join("store");
+
+        let manager = StorageManager::new(path.to_str().unwrap()).await.unwrap();
+        assert!(manager.storage_path().is_absolute());
+        assert_eq!(manager.storage_path(), dir.path().canonicalize().unwrap().join("store"));
+    }
+
+    #[tokio::test]
+    async fn uncreatable_storage_path_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("not_a_directory");
+        std::fs::write(&file, b"").unwrap();
+
+        assert!(StorageManager::new(file.join("store").to_str().unwrap()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn downloads_share_the_storage_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = StorageManager::new(dir.path().to_str().unwrap()).await.unwrap();
+        let stored = manager.upload_file("notes.txt", b"read by many").await.unwrap();
+
+        // Stands in for a slow download still holding its read guard
+        let storage = manager.get_arc_rwlock();
+        let _reading = storage.read().await;
+        let mut downloads = tokio::task::JoinSet::new();
+        for _ in 0..8 {
+            let manager = manager.clone();
+            downloads.spawn(async move { manager.download_file(&stored.id).await });
+        }
+        let results = tokio::time::timeout(Duration::from_secs(5), downloads.join_all()).await.expect("downloads waited on each other");
+        assert!(results.into_iter().all(|data| data.unwrap() == b"read by many"));
+    }
+
+    #[tokio::test]
+    async fn uploads_wait_for_downloads_to_finish() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = StorageManager::new(dir.path().to_str().unwrap()).await.unwrap();
+
+        let storage = manager.get_arc_rwlock();
+        let reading = storage.read().await;
+        assert!(tokio::time::timeout(Duration::from_millis(100), manager.upload_file("notes.txt", b"new")).await.is_err());
+        drop(reading);
+        manager.upload_file("notes.txt", b"new").await.unwrap();
+    }
+}
@@ -1,19 +1,39 @@
 use clap::{Parser, Subcommand};
+use indicatif::{ProgressBar, ProgressStyle};
 use tonic::{Request, transport::Channel};
 use std::error::Error;
 use base64::prelude::*;
 use std::fs;
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 use common::brain_service;
+use prost::Message;
+use storage_engine::crypto::encryption::{generate_salt, EncryptionConfig, SALT_LEN};
+use storage_engine::storage::disk::DiskStorage;
+use storage_engine::storage::progress::{ProgressFormatter, ProgressStats};
 
 use brain_service::{
     brain_service_client::BrainServiceClient,
+    message_route_event::Event,
+    storage_command::Operation,
     ComponentRegistration,
     UnregistrationRequest,
     MessageRouteRequest,
     ComponentType,
     MessageType,
+    ProgressUpdate,
+    StorageCommand,
+    FileRef,
+    ListFiles,
+    UploadFile,
+    DownloadFile,
+    DeleteFile,
+    GetFileInfo,
+    GetProgress,
+    EncryptionAudit,
+    CompressionStats,
 };
 
 #[derive(Parser)]
@@ -23,6 +43,10 @@ pub struct Cli {
     #[arg(short, long, default_value = "[::1]:2207")]
     server_address: String,
 
+    /// Don't draw a progress bar for uploads and downloads
+    #[arg(long, global = true)]
+    no_progress: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -45,11 +69,54 @@ pub enum Commands {
 
         #[arg(short, long)]
         output: PathBuf,
+
+        /// Check the downloaded bytes against the checksum reported by the brain
+        #[arg(long)]
+        verify: bool,
     },
 
     /// List files in storage
     List,
 
+    /// Show a file's metadata without downloading it
+    Info {
+        #[arg(short = 'i', long = "file-id")]
+        file_id: Option<String>,
+
+        #[arg(short = 'n', long = "file-name")]
+        file_name: Option<String>,
+    },
+
+    /// Report which stored files are still unencrypted
+    EncryptionAudit,
+
+    /// Show how well each file type compresses on average
+    Stats,
+
+    /// Encrypt a local file with a password, without contacting storage. The password
+    /// is read from STORAGE_CLI_PASSWORD, or prompted for when that isn't set
+    Encrypt {
+        #[arg(long = "in")]
+        input: PathBuf,
+
+        #[arg(long = "out")]
+        output: PathBuf,
+    },
+
+    /// Decrypt a file produced by `encrypt`, taking the password like `encrypt` does
+    Decrypt {
+        #[arg(long = "in")]
+        input: PathBuf,
+
+        #[arg(long = "out")]
+        output: PathBuf,
+    },
+
+    /// Watch the progress of an ongoing storage operation
+    Progress {
+        operation_id: String,
+    },
+
     /// Delete a file from storage
     Delete {
         #[arg(short = 'i', long = "file-id")]
@@ -110,13 +177,18 @@ impl StorageCli {
         Ok(())
     }
 
-    async fn send_storage_command(&mut self, command: String) -> Result<String, Box<dyn Error>> {
-        let request = Request::new(MessageRouteRequest{
+    fn storage_request(&self, operation: Operation) -> Request<MessageRouteRequest> {
+        Request::new(MessageRouteRequest{
             source_component: self.component_id.clone(),
             destination_component: "brain".to_string(),
-            payload: command.into_bytes(),
+            payload: StorageCommand::from(operation).encode_to_vec(),
             message_type: MessageType::StorageRequest as i32,
-        });
+            request_id: Uuid::new_v4().to_string(),
+        })
+    }
+
+    async fn send_storage_command(&mut self, operation: Operation) -> Result<String, Box<dyn Error>> {
+        let request = self.storage_request(operation);
 
         let response = self.client.route_message(request).await?;
         let response_inner = response.into_inner();
@@ -128,71 +200,276 @@ impl StorageCli {
         }
     }
 
-    async fn upload_file(&mut self, file_path: &Path) -> Result<String, Box<dyn Error>> {
+    /// Sends a storage command, drawing a progress bar from the updates the brain streams
+    /// back while it runs.
+    async fn send_storage_command_with_progress(&mut self, operation: Operation) -> Result<String, Box<dyn Error>> {
+        let request = self.storage_request(operation);
+        let mut events = self.client.route_message_with_progress(request).await?.into_inner();
+
+        let bar = ProgressBar::new(0);
+        bar.set_style(ProgressStyle::with_template("[{bar:40}] {msg}")?.progress_chars("## "));
+        while let Some(event) = events.message().await? {
+            match event.event {
+                Some(Event::Progress(update)) => {
+                    bar.set_length(update.total_bytes);
+                    bar.set_position(update.processed_bytes);
+                    bar.set_message(progress_message(&update));
+                }
+                Some(Event::Response(response)) => {
+                    bar.finish();
+                    return if response.success {
+                        Ok(response.error_message)
+                    } else {
+                        Err(response.error_message.into())
+                    };
+                }
+                None => {}
+            }
+        }
+
+        bar.abandon();
+        Err("Brain ended the progress stream without a response".into())
+    }
+
+    async fn run_storage_command(&mut self, operation: Operation, show_progress: bool) -> Result<String, Box<dyn Error>> {
+        if show_progress {
+            self.send_storage_command_with_progress(operation).await
+        } else {
+            self.send_storage_command(operation).await
+        }
+    }
+
+    async fn upload_file(&mut self, file_path: &Path, show_progress: bool) -> Result<String, Box<dyn Error>> {
         if !file_path.exists() {
             return Err(format!("File not found: {}", file_path.display()).into());
         }
 
-        let file_data = fs::read(&file_path)?;
+        let file_data = fs::read(file_path)?;
 
         let filename = file_path.file_name().ok_or("Invalid filename")?.to_str().ok_or("Invalid filename")?;
-        let encoded_data = BASE64_STANDARD.encode(&file_data);
 
-        let command = format!("upload {} {}", filename, encoded_data);
+        let operation = Operation::Upload(UploadFile {
+            name: filename.to_string(),
+            data: file_data,
+        });
 
-        let result = self.send_storage_command(command).await?;
+        let result = self.run_storage_command(operation, show_progress).await?;
         
         Ok(result)
     }
 
-    async fn download_file(&mut self, parameter_type: &str, parameter: String, output: PathBuf) -> Result<String, Box<dyn Error>> {
-        let command = format!("download {} {}", parameter_type, parameter);
-        let result = self.send_storage_command(command).await?;
-        let decoded_data = BASE64_STANDARD.decode(&result)?;
+    async fn download_file(&mut self, file: FileRef, output: PathBuf, verify: bool, show_progress: bool) -> Result<String, Box<dyn Error>> {
+        let operation = Operation::Download(DownloadFile {
+            file: Some(file),
+            with_checksum: verify,
+        });
+        let result = self.run_storage_command(operation, show_progress).await?;
+        let decoded_data = if verify {
+            decode_checked_payload(&result)?
+        } else {
+            BASE64_STANDARD.decode(&result)?
+        };
         fs::write(&output, decoded_data)?;
 
         Ok(format!("File downloaded to {}", output.display()))
     }
 
-    async fn delete_file(&mut self, parameter_type: &str, parameter: String)  -> Result<String, Box<dyn Error>> {
-        let command = format!("delete {} {}", parameter_type, parameter);
-        let result = self.send_storage_command(command).await?;
+    async fn file_info(&mut self, file: FileRef) -> Result<String, Box<dyn Error>> {
+        let operation = Operation::Info(GetFileInfo { file: Some(file) });
+        let result = self.send_storage_command(operation).await?;
+
+        Ok(result)
+    }
+
+    async fn watch_progress(&mut self, operation_id: &str) -> Result<String, Box<dyn Error>> {
+        const BAR_WIDTH: usize = 40;
+        let mut seen = false;
+
+        loop {
+            // The brain forgets an operation once it completes
+            let operation = Operation::Progress(GetProgress {
+                operation_id: operation_id.to_string(),
+            });
+            let status = match self.send_storage_command(operation).await {
+                Ok(status) => status,
+                Err(_) if seen => {
+                    println!();
+                    return Ok(format!("Operation {} completed", operation_id));
+                }
+                Err(e) => return Err(e),
+            };
+            seen = true;
+
+            let fields: Vec<u64> = status.split_whitespace().filter_map(|f| f.parse().ok()).collect();
+            let [processed, total, speed, eta] = fields[..] else {
+                return Err(format!("Unexpected progress response: {}", status).into());
+            };
+
+            let ratio = if total == 0 { 1.0 } else { (processed as f64 / total as f64).min(1.0) };
+            let filled = (ratio * BAR_WIDTH as f64) as usize;
+            print!(
+                "\r[{}{}] {:>5.1}% ({}/{} bytes, {} B/s, {}s remaining)",
+                "#".repeat(filled),
+                " ".repeat(BAR_WIDTH - filled),
+                ratio * 100.0,
+                processed,
+                total,
+                speed,
+                eta
+            );
+            std::io::stdout().flush()?;
+
+            if processed >= total {
+                println!();
+                return Ok(format!("Operation {} completed", operation_id));
+            }
+
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+
+    async fn delete_file(&mut self, file: FileRef)  -> Result<String, Box<dyn Error>> {
+        let operation = Operation::Delete(DeleteFile { file: Some(file) });
+        let result = self.send_storage_command(operation).await?;
 
         Ok(result)
     }
 }
 
+/// The file named by `--file-id`, or else `--file-name`.
+fn file_ref(file_id: Option<String>, file_name: Option<String>) -> Result<FileRef, Box<dyn Error>> {
+    match (file_id, file_name) {
+        (Some(id), _) => Ok(FileRef::id(id)),
+        (None, Some(name)) => Ok(FileRef::name(name)),
+        _ => Err("Either file ID or file name must be provided".into()),
+    }
+}
+
+/// Percentage, speed and time remaining for a progress update, e.g.
+/// `50.0% (512/1024 bytes), 2.00 KB/s, 0s remaining`.
+fn progress_message(update: &ProgressUpdate) -> String {
+    let stats = ProgressStats {
+        total_bytes: update.total_bytes,
+        processed_bytes: update.processed_bytes,
+        // Not used by the formatters
+        start_time: Instant::now(),
+        current_speed: update.current_speed,
+        percent_complete: update.percent_complete,
+        estimated_time_remaining: Duration::from_secs(update.seconds_remaining),
+    };
+    format!("{}, {}, {}", stats.format_progress(), stats.format_speed(), stats.format_time_remaining())
+}
+
+/// Decodes the response to a download `with_checksum`, `<sha256 hex> <base64 data>`,
+/// failing if the decoded bytes don't hash to the checksum the brain reported.
+fn decode_checked_payload(response: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (expected, encoded) = response
+        .split_once(' ')
+        .ok_or("Malformed checked download response")?;
+    let data = BASE64_STANDARD.decode(encoded)?;
+    let actual = DiskStorage::calculate_checksum(&data);
+    if actual != expected {
+        return Err(format!("Checksum mismatch: expected {}, got {}", expected, actual).into());
+    }
+
+    Ok(data)
+}
+
+// Kept off the command line so the password doesn't show up in `ps` or shell history
+fn read_password() -> Result<String, Box<dyn Error>> {
+    match std::env::var("STORAGE_CLI_PASSWORD") {
+        Ok(password) if !password.is_empty() => Ok(password),
+        _ => rpassword::prompt_password("Password: ").map_err(|e| format!("Cannot prompt for a password ({}), set STORAGE_CLI_PASSWORD instead", e).into()),
+    }
+}
+
+// Offline encrypted files are the magic, the key derivation salt, then the ciphertext
+const ENCRYPTED_FILE_MAGIC: &[u8] = b"SCE1";
+
+fn encrypt_local_file(input: &Path, output: &Path, password: &str) -> Result<String, Box<dyn Error>> {
+    let data = fs::read(input)?;
+    let salt = generate_salt();
+    let config = EncryptionConfig::from_passphrase(password, &salt)?;
+    let ciphertext = config.encrypt(&data, None)?;
+
+    let mut encrypted = Vec::with_capacity(ENCRYPTED_FILE_MAGIC.len() + SALT_LEN + ciphertext.len());
+    encrypted.extend_from_slice(ENCRYPTED_FILE_MAGIC);
+    encrypted.extend_from_slice(&salt);
+    encrypted.extend_from_slice(&ciphertext);
+    fs::write(output, encrypted)?;
+
+    Ok(format!("Encrypted {} to {}", input.display(), output.display()))
+}
+
+fn decrypt_local_file(input: &Path, output: &Path, password: &str) -> Result<String, Box<dyn Error>> {
+    let data = fs::read(input)?;
+    let body = data
+        .strip_prefix(ENCRYPTED_FILE_MAGIC)
+        .filter(|body| body.len() >= SALT_LEN)
+        .ok_or_else(|| format!("{} is not an encrypted file", input.display()))?;
+    let (salt, ciphertext) = body.split_at(SALT_LEN);
+
+    let config = EncryptionConfig::from_passphrase(password, salt)?;
+    fs::write(output, config.decrypt(ciphertext, None)?)?;
+
+    Ok(format!("Decrypted {} to {}", input.display(), output.display()))
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
+
+    // Local crypto commands don't need a brain connection
+    match &cli.command {
+        Commands::Encrypt { input, output } => {
+            println!("{}", encrypt_local_file(input, output, &read_password()?)?);
+            return Ok(());
+        },
+        Commands::Decrypt { input, output } => {
+            println!("{}", decrypt_local_file(input, output, &read_password()?)?);
+            return Ok(());
+        },
+        _ => {}
+    }
+
     let mut storage_cli = StorageCli::new(&cli.server_address).await?;
+    let show_progress = !cli.no_progress;
 
     match cli.command {
         Commands::Upload { file } => {
-            let result = storage_cli.upload_file(&file).await?;
+            let result = storage_cli.upload_file(&file, show_progress).await?;
             println!("{}", result);
 
         },
-        Commands::Download { file_id, file_name, output } => {
-            let result = match (file_id, file_name) {
-                (Some(id), _) => storage_cli.download_file("id", id, output).await?,
-                (None, Some(name)) => storage_cli.download_file("name", name, output).await?,
-                _ => return Err("Either file ID or file name must be provided".into()),
-            };
+        Commands::Download { file_id, file_name, output, verify } => {
+            let result = storage_cli.download_file(file_ref(file_id, file_name)?, output, verify, show_progress).await?;
             println!("{}", result);
         },
         Commands::List => {
-            let result = storage_cli.send_storage_command("list".to_string()).await?;
+            let result = storage_cli.send_storage_command(Operation::List(ListFiles {})).await?;
+            println!("{}", result);
+        },
+        Commands::Info { file_id, file_name } => {
+            let result = storage_cli.file_info(file_ref(file_id, file_name)?).await?;
+            println!("{}", result);
+        },
+        Commands::EncryptionAudit => {
+            let result = storage_cli.send_storage_command(Operation::EncryptionAudit(EncryptionAudit {})).await?;
+            println!("{}", result);
+        },
+        Commands::Stats => {
+            let result = storage_cli.send_storage_command(Operation::Stats(CompressionStats {})).await?;
+            println!("{}", result);
+        },
+        Commands::Progress { operation_id } => {
+            let result = storage_cli.watch_progress(&operation_id).await?;
             println!("{}", result);
         },
         Commands::Delete { file_id, file_name } => {
-            let result = match (file_id, file_name) {
-                (Some(id), _) => storage_cli.delete_file("id", id).await?,
-                (None, Some(name)) => storage_cli.delete_file("name", name).await?,
-                _ => return Err("Either file ID or file name must be provided".into()),
-            };
+            let result = storage_cli.delete_file(file_ref(file_id, file_name)?).await?;
             println!("{}", result);
         },
+        Commands::Encrypt { .. } | Commands::Decrypt { .. } => unreachable!("handled before connecting"),
     }
     
 
@@ -200,3 +477,46 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypted_files_decrypt_with_the_same_password() {
+        let dir = tempfile::tempdir().unwrap();
+        let (plain, sealed, opened) = (dir.path().join("plain.txt"), dir.path().join("plain.sce"), dir.path().join("opened.txt"));
+        fs::write(&plain, b"keep this private").unwrap();
+
+        encrypt_local_file(&plain, &sealed, "hunter2").unwrap();
+        assert!(fs::read(&sealed).unwrap().starts_with(ENCRYPTED_FILE_MAGIC));
+        decrypt_local_file(&sealed, &opened, "hunter2").unwrap();
+        assert_eq!(fs::read(&opened).unwrap(), b"keep this private");
+    }
+
+    #[test]
+    fn wrong_password_fails_to_decrypt() {
+        let dir = tempfile::tempdir().unwrap();
+        let (plain, sealed, opened) = (dir.path().join("plain.txt"), dir.path().join("plain.sce"), dir.path().join("opened.txt"));
+        fs::write(&plain, b"keep this private").unwrap();
+
+        encrypt_local_file(&plain, &sealed, "hunter2").unwrap();
+        assert!(decrypt_local_file(&sealed, &opened, "hunter3").is_err());
+        assert!(!opened.exists());
+    }
+
+    #[test]
+    fn progress_messages_show_percentage_speed_and_time_left() {
+        let update = |processed_bytes, current_speed, seconds_remaining| ProgressUpdate {
+            processed_bytes,
+            total_bytes: 1024,
+            current_speed,
+            percent_complete: processed_bytes as f32 / 10.24,
+            seconds_remaining,
+        };
+
+        assert_eq!(progress_message(&update(512, 2000.0, 0)), "50.0% (512/1024 bytes), 2.00 KB/s, 0s remaining");
+        assert_eq!(progress_message(&update(256, 500.0, 90)), "25.0% (256/1024 bytes), 500 B/s, 1.5m remaining");
+        assert_eq!(progress_message(&update(1024, 3_500_000.0, 7200)), "100.0% (1024/1024 bytes), 3.50 MB/s, 2.0h remaining");
+    }
+}
@@ -1,4 +1,20 @@
 pub mod brain_service {
     tonic::include_proto!("brain_service");
     pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("communication_descriptor");
+
+    impl FileRef {
+        pub fn id(id: impl Into<String>) -> Self {
+            Self { file: Some(file_ref::File::Id(id.into())) }
+        }
+
+        pub fn name(name: impl Into<String>) -> Self {
+            Self { file: Some(file_ref::File::Name(name.into())) }
+        }
+    }
+
+    impl From<storage_command::Operation> for StorageCommand {
+        fn from(operation: storage_command::Operation) -> Self {
+            Self { operation: Some(operation) }
+        }
+    }
 }
@@ -1,25 +1,57 @@
-use common::brain_service::{self, MessageRouteResponse, UnregistrationRequest};
+mod share;
+
+use base64::prelude::*;
+use common::brain_service::{self, HeartbeatRequest, MessageRouteResponse, UnregistrationRequest};
 use rocket::{
-    get, post, routes,
+    data::{Limits, ToByteUnit},
+    form::Form,
+    fs::TempFile,
+    get, post, routes, FromForm,
+    http::{ContentType, Status},
+    request::{self, FromRequest, Outcome},
+    response::{self, Responder},
     serde::{json::Json, Deserialize, Serialize},
     State,
 };
+use prost::Message;
 use std::error::Error;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tonic::{transport::Channel, Request};
+use std::time::Duration;
+use tokio::{fs::File, io::{AsyncRead, AsyncReadExt}, sync::Mutex, task::JoinHandle};
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::io::ReaderStream;
+use tonic::{transport::Channel, Code, Request};
 use uuid::Uuid;
 
+use share::{unix_now, ShareSigner, TokenError};
+
 use brain_service::{
-    brain_service_client::BrainServiceClient, ComponentRegistration, ComponentType,
-    MessageRouteRequest, MessageType,
+    brain_service_client::BrainServiceClient, storage_command::Operation, ComponentRegistration, ComponentType,
+    DeleteFile, DownloadFile, DownloadRange, FileRef, GetFileInfo, ListFiles, MessageRouteRequest, MessageType,
+    StorageCommand, UpdateFile, UploadFile, UploadPart, VerifyChecksums,
 };
 
+// Well inside the brain's default 60 second staleness window
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+// Bytes of a streamed upload sent to the brain per message
+const UPLOAD_PART_SIZE: usize = 1024 * 1024;
+
 struct ApiServer {
     client: BrainServiceClient<Channel>,
     component_id: String,
 }
 
+fn registration(component_id: &str) -> ComponentRegistration {
+    ComponentRegistration {
+        component_id: component_id.to_string(),
+        component_type: ComponentType::Server as i32,
+        ip_address: "127.0.0.1".to_string(),
+        // The HTTP port isn't a gRPC endpoint, so the brain has nowhere to forward messages
+        port: 0,
+    }
+}
+
 impl ApiServer {
     async fn new() -> Result<Self, Box<dyn Error>> {
         let channel = Channel::from_static("http://[::1]:2207").connect().await?;
@@ -27,12 +59,7 @@ impl ApiServer {
 
         let component_id = "api_server".to_string();
 
-        let request = Request::new(ComponentRegistration {
-            component_id: component_id.clone(),
-            component_type: ComponentType::Server as i32,
-            ip_address: "127.0.0.1".to_string(),
-            port: 8000,
-        });
+        let request = Request::new(registration(&component_id));
 
         let response = client.register_component(request).await?;
         let response_inner = response.into_inner();
@@ -47,6 +74,35 @@ impl ApiServer {
         })
     }
 
+    /// Sends the brain a heartbeat every `interval` until the returned task is aborted,
+    /// registering again if the brain dropped the server in the meantime.
+    fn spawn_heartbeat(&self, interval: Duration) -> JoinHandle<()> {
+        // Its own handle to the channel, so heartbeats don't queue behind requests
+        let mut client = self.client.clone();
+        let component_id = self.component_id.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // Registering just counted as one
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let request = Request::new(HeartbeatRequest {
+                    component_id: component_id.clone(),
+                });
+                match client.heartbeat(request).await {
+                    Ok(_) => {}
+                    Err(status) if status.code() == Code::NotFound => {
+                        println!("Brain dropped the API Server, registering again");
+                        if let Err(e) = client.register_component(Request::new(registration(&component_id))).await {
+                            eprintln!("Failed to register API Server again: {}", e.message());
+                        }
+                    }
+                    Err(e) => eprintln!("Heartbeat failed: {}", e.message()),
+                }
+            }
+        })
+    }
+
     async fn unregister(&mut self) -> Result<(), Box<dyn Error>> {
         let request = Request::new(UnregistrationRequest {
             component_id: self.component_id.clone(),
@@ -67,18 +123,42 @@ impl ApiServer {
         Ok(())
     }
 
+    /// Uploads `file` to the brain a part at a time, so it is never read into memory whole.
+    async fn stream_upload<R: AsyncRead + Send + 'static>(&mut self, name: &str, file: R, size: u64) -> Result<MessageRouteResponse, Box<dyn Error>> {
+        let parts = upload_parts(self.component_id.clone(), name.to_string(), file, size);
+        let response = self.client.stream_upload(Request::new(parts)).await?;
+        Ok(response.into_inner())
+    }
+
+    // The brain's `info` output for the file, if it could be described
+    async fn fetch_info(&mut self, identifier: &Identifier) -> Option<FileInfo> {
+        let component_id = self.component_id.clone();
+        let response = self
+            .route_message(component_id, "brain", identifier.info(), MessageType::StorageRequest)
+            .await
+            .ok()?;
+
+        response.success.then_some(FileInfo(response.error_message))
+    }
+
+    // The ETag is the checksum the brain reports for the file
+    async fn fetch_etag(&mut self, identifier: &Identifier) -> Option<String> {
+        self.fetch_info(identifier).await?.etag()
+    }
+
     async fn route_message(
         &mut self,
         source: String,
         destination: &str,
-        payload: String,
+        operation: Operation,
         message_type: MessageType,
     ) -> Result<MessageRouteResponse, Box<dyn Error>> {
         let request = Request::new(MessageRouteRequest {
             source_component: source,
             destination_component: destination.to_string(),
-            payload: payload.into_bytes(),
+            payload: StorageCommand::from(operation).encode_to_vec(),
             message_type: message_type as i32,
+            request_id: Uuid::new_v4().to_string(),
         });
 
         let response = self.client.route_message(request).await?;
@@ -88,8 +168,43 @@ impl ApiServer {
     }
 }
 
+/// Parts of a streamed upload of `file`: one naming the file, then its data. A read error
+/// ends the stream early, which the brain rejects as falling short of `size`.
+fn upload_parts<R: AsyncRead + Send + 'static>(source: String, name: String, file: R, size: u64) -> impl Stream<Item = UploadPart> {
+    let header = UploadPart {
+        source_component: source,
+        name,
+        size,
+        data: Vec::new(),
+        request_id: Uuid::new_v4().to_string(),
+    };
+    let data = ReaderStream::with_capacity(file, UPLOAD_PART_SIZE)
+        .map_while(Result::ok)
+        .map(|data| UploadPart { data: data.to_vec(), ..Default::default() });
+    tokio_stream::once(header).chain(data)
+}
+
+/// `Field: value` lines returned by the brain's `info` command.
+struct FileInfo(String);
+
+impl FileInfo {
+    fn field(&self, prefix: &str) -> Option<String> {
+        self.0.lines().find_map(|line| line.strip_prefix(prefix)).map(str::to_string)
+    }
+
+    // Prefer the plaintext hash; older files only report the stored-data checksum
+    fn etag(&self) -> Option<String> {
+        self.field("Content-Checksum: ").or_else(|| self.field("Checksum: "))
+    }
+
+    fn content_type(&self) -> Option<String> {
+        self.field("Content-Type: ")
+    }
+}
+
 struct AppState {
     client: Arc<Mutex<ApiServer>>,
+    signer: ShareSigner,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -98,19 +213,173 @@ struct StorageUploadRequest {
     file_content: String, // base64 encoded
 }
 
+#[derive(Serialize, Deserialize)]
+struct StorageUpdateRequest {
+    file_content: String, // base64 encoded
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExpectedChecksum {
+    name: String,
+    checksum: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StorageVerifyRequest {
+    files: Vec<ExpectedChecksum>,
+}
+
 #[derive(Serialize, Deserialize)]
 struct StorageResponse {
     success: bool,
     message: String,
 }
 
+fn wants_protobuf(req: &rocket::Request<'_>) -> bool {
+    let protobuf = ContentType::new("application", "x-protobuf");
+    req.accept()
+        .is_some_and(|accept| *accept.preferred().media_type() == *protobuf.media_type())
+}
+
+/// JSON by default; clients that accept `application/x-protobuf` get the same
+/// fields encoded as a `MessageRouteResponse`.
+impl<'r> Responder<'r, 'static> for StorageResponse {
+    fn respond_to(self, req: &'r rocket::Request<'_>) -> response::Result<'static> {
+        if wants_protobuf(req) {
+            let protobuf = ContentType::new("application", "x-protobuf");
+            let encoded = MessageRouteResponse {
+                success: self.success,
+                error_message: self.message,
+            }
+            .encode_to_vec();
+            (protobuf, encoded).respond_to(req)
+        } else {
+            Json(self).respond_to(req)
+        }
+    }
+}
+
+/// A download with what the brain knows about the file. `content_type` and `size`
+/// are left out of the JSON when the download failed or they are unknown.
+#[derive(Serialize)]
+struct DownloadBody {
+    #[serde(flatten)]
+    response: StorageResponse,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+}
+
+// Protobuf clients get the plain `MessageRouteResponse`, which has no room for the extra fields
+impl<'r> Responder<'r, 'static> for DownloadBody {
+    fn respond_to(self, req: &'r rocket::Request<'_>) -> response::Result<'static> {
+        if wants_protobuf(req) {
+            self.response.respond_to(req)
+        } else {
+            Json(self).respond_to(req)
+        }
+    }
+}
+
+/// Value of the `If-Match` request header, if any.
+struct IfMatch(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfMatch {
+    type Error = ();
+
+    async fn from_request(req: &'r rocket::Request<'_>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(IfMatch(req.headers().get_one("If-Match").map(str::to_string)))
+    }
+}
+
+impl IfMatch {
+    fn matches(&self, etag: Option<&str>) -> bool {
+        match (&self.0, etag) {
+            (None, _) => true,
+            (Some(expected), _) if expected.trim() == "*" => etag.is_some(),
+            (Some(expected), Some(etag)) => expected
+                .split(',')
+                .any(|candidate| candidate.trim().trim_matches('"') == etag),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// Requested `bytes=start-end` range, if any. Suffix and multi-part ranges are
+/// ignored, in which case the whole file is served.
+struct ByteRange(Option<(u64, u64)>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ByteRange {
+    type Error = ();
+
+    async fn from_request(req: &'r rocket::Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let range = req
+            .headers()
+            .get_one("Range")
+            .and_then(|value| value.strip_prefix("bytes="))
+            .filter(|spec| !spec.contains(','))
+            .and_then(|spec| spec.split_once('-'))
+            .and_then(|(start, end)| {
+                let start = start.trim().parse().ok()?;
+                let end = match end.trim() {
+                    "" => u64::MAX,
+                    end => end.parse().ok()?,
+                };
+                Some((start, end))
+            });
+        Outcome::Success(ByteRange(range))
+    }
+}
+
+/// Adds extra headers such as `ETag` to a response.
+struct WithHeaders<R> {
+    inner: R,
+    headers: Vec<(&'static str, String)>,
+}
+
+impl<R> WithHeaders<R> {
+    fn with_etag(inner: R, etag: Option<String>) -> Self {
+        let headers = etag.map(|etag| ("ETag", format!("\"{}\"", etag))).into_iter().collect();
+        Self { inner, headers }
+    }
+}
+
+impl<'r, R: Responder<'r, 'static>> Responder<'r, 'static> for WithHeaders<R> {
+    fn respond_to(self, req: &'r rocket::Request<'_>) -> response::Result<'static> {
+        let mut response = self.inner.respond_to(req)?;
+        for (name, value) in self.headers {
+            response.set_raw_header(name, value);
+        }
+        Ok(response)
+    }
+}
+
+#[derive(Responder)]
+enum DownloadResponse {
+    #[response(status = 200)]
+    Full(WithHeaders<DownloadBody>),
+    #[response(status = 206)]
+    Partial(WithHeaders<DownloadBody>),
+}
+
+#[derive(Responder)]
+enum ConditionalResponse {
+    #[response(status = 200)]
+    Done(StorageResponse),
+    #[response(status = 412)]
+    PreconditionFailed(StorageResponse),
+}
+
 #[get("/")]
 fn index() -> &'static str {
     "hello world!"
 }
 
 #[get("/storage/list")]
-async fn list_files(state: &State<AppState>) -> Json<StorageResponse> {
+async fn list_files(state: &State<AppState>) -> StorageResponse {
     let mut client = state.client.lock().await;
 
     let component_id = client.component_id.clone();
@@ -119,39 +388,100 @@ async fn list_files(state: &State<AppState>) -> Json<StorageResponse> {
         .route_message(
             component_id,
             "brain",
-            "list".to_string(),
+            Operation::List(ListFiles {}),
             MessageType::StorageRequest,
         )
         .await
     {
-        Ok(response) => Json(StorageResponse {
+        Ok(response) => StorageResponse {
             success: response.success,
             message: response.error_message,
-        }),
-        Err(e) => Json(StorageResponse {
+        },
+        Err(e) => StorageResponse {
             success: false,
             message: format!("Error listing files: {}", e),
-        }),
+        },
     }
 }
 
 #[post("/storage/upload", format = "json", data = "<upload_request>")]
-async fn upload_file(state: &State<AppState>, upload_request: Json<StorageUploadRequest>) -> Json<StorageResponse> {
+async fn upload_file(state: &State<AppState>, upload_request: Json<StorageUploadRequest>) -> StorageResponse {
+    match BASE64_STANDARD.decode(&upload_request.file_content) {
+        Ok(content) => forward_upload(state, &upload_request.file_name, content).await,
+        Err(_) => StorageResponse {
+            success: false,
+            message: "Invalid base64 content".to_string(),
+        },
+    }
+}
+
+// Largest file accepted by the multipart upload route
+const MAX_UPLOAD_SIZE_MIB: u64 = 1024;
+
+#[derive(FromForm)]
+struct MultipartUpload<'r> {
+    file_name: String,
+    file: TempFile<'r>,
+}
+
+/// Multipart upload. Rocket streams the file part into a temp file instead of holding
+/// it in memory, and the temp file is sent on to the brain in parts. It is removed when
+/// the form is dropped, including on error.
+#[post("/storage/upload", format = "multipart/form-data", data = "<upload>")]
+async fn upload_multipart(state: &State<AppState>, upload: Form<MultipartUpload<'_>>) -> StorageResponse {
+    let size = upload.file.len();
+    let sent = {
+        let mut client = state.client.lock().await;
+        match upload.file.path() {
+            Some(path) => match File::open(path).await {
+                Ok(file) => client.stream_upload(&upload.file_name, file, size).await,
+                Err(e) => Err(e.into()),
+            },
+            // Small parts Rocket kept in memory rather than spilling them
+            None => match upload.file.open().await {
+                Ok(mut file) => {
+                    let mut content = Vec::new();
+                    match file.read_to_end(&mut content).await {
+                        Ok(_) => client.stream_upload(&upload.file_name, std::io::Cursor::new(content), size).await,
+                        Err(e) => Err(e.into()),
+                    }
+                }
+                Err(e) => Err(e.into()),
+            },
+        }
+    };
+
+    match sent {
+        Ok(response) => StorageResponse {
+            success: response.success,
+            message: response.error_message,
+        },
+        Err(e) => StorageResponse {
+            success: false,
+            message: format!("Error uploading file: {}", e),
+        },
+    }
+}
+
+async fn forward_upload(state: &State<AppState>, file_name: &str, file_content: Vec<u8>) -> StorageResponse {
     let mut client = state.client.lock().await;
 
-    let command = format!("upload {} {}", upload_request.file_name, upload_request.file_content);
+    let command = Operation::Upload(UploadFile {
+        name: file_name.to_string(),
+        data: file_content,
+    });
 
     let component_id = client.component_id.clone();
 
     match client.route_message(component_id, "brain", command, MessageType::StorageRequest).await {
-        Ok(response) => Json(StorageResponse {
+        Ok(response) => StorageResponse {
             success: response.success,
             message: response.error_message,
-        }),
-        Err(e) => Json(StorageResponse {
+        },
+        Err(e) => StorageResponse {
             success: false,
             message: format!("Error uploading file: {}", e),
-        })
+        }
     }
 }
 
@@ -161,6 +491,19 @@ enum Identifier {
     Name(String),
 }
 
+impl Identifier {
+    fn file_ref(&self) -> FileRef {
+        match self {
+            Identifier::Id(id) => FileRef::id(id),
+            Identifier::Name(name) => FileRef::name(name),
+        }
+    }
+
+    fn info(&self) -> Operation {
+        Operation::Info(GetFileInfo { file: Some(self.file_ref()) })
+    }
+}
+
 impl<'r> rocket::request::FromParam<'r> for Identifier {
     type Error = &'static str;
 
@@ -176,69 +519,263 @@ impl<'r> rocket::request::FromParam<'r> for Identifier {
 }
 
 #[get("/storage/download/<identifier>")]
-async fn download_file(state: &State<AppState>, identifier: Identifier) -> Json<StorageResponse> {
+async fn download_file(state: &State<AppState>, identifier: Identifier, range: ByteRange) -> DownloadResponse {
+    let mut client = state.client.lock().await;
+
+    let info = client.fetch_info(&identifier).await;
+    let etag = info.as_ref().and_then(FileInfo::etag);
+    let component_id = client.component_id.clone();
+
+    let file = Some(identifier.file_ref());
+    let command = match range.0 {
+        Some((start, end)) => Operation::Range(DownloadRange { file, start, end }),
+        None => Operation::Download(DownloadFile { file, with_checksum: false }),
+    };
+
+    let response = match client.route_message(component_id, "brain", command, MessageType::StorageRequest).await {
+        Ok(response) => StorageResponse {
+            success: response.success,
+            message: response.error_message,
+        },
+        Err(e) => StorageResponse {
+            success: false,
+            message: format!("Error downloading file: {}", e),
+        }
+    };
+
+    let length = if response.success {
+        BASE64_STANDARD.decode(&response.message).map(|data| data.len() as u64).ok()
+    } else {
+        None
+    };
+    let body = DownloadBody {
+        content_type: info.as_ref().and_then(FileInfo::content_type).filter(|_| response.success),
+        // A range only covers part of the file, so its length isn't the file's size
+        size: length.filter(|_| range.0.is_none()),
+        response,
+    };
+
+    match range.0 {
+        Some((start, _)) if body.response.success => {
+            let length = length.unwrap_or(0);
+            let mut partial = WithHeaders::with_etag(body, etag);
+            partial.headers.push(("Content-Range", format!("bytes {}-{}/*", start, (start + length).saturating_sub(1))));
+            DownloadResponse::Partial(partial)
+        }
+        _ => DownloadResponse::Full(WithHeaders::with_etag(body, etag)),
+    }
+}
+
+#[get("/storage/info/<identifier>")]
+async fn file_info(state: &State<AppState>, identifier: Identifier) -> WithHeaders<StorageResponse> {
     let mut client = state.client.lock().await;
 
-    let command = match identifier {
-        Identifier::Id(id) => format!("download id {}", id),
-        Identifier::Name(name) => format!("download name {}", name),
+    let etag = client.fetch_etag(&identifier).await;
+    let component_id = client.component_id.clone();
+
+    let inner = match client.route_message(component_id, "brain", identifier.info(), MessageType::StorageRequest).await {
+        Ok(response) => StorageResponse {
+            success: response.success,
+            message: response.error_message,
+        },
+        Err(e) => StorageResponse {
+            success: false,
+            message: format!("Error fetching file info: {}", e),
+        }
     };
 
+    WithHeaders::with_etag(inner, etag)
+}
+
+/// Reports whether each named file matches, differs from or is missing its expected
+/// checksum, one `name: status` line per entry, without transferring file contents.
+#[post("/storage/verify", format = "json", data = "<verify_request>")]
+async fn verify_files(state: &State<AppState>, verify_request: Json<StorageVerifyRequest>) -> StorageResponse {
+    let command = Operation::VerifyChecksums(VerifyChecksums {
+        files: verify_request
+            .files
+            .iter()
+            .map(|entry| brain_service::ExpectedChecksum {
+                name: entry.name.clone(),
+                checksum: entry.checksum.clone(),
+            })
+            .collect(),
+    });
+
+    let mut client = state.client.lock().await;
     let component_id = client.component_id.clone();
 
     match client.route_message(component_id, "brain", command, MessageType::StorageRequest).await {
-        Ok(response) => Json(StorageResponse {
+        Ok(response) => StorageResponse {
             success: response.success,
             message: response.error_message,
-        }),
-        Err(e) => Json(StorageResponse {
+        },
+        Err(e) => StorageResponse {
             success: false,
-            message: format!("Error downloading file: {}", e),
-        })
+            message: format!("Error verifying files: {}", e),
+        }
     }
 }
 
+// The precondition is checked while holding the client lock, so requests going
+// through this server cannot interleave between the check and the write.
+#[post("/storage/update/<identifier>", format = "json", data = "<update_request>")]
+async fn update_file(state: &State<AppState>, identifier: Identifier, if_match: IfMatch, update_request: Json<StorageUpdateRequest>) -> ConditionalResponse {
+    let Ok(content) = BASE64_STANDARD.decode(&update_request.file_content) else {
+        return ConditionalResponse::Done(StorageResponse {
+            success: false,
+            message: "Invalid base64 content".to_string(),
+        });
+    };
+
+    let mut client = state.client.lock().await;
+
+    let etag = client.fetch_etag(&identifier).await;
+    if !if_match.matches(etag.as_deref()) {
+        return ConditionalResponse::PreconditionFailed(StorageResponse {
+            success: false,
+            message: "File has changed since the provided ETag".to_string(),
+        });
+    }
+
+    let command = Operation::Update(UpdateFile {
+        file: Some(identifier.file_ref()),
+        data: content,
+    });
+    let component_id = client.component_id.clone();
+
+    ConditionalResponse::Done(match client.route_message(component_id, "brain", command, MessageType::StorageRequest).await {
+        Ok(response) => StorageResponse {
+            success: response.success,
+            message: response.error_message,
+        },
+        Err(e) => StorageResponse {
+            success: false,
+            message: format!("Error updating file: {}", e),
+        }
+    })
+}
+
 #[post("/storage/delete/<identifier>")]
-async fn delete_file(state: &State<AppState>, identifier: Identifier) -> Json<StorageResponse> {
+async fn delete_file(state: &State<AppState>, identifier: Identifier, if_match: IfMatch) -> ConditionalResponse {
     let mut client = state.client.lock().await;
 
-    let command = match identifier {
-        Identifier::Id(id) => format!("delete id {}", id),
-        Identifier::Name(name) => format!("delete name {}", name),
-    };
+    if if_match.0.is_some() {
+        let etag = client.fetch_etag(&identifier).await;
+        if !if_match.matches(etag.as_deref()) {
+            return ConditionalResponse::PreconditionFailed(StorageResponse {
+                success: false,
+                message: "File has changed since the provided ETag".to_string(),
+            });
+        }
+    }
 
     let component_id = client.component_id.clone();
 
-    match client.route_message(component_id, "brain", command, MessageType::StorageRequest).await {
-        Ok(response) => Json(StorageResponse {
+    ConditionalResponse::Done(match client.route_message(component_id, "brain", Operation::Delete(DeleteFile { file: Some(identifier.file_ref()) }), MessageType::StorageRequest).await {
+        Ok(response) => StorageResponse {
             success: response.success,
             message: response.error_message,
-        }),
-        Err(e) => Json(StorageResponse {
+        },
+        Err(e) => StorageResponse {
             success: false,
             message: format!("Error downloading file: {}", e),
-        })
+        }
+    })
+}
+
+// Lifetime of a share token when the request doesn't specify one
+const DEFAULT_SHARE_TTL_SECS: u64 = 3600;
+// Longest lifetime a request can ask for; longer ones are cut down to this
+const MAX_SHARE_TTL_SECS: u64 = 30 * 24 * 3600;
+
+/// Issues a token that downloads the file through `/storage/shared/<token>` until it expires.
+/// Tokens aren't tracked once issued, so one can be redeemed any number of times before then.
+#[post("/storage/share/<identifier>?<ttl>")]
+async fn share_file(state: &State<AppState>, identifier: Identifier, ttl: Option<u64>) -> StorageResponse {
+    let mut client = state.client.lock().await;
+    let component_id = client.component_id.clone();
+
+    // Tokens are bound to the id so renaming or reusing a name doesn't change what they grant
+    let file_id = match client.route_message(component_id, "brain", identifier.info(), MessageType::StorageRequest).await {
+        Ok(response) if response.success => response.error_message.lines().find_map(|line| line.strip_prefix("ID: ")).map(str::to_string),
+        Ok(response) => {
+            return StorageResponse {
+                success: false,
+                message: response.error_message,
+            }
+        }
+        Err(e) => {
+            return StorageResponse {
+                success: false,
+                message: format!("Error sharing file: {}", e),
+            }
+        }
+    };
+
+    match file_id {
+        Some(file_id) => StorageResponse {
+            success: true,
+            message: state.signer.issue(&file_id, unix_now().saturating_add(ttl.unwrap_or(DEFAULT_SHARE_TTL_SECS).min(MAX_SHARE_TTL_SECS))),
+        },
+        None => StorageResponse {
+            success: false,
+            message: "Error sharing file: no file id in info response".to_string(),
+        },
+    }
+}
+
+#[get("/storage/shared/<token>")]
+async fn shared_download(state: &State<AppState>, token: &str) -> Result<(ContentType, Vec<u8>), Status> {
+    let file_id = state.signer.verify(token, unix_now()).map_err(|e| match e {
+        TokenError::Malformed => Status::BadRequest,
+        TokenError::BadSignature | TokenError::Expired => Status::Forbidden,
+    })?;
+
+    let mut client = state.client.lock().await;
+    let component_id = client.component_id.clone();
+    let command = Operation::Download(DownloadFile {
+        file: Some(FileRef::id(file_id)),
+        with_checksum: false,
+    });
+
+    match client.route_message(component_id, "brain", command, MessageType::StorageRequest).await {
+        Ok(response) if response.success => BASE64_STANDARD
+            .decode(&response.error_message)
+            .map(|data| (ContentType::Binary, data))
+            .map_err(|_| Status::InternalServerError),
+        Ok(_) => Err(Status::NotFound),
+        Err(_) => Err(Status::BadGateway),
     }
 }
 
 #[rocket::main]
-async fn main() -> Result<(), rocket::Error> {
+async fn main() -> Result<(), Box<rocket::Error>> {
     let client = ApiServer::new()
         .await
         .expect("Failed to create brain service client");
+    let heartbeat = client.spawn_heartbeat(HEARTBEAT_INTERVAL);
     let app_state = AppState {
         client: Arc::new(Mutex::new(client)),
+        signer: ShareSigner::from_env(),
     };
 
     let shutdown_state = app_state.client.clone();
 
-    let rocket = rocket::build()
+    let limits = Limits::default()
+        .limit("file", MAX_UPLOAD_SIZE_MIB.mebibytes())
+        .limit("data-form", MAX_UPLOAD_SIZE_MIB.mebibytes());
+    let figment = rocket::Config::figment().merge(("limits", limits));
+
+    let rocket = rocket::custom(figment)
         .manage(app_state)
-        .mount("/", routes![index, list_files, upload_file, download_file, delete_file])
+        .mount("/", routes![index, list_files, upload_file, upload_multipart, download_file, file_info, verify_files, update_file, delete_file, share_file, shared_download])
         .attach(rocket::fairing::AdHoc::on_shutdown(
             "Unregister Component",
             move |_| {
                 Box::pin(async move {
+                    // Otherwise it could register the server again right after it leaves
+                    heartbeat.abort();
                     let mut client = shutdown_state.lock().await;
                     if let Err(e) = client.unregister().await {
                         eprintln!("Error during unregistration: {}", e);
@@ -252,3 +789,181 @@ async fn main() -> Result<(), rocket::Error> {
     rocket.launch().await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use brain_service::{
+        brain_service_server::{BrainService, BrainServiceServer}, HeartbeatResponse, MessageRouteEvent, RegistrationResponse,
+        SystemStatusRequest, SystemStatusResponse, UnregistrationResponse,
+    };
+
+    #[tokio::test]
+    async fn upload_parts_send_the_file_in_bounded_parts() {
+        let content: Vec<u8> = (0..UPLOAD_PART_SIZE * 2 + 100).map(|i| i as u8).collect();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), &content).unwrap();
+
+        let parts: Vec<UploadPart> = upload_parts("api_server".to_string(), "big.bin".to_string(), File::open(file.path()).await.unwrap(), content.len() as u64)
+            .collect()
+            .await;
+
+        assert_eq!(parts[0].name, "big.bin");
+        assert_eq!(parts[0].size, content.len() as u64);
+        assert!(parts.iter().all(|part| part.data.len() <= UPLOAD_PART_SIZE));
+        assert_eq!(parts.iter().flat_map(|part| part.data.clone()).collect::<Vec<_>>(), content);
+    }
+
+    #[tokio::test]
+    async fn empty_upload_still_names_the_file() {
+        let parts: Vec<UploadPart> = upload_parts("api_server".to_string(), "empty.txt".to_string(), std::io::Cursor::new(Vec::new()), 0)
+            .collect()
+            .await;
+
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].name, "empty.txt");
+    }
+
+    #[test]
+    fn stale_etags_fail_the_precondition() {
+        let current = Some("abc123");
+        assert!(!IfMatch(Some("\"old456\"".to_string())).matches(current));
+        assert!(IfMatch(Some("\"abc123\"".to_string())).matches(current));
+        assert!(IfMatch(Some("\"old456\", \"abc123\"".to_string())).matches(current));
+        assert!(IfMatch(None).matches(current));
+    }
+
+    #[test]
+    fn wildcard_etag_only_matches_existing_files() {
+        assert!(IfMatch(Some("*".to_string())).matches(Some("abc123")));
+        assert!(!IfMatch(Some("*".to_string())).matches(None));
+    }
+
+    #[test]
+    fn etag_prefers_the_content_checksum() {
+        let info = FileInfo("ID: 1\nChecksum: stored\nContent-Checksum: plain".to_string());
+        assert_eq!(info.etag().as_deref(), Some("plain"));
+        assert_eq!(FileInfo("ID: 1\nChecksum: stored".to_string()).etag().as_deref(), Some("stored"));
+    }
+
+    #[get("/response")]
+    fn canned_response() -> StorageResponse {
+        StorageResponse { success: true, message: "stored".to_string() }
+    }
+
+    async fn canned_client() -> rocket::local::asynchronous::Client {
+        rocket::local::asynchronous::Client::untracked(rocket::build().mount("/", routes![canned_response])).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn responses_are_json_by_default() {
+        let client = canned_client().await;
+        let response = client.get("/response").dispatch().await;
+
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let body: StorageResponse = response.into_json().await.unwrap();
+        assert!(body.success);
+        assert_eq!(body.message, "stored");
+    }
+
+    #[tokio::test]
+    async fn protobuf_clients_get_a_message_route_response() {
+        let client = canned_client().await;
+        let response = client.get("/response").header(rocket::http::Header::new("Accept", "application/x-protobuf")).dispatch().await;
+
+        assert_eq!(response.content_type(), Some(ContentType::new("application", "x-protobuf")));
+        let body = MessageRouteResponse::decode(response.into_bytes().await.unwrap().as_slice()).unwrap();
+        assert!(body.success);
+        assert_eq!(body.error_message, "stored");
+    }
+
+    // The start of a PNG: its signature and the header chunk of a 1x1 image
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0\0\x01\0\0\0\x01\x08\x06\0\0\0";
+
+    /// Stands in for the brain, answering `info` and downloads for a single stored PNG.
+    struct FakeBrain;
+
+    #[tonic::async_trait]
+    impl BrainService for FakeBrain {
+        async fn register_component(&self, _request: Request<ComponentRegistration>) -> Result<tonic::Response<RegistrationResponse>, tonic::Status> {
+            Err(tonic::Status::unimplemented("fake brain"))
+        }
+
+        async fn unregister_component(&self, _request: Request<UnregistrationRequest>) -> Result<tonic::Response<UnregistrationResponse>, tonic::Status> {
+            Err(tonic::Status::unimplemented("fake brain"))
+        }
+
+        async fn heartbeat(&self, _request: Request<HeartbeatRequest>) -> Result<tonic::Response<HeartbeatResponse>, tonic::Status> {
+            Err(tonic::Status::unimplemented("fake brain"))
+        }
+
+        async fn route_message(&self, request: Request<MessageRouteRequest>) -> Result<tonic::Response<MessageRouteResponse>, tonic::Status> {
+            let command = StorageCommand::decode(request.into_inner().payload.as_slice()).unwrap();
+            let error_message = match command.operation.unwrap() {
+                Operation::Info(_) => "ID: 1\nName: pixel.png\nContent-Type: image/png\nContent-Checksum: abc123".to_string(),
+                Operation::Download(_) => BASE64_STANDARD.encode(PNG),
+                operation => return Err(tonic::Status::unimplemented(format!("fake brain: {:?}", operation))),
+            };
+            Ok(tonic::Response::new(MessageRouteResponse { success: true, error_message }))
+        }
+
+        type RouteMessageWithProgressStream = tokio_stream::wrappers::ReceiverStream<Result<MessageRouteEvent, tonic::Status>>;
+
+        async fn route_message_with_progress(&self, _request: Request<MessageRouteRequest>) -> Result<tonic::Response<Self::RouteMessageWithProgressStream>, tonic::Status> {
+            Err(tonic::Status::unimplemented("fake brain"))
+        }
+
+        async fn stream_upload(&self, _request: Request<tonic::Streaming<UploadPart>>) -> Result<tonic::Response<MessageRouteResponse>, tonic::Status> {
+            Err(tonic::Status::unimplemented("fake brain"))
+        }
+
+        async fn get_system_status(&self, _request: Request<SystemStatusRequest>) -> Result<tonic::Response<SystemStatusResponse>, tonic::Status> {
+            Err(tonic::Status::unimplemented("fake brain"))
+        }
+    }
+
+    /// A client for the server's routes, talking to a `FakeBrain` on an unused local port.
+    async fn fake_brain_client() -> rocket::local::asynchronous::Client {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let incoming = tokio_stream::wrappers::TcpListenerStream::new(listener);
+        tokio::spawn(tonic::transport::Server::builder().add_service(BrainServiceServer::new(FakeBrain)).serve_with_incoming(incoming));
+
+        let channel = Channel::from_shared(format!("http://{}", address)).unwrap().connect_lazy();
+        let state = AppState {
+            client: Arc::new(Mutex::new(ApiServer { client: BrainServiceClient::new(channel), component_id: "api_server".to_string() })),
+            signer: ShareSigner::new(b"test secret"),
+        };
+        rocket::local::asynchronous::Client::untracked(rocket::build().manage(state).mount("/", routes![download_file, share_file, shared_download])).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn downloads_carry_the_content_type_and_size() {
+        let client = fake_brain_client().await;
+        let response = client.get("/storage/download/pixel.png").dispatch().await;
+
+        assert_eq!(response.status(), Status::Ok);
+        let body: rocket::serde::json::Value = response.into_json().await.unwrap();
+        assert_eq!(body["success"], true);
+        assert_eq!(body["content_type"], "image/png");
+        assert_eq!(body["size"], PNG.len() as u64);
+        assert_eq!(BASE64_STANDARD.decode(body["message"].as_str().unwrap()).unwrap(), PNG);
+    }
+
+    #[tokio::test]
+    async fn share_lifetimes_are_capped_and_tokens_can_be_reused() {
+        let client = fake_brain_client().await;
+        let response = client.post(format!("/storage/share/pixel.png?ttl={}", u64::MAX)).dispatch().await;
+        let body: rocket::serde::json::Value = response.into_json().await.unwrap();
+        assert_eq!(body["success"], true);
+
+        let token = body["message"].as_str().unwrap().to_string();
+        let expires_at: u64 = token.split('.').nth(1).unwrap().parse().unwrap();
+        assert!(expires_at <= unix_now() + MAX_SHARE_TTL_SECS);
+        for _ in 0..2 {
+            let response = client.get(format!("/storage/shared/{}", token)).dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+            assert_eq!(response.into_bytes().await.unwrap(), PNG);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use base64::prelude::*;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TokenError {
+    Malformed,
+    BadSignature,
+    Expired,
+}
+
+/// Issues and checks share tokens of the form `<file id>.<expiry>.<signature>`,
+/// where the signature is an HMAC-SHA256 over the id and the unix expiry time.
+pub struct ShareSigner {
+    secret: Vec<u8>,
+}
+
+impl ShareSigner {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            secret: secret.to_vec(),
+        }
+    }
+
+    /// Uses `SHARE_TOKEN_SECRET` when set. Otherwise the secret is random, so
+    /// tokens stop working when the server restarts.
+    pub fn from_env() -> Self {
+        match std::env::var("SHARE_TOKEN_SECRET") {
+            Ok(secret) if !secret.is_empty() => Self::new(secret.as_bytes()),
+            _ => Self::new(&rand::random::<[u8; 32]>()),
+        }
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    pub fn issue(&self, file_id: &str, expires_at: u64) -> String {
+        let payload = format!("{}.{}", file_id, expires_at);
+        let signature = BASE64_URL_SAFE_NO_PAD.encode(self.mac(&payload).finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    /// Returns the file id the token grants access to.
+    pub fn verify(&self, token: &str, now: u64) -> Result<String, TokenError> {
+        let (payload, signature) = token.rsplit_once('.').ok_or(TokenError::Malformed)?;
+        let (file_id, expires_at) = payload.rsplit_once('.').ok_or(TokenError::Malformed)?;
+        let expires_at: u64 = expires_at.parse().map_err(|_| TokenError::Malformed)?;
+        let signature = BASE64_URL_SAFE_NO_PAD.decode(signature).map_err(|_| TokenError::Malformed)?;
+
+        self.mac(payload)
+            .verify_slice(&signature)
+            .map_err(|_| TokenError::BadSignature)?;
+        if now >= expires_at {
+            return Err(TokenError::Expired);
+        }
+
+        Ok(file_id.to_string())
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE_ID: &str = "6f1c2a3e-8f43-4d3e-9a51-0c9f7f1e2b44";
+
+    #[test]
+    fn valid_token_grants_its_file() {
+        let signer = ShareSigner::new(b"secret");
+        let token = signer.issue(FILE_ID, 2000);
+
+        assert_eq!(signer.verify(&token, 1000), Ok(FILE_ID.to_string()));
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let signer = ShareSigner::new(b"secret");
+        let token = signer.issue(FILE_ID, 2000);
+
+        assert_eq!(signer.verify(&token, 2000), Err(TokenError::Expired));
+    }
+
+    #[test]
+    fn tampered_token_is_rejected() {
+        let signer = ShareSigner::new(b"secret");
+        let token = signer.issue(FILE_ID, 2000);
+
+        let extended = token.replacen(".2000.", ".9999.", 1);
+        assert_eq!(signer.verify(&extended, 1000), Err(TokenError::BadSignature));
+        assert_eq!(ShareSigner::new(b"other").verify(&token, 1000), Err(TokenError::BadSignature));
+        assert_eq!(signer.verify("not-a-token", 1000), Err(TokenError::Malformed));
+    }
+}
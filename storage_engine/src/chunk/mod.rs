@@ -1,21 +1,119 @@
+    use rayon::prelude::*;
     use sha2::{Sha256, Digest};
-    use crate::{Chunk, ChunkId};
-    use uuid::Uuid;
+    use crate::{AppError, Chunk, ChunkId, Result, StorageError};
+
+    /// Where chunk boundaries fall.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ChunkingMode {
+        /// Every chunk but the last is exactly `chunk_size` bytes.
+        Fixed,
+        /// Boundaries follow the content, so inserting or removing bytes only
+        /// changes the chunks around the edit.
+        ContentDefined { min: usize, avg: usize, max: usize },
+    }
 
     pub struct ChunkManager {
         chunk_size: usize,
+        mode: ChunkingMode,
     }
 
     pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
 
+    // Below this many chunks, spreading the hashing over threads costs more than it saves
+    const PARALLEL_MIN_CHUNKS: usize = 4;
+
+    impl ChunkManager {
+        pub fn new(chunk_size: usize) -> Self {
+            Self { chunk_size, mode: ChunkingMode::Fixed }
+        }
+
+        /// Cuts chunks with a FastCDC-style rolling hash, aiming for `avg` bytes and
+        /// never going below `min` (except for the last chunk) or above `max`.
+        pub fn content_defined(min: usize, avg: usize, max: usize) -> Result<Self> {
+            if min == 0 || min > avg || avg > max {
+                return Err(AppError::Storage(StorageError::InvalidInput(format!(
+                    "Content-defined chunk sizes must satisfy 0 < min <= avg <= max, got {}/{}/{}",
+                    min, avg, max
+                ))));
+            }
+            Ok(Self { chunk_size: avg, mode: ChunkingMode::ContentDefined { min, avg, max } })
+        }
+
+        pub fn mode(&self) -> ChunkingMode {
+            self.mode
+        }
+    }
+
     impl Default for ChunkManager {
         fn default() -> Self {
             Self {
                 chunk_size: DEFAULT_CHUNK_SIZE,
+                mode: ChunkingMode::Fixed,
             }
         }
     }
 
+    // Random per-byte values mixed into the rolling hash; fixed at compile time so
+    // the same content always cuts at the same places
+    const GEAR: [u64; 256] = gear_table();
+
+    const fn gear_table() -> [u64; 256] {
+        let mut table = [0u64; 256];
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut i = 0;
+        while i < 256 {
+            // splitmix64
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            table[i] = z ^ (z >> 31);
+            i += 1;
+        }
+        table
+    }
+
+    // Mask over the top `bits` bits of the hash, which depend on the most bytes
+    fn high_mask(bits: u32) -> u64 {
+        match bits {
+            0 => 0,
+            64.. => u64::MAX,
+            _ => !(u64::MAX >> bits),
+        }
+    }
+
+    /// Length of the next content-defined chunk at the start of `data`. Uses a
+    /// stricter mask before `avg` and a looser one after it, which keeps chunk
+    /// sizes close to `avg`.
+    fn cdc_cut(data: &[u8], min: usize, avg: usize, max: usize) -> usize {
+        if data.len() <= min {
+            return data.len();
+        }
+        let end = data.len().min(max);
+        let normal = end.min(avg);
+        let bits = avg.max(1).ilog2();
+        let mask_strict = high_mask(bits + 1);
+        let mask_loose = high_mask(bits.saturating_sub(1));
+
+        let mut hash: u64 = 0;
+        let mut i = min;
+        while i < normal {
+            hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+            if hash & mask_strict == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        while i < end {
+            hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+            if hash & mask_loose == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        end
+    }
+
     pub struct FileChunker {
         config: ChunkManager,
     }
@@ -25,7 +123,57 @@
             Self { config }
         }
 
+        /// Size data is split at; the target average under content-defined chunking.
+        pub fn chunk_size(&self) -> usize {
+            self.config.chunk_size
+        }
+
+        /// Smallest size any chunk but the last can have.
+        pub fn min_chunk_size(&self) -> usize {
+            match self.config.mode {
+                ChunkingMode::Fixed => self.config.chunk_size,
+                ChunkingMode::ContentDefined { min, .. } => min,
+            }
+        }
+
+        /// Largest size any chunk can have.
+        pub fn max_chunk_size(&self) -> usize {
+            match self.config.mode {
+                ChunkingMode::Fixed => self.config.chunk_size,
+                ChunkingMode::ContentDefined { max, .. } => max,
+            }
+        }
+
+        /// Cuts the next chunk off the front of `data`, for callers that don't have the
+        /// whole input at once. Unless `data` runs to the end of the input it must hold at
+        /// least `max_chunk_size` bytes, so the cut lands where `chunk_data` would put it.
+        pub fn next_chunk(&self, data: &[u8]) -> Chunk {
+            let len = match self.config.mode {
+                ChunkingMode::Fixed => data.len().min(self.config.chunk_size),
+                ChunkingMode::ContentDefined { min, avg, max } => cdc_cut(data, min, avg, max),
+            };
+            self.make_chunk(&data[..len])
+        }
+
         pub fn chunk_data(&self, data: &[u8]) -> Vec<Chunk> {
+            if let ChunkingMode::ContentDefined { min, avg, max } = self.config.mode {
+                return self.chunk_data_content_defined(data, min, avg, max);
+            }
+            if data.len() >= self.config.chunk_size * PARALLEL_MIN_CHUNKS {
+                self.chunk_data_parallel(data)
+            } else {
+                self.chunk_data_serial(data)
+            }
+        }
+
+        /// Hashes chunks across the rayon pool; chunk order matches the input.
+        pub fn chunk_data_parallel(&self, data: &[u8]) -> Vec<Chunk> {
+            data.par_chunks(self.config.chunk_size)
+                .map(|chunk_data| self.make_chunk(chunk_data))
+                .collect()
+        }
+
+        pub fn chunk_data_serial(&self, data: &[u8]) -> Vec<Chunk> {
             let mut chunks = Vec::new();
             let mut position = 0;
 
@@ -33,12 +181,7 @@
                 let end = (position + self.config.chunk_size).min(data.len());
                 let chunk_data = &data[position..end];
                 
-                chunks.push(Chunk {
-                    id: ChunkId(Uuid::new_v4()),
-                    data: chunk_data.to_vec(),
-                    checksum: self.calculate_checksum(chunk_data),
-                    size: chunk_data.len(),
-                });
+                chunks.push(self.make_chunk(chunk_data));
 
                 position = end;
             }
@@ -46,9 +189,112 @@
             chunks
         }
 
+        fn chunk_data_content_defined(&self, data: &[u8], min: usize, avg: usize, max: usize) -> Vec<Chunk> {
+            let mut slices = Vec::new();
+            let mut position = 0;
+            while position < data.len() {
+                let len = cdc_cut(&data[position..], min, avg, max);
+                slices.push(&data[position..position + len]);
+                position += len;
+            }
+
+            if slices.len() >= PARALLEL_MIN_CHUNKS {
+                slices.par_iter().map(|chunk_data| self.make_chunk(chunk_data)).collect()
+            } else {
+                slices.iter().map(|chunk_data| self.make_chunk(chunk_data)).collect()
+            }
+        }
+
+        // Chunks are named by their checksum, so equal data always gets the same id
+        fn make_chunk(&self, chunk_data: &[u8]) -> Chunk {
+            let checksum = self.calculate_checksum(chunk_data);
+            Chunk {
+                id: ChunkId(checksum.clone()),
+                data: chunk_data.to_vec(),
+                checksum,
+                size: chunk_data.len(),
+            }
+        }
+
         fn calculate_checksum(&self, data: &[u8]) -> String {
             let mut hasher = Sha256::new();
             hasher.update(data);
             format!("{:x}", hasher.finalize())
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn bytes(len: usize) -> Vec<u8> {
+            (0..len).map(|i| (i * 31 % 251) as u8).collect()
+        }
+
+        #[test]
+        fn parallel_chunking_matches_serial_chunking() {
+            let chunker = FileChunker::new(ChunkManager::new(1000));
+            let data = bytes(10_500);
+
+            let parallel = chunker.chunk_data_parallel(&data);
+            let serial = chunker.chunk_data_serial(&data);
+            assert_eq!(parallel.len(), 11);
+            assert_eq!(
+                parallel.iter().map(|c| (&c.data, &c.checksum)).collect::<Vec<_>>(),
+                serial.iter().map(|c| (&c.data, &c.checksum)).collect::<Vec<_>>()
+            );
+        }
+
+        // Bytes without any period a rolling hash could lock onto
+        fn noise(len: usize) -> Vec<u8> {
+            let mut state = 0x2545_f491u32;
+            (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    state as u8
+                })
+                .collect()
+        }
+
+        #[test]
+        fn content_defined_chunks_survive_an_inserted_byte() {
+            let chunker = FileChunker::new(ChunkManager::content_defined(2048, 8192, 32768).unwrap());
+            let data = noise(4 * 1024 * 1024);
+            let mut shifted = vec![0x42];
+            shifted.extend_from_slice(&data);
+
+            let original: std::collections::HashSet<String> = chunker.chunk_data(&data).into_iter().map(|c| c.checksum).collect();
+            let after = chunker.chunk_data(&shifted);
+            let shared = after.iter().filter(|c| original.contains(&c.checksum)).count();
+            assert!(shared * 10 >= original.len() * 9, "{} of {} chunks unchanged", shared, original.len());
+        }
+
+        #[test]
+        fn content_defined_chunks_stay_within_their_bounds() {
+            let chunker = FileChunker::new(ChunkManager::content_defined(2048, 8192, 32768).unwrap());
+            let data = noise(1024 * 1024);
+            let chunks = chunker.chunk_data(&data);
+
+            let (last, rest) = chunks.split_last().unwrap();
+            assert!(rest.iter().all(|c| (2048..=32768).contains(&c.size)));
+            assert!(last.size <= 32768);
+            assert_eq!(chunks.iter().flat_map(|c| c.data.clone()).collect::<Vec<_>>(), data);
+        }
+
+        #[test]
+        fn fixed_size_chunking_stays_the_default() {
+            assert_eq!(ChunkManager::default().mode(), ChunkingMode::Fixed);
+            let chunker = FileChunker::new(ChunkManager::new(1000));
+            let sizes: Vec<usize> = chunker.chunk_data(&noise(2500)).iter().map(|c| c.size).collect();
+            assert_eq!(sizes, [1000, 1000, 500]);
+        }
+
+        #[test]
+        fn content_defined_sizes_must_be_ordered() {
+            assert!(ChunkManager::content_defined(0, 8192, 32768).is_err());
+            assert!(ChunkManager::content_defined(8192, 2048, 32768).is_err());
+            assert!(ChunkManager::content_defined(2048, 32768, 8192).is_err());
+        }
+}
@@ -1,41 +1,348 @@
-use aes_gcm::{aead::Aead, Aes256Gcm, Key, KeyInit, Nonce};
+use aes_gcm::{aead::{Aead, OsRng, Payload, rand_core::RngCore}, Aes256Gcm, Key, KeyInit, Nonce};
+use chacha20poly1305::ChaCha20Poly1305;
+use argon2::Argon2;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::sync::Arc;
+use zeroize::{ZeroizeOnDrop, Zeroizing};
 use crate::{Result, StorageError};
 
+pub const SALT_LEN: usize = 16;
+
+// Ciphertext layout: algorithm byte, random nonce, then the AEAD output
+pub const NONCE_LEN: usize = 12;
+// Data written before random nonces were introduced has no header and used this nonce
+const LEGACY_NONCE: &[u8; NONCE_LEN] = b"somedumbshit";
+// Authentication tag both ciphers append to their output
+const TAG_LEN: usize = 16;
+
+/// AEAD cipher used for new ciphertexts. Reads pick the cipher from the leading
+/// byte, so a store can hold data written with either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncryptionAlgorithm {
+    #[default]
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl EncryptionAlgorithm {
+    fn id(self) -> u8 {
+        match self {
+            EncryptionAlgorithm::Aes256Gcm => 1,
+            EncryptionAlgorithm::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(EncryptionAlgorithm::Aes256Gcm),
+            2 => Some(EncryptionAlgorithm::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    fn seal(self, key: &[u8; 32], nonce: &[u8], data: &[u8], aad: &[u8]) -> std::result::Result<Vec<u8>, aes_gcm::aead::Error> {
+        match self {
+            EncryptionAlgorithm::Aes256Gcm => {
+                Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)).encrypt(Nonce::from_slice(nonce), Payload { msg: data, aad })
+            }
+            EncryptionAlgorithm::ChaCha20Poly1305 => {
+                ChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key)).encrypt(chacha20poly1305::Nonce::from_slice(nonce), Payload { msg: data, aad })
+            }
+        }
+    }
+
+    fn open(self, key: &[u8; 32], nonce: &[u8], data: &[u8], aad: &[u8]) -> std::result::Result<Vec<u8>, aes_gcm::aead::Error> {
+        match self {
+            EncryptionAlgorithm::Aes256Gcm => {
+                Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)).decrypt(Nonce::from_slice(nonce), Payload { msg: data, aad })
+            }
+            EncryptionAlgorithm::ChaCha20Poly1305 => {
+                ChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key)).decrypt(chacha20poly1305::Nonce::from_slice(nonce), Payload { msg: data, aad })
+            }
+        }
+    }
+}
+
+/// Supplies the nonce for each new ciphertext. A nonce must never repeat under the
+/// same key, so anything but the default `RandomNonce` is only fit for tests that
+/// need reproducible output.
+pub trait NonceProvider: Send + Sync {
+    fn next_nonce(&self) -> [u8; NONCE_LEN];
+}
+
+/// Draws every nonce from the OS RNG.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomNonce;
+
+impl NonceProvider for RandomNonce {
+    fn next_nonce(&self) -> [u8; NONCE_LEN] {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        nonce
+    }
+}
+
+pub fn generate_salt() -> [u8; SALT_LEN] {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+/// Holds the key in a buffer that is wiped on drop. Deliberately not `Clone`, so the
+/// key isn't duplicated around memory.
+///
+/// ```compile_fail
+/// use storage_engine::crypto::encryption::EncryptionConfig;
+///
+/// let config = EncryptionConfig::new([7; 32]);
+/// let copy = config.clone();
+/// ```
 pub struct EncryptionConfig {
-    key: [u8; 32],
+    key: Zeroizing<[u8; 32]>,
     enabled: bool,
+    algorithm: EncryptionAlgorithm,
+    nonces: Arc<dyn NonceProvider>,
 }
 
 impl EncryptionConfig {
     pub fn new(key: [u8; 32]) -> Self {
         Self {
-            key,
+            key: Zeroizing::new(key),
             enabled: true,
+            algorithm: EncryptionAlgorithm::default(),
+            nonces: Arc::new(RandomNonce),
         }
     }
 
-    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
-        if !self.enabled {
-            return Ok(data.to_vec());
+    /// A config that leaves newly encrypted data as plaintext until enabled.
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            ..Self::new([0u8; 32])
         }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
 
-        let key = Key::<Aes256Gcm>::from_slice(&self.key);
-        let cipher = Aes256Gcm::new(key);
-        let nonce = Nonce::from_slice(b"somedumbshit");
+    pub fn with_nonce_provider(mut self, nonces: impl NonceProvider + 'static) -> Self {
+        self.nonces = Arc::new(nonces);
+        self
+    }
+
+    /// A config keyed with an HKDF-SHA256 subkey of this one for `context`. The same
+    /// context always yields the same subkey, so it can be re-derived for reads.
+    pub fn derive(&self, context: &[u8]) -> Result<Self> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        Hkdf::<Sha256>::new(None, self.key.as_ref())
+            .expand(context, key.as_mut())
+            .map_err(|e| crate::AppError::Storage(StorageError::Storage(format!("Key derivation error: {}", e))))?;
+        Ok(Self {
+            key,
+            enabled: self.enabled,
+            algorithm: self.algorithm,
+            nonces: Arc::clone(&self.nonces),
+        })
+    }
 
-        cipher.encrypt(nonce, data).map_err(|err| crate::AppError::Storage(StorageError::Storage(format!("Encryption Error: {}", err))))
+    pub fn with_algorithm(mut self, algorithm: EncryptionAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
     }
 
-    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+    /// Derives the key from a passphrase with Argon2id. The same passphrase and
+    /// salt always produce the same key, so the salt must be kept with the data.
+    pub fn from_passphrase(passphrase: &str, salt: &[u8]) -> Result<Self> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+            .map_err(|e| crate::AppError::Storage(StorageError::Storage(format!("Key derivation error: {}", e))))?;
+        Ok(Self {
+            key,
+            enabled: true,
+            algorithm: EncryptionAlgorithm::default(),
+            nonces: Arc::new(RandomNonce),
+        })
+    }
+
+    /// Encrypts `data`, authenticating `aad` alongside it. The same `aad` must be
+    /// passed to `decrypt`, so it can tie a ciphertext to where it belongs.
+    pub fn encrypt(&self, data: &[u8], aad: Option<&[u8]>) -> Result<Vec<u8>> {
         if !self.enabled {
             return Ok(data.to_vec());
         }
 
-        let key = Key::<Aes256Gcm>::from_slice(&self.key);
-        let cipher = Aes256Gcm::new(key);
-        let nonce = Nonce::from_slice(b"somedumbshit");
+        let nonce = self.nonces.next_nonce();
+
+        let ciphertext = self
+            .algorithm
+            .seal(&self.key, &nonce, data, aad.unwrap_or_default())
+            .map_err(|err| crate::AppError::Storage(StorageError::Storage(format!("Encryption Error: {}", err))))?;
+
+        let mut encrypted = Vec::with_capacity(1 + NONCE_LEN + ciphertext.len());
+        encrypted.push(self.algorithm.id());
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&ciphertext);
+        Ok(encrypted)
+    }
+
+    /// Fails with `StorageError::IntegrityError` when authentication fails, which means the
+    /// key, nonce or `aad` is wrong or the data was altered. Decrypts whether or not the
+    /// config is enabled, since that only decides whether new data gets encrypted.
+    pub fn decrypt(&self, data: &[u8], aad: Option<&[u8]>) -> Result<Vec<u8>> {
+        if data.len() < TAG_LEN {
+            return Err(crate::AppError::Storage(StorageError::IntegrityError(format!("Ciphertext too short to decrypt: {} bytes", data.len()))));
+        }
+
+        // A legacy blob can start with an algorithm byte by chance; authentication
+        // rejects the wrong reading, so fall back to the legacy nonce on failure
+        if let Some((&id, rest)) = data.split_first() {
+            if let Some(algorithm) = EncryptionAlgorithm::from_id(id).filter(|_| rest.len() >= NONCE_LEN) {
+                let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+                if let Ok(plaintext) = algorithm.open(&self.key, nonce, ciphertext, aad.unwrap_or_default()) {
+                    return Ok(plaintext);
+                }
+            }
+        }
+
+        EncryptionAlgorithm::Aes256Gcm
+            .open(&self.key, LEGACY_NONCE, data, aad.unwrap_or_default())
+            .map_err(|_| crate::AppError::Storage(StorageError::IntegrityError("decryption failed: wrong key or corrupted data".to_string())))
+    }
+}
+
+// The key field wipes itself when dropped
+impl ZeroizeOnDrop for EncryptionConfig {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_encryption_uses_a_fresh_nonce() {
+        let config = EncryptionConfig::new([7; 32]);
+        let first = config.encrypt(b"same plaintext", None).unwrap();
+        let second = config.encrypt(b"same plaintext", None).unwrap();
+
+        assert_ne!(first[1..1 + NONCE_LEN], second[1..1 + NONCE_LEN]);
+        assert_ne!(first, second);
+        assert_eq!(config.decrypt(&first, None).unwrap(), b"same plaintext");
+        assert_eq!(config.decrypt(&second, None).unwrap(), b"same plaintext");
+    }
 
-        cipher.decrypt(nonce, data)
-            .map_err(|e| crate::AppError::Storage(StorageError::Storage(format!("Decryption error: {}", e))))
+    #[test]
+    fn blobs_written_with_the_legacy_nonce_still_decrypt() {
+        let key = [7; 32];
+        let legacy = EncryptionAlgorithm::Aes256Gcm.seal(&key, LEGACY_NONCE, b"written long ago", b"").unwrap();
+
+        assert_eq!(EncryptionConfig::new(key).decrypt(&legacy, None).unwrap(), b"written long ago");
+    }
+
+    #[test]
+    fn same_passphrase_and_salt_derive_the_same_key() {
+        let salt = generate_salt();
+        let sealed = EncryptionConfig::from_passphrase("correct horse", &salt).unwrap().encrypt(b"secret", None).unwrap();
+
+        let reopened = EncryptionConfig::from_passphrase("correct horse", &salt).unwrap();
+        assert_eq!(reopened.decrypt(&sealed, None).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn different_salts_derive_incompatible_keys() {
+        let sealed = EncryptionConfig::from_passphrase("correct horse", &[1; SALT_LEN]).unwrap().encrypt(b"secret", None).unwrap();
+
+        let other = EncryptionConfig::from_passphrase("correct horse", &[2; SALT_LEN]).unwrap();
+        assert!(matches!(other.decrypt(&sealed, None), Err(crate::AppError::Storage(StorageError::IntegrityError(_)))));
+    }
+
+    #[test]
+    fn both_ciphers_round_trip_and_tag_their_output() {
+        for algorithm in [EncryptionAlgorithm::Aes256Gcm, EncryptionAlgorithm::ChaCha20Poly1305] {
+            let config = EncryptionConfig::new([7; 32]).with_algorithm(algorithm);
+            let sealed = config.encrypt(b"secret", Some(b"file")).unwrap();
+
+            assert_eq!(sealed[0], algorithm.id());
+            assert_eq!(config.decrypt(&sealed, Some(b"file")).unwrap(), b"secret", "{:?}", algorithm);
+            // Reads follow the leading byte, whichever cipher the reader writes with
+            assert_eq!(EncryptionConfig::new([7; 32]).decrypt(&sealed, Some(b"file")).unwrap(), b"secret");
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn wrong_key_fails_cleanly_for_both_ciphers() {
+        for algorithm in [EncryptionAlgorithm::Aes256Gcm, EncryptionAlgorithm::ChaCha20Poly1305] {
+            let sealed = EncryptionConfig::new([7; 32]).with_algorithm(algorithm).encrypt(b"secret", None).unwrap();
+
+            let result = EncryptionConfig::new([8; 32]).with_algorithm(algorithm).decrypt(&sealed, None);
+            assert!(matches!(result, Err(crate::AppError::Storage(StorageError::IntegrityError(_)))), "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn wrong_key_reports_an_integrity_error() {
+        let sealed = EncryptionConfig::new([7; 32]).encrypt(b"secret", None).unwrap();
+
+        let err = EncryptionConfig::new([8; 32]).decrypt(&sealed, None).unwrap_err();
+        assert!(matches!(err, crate::AppError::Storage(StorageError::IntegrityError(_))), "{:?}", err);
+        assert!(err.to_string().contains("decryption failed: wrong key or corrupted data"), "{}", err);
+    }
+
+    #[test]
+    fn truncated_ciphertext_fails_cleanly() {
+        let config = EncryptionConfig::new([7; 32]);
+        assert!(config.decrypt(&[2, 0, 0], None).is_err());
+        assert!(config.decrypt(&[], None).is_err());
+    }
+
+    #[test]
+    fn dropping_a_config_wipes_its_key() {
+        let mut slot = std::mem::MaybeUninit::new(EncryptionConfig::new([7; 32]));
+        // SAFETY: the config is dropped exactly once and its key bytes are only read
+        // back afterwards, while `slot` still owns the memory
+        let key: [u8; 32] = unsafe {
+            std::ptr::drop_in_place(slot.as_mut_ptr());
+            **std::ptr::addr_of!((*slot.as_ptr()).key)
+        };
+        assert_eq!(key, [0; 32]);
+    }
+
+    struct FixedNonce([u8; NONCE_LEN]);
+
+    impl NonceProvider for FixedNonce {
+        fn next_nonce(&self) -> [u8; NONCE_LEN] {
+            self.0
+        }
+    }
+
+    #[test]
+    fn fixed_nonce_gives_exact_ciphertext() {
+        // Test case 14 of the GCM specification: zero key, zero nonce, one zero block
+        let config = EncryptionConfig::new([0; 32]).with_nonce_provider(FixedNonce([0; NONCE_LEN]));
+        let encrypted = config.encrypt(&[0; 16], None).unwrap();
+
+        let mut expected = vec![EncryptionAlgorithm::Aes256Gcm.id()];
+        expected.extend_from_slice(&[0; NONCE_LEN]);
+        expected.extend_from_slice(&[0xce, 0xa7, 0x40, 0x3d, 0x4d, 0x60, 0x6b, 0x6e, 0x07, 0x4e, 0xc5, 0xd3, 0xba, 0xf3, 0x9d, 0x18]);
+        expected.extend_from_slice(&[0xd0, 0xd1, 0xc8, 0xa7, 0x99, 0x99, 0x6b, 0xf0, 0x26, 0x5b, 0x98, 0xb5, 0xd4, 0x8a, 0xb9, 0x19]);
+        assert_eq!(encrypted, expected);
+        assert_eq!(config.encrypt(&[0; 16], None).unwrap(), expected);
+    }
+
+    #[test]
+    fn decryption_fails_under_another_files_id() {
+        let config = EncryptionConfig::new([7; 32]);
+        let (owner, other) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+        let encrypted = config.encrypt(b"belongs to one file", Some(owner.as_bytes())).unwrap();
+
+        assert_eq!(config.decrypt(&encrypted, Some(owner.as_bytes())).unwrap(), b"belongs to one file");
+        let err = config.decrypt(&encrypted, Some(other.as_bytes())).unwrap_err();
+        assert!(matches!(err, crate::AppError::Storage(StorageError::IntegrityError(_))), "{:?}", err);
+        assert!(config.decrypt(&encrypted, None).is_err());
+    }
+}
@@ -8,6 +8,37 @@ pub enum StorageError {
     NotFound(String),
     #[error("Storage error: {0}")]
     Storage(String),
+    #[error("Insufficient disk space: {available} bytes available, {required} bytes required")]
+    InsufficientSpace { available: u64, required: u64 },
+    /// Stored data doesn't match what was recorded for it, or can't be decoded.
+    #[error("Integrity check failed: {0}")]
+    IntegrityError(String),
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+}
+
+impl StorageError {
+    /// Whether trying again could succeed. Missing files, bad input, lack of space and
+    /// corrupt or tampered data come out the same every time; timeouts, dropped
+    /// connections and errors of unknown cause may not.
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            StorageError::Io(e) => !matches!(
+                e.kind(),
+                std::io::ErrorKind::NotFound
+                    | std::io::ErrorKind::PermissionDenied
+                    | std::io::ErrorKind::AlreadyExists
+                    | std::io::ErrorKind::InvalidInput
+                    | std::io::ErrorKind::InvalidData
+                    | std::io::ErrorKind::Unsupported
+            ),
+            StorageError::Storage(_) => true,
+            StorageError::NotFound(_)
+            | StorageError::InsufficientSpace { .. }
+            | StorageError::IntegrityError(_)
+            | StorageError::InvalidInput(_) => false,
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -28,6 +59,49 @@ pub enum AppError { // New encompassing error type
     Daemon(#[from] DaemonError),
     #[error("Other application error: {0}")]
     Other(String), // For other non-storage, non-daemon errors
+    #[error("Gave up after {attempts} attempts in {elapsed:?}: {source}")]
+    RetriesExhausted {
+        attempts: u32,
+        elapsed: std::time::Duration,
+        source: Box<AppError>,
+    },
+    #[error("Cancelled after {attempts} attempts")]
+    Cancelled {
+        attempts: u32,
+    },
+}
+
+impl AppError {
+    /// Whether `with_retry` should try the operation again after this error.
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            AppError::Storage(e) => e.is_retriable(),
+            AppError::Daemon(_) | AppError::Other(_) => true,
+            AppError::RetriesExhausted { .. } | AppError::Cancelled { .. } => false,
+        }
+    }
 }
 
-pub type Result<T> = std::result::Result<T, AppError>;
\ No newline at end of file
+pub type Result<T> = std::result::Result<T, AppError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transient_failures_are_retriable() {
+        assert!(StorageError::Io(std::io::Error::from(std::io::ErrorKind::TimedOut)).is_retriable());
+        assert!(StorageError::Io(std::io::Error::from(std::io::ErrorKind::ConnectionReset)).is_retriable());
+        assert!(AppError::Other("unknown".to_string()).is_retriable());
+    }
+
+    #[test]
+    fn permanent_failures_are_not_retriable() {
+        assert!(!StorageError::Io(std::io::Error::from(std::io::ErrorKind::NotFound)).is_retriable());
+        assert!(!StorageError::NotFound("notes.txt".to_string()).is_retriable());
+        assert!(!StorageError::IntegrityError("chunk corrupted".to_string()).is_retriable());
+        assert!(!StorageError::InvalidInput("empty file".to_string()).is_retriable());
+        assert!(!StorageError::InsufficientSpace { available: 0, required: 1 }.is_retriable());
+        assert!(!AppError::Cancelled { attempts: 1 }.is_retriable());
+    }
+}
@@ -1,33 +1,381 @@
 use lru::LruCache;
+use std::collections::HashMap;
 use std::num::NonZeroUsize;
+use std::path::PathBuf;
 use std::sync::Arc;
+use tokio::fs;
 use tokio::sync::Mutex;
 use uuid::Uuid;
+use crate::{AppError, ChunkId, FileId, Result, StorageError};
+
+struct CacheEntries {
+    lru: LruCache<FileId, Vec<u8>>,
+    // Pinned ids live outside the LRU so they are never evicted; `None` until first cached
+    pinned: HashMap<FileId, Option<Vec<u8>>>,
+    // Bytes held by both LRU and pinned entries
+    total_bytes: u64,
+}
+
+impl CacheEntries {
+    fn pop_lru_entry(&mut self, id: &FileId) {
+        if let Some(data) = self.lru.pop(id) {
+            self.total_bytes -= data.len() as u64;
+        }
+    }
+
+    /// Evicts least recently used entries until `incoming` more bytes fit under `max_bytes`.
+    fn make_room(&mut self, incoming: u64, max_bytes: u64) -> bool {
+        while self.total_bytes + incoming > max_bytes {
+            match self.lru.pop_lru() {
+                Some((_, data)) => self.total_bytes -= data.len() as u64,
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub pinned: usize,
+    pub bytes: u64,
+}
 
 pub struct CacheManager {
-    cache: Arc<Mutex<LruCache<Uuid, Vec<u8>>>>,
+    cache: Arc<Mutex<CacheEntries>>,
+    max_pinned: usize,
+    max_bytes: Option<u64>,
 }
 
 impl CacheManager {
     pub fn new(cache_size: usize) -> Self {
         Self {
-            cache: Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(cache_size).unwrap())))
+            cache: Arc::new(Mutex::new(CacheEntries {
+                lru: LruCache::new(NonZeroUsize::new(cache_size).unwrap()),
+                pinned: HashMap::new(),
+                total_bytes: 0,
+            })),
+            // Leave at least half the cache to LRU entries
+            max_pinned: (cache_size / 2).max(1),
+            max_bytes: None,
+        }
+    }
+
+    /// Caps the bytes held by the cache. `put` evicts before inserting, under the
+    /// cache lock, so concurrent puts wait their turn instead of overshooting the cap.
+    /// Entries that can't fit even after eviction are not cached.
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    pub async fn get(&self, id: &FileId) -> Option<Vec<u8>> {
+        let mut cache = self.cache.lock().await;
+        if let Some(entry) = cache.pinned.get(id) {
+            return entry.clone();
+        }
+        cache.lru.get(id).cloned()
+    }
+
+    pub async fn put(&self, id: FileId, data: Vec<u8>) {
+        let mut cache = self.cache.lock().await;
+        let incoming = data.len() as u64;
+
+        if let Some(previous) = cache.pinned.get_mut(&id).map(Option::take) {
+            cache.total_bytes -= previous.map_or(0, |data| data.len() as u64);
+            if self.max_bytes.is_some_and(|max_bytes| !cache.make_room(incoming, max_bytes)) {
+                return;
+            }
+            cache.total_bytes += incoming;
+            cache.pinned.insert(id, Some(data));
+            return;
+        }
+
+        cache.pop_lru_entry(&id);
+        if self.max_bytes.is_some_and(|max_bytes| !cache.make_room(incoming, max_bytes)) {
+            return;
+        }
+        cache.total_bytes += incoming;
+        if let Some((_, evicted)) = cache.lru.push(id, data) {
+            cache.total_bytes -= evicted.len() as u64;
+        }
+    }
+
+    pub async fn invalidate(&self, id: &FileId) {
+        let mut cache = self.cache.lock().await;
+        if let Some(Some(data)) = cache.pinned.get_mut(id).map(Option::take) {
+            cache.total_bytes -= data.len() as u64;
+        }
+        cache.pop_lru_entry(id);
+    }
+
+    /// Keeps `id` cached regardless of LRU pressure until it is unpinned.
+    pub async fn pin(&self, id: FileId) -> Result<()> {
+        let mut cache = self.cache.lock().await;
+        if cache.pinned.contains_key(&id) {
+            return Ok(());
+        }
+        if cache.pinned.len() >= self.max_pinned {
+            return Err(AppError::Storage(StorageError::InvalidInput(format!(
+                "Cannot pin more than {} files",
+                self.max_pinned
+            ))));
+        }
+
+        let entry = cache.lru.pop(&id);
+        cache.pinned.insert(id, entry);
+        Ok(())
+    }
+
+    pub async fn unpin(&self, id: &FileId) {
+        let mut cache = self.cache.lock().await;
+        if let Some(Some(data)) = cache.pinned.remove(id) {
+            if let Some((_, evicted)) = cache.lru.push(*id, data) {
+                cache.total_bytes -= evicted.len() as u64;
+            }
+        }
+    }
+
+    pub async fn stats(&self) -> CacheStats {
+        let cache = self.cache.lock().await;
+        CacheStats {
+            entries: cache.lru.len() + cache.pinned.values().filter(|entry| entry.is_some()).count(),
+            pinned: cache.pinned.len(),
+            bytes: cache.total_bytes,
+        }
+    }
+}
+
+/// Stored (still processed) chunk bytes. Chunks are never rewritten in place, so
+/// entries can't go stale; removed chunks simply stop being requested.
+pub struct ChunkCache {
+    cache: Mutex<LruCache<ChunkId, Vec<u8>>>,
+}
+
+impl ChunkCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            cache: Mutex::new(LruCache::new(NonZeroUsize::new(capacity).unwrap())),
         }
     }
 
-    pub async fn get(&self, id: &Uuid) -> Option<Vec<u8>> {
+    pub async fn get(&self, id: &ChunkId) -> Option<Vec<u8>> {
         let mut cache = self.cache.lock().await;
         cache.get(id).cloned()
     }
 
-    pub async fn put(&self, id: Uuid, data: Vec<u8>) {
+    pub async fn put(&self, id: ChunkId, data: Vec<u8>) {
         let mut cache = self.cache.lock().await;
         cache.put(id, data);
     }
+}
 
-    pub async fn invalidate(&self, id: &Uuid) {
-        let mut cache = self.cache.lock().await;
-        cache.pop(id);
+struct PersistentEntries {
+    sizes: LruCache<FileId, u64>,
+    total_bytes: u64,
+}
+
+/// Second cache tier keeping decoded files on disk so warm data survives restarts.
+pub struct PersistentCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    entries: Mutex<PersistentEntries>,
+}
+
+impl PersistentCache {
+    pub fn new(dir: PathBuf, max_bytes: u64) -> Result<Self> {
+        std::fs::create_dir_all(&dir).map_err(|e| AppError::Storage(StorageError::Storage(e.to_string())))?;
+
+        // Rebuild the LRU order from modification times so the oldest entries go first
+        let mut existing = Vec::new();
+        for entry in std::fs::read_dir(&dir).map_err(|e| AppError::Storage(StorageError::Storage(e.to_string())))? {
+            let entry = entry.map_err(|e| AppError::Storage(StorageError::Storage(e.to_string())))?;
+            let Some(id) = entry.file_name().to_str().and_then(|name| Uuid::parse_str(name).ok().map(FileId)) else {
+                continue;
+            };
+            let metadata = entry.metadata().map_err(|e| AppError::Storage(StorageError::Storage(e.to_string())))?;
+            existing.push((metadata.modified().ok(), id, metadata.len()));
+        }
+        existing.sort();
+
+        let mut entries = PersistentEntries {
+            sizes: LruCache::unbounded(),
+            total_bytes: 0,
+        };
+        for (_, id, size) in existing {
+            entries.sizes.put(id, size);
+            entries.total_bytes += size;
+        }
+
+        Ok(Self {
+            dir,
+            max_bytes,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    fn entry_path(&self, id: &FileId) -> PathBuf {
+        self.dir.join(id.to_string())
     }
-    
-}
\ No newline at end of file
+
+    pub async fn get(&self, id: &FileId) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().await;
+        entries.sizes.get(id)?;
+
+        match fs::read(self.entry_path(id)).await {
+            Ok(data) => Some(data),
+            Err(_) => {
+                if let Some(size) = entries.sizes.pop(id) {
+                    entries.total_bytes -= size;
+                }
+                None
+            }
+        }
+    }
+
+    pub async fn put(&self, id: FileId, data: &[u8]) -> Result<()> {
+        let size = data.len() as u64;
+        if size > self.max_bytes {
+            return Ok(());
+        }
+
+        let mut entries = self.entries.lock().await;
+        if let Some(previous) = entries.sizes.pop(&id) {
+            entries.total_bytes -= previous;
+        }
+
+        while entries.total_bytes + size > self.max_bytes {
+            let Some((evicted, evicted_size)) = entries.sizes.pop_lru() else {
+                break;
+            };
+            entries.total_bytes -= evicted_size;
+            let _ = fs::remove_file(self.entry_path(&evicted)).await;
+        }
+
+        fs::write(self.entry_path(&id), data).await.map_err(|e| AppError::Storage(StorageError::Storage(e.to_string())))?;
+        entries.sizes.put(id, size);
+        entries.total_bytes += size;
+        Ok(())
+    }
+
+    pub async fn invalidate(&self, id: &FileId) {
+        let mut entries = self.entries.lock().await;
+        if let Some(size) = entries.sizes.pop(id) {
+            entries.total_bytes -= size;
+        }
+        let _ = fs::remove_file(self.entry_path(id)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn persistent_entries_survive_a_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let id = FileId::new();
+        PersistentCache::new(dir.path().to_path_buf(), 1000).unwrap().put(id, b"warm").await.unwrap();
+
+        let reopened = PersistentCache::new(dir.path().to_path_buf(), 1000).unwrap();
+        assert_eq!(reopened.get(&id).await.unwrap(), b"warm");
+    }
+
+    #[tokio::test]
+    async fn persistent_cache_evicts_the_least_recently_used_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = PersistentCache::new(dir.path().to_path_buf(), 10).unwrap();
+        let (first, second, third) = (FileId::new(), FileId::new(), FileId::new());
+        cache.put(first, b"aaaa").await.unwrap();
+        cache.put(second, b"bbbb").await.unwrap();
+        cache.get(&first).await.unwrap();
+
+        cache.put(third, b"cccc").await.unwrap();
+        assert!(cache.get(&second).await.is_none());
+        assert!(!dir.path().join(second.to_string()).exists());
+        assert_eq!(cache.get(&first).await.unwrap(), b"aaaa");
+        assert_eq!(cache.get(&third).await.unwrap(), b"cccc");
+    }
+
+    #[tokio::test]
+    async fn invalidated_persistent_entries_are_removed_from_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = PersistentCache::new(dir.path().to_path_buf(), 1000).unwrap();
+        let id = FileId::new();
+        cache.put(id, b"stale").await.unwrap();
+
+        cache.invalidate(&id).await;
+        assert!(cache.get(&id).await.is_none());
+        assert!(!dir.path().join(id.to_string()).exists());
+    }
+
+    #[tokio::test]
+    async fn pinned_entries_survive_a_flood() {
+        let cache = CacheManager::new(4);
+        let pinned = FileId::new();
+        cache.put(pinned, b"hot".to_vec()).await;
+        cache.pin(pinned).await.unwrap();
+
+        for _ in 0..20 {
+            cache.put(FileId::new(), b"cold".to_vec()).await;
+        }
+        assert_eq!(cache.get(&pinned).await.unwrap(), b"hot");
+        assert_eq!(cache.stats().await.pinned, 1);
+    }
+
+    #[tokio::test]
+    async fn pinning_is_capped_at_half_the_cache() {
+        let cache = CacheManager::new(4);
+        cache.pin(FileId::new()).await.unwrap();
+        cache.pin(FileId::new()).await.unwrap();
+
+        let err = cache.pin(FileId::new()).await.unwrap_err();
+        assert!(matches!(err, AppError::Storage(StorageError::InvalidInput(_))));
+    }
+
+    #[tokio::test]
+    async fn unpinned_entries_can_be_evicted_again() {
+        let cache = CacheManager::new(2);
+        let id = FileId::new();
+        cache.put(id, b"hot".to_vec()).await;
+        cache.pin(id).await.unwrap();
+        cache.unpin(&id).await;
+
+        cache.put(FileId::new(), b"cold".to_vec()).await;
+        cache.put(FileId::new(), b"cold".to_vec()).await;
+        assert!(cache.get(&id).await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_puts_stay_under_the_byte_ceiling() {
+        let cache = Arc::new(CacheManager::new(100).with_max_bytes(10_000));
+        let mut puts = tokio::task::JoinSet::new();
+        for _ in 0..8 {
+            let cache = cache.clone();
+            puts.spawn(async move {
+                for _ in 0..50 {
+                    cache.put(FileId::new(), vec![0; 3000]).await;
+                }
+            });
+        }
+
+        while !puts.is_empty() {
+            assert!(cache.stats().await.bytes <= 10_000);
+            if let Ok(Some(joined)) = tokio::time::timeout(std::time::Duration::from_millis(1), puts.join_next()).await {
+                joined.unwrap();
+            }
+        }
+        assert!(cache.stats().await.bytes <= 10_000);
+    }
+
+    #[tokio::test]
+    async fn entries_larger_than_the_ceiling_are_not_cached() {
+        let cache = CacheManager::new(10).with_max_bytes(100);
+        let id = FileId::new();
+        cache.put(id, vec![0; 101]).await;
+
+        assert!(cache.get(&id).await.is_none());
+        assert_eq!(cache.stats().await.bytes, 0);
+    }
+}
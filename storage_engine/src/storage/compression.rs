@@ -1,37 +1,480 @@
 use flate2::{write::GzEncoder, read::GzDecoder, Compression};
+use serde::{Deserialize, Serialize};
 use std::io::prelude::*;
+use std::sync::Mutex;
+use std::time::Instant;
 use crate::{AppError, Result};
 
+// Bytes inspected when estimating entropy
+const ENTROPY_SAMPLE_SIZE: usize = 64 * 1024;
+// Above this many bits per byte, gzip rarely gains anything worth the CPU
+const INCOMPRESSIBLE_ENTROPY: f64 = 7.5;
+// Levels tried while tuning, fastest first
+const CANDIDATE_LEVELS: [u32; 4] = [1, 3, 6, 9];
+// Bytes of each file compressed at every candidate level while tuning
+const TUNING_SAMPLE_SIZE: usize = 1024 * 1024;
+// Longest prefix of a file that compression decisions depend on
+pub(crate) const DECISION_PREFIX_SIZE: usize = if ENTROPY_SAMPLE_SIZE > TUNING_SAMPLE_SIZE { ENTROPY_SAMPLE_SIZE } else { TUNING_SAMPLE_SIZE };
+
+/// Picks the compression level from the first few files instead of using a fixed one. Each
+/// sampled file votes for the best-compressing level that still meets the throughput
+/// target, and the most voted level is kept for every later file.
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptiveCompression {
+    pub sample_files: usize,
+    /// Minimum compression speed in bytes per second.
+    pub min_throughput: f64,
+}
+
+impl Default for AdaptiveCompression {
+    fn default() -> Self {
+        Self {
+            sample_files: 3,
+            min_throughput: 20.0 * 1024.0 * 1024.0,
+        }
+    }
+}
+
+// Zstd level for each gzip-style level 0-9; zstd's own scale runs much higher
+const ZSTD_LEVELS: [i32; 10] = [1, 1, 2, 3, 4, 5, 6, 9, 15, 19];
+// Gzip output always starts with this byte, which marks data written before tags existed
+const LEGACY_GZIP_MAGIC: u8 = 0x1f;
+
+/// Codec for newly compressed data. Output starts with a byte naming the codec, so
+/// `decompress` handles data written with any of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum CompressionAlgorithm {
+    #[default]
+    Gzip,
+    Zstd,
+    /// Ignores the compression level.
+    Lz4,
+}
+
+impl CompressionAlgorithm {
+    fn tag(self) -> u8 {
+        match self {
+            CompressionAlgorithm::Gzip => 1,
+            CompressionAlgorithm::Zstd => 2,
+            CompressionAlgorithm::Lz4 => 3,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(CompressionAlgorithm::Gzip),
+            2 => Some(CompressionAlgorithm::Zstd),
+            3 => Some(CompressionAlgorithm::Lz4),
+            _ => None,
+        }
+    }
+
+    pub fn uses_level(self) -> bool {
+        self != CompressionAlgorithm::Lz4
+    }
+
+    fn compress(self, data: &[u8], level: u32) -> Result<Vec<u8>> {
+        match self {
+            CompressionAlgorithm::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+                encoder.write_all(data).map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+                encoder.finish().map_err(|e| crate::AppError::Storage(crate::StorageError::Storage(e.to_string())))
+            }
+            CompressionAlgorithm::Zstd => zstd::encode_all(data, ZSTD_LEVELS[level.min(9) as usize])
+                .map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string()))),
+            CompressionAlgorithm::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+        }
+    }
+
+    fn decompress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            CompressionAlgorithm::Gzip => {
+                let mut decoder = GzDecoder::new(data);
+                let mut decompressed = Vec::new();
+                decoder.read_to_end(&mut decompressed).map_err(|e| AppError::Storage(crate::StorageError::IntegrityError(e.to_string())))?;
+                Ok(decompressed)
+            }
+            CompressionAlgorithm::Zstd => zstd::decode_all(data)
+                .map_err(|e| AppError::Storage(crate::StorageError::IntegrityError(e.to_string()))),
+            CompressionAlgorithm::Lz4 => lz4_flex::decompress_size_prepended(data)
+                .map_err(|e| AppError::Storage(crate::StorageError::IntegrityError(e.to_string()))),
+        }
+    }
+}
+
+/// Compresses data fed to it piece by piece, producing the same bytes `compress_with_level`
+/// would for all of it at once. Lz4's output is prefixed with the input length, so that
+/// codec keeps the input in memory until `finish`.
+pub struct StreamEncoder {
+    inner: StreamEncoderInner,
+}
+
+enum StreamEncoderInner {
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Lz4(Vec<u8>),
+}
+
+impl StreamEncoder {
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        match &mut self.inner {
+            StreamEncoderInner::Gzip(encoder) => encoder.write_all(data).map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string()))),
+            StreamEncoderInner::Zstd(encoder) => encoder.write_all(data).map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string()))),
+            StreamEncoderInner::Lz4(input) => {
+                input.extend_from_slice(data);
+                Ok(())
+            }
+        }
+    }
+
+    /// Compressed bytes produced since the last call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        match &mut self.inner {
+            StreamEncoderInner::Gzip(encoder) => std::mem::take(encoder.get_mut()),
+            StreamEncoderInner::Zstd(encoder) => std::mem::take(encoder.get_mut()),
+            StreamEncoderInner::Lz4(_) => Vec::new(),
+        }
+    }
+
+    /// Compressed bytes not yet returned by `take_output`.
+    pub fn finish(self) -> Result<Vec<u8>> {
+        match self.inner {
+            StreamEncoderInner::Gzip(encoder) => encoder.finish().map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string()))),
+            StreamEncoderInner::Zstd(encoder) => encoder.finish().map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string()))),
+            StreamEncoderInner::Lz4(input) => {
+                let mut compressed = vec![CompressionAlgorithm::Lz4.tag()];
+                compressed.extend(lz4_flex::compress_prepend_size(&input));
+                Ok(compressed)
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct TuningState {
+    votes: Vec<u32>,
+    chosen: Option<u32>,
+}
+
 pub struct CompressionManager {
     enabled: bool,
+    algorithm: CompressionAlgorithm,
+    level: u32,
+    adaptive: Option<AdaptiveCompression>,
+    tuning: Mutex<TuningState>,
 }
 
 impl CompressionManager {
     pub fn new(enabled: bool) -> Self {
         Self {
             enabled,
+            algorithm: CompressionAlgorithm::default(),
+            level: Compression::default().level(),
+            adaptive: None,
+            tuning: Mutex::new(TuningState::default()),
         }
     }
 
+    /// Sets the level on gzip's 0-9 scale, 9 being smallest and slowest. Other codecs
+    /// map it onto their own range; higher values are clamped to 9.
+    pub fn with_level(mut self, level: u32) -> Self {
+        self.level = level.min(9);
+        self
+    }
+
+    pub fn with_algorithm(mut self, algorithm: CompressionAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    pub fn algorithm(&self) -> CompressionAlgorithm {
+        self.algorithm
+    }
+
+    pub fn with_adaptive(mut self, adaptive: AdaptiveCompression) -> Self {
+        self.adaptive = Some(adaptive);
+        self
+    }
+
+    /// Configured level, which `compress` uses. Adaptive mode never changes it.
+    pub fn level(&self) -> u32 {
+        self.level
+    }
+
+    /// Level adaptive mode settled on, once it has seen enough files.
+    pub fn tuned_level(&self) -> Option<u32> {
+        self.tuning.lock().unwrap().chosen
+    }
+
+    /// Level a file compressed now would get: None for codecs without levels and while
+    /// adaptive mode is still tuning.
+    pub fn expected_level(&self) -> Option<u32> {
+        if !self.algorithm.uses_level() {
+            return None;
+        }
+        match self.adaptive {
+            Some(_) => self.tuned_level(),
+            None => Some(self.level()),
+        }
+    }
+
+    /// Level adaptive mode settled on, or the configured level before then.
+    pub fn current_level(&self) -> u32 {
+        self.tuned_level().unwrap_or(self.level)
+    }
+
+    /// Lets adaptive mode sample `data` while it is still tuning, then returns the
+    /// level to compress the file with, which goes to `compress_with_level`. Call once
+    /// per file before compressing it.
+    pub fn tune(&self, data: &[u8]) -> Result<u32> {
+        let Some(adaptive) = self.adaptive.filter(|_| self.enabled && self.algorithm.uses_level()) else {
+            return Ok(self.level);
+        };
+        if let Some(level) = self.tuned_level() {
+            return Ok(level);
+        }
+
+        // Sampling compresses the data several times, so other writers aren't made to wait on it
+        let level = self.best_level(&data[..data.len().min(TUNING_SAMPLE_SIZE)], adaptive.min_throughput)?;
+        let mut tuning = self.tuning.lock().unwrap();
+        if let Some(chosen) = tuning.chosen {
+            return Ok(chosen);
+        }
+        tuning.votes.push(level);
+        if tuning.votes.len() >= adaptive.sample_files {
+            // Ties go to the faster level
+            let chosen = CANDIDATE_LEVELS
+                .iter()
+                .copied()
+                .max_by_key(|candidate| (tuning.votes.iter().filter(|vote| *vote == candidate).count(), std::cmp::Reverse(*candidate)))
+                .unwrap_or(level);
+            tuning.chosen = Some(chosen);
+            return Ok(chosen);
+        }
+
+        Ok(level)
+    }
+
+    // Smallest output among the levels meeting the throughput target, or the fastest level
+    fn best_level(&self, sample: &[u8], min_throughput: f64) -> Result<u32> {
+        let mut best: Option<(u32, usize)> = None;
+        for level in CANDIDATE_LEVELS {
+            let started = Instant::now();
+            let size = self.algorithm.compress(sample, level)?.len();
+            let throughput = sample.len() as f64 / started.elapsed().as_secs_f64().max(f64::EPSILON);
+            if throughput >= min_throughput && best.is_none_or(|(_, best_size)| size < best_size) {
+                best = Some((level, size));
+            }
+        }
+        Ok(best.map_or(CANDIDATE_LEVELS[0], |(level, _)| level))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Shannon entropy in bits per byte of the first `ENTROPY_SAMPLE_SIZE` bytes.
+    pub fn estimate_entropy(data: &[u8]) -> f64 {
+        let sample = &data[..data.len().min(ENTROPY_SAMPLE_SIZE)];
+        if sample.is_empty() {
+            return 0.0;
+        }
+
+        let mut counts = [0usize; 256];
+        for byte in sample {
+            counts[*byte as usize] += 1;
+        }
+
+        let len = sample.len() as f64;
+        counts
+            .iter()
+            .filter(|count| **count > 0)
+            .map(|count| {
+                let p = *count as f64 / len;
+                -p * p.log2()
+            })
+            .sum()
+    }
+
+    /// Whether compression is enabled and a sample of `data` looks compressible.
+    pub fn is_worth_compressing(&self, data: &[u8]) -> bool {
+        self.enabled && Self::estimate_entropy(data) < INCOMPRESSIBLE_ENTROPY
+    }
+
     pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.compress_with_level(data, self.level)
+    }
+
+    /// Compresses at `level` instead of the configured one, for levels picked by `tune`.
+    pub fn compress_with_level(&self, data: &[u8], level: u32) -> Result<Vec<u8>> {
         if !self.enabled {
             return  Ok(data.to_vec());
         }
 
-        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-        encoder.write_all(data).map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())));
-        encoder.finish().map_err(|e| crate::AppError::Storage(crate::StorageError::Storage(e.to_string())))
+        let mut compressed = vec![self.algorithm.tag()];
+        compressed.extend(self.algorithm.compress(data, level.min(9))?);
+        Ok(compressed)
+    }
 
+    /// Starts compressing a stream at `level`. Its output begins with the codec tag, like
+    /// `compress_with_level`'s.
+    pub fn stream_encoder(&self, level: u32) -> Result<StreamEncoder> {
+        let level = level.min(9);
+        let inner = match self.algorithm {
+            CompressionAlgorithm::Gzip => StreamEncoderInner::Gzip(GzEncoder::new(vec![self.algorithm.tag()], Compression::new(level))),
+            CompressionAlgorithm::Zstd => StreamEncoderInner::Zstd(
+                zstd::stream::write::Encoder::new(vec![self.algorithm.tag()], ZSTD_LEVELS[level as usize])
+                    .map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?,
+            ),
+            CompressionAlgorithm::Lz4 => StreamEncoderInner::Lz4(Vec::new()),
+        };
+        Ok(StreamEncoder { inner })
     }
 
+    /// Decodes data from `compress`, picking the codec from its tag. Works whether or not
+    /// compression is enabled, which only decides whether new data gets compressed.
     pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
-        if !self.enabled {
-            return Ok(data.to_vec());
+        match data.split_first() {
+            Some((&LEGACY_GZIP_MAGIC, _)) => CompressionAlgorithm::Gzip.decompress(data),
+            Some((&tag, body)) => CompressionAlgorithm::from_tag(tag)
+                .ok_or_else(|| AppError::Storage(crate::StorageError::IntegrityError(format!("Unknown compression tag {}", tag))))?
+                .decompress(body),
+            None => Err(AppError::Storage(crate::StorageError::IntegrityError("Empty compressed data".to_string()))),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrupt_gzip_stream_fails_to_decompress() {
+        let manager = CompressionManager::new(true);
+        let data = b"the quick brown fox jumps over the lazy dog\n".repeat(1000);
+        let mut compressed = manager.compress(&data).unwrap();
+        let middle = compressed.len() / 2;
+        for byte in &mut compressed[middle..middle + 8] {
+            *byte ^= 0xff;
         }
 
-        let mut decoder = GzDecoder::new(data);
-        let mut decompressed = Vec::new();
-        decoder.read_to_end(&mut decompressed).map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())));
-        Ok(decompressed)
+        assert!(manager.decompress(&compressed).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn truncated_gzip_stream_fails_to_decompress() {
+        let manager = CompressionManager::new(true);
+        let compressed = manager.compress(&b"the quick brown fox jumps over the lazy dog\n".repeat(1000)).unwrap();
+
+        assert!(manager.decompress(&compressed[..compressed.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn adaptive_mode_settles_on_a_level_without_changing_the_configured_one() {
+        let manager = CompressionManager::new(true).with_level(9).with_adaptive(AdaptiveCompression {
+            sample_files: 2,
+            min_throughput: f64::INFINITY,
+        });
+        let data = b"the quick brown fox jumps over the lazy dog\n".repeat(1000);
+
+        // No level meets an infinite throughput target, so every vote goes to the fastest
+        assert_eq!(manager.tune(&data).unwrap(), 1);
+        assert_eq!(manager.tuned_level(), None);
+        assert_eq!(manager.tune(&data).unwrap(), 1);
+        assert_eq!(manager.tuned_level(), Some(1));
+        assert_eq!(manager.tune(&data).unwrap(), 1);
+        assert_eq!(manager.level(), 9);
+        assert_eq!(
+            manager.decompress(&manager.compress_with_level(&data, manager.current_level()).unwrap()).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn high_entropy_data_skips_compression() {
+        let manager = CompressionManager::new(true);
+        let mut state = 0x2545_f491u32;
+        let random: Vec<u8> = (0..100_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let text = b"the quick brown fox jumps over the lazy dog\n".repeat(1000);
+
+        assert!(CompressionManager::estimate_entropy(&random) > INCOMPRESSIBLE_ENTROPY);
+        assert!(!manager.is_worth_compressing(&random));
+        assert!(manager.is_worth_compressing(&text));
+        assert!(!CompressionManager::new(false).is_worth_compressing(&text));
+    }
+
+    const ALGORITHMS: [CompressionAlgorithm; 3] = [CompressionAlgorithm::Gzip, CompressionAlgorithm::Zstd, CompressionAlgorithm::Lz4];
+
+    #[test]
+    fn every_algorithm_round_trips_and_tags_its_output() {
+        let data = b"the quick brown fox jumps over the lazy dog\n".repeat(1000);
+        // Any manager can decompress, whatever codec it writes with
+        let reader = CompressionManager::new(true);
+
+        for algorithm in ALGORITHMS {
+            let compressed = CompressionManager::new(true).with_algorithm(algorithm).compress(&data).unwrap();
+            assert_eq!(compressed[0], algorithm.tag());
+            assert!(compressed.len() < data.len());
+            assert_eq!(reader.decompress(&compressed).unwrap(), data, "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn compressed_sizes_of_a_megabyte_of_repetitive_data() {
+        let data: Vec<u8> = b"the quick brown fox jumps over the lazy dog\n".iter().copied().cycle().take(1 << 20).collect();
+        let sizes: Vec<(CompressionAlgorithm, usize)> = ALGORITHMS
+            .into_iter()
+            .map(|algorithm| (algorithm, CompressionManager::new(true).with_algorithm(algorithm).compress(&data).unwrap().len()))
+            .collect();
+
+        for (algorithm, size) in &sizes {
+            assert!(*size < data.len() / 50, "{:?} left {} bytes", algorithm, size);
+        }
+        // Zstd beats gzip on repetitive data at the same level
+        assert!(sizes[1].1 < sizes[0].1, "{:?}", sizes);
+    }
+
+    #[test]
+    fn unknown_tag_fails_to_decompress() {
+        let err = CompressionManager::new(true).decompress(&[0x7f, 1, 2, 3]).unwrap_err();
+        assert!(matches!(err, AppError::Storage(crate::StorageError::IntegrityError(_))), "{:?}", err);
+    }
+
+    #[test]
+    fn level_nine_compresses_smaller_than_level_one() {
+        let data = b"the quick brown fox jumps over the lazy dog\n".iter().copied().cycle().take(1 << 20).collect::<Vec<u8>>();
+        let fast = CompressionManager::new(true).with_level(1).compress(&data).unwrap();
+        let small = CompressionManager::new(true).with_level(9).compress(&data).unwrap();
+
+        assert!(small.len() < fast.len(), "level 9: {} bytes, level 1: {} bytes", small.len(), fast.len());
+        assert_eq!(CompressionManager::new(true).decompress(&small).unwrap(), data);
+    }
+
+    #[test]
+    fn default_level_is_unchanged_and_high_levels_are_clamped() {
+        assert_eq!(CompressionManager::new(true).level(), Compression::default().level());
+        assert_eq!(CompressionManager::new(true).with_level(42).level(), 9);
+    }
+
+    #[test]
+    fn streamed_output_matches_compressing_at_once() {
+        let data = b"the quick brown fox jumps over the lazy dog\n".repeat(1000);
+        for algorithm in ALGORITHMS {
+            let manager = CompressionManager::new(true).with_algorithm(algorithm);
+            let mut encoder = manager.stream_encoder(manager.level()).unwrap();
+            let mut streamed = Vec::new();
+            for piece in data.chunks(4096) {
+                encoder.write(piece).unwrap();
+                streamed.extend(encoder.take_output());
+            }
+            streamed.extend(encoder.finish().unwrap());
+            assert_eq!(streamed, manager.compress(&data).unwrap(), "{:?}", algorithm);
+        }
+    }
+}
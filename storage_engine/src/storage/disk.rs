@@ -1,27 +1,154 @@
 use crate::{
     chunk::{ChunkManager, FileChunker},
-    crypto::encryption::EncryptionConfig, storage::progress::ProgressFormatter, AppError,
+    crypto::encryption::{generate_salt, EncryptionAlgorithm, EncryptionConfig}, AppError,
 };
-use crate::{Chunk, ChunkId, FileMetadata, FileType, FileTypeDetector, Result, StorageError};
+use crate::{Chunk, ChunkId, FileId, FileMetadata, FileType, FileTypeDetector, FileVersion, Result, StorageError, VersionedMetadata};
 use async_trait::async_trait;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
 use sha2::{Digest, Sha256};
 use std::{
     collections::{HashMap, HashSet},
+    io,
     path::{Path, PathBuf},
+    pin::Pin,
 };
-use tokio::fs;
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    sync::{watch, Mutex, RwLock},
+};
+use tokio_util::io::StreamReader;
+use tracing::{info, instrument, warn};
 use uuid::Uuid;
 
 use super::{
-    cache::CacheManager, compression::CompressionManager, progress::ProgressTracker, retry::{with_retry, RetryConfig}, validation::ValidationManager
+    cache::{CacheManager, CacheStats, ChunkCache, PersistentCache}, compression::{AdaptiveCompression, CompressionAlgorithm, CompressionManager, DECISION_PREFIX_SIZE}, index::{JsonNameIndex, NameIndex}, pipeline::{PipelineStage, ProcessingPipeline}, progress::{ProgressStats, ProgressTracker}, space::{DiskSpaceProbe, SystemDiskSpace}, retry::{with_retry, with_retry_budget, RetryBudget, RetryConfig}, validation::{OrphanedMetadataPolicy, ValidationManager}
 };
 
+// Chunk files written at once by a single store, unless configured otherwise
+const DEFAULT_WRITE_CONCURRENCY: usize = 4;
+
+// Decoded chunks of a file being read as a stream
+type ChunkStream<'a> = Pin<Box<dyn Stream<Item = Result<io::Cursor<Vec<u8>>>> + Send + 'a>>;
+
 #[async_trait]
 pub trait StorageBackend: Send + Sync {
     async fn store_file(&self, name: &str, data: &[u8]) -> Result<FileMetadata>;
-    async fn get_file(&self, id: &Uuid) -> Result<Vec<u8>>;
-    async fn delete_file(&self, id: &Uuid) -> Result<()>;
+    async fn get_file(&self, id: &FileId) -> Result<Vec<u8>>;
+    async fn delete_file(&self, id: &FileId) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EncryptionAudit {
+    pub encrypted: usize,
+    pub plaintext: usize,
+    pub plaintext_ids: Vec<FileId>,
+}
+
+/// Average compression ratio (original size over stored size) of the files of one type.
+#[derive(Debug, Clone, Default)]
+pub struct CompressionStats {
+    pub files: usize,
+    pub average_ratio: f64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CompressionReport {
+    pub by_type: HashMap<FileType, CompressionStats>,
+}
+
+/// How a stored file compares to the checksum a client expects it to have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumStatus {
+    Match,
+    Differs,
+    Missing,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CompactionReport {
+    pub files_compacted: usize,
+    pub chunks_removed: usize,
+    pub index_entries_removed: usize,
+}
+
+/// A metadata file `list_files_with_errors` couldn't read, with the reason.
+#[derive(Debug, Clone)]
+pub struct UnreadableMetadata {
+    pub path: PathBuf,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScanReport {
+    pub checked: usize,
+    // Files with missing chunks, whatever the policy did with them
+    pub orphaned: Vec<FileId>,
+    // Files that failed a full integrity check, with the reason; only `IntegrityScanner` fills this
+    pub corrupted: Vec<(FileId, String)>,
+}
+
+// File data after the pipeline has run, ready to be written as chunks
+struct ProcessedFile {
+    file_type: FileType,
+    chunks: Vec<Chunk>,
+    pipeline: Vec<PipelineStage>,
+    chunk_compressed: Vec<bool>,
+    chunk_sizes: Vec<u64>,
+    chunk_size: usize,
+    size: u64,
+    checksum: String,
+    content_checksum: String,
+    compression_ratio: f64,
+    compression_level: Option<u32>,
+    compression_algorithm: Option<CompressionAlgorithm>,
+    per_chunk_keys: bool,
+    chunk_unpadded_sizes: Vec<u64>,
+    chunk_checksums: Vec<String>,
+}
+
+// Chunks written by uploads whose metadata isn't on disk yet; orphan cleanup skips them
+#[derive(Default)]
+struct InFlightChunks {
+    ids: std::sync::Mutex<HashSet<ChunkId>>,
+}
+
+impl InFlightChunks {
+    fn track(&self, ids: Vec<ChunkId>) -> InFlightGuard<'_> {
+        self.ids.lock().unwrap().extend(ids.iter().cloned());
+        InFlightGuard { chunks: self, ids }
+    }
+
+    fn contains(&self, id: &ChunkId) -> bool {
+        self.ids.lock().unwrap().contains(id)
+    }
+
+    fn snapshot(&self) -> HashSet<ChunkId> {
+        self.ids.lock().unwrap().clone()
+    }
+}
+
+struct InFlightGuard<'a> {
+    chunks: &'a InFlightChunks,
+    ids: Vec<ChunkId>,
+}
+
+impl InFlightGuard<'_> {
+    // For writers that learn their chunk ids as they go
+    fn extend(&mut self, ids: &[ChunkId]) {
+        self.chunks.ids.lock().unwrap().extend(ids.iter().cloned());
+        self.ids.extend_from_slice(ids);
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        let mut ids = self.chunks.ids.lock().unwrap();
+        for id in &self.ids {
+            ids.remove(id);
+        }
+    }
 }
 
 pub struct DiskStorage {
@@ -29,11 +156,38 @@ pub struct DiskStorage {
     metadata_path: PathBuf,
     chunks_path: PathBuf,
     chunker: FileChunker,
+    name_index: Box<dyn NameIndex>,
+    type_chunk_sizes: HashMap<FileType, usize>,
     encryption: Option<EncryptionConfig>,
     cache: Option<CacheManager>,
+    persistent_cache: Option<PersistentCache>,
+    chunk_cache: Option<ChunkCache>,
     compression: Option<CompressionManager>,
+    pipeline: ProcessingPipeline,
+    chunk_compression: bool,
+    lazy_recompress: bool,
+    unknown_file_type: FileType,
+    reject_empty_files: bool,
+    idempotent_uploads: bool,
+    overwrite_by_name: bool,
+    versioning: bool,
+    // Held while a name's version history is read and rewritten
+    versions_lock: Mutex<()>,
+    auto_gc: bool,
+    orphan_grace_period: Option<std::time::Duration>,
+    in_flight_chunks: InFlightChunks,
+    orphaned_metadata_policy: OrphanedMetadataPolicy,
+    min_free_space: Option<u64>,
+    space_probe: Box<dyn DiskSpaceProbe>,
     retry_config: RetryConfig,
+    per_chunk_keys: bool,
+    // Encrypted chunks are padded to a multiple of this many bytes
+    padding_block: Option<usize>,
+    retry_budget: u32,
+    write_concurrency: usize,
     progress_tracker: ProgressTracker,
+    // Readers hold this while touching chunks; chunk removal takes it exclusively
+    chunk_gc_lock: RwLock<()>,
 }
 
 impl DiskStorage {
@@ -42,82 +196,491 @@ impl DiskStorage {
         let metadata_path = base_path.join("metadata");
         let chunks_path = base_path.join("chunks");
 
-        fs::create_dir_all(&base_path).await.unwrap();
-        fs::create_dir_all(&metadata_path).await.unwrap();
+        fs::create_dir_all(&base_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+        fs::create_dir_all(&metadata_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
         fs::create_dir_all(&chunks_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
 
         let chunker = FileChunker::new(ChunkManager::default());
+        let name_index = Box::new(JsonNameIndex::new(base_path.join("name_to_id.json")));
         Ok(Self {
             base_path,
             metadata_path,
             chunks_path,
             chunker,
+            name_index,
+            type_chunk_sizes: HashMap::new(),
             encryption: None,
             cache: None,
+            persistent_cache: None,
+            chunk_cache: None,
             compression: None,
+            pipeline: ProcessingPipeline::default(),
+            chunk_compression: false,
+            lazy_recompress: false,
+            unknown_file_type: FileType::Unknown,
+            reject_empty_files: false,
+            idempotent_uploads: false,
+            overwrite_by_name: false,
+            versioning: false,
+            versions_lock: Mutex::new(()),
+            auto_gc: true,
+            orphan_grace_period: None,
+            in_flight_chunks: InFlightChunks::default(),
+            orphaned_metadata_policy: OrphanedMetadataPolicy::default(),
+            min_free_space: None,
+            space_probe: Box::new(SystemDiskSpace),
             retry_config: RetryConfig::default(),
+            per_chunk_keys: false,
+            padding_block: None,
+            retry_budget: 3,
+            write_concurrency: DEFAULT_WRITE_CONCURRENCY,
             progress_tracker: ProgressTracker::new(),
+            chunk_gc_lock: RwLock::new(()),
         })
     }
 
+    pub fn base_path(&self) -> &Path {
+        &self.base_path
+    }
+
+    /// Replaces the default `name_to_id.json` index, e.g. with a `RedbNameIndex`.
+    pub fn with_name_index(mut self, index: impl NameIndex + 'static) -> Self {
+        self.name_index = Box::new(index);
+        self
+    }
+
     pub fn with_encryption(mut self, key: [u8; 32]) -> Self {
         self.encryption = Some(EncryptionConfig::new(key));
         self
     }
 
+    /// Encrypts with a key derived from `passphrase`. The salt is generated on first
+    /// use and kept in `encryption_salt` next to the metadata, so reopening the store
+    /// with the same passphrase reconstructs the same key.
+    pub fn with_passphrase(mut self, passphrase: &str) -> Result<Self> {
+        let salt_path = self.base_path.join("encryption_salt");
+        let salt = if salt_path.exists() {
+            std::fs::read(&salt_path).map_err(|e| AppError::Storage(StorageError::Storage(e.to_string())))?
+        } else {
+            let salt = generate_salt().to_vec();
+            std::fs::write(&salt_path, &salt).map_err(|e| AppError::Storage(StorageError::Storage(e.to_string())))?;
+            salt
+        };
+
+        self.encryption = Some(EncryptionConfig::from_passphrase(passphrase, &salt)?);
+        Ok(self)
+    }
+
+    /// Cipher for newly written data. Only applies after encryption is configured.
+    pub fn with_encryption_algorithm(mut self, algorithm: EncryptionAlgorithm) -> Self {
+        self.encryption = self.encryption.map(|encryption| encryption.with_algorithm(algorithm));
+        self
+    }
+
+    /// Toggles encryption of newly written data. Only applies after encryption is
+    /// configured; files written while disabled record no encrypt stage. Files recorded
+    /// as encrypted are still decrypted on read.
+    pub fn with_encryption_enabled(mut self, enabled: bool) -> Self {
+        if let Some(encryption) = &mut self.encryption {
+            encryption.set_enabled(enabled);
+        }
+        self
+    }
+
+    /// Encrypts each chunk with its own key, derived from the master key and the chunk
+    /// id, so one leaked chunk key exposes nothing else. Affects newly written files.
+    pub fn with_per_chunk_keys(mut self, enabled: bool) -> Self {
+        self.per_chunk_keys = enabled;
+        self
+    }
+
+    /// Pads each encrypted chunk to a multiple of `block_size` bytes before encrypting it,
+    /// so chunk files only reveal sizes to that granularity. The true lengths are kept in
+    /// metadata, which isn't encrypted, so this hides sizes from anyone who can see the
+    /// chunks but not the metadata. Only applies while encryption is enabled; 0 turns it off.
+    pub fn with_padding(mut self, block_size: usize) -> Self {
+        self.padding_block = (block_size > 0).then_some(block_size);
+        self
+    }
+
+    pub fn with_retry_config(mut self, retry_config: RetryConfig) -> Self {
+        self.retry_config = retry_config;
+        self
+    }
+
     pub fn with_cache(mut self, cache_size: usize) -> Self {
         self.cache = Some(CacheManager::new(cache_size));
         self
     }
 
+    /// Caps the memory cache at `max_bytes`. Only applies after `with_cache`.
+    pub fn with_cache_memory_limit(mut self, max_bytes: u64) -> Self {
+        self.cache = self.cache.map(|cache| cache.with_max_bytes(max_bytes));
+        self
+    }
+
+    pub async fn cache_stats(&self) -> Option<CacheStats> {
+        match &self.cache {
+            Some(cache) => Some(cache.stats().await),
+            None => None,
+        }
+    }
+
+    pub fn with_persistent_cache(mut self, max_bytes: u64) -> Result<Self> {
+        self.persistent_cache = Some(PersistentCache::new(self.base_path.join("cache"), max_bytes)?);
+        Ok(self)
+    }
+
+    /// Caches up to `capacity` individual chunks, so reads of a partly cached
+    /// file only go to disk for the missing chunks. A `capacity` of 0 turns the
+    /// chunk cache off.
+    pub fn with_chunk_cache(mut self, capacity: usize) -> Self {
+        self.chunk_cache = (capacity > 0).then(|| ChunkCache::new(capacity));
+        self
+    }
+
+    /// Pins a file in the memory cache so it is never evicted, loading it if needed.
+    pub async fn pin_file(&self, id: &FileId) -> Result<()> {
+        let cache = self.cache.as_ref().ok_or_else(|| {
+            AppError::Storage(StorageError::InvalidInput("Cannot pin files without a cache".to_string()))
+        })?;
+        cache.pin(*id).await?;
+        if let Err(e) = self.get_file(id).await {
+            cache.unpin(id).await;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub async fn unpin_file(&self, id: &FileId) {
+        if let Some(cache) = &self.cache {
+            cache.unpin(id).await;
+        }
+    }
+
     pub fn with_compression(mut self, enabled: bool) -> Self {
         self.compression = Some(CompressionManager::new(enabled));
         self
     }
 
+    pub fn with_pipeline(mut self, stages: Vec<PipelineStage>) -> Result<Self> {
+        self.pipeline = ProcessingPipeline::new(stages)?;
+        Ok(self)
+    }
+
+    /// Codec for newly compressed data. Only applies after compression is configured.
+    pub fn with_compression_algorithm(mut self, algorithm: CompressionAlgorithm) -> Self {
+        self.compression = self.compression.map(|compression| compression.with_algorithm(algorithm));
+        self
+    }
+
+    /// Compression level on gzip's 0-9 scale. Only applies after compression is configured.
+    pub fn with_compression_level(mut self, level: u32) -> Self {
+        self.compression = self.compression.map(|compression| compression.with_level(level));
+        self
+    }
+
+    /// Tunes the compression level on the first few files instead of using the default. Only
+    /// applies after compression is configured.
+    pub fn with_adaptive_compression(mut self, adaptive: AdaptiveCompression) -> Self {
+        self.compression = self.compression.map(|compression| compression.with_adaptive(adaptive));
+        self
+    }
+
+    /// Runs the pipeline on each chunk separately instead of on the whole file, keeping
+    /// compression only for the chunks where it actually shrinks the data. Always the
+    /// case while encryption is enabled.
+    pub fn with_chunk_compression(mut self, enabled: bool) -> Self {
+        self.chunk_compression = enabled;
+        self
+    }
+
+    /// Re-stores files on read when they were written with a different processing
+    /// configuration, such as another compression codec or level, so the store migrates
+    /// gradually instead of in one batch job.
+    pub fn with_lazy_recompress(mut self, enabled: bool) -> Self {
+        self.lazy_recompress = enabled;
+        self
+    }
+
+    /// Replaces the default fixed-size chunking, e.g. with `ChunkManager::content_defined`.
+    /// Per-type and per-call chunk sizes still split at fixed boundaries.
+    pub fn with_chunking(mut self, config: ChunkManager) -> Self {
+        self.chunker = FileChunker::new(config);
+        self
+    }
+
+    /// Splits files detected as `file_type` at `chunk_size` instead of the default.
+    /// Fails with `InvalidInput` for a zero `chunk_size`.
+    pub fn with_chunk_size_for(mut self, file_type: FileType, chunk_size: usize) -> Result<Self> {
+        if chunk_size == 0 {
+            return Err(AppError::Storage(StorageError::InvalidInput("Chunk size must be greater than zero".to_string())));
+        }
+        self.type_chunk_sizes.insert(file_type, chunk_size);
+        Ok(self)
+    }
+
+    /// Type recorded for data the detector can't classify. Image, video and audio
+    /// types skip compression, documents and `Unknown` are compressed too.
+    pub fn with_unknown_file_type(mut self, file_type: FileType) -> Self {
+        self.unknown_file_type = file_type;
+        self
+    }
+
+    /// Treats zero-byte uploads as an error instead of storing an empty file.
+    pub fn with_reject_empty_files(mut self, reject: bool) -> Self {
+        self.reject_empty_files = reject;
+        self
+    }
+
+    fn ensure_not_empty(&self, name: &str, data: &[u8]) -> Result<()> {
+        if self.reject_empty_files && data.is_empty() {
+            return Err(AppError::Storage(StorageError::InvalidInput(format!("Refusing to store empty file {}", name))));
+        }
+        Ok(())
+    }
+
+    /// Re-uploading a name with byte-identical content returns the existing
+    /// metadata instead of storing a second copy.
+    pub fn with_idempotent_uploads(mut self, enabled: bool) -> Self {
+        self.idempotent_uploads = enabled;
+        self
+    }
+
+    /// Uploading under a name that already exists updates that file in place,
+    /// keeping its id, instead of storing a second file and repointing the name.
+    pub fn with_overwrite_by_name(mut self, enabled: bool) -> Self {
+        self.overwrite_by_name = enabled;
+        self
+    }
+
+    /// Keeps every file uploaded under a name as a numbered version instead of only the
+    /// latest, so earlier contents stay readable through `get_file_version`. The name
+    /// resolves to the newest version. Takes precedence over overwrite by name.
+    pub fn with_versioning(mut self, enabled: bool) -> Self {
+        self.versioning = enabled;
+        self
+    }
+
+    async fn find_identical(&self, name: &str, data: &[u8]) -> Result<Option<FileMetadata>> {
+        let Some(id) = self.lookup_name(name).await? else {
+            return Ok(None);
+        };
+        let metadata = match self.get_metadata(&id).await {
+            Ok(metadata) => metadata,
+            Err(AppError::Storage(StorageError::NotFound(_))) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if !metadata.content_checksum.is_empty() {
+            let identical = metadata.content_checksum == Self::calculate_checksum(data);
+            return Ok(identical.then_some(metadata));
+        }
+
+        // Older files only have a checksum over processed bytes, so compare the decoded contents
+        let existing = self.get_file(&id).await?;
+        Ok((existing == data).then_some(metadata))
+    }
+
+    /// With auto GC off, deleting a file only drops its metadata and the chunks
+    /// linger until `gc` is called, so deleting many files costs one scan.
+    pub fn with_auto_gc(mut self, enabled: bool) -> Self {
+        self.auto_gc = enabled;
+        self
+    }
+
+    pub fn with_orphaned_metadata_policy(mut self, policy: OrphanedMetadataPolicy) -> Self {
+        self.orphaned_metadata_policy = policy;
+        self
+    }
+
+    /// Checks every file for missing chunks and applies the orphaned metadata policy.
+    pub async fn scan(&self) -> Result<ScanReport> {
+        let validation = ValidationManager::new(self.base_path.clone());
+        let mut report = ScanReport::default();
+
+        for metadata in self.list_files().await? {
+            report.checked += 1;
+            let missing = validation.missing_chunks(&metadata);
+            if missing.is_empty() {
+                continue;
+            }
+
+            warn!(id = %metadata.id, missing = missing.len(), total = metadata.chunk_ids.len(), "file is missing chunks");
+            report.orphaned.push(metadata.id);
+
+            let metadata_path = self.get_metadata_path(&metadata.id);
+            match self.orphaned_metadata_policy {
+                OrphanedMetadataPolicy::Report => continue,
+                OrphanedMetadataPolicy::Quarantine => {
+                    let corrupt_path = self.base_path.join("corrupt");
+                    fs::create_dir_all(&corrupt_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+                    fs::rename(&metadata_path, corrupt_path.join(format!("{}.json", metadata.id))).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+                }
+                OrphanedMetadataPolicy::Delete => {
+                    fs::remove_file(&metadata_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+                }
+            }
+
+            self.invalidate_caches(&metadata.id).await;
+            if self.lookup_name(&metadata.name).await? == Some(metadata.id) {
+                self.name_index.remove(&metadata.name).await?;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Orphan cleanup leaves chunk files younger than `grace_period` alone, covering
+    /// uploads from other processes sharing the store whose metadata isn't written yet.
+    /// Uploads in this process are protected regardless.
+    pub fn with_orphan_grace_period(mut self, grace_period: std::time::Duration) -> Self {
+        self.orphan_grace_period = Some(grace_period);
+        self
+    }
+
+    /// Removes every chunk no longer referenced by any file. Nothing is removed while
+    /// any metadata file can't be read, since its chunks can't be told apart from orphans.
+    pub async fn gc(&self) -> Result<()> {
+        let _guard = self.chunk_gc_lock.write().await;
+        self.cleanup_orphaned_chunks().await
+    }
+
+    /// Rejects writes that would leave less than `bytes` free on the storage volume.
+    pub fn with_min_free_space(mut self, bytes: u64) -> Self {
+        self.min_free_space = Some(bytes);
+        self
+    }
+
+    pub fn with_disk_space_probe(mut self, probe: impl DiskSpaceProbe + 'static) -> Self {
+        self.space_probe = Box::new(probe);
+        self
+    }
+
+    fn ensure_free_space(&self, incoming: u64) -> Result<()> {
+        let Some(min_free_space) = self.min_free_space else {
+            return Ok(());
+        };
+
+        let available = self.space_probe.available_space(&self.base_path)?;
+        let required = incoming.saturating_add(min_free_space);
+        if available < required {
+            return Err(AppError::Storage(StorageError::InsufficientSpace { available, required }));
+        }
+
+        Ok(())
+    }
+
+    /// Total retries allowed across all chunk writes of a single store.
+    pub fn with_retry_budget(mut self, retries: u32) -> Self {
+        self.retry_budget = retries;
+        self
+    }
+
+    /// Number of chunk files a single store writes concurrently; at least 1.
+    pub fn with_write_concurrency(mut self, limit: usize) -> Self {
+        self.write_concurrency = limit.max(1);
+        self
+    }
+
     fn get_chunk_path(&self, chunk_id: &ChunkId) -> PathBuf {
-        self.chunks_path.join(chunk_id.0.to_string())
+        self.chunks_path.join(&chunk_id.0)
     }
 
-    async fn store_chunks(&self, chunks: Vec<Chunk>) -> Result<Vec<ChunkId>> {
-        let mut chunk_ids = Vec::new();
+    /// Reads a chunk's stored bytes, checking them against `checksum` when one was recorded.
+    /// Only verified data is cached.
+    async fn read_chunk(&self, chunk_id: &ChunkId, checksum: Option<&str>) -> Result<Vec<u8>> {
+        if let Some(chunk_cache) = &self.chunk_cache {
+            if let Some(data) = chunk_cache.get(chunk_id).await {
+                return Ok(data);
+            }
+        }
 
-        for chunk in chunks {
-            let chunk_path = self.get_chunk_path(&chunk.id);
-            fs::write(&chunk_path, &chunk.data).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
-            chunk_ids.push(chunk.id);
+        // The I/O error is kept whole so a missing chunk isn't retried like a timeout
+        let chunk_path = self.get_chunk_path(chunk_id);
+        let data = with_retry(&self.retry_config, || async {
+            fs::read(&chunk_path).await.map_err(|e| AppError::Storage(StorageError::Io(e)))
+        })
+        .await?;
+        if checksum.is_some_and(|checksum| Self::calculate_checksum(&data) != checksum) {
+            return Err(AppError::Storage(StorageError::IntegrityError(format!("chunk {} corrupted", chunk_id.0))));
+        }
+        if let Some(chunk_cache) = &self.chunk_cache {
+            chunk_cache.put(chunk_id.clone(), data.clone()).await;
         }
+        Ok(data)
+    }
+
+    /// Writes chunks that aren't already on disk. A chunk named by its checksum that
+    /// already exists holds the same bytes, so it is shared instead of written again.
+    /// Callers must track the chunks as in flight first, which keeps deletes from
+    /// removing a shared chunk before the new metadata references it.
+    ///
+    /// Up to `write_concurrency` chunks are written at once. The ids come back in input
+    /// order, and the first failed write stops the rest. With `progress`, the operation
+    /// advances through the given number of bytes as the chunks land. Retries are drawn
+    /// from `budget`, which the caller creates once for the whole store.
+    async fn store_chunks(&self, chunks: Vec<Chunk>, progress: Option<(&Uuid, u64)>, budget: &RetryBudget) -> Result<Vec<ChunkId>> {
+        let total_chunks = chunks.len() as u64;
+        // Deletes check in-flight chunks under the write lock, so none can be halfway through
+        let _guard = self.chunk_gc_lock.read().await;
 
+        let writes = chunks.into_iter().map(|chunk| {
+            async move {
+                let chunk_path = self.get_chunk_path(&chunk.id);
+                let exists = fs::try_exists(&chunk_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+                if !exists {
+                    // Written aside and renamed in, so a chunk file that exists is always complete. The
+                    // name is unique because the same chunk can be written by two stores at once.
+                    let tmp_path = chunk_path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+                    with_retry_budget(&self.retry_config, budget, || async {
+                        fs::write(&tmp_path, &chunk.data).await.map_err(|e| AppError::Storage(StorageError::Io(e)))?;
+                        fs::rename(&tmp_path, &chunk_path).await.map_err(|e| AppError::Storage(StorageError::Io(e)))
+                    })
+                    .await?;
+                }
+                Ok::<_, AppError>(chunk.id)
+            }
+        });
+
+        let mut written = stream::iter(writes).buffered(self.write_concurrency);
+        let mut chunk_ids = Vec::with_capacity(total_chunks as usize);
+        while let Some(chunk_id) = written.try_next().await? {
+            chunk_ids.push(chunk_id);
+            if let Some((operation_id, total_bytes)) = progress {
+                let done = total_bytes * chunk_ids.len() as u64 / total_chunks;
+                self.progress_tracker.update_progress(operation_id, done).await;
+            }
+        }
         Ok(chunk_ids)
     }
 
-    async fn process_file_by_type(&self, file_type: FileType, data: &[u8]) -> Result<Vec<u8>> {
+    async fn process_file_by_type(&self, file_type: FileType, data: &[u8], aad: Option<&[u8]>, level: Option<u32>, encrypt: bool) -> Result<(Vec<u8>, Vec<PipelineStage>)> {
         match file_type {
             FileType::Image(_) => {
                 // Here you could add image processing logic
                 // For example, resizing, compression, format conversion
-                Ok(data.to_vec())
+                Ok((data.to_vec(), Vec::new()))
             }
             FileType::Document(_) => {
                 // Document processing logic
                 // For example, text extraction, metadata parsing
-                self.process_data(data).await
+                self.process_data(data, aad, level, encrypt).await
             }
             FileType::Video(_) => {
                 // Video processing logic
                 // For example, thumbnail generation, transcoding
-                Ok(data.to_vec())
+                Ok((data.to_vec(), Vec::new()))
             }
             FileType::Audio(_) => {
                 // Audio processing logic
                 // For example, format conversion, metadata extraction
-                Ok(data.to_vec())
+                Ok((data.to_vec(), Vec::new()))
             }
-            FileType::Unknown => self.process_data(data).await,
+            FileType::Unknown => self.process_data(data, aad, level, encrypt).await,
         }
     }
 
-    async fn deprocess_file_by_type(&self, file_type: FileType, data: &[u8]) -> Result<Vec<u8>> {
+    async fn deprocess_file_by_type(&self, file_type: FileType, pipeline: &[PipelineStage], data: &[u8], aad: Option<&[u8]>) -> Result<Vec<u8>> {
         match file_type {
             FileType::Image(_) => {
                 // Here you could add image deprocessing logic
@@ -127,7 +690,7 @@ impl DiskStorage {
             FileType::Document(_) => {
                 // Document deprocessing logic
                 // For example, text extraction, metadata parsing
-                self.deprocess_data(data).await
+                self.deprocess_data(data, pipeline, aad).await
             }
             FileType::Video(_) => {
                 // Video deprocessing logic
@@ -139,140 +702,278 @@ impl DiskStorage {
                 // For example, format conversion, metadata extraction
                 Ok(data.to_vec())
             }
-            FileType::Unknown => self.deprocess_data(data).await,
+            FileType::Unknown => self.deprocess_data(data, pipeline, aad).await,
         }
     }
 
-    async fn process_data(&self, data: &[u8]) -> Result<Vec<u8>> {
-        let compressed_data = if let Some(compression) = &self.compression {
-            compression.compress(data)?
-        } else {
-            data.to_vec()
-        };
+    /// Runs the configured pipeline and returns the processed data along with the
+    /// stages that were actually applied, which is what gets recorded in metadata.
+    /// `level` is the compression level to use, None to skip compression.
+    async fn process_data(&self, data: &[u8], aad: Option<&[u8]>, level: Option<u32>, encrypt: bool) -> Result<(Vec<u8>, Vec<PipelineStage>)> {
+        let mut processed = data.to_vec();
+        let mut applied = Vec::new();
 
-        let encrypted_data = if let Some(encryption) = &self.encryption {
-            encryption.encrypt(&compressed_data)?
-        } else {
-            compressed_data
-        };
+        for stage in self.pipeline.stages() {
+            match stage {
+                PipelineStage::Compress => {
+                    if let (Some(compression), Some(level)) = (self.compression.as_ref().filter(|c| c.is_worth_compressing(&processed)), level) {
+                        processed = compression.compress_with_level(&processed, level)?;
+                        applied.push(*stage);
+                    }
+                }
+                PipelineStage::Encrypt => {
+                    if let Some(encryption) = self.write_encryption(encrypt) {
+                        processed = encryption.encrypt(&processed, aad)?;
+                        applied.push(*stage);
+                    }
+                }
+            }
+        }
 
-        Ok(encrypted_data)
+        Ok((processed, applied))
     }
 
-    pub async fn deprocess_data(&self, data: &[u8]) -> Result<Vec<u8>> {
-        let decrypted_data = if let Some(encryption) = &self.encryption {
-            encryption.decrypt(data)?
-        } else {
-            data.to_vec()
-        };
+    pub async fn deprocess_data(&self, data: &[u8], pipeline: &[PipelineStage], aad: Option<&[u8]>) -> Result<Vec<u8>> {
+        let mut processed = data.to_vec();
 
-        let decompressed_data = if let Some(compression) = &self.compression {
-            compression.decompress(&decrypted_data)?
-        } else {
-            decrypted_data
-        };
+        for stage in pipeline.iter().rev() {
+            match stage {
+                PipelineStage::Compress => {
+                    processed = self.decompress(&processed)?;
+                }
+                PipelineStage::Encrypt => {
+                    processed = self.read_encryption()?.decrypt(&processed, aad)?;
+                }
+            }
+        }
 
-        Ok(decompressed_data)
+        Ok(processed)
     }
 
-    async fn is_chunk_used_by_others(
-        &self,
-        chunk_id: &ChunkId,
-        current_file_id: &Uuid,
-    ) -> Result<bool> {
-        let mut entries = fs::read_dir(&self.metadata_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
-        while let Some(entry) = entries.next_entry().await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))? {
-            if entry.file_type().await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?.is_file() {
-                if let Some(ext) = entry.path().extension() {
-                    if ext == "json" {
-                        let metadata_content = fs::read_to_string(entry.path()).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
-                        let metadata: FileMetadata = serde_json::from_str(&metadata_content)
-                            .map_err(|e| {
-                                StorageError::Storage(format!("Failed to parse metadata: {}", e))
-                            })?;
+    /// `key_chunk` names the chunk whose derived key encrypts it, when per-chunk keys are on,
+    /// and `level` the compression level, None to skip compression. Also returns whether
+    /// the chunk was compressed and, if it was padded, its length before padding.
+    async fn process_chunk(&self, data: &[u8], aad: Option<&[u8]>, key_chunk: Option<&ChunkId>, level: Option<u32>, encrypt: bool) -> Result<(Vec<u8>, bool, Option<u64>)> {
+        let mut processed = data.to_vec();
+        let mut compressed = false;
+        let mut unpadded = None;
 
-                        // Skip the current file being deleted
-                        if metadata.id != *current_file_id && metadata.chunk_ids.contains(chunk_id)
-                        {
-                            return Ok(true);
+        for stage in self.pipeline.stages() {
+            match stage {
+                PipelineStage::Compress => {
+                    if let (Some(compression), Some(level)) = (self.compression.as_ref().filter(|c| c.is_worth_compressing(&processed)), level) {
+                        let candidate = compression.compress_with_level(&processed, level)?;
+                        if candidate.len() < processed.len() {
+                            processed = candidate;
+                            compressed = true;
+                        }
+                    }
+                }
+                PipelineStage::Encrypt => {
+                    if let Some(encryption) = self.write_encryption(encrypt) {
+                        if let Some(block) = self.padding_block {
+                            unpadded = Some(processed.len() as u64);
+                            processed.resize(processed.len().div_ceil(block).max(1) * block, 0);
                         }
+                        processed = match key_chunk {
+                            Some(chunk_id) => encryption.derive(&chunk_id.key_context())?.encrypt(&processed, aad)?,
+                            None => encryption.encrypt(&processed, aad)?,
+                        };
                     }
                 }
             }
         }
-        Ok(false)
+
+        Ok((processed, compressed, unpadded))
     }
 
-    async fn cleanup_orphaned_chunks(&self) -> Result<()> {
-        // Get all existing chunk files
-        let mut chunk_files = HashSet::new();
-        let mut entries = fs::read_dir(&self.chunks_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
-        while let Some(entry) = entries.next_entry().await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))? {
-            if let Some(file_name) = entry.file_name().to_str() {
-                chunk_files.insert(file_name.to_string());
-            }
-        }
+    async fn deprocess_chunk(&self, data: &[u8], pipeline: &[PipelineStage], compressed: bool, aad: Option<&[u8]>, key_chunk: Option<&ChunkId>, unpadded: Option<u64>) -> Result<Vec<u8>> {
+        let mut processed = data.to_vec();
 
-        // Get all chunks referenced in metadata
-        let mut referenced_chunks = HashSet::new();
-        let mut metadata_entries = fs::read_dir(&self.metadata_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
-        while let Some(entry) = metadata_entries.next_entry().await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))? {
-            if let Some(ext) = entry.path().extension() {
-                if ext == "json" {
-                    let metadata_content = fs::read_to_string(entry.path()).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
-                    if let Ok(metadata) = serde_json::from_str::<FileMetadata>(&metadata_content) {
-                        for chunk_id in metadata.chunk_ids {
-                            referenced_chunks.insert(chunk_id.0.to_string());
+        for stage in pipeline.iter().rev() {
+            match stage {
+                PipelineStage::Compress => {
+                    if compressed {
+                        processed = self.decompress(&processed)?;
+                    }
+                }
+                PipelineStage::Encrypt => {
+                    let encryption = self.read_encryption()?;
+                    processed = match key_chunk {
+                        Some(chunk_id) => encryption.derive(&chunk_id.key_context())?.decrypt(&processed, aad)?,
+                        None => encryption.decrypt(&processed, aad)?,
+                    };
+                    if let Some(len) = unpadded {
+                        if len > processed.len() as u64 {
+                            return Err(AppError::Storage(StorageError::IntegrityError(format!("Recorded chunk length {} exceeds padded length {}", len, processed.len()))));
                         }
+                        processed.truncate(len as usize);
                     }
                 }
             }
         }
 
-        // Delete orphaned chunks
-        for chunk_file in chunk_files {
-            if !referenced_chunks.contains(&chunk_file) {
-                let chunk_path = self.chunks_path.join(&chunk_file);
-                if let Err(e) = fs::remove_file(&chunk_path).await {
-                    eprintln!("Failed to delete orphaned chunk {}: {}", chunk_file, e);
-                }
-            }
+        Ok(processed)
+    }
+
+    // Compressed data carries its codec tag, so it decodes however compression is configured now
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match &self.compression {
+            Some(compression) => compression.decompress(data),
+            None => CompressionManager::new(false).decompress(data),
         }
+    }
 
-        Ok(())
+    // Encryption for reading data recorded as encrypted, whether or not new writes are
+    fn read_encryption(&self) -> Result<&EncryptionConfig> {
+        self.encryption
+            .as_ref()
+            .ok_or_else(|| AppError::Storage(StorageError::Storage("Data is encrypted but no encryption key is configured".to_string())))
     }
 
-    fn get_metadata_path(&self, id: &Uuid) -> PathBuf {
-        self.metadata_path.join(format!("{}.json", id))
+    // Encryption applied to a write; `encrypt` is false for files kept unencrypted on purpose
+    fn write_encryption(&self, encrypt: bool) -> Option<&EncryptionConfig> {
+        self.encryption.as_ref().filter(|e| encrypt && e.is_enabled())
     }
 
-    fn calculate_checksum(data: &[u8]) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-        format!("{:x}", hasher.finalize())
+    // Stages a write records; `compress` is false for file types that are never compressed
+    fn configured_stages(&self, compress: bool, encrypt: bool) -> Vec<PipelineStage> {
+        self.pipeline
+            .stages()
+            .iter()
+            .copied()
+            .filter(|stage| match stage {
+                PipelineStage::Compress => compress && self.compression.as_ref().is_some_and(|c| c.is_enabled()),
+                PipelineStage::Encrypt => self.write_encryption(encrypt).is_some(),
+            })
+            .collect()
     }
 
-    async fn update_name_index(&self, name: &str, id: &Uuid) -> Result<()> {
-        let index_path = self.base_path.join("name_to_id.json");
+    fn needs_reprocessing(&self, metadata: &FileMetadata, data: &[u8]) -> bool {
+        // Whole-file processing skips compression for data that looks incompressible
+        let mut expected = self.configured_stages(is_compressible_type(&metadata.file_type), !metadata.keep_plaintext);
+        if metadata.chunk_compressed.is_empty() && !self.compression.as_ref().is_some_and(|c| c.is_worth_compressing(data)) {
+            expected.retain(|stage| *stage != PipelineStage::Compress);
+        }
+        if metadata.pipeline != expected {
+            return true;
+        }
 
-        let mut index: HashMap<String, Uuid> = if index_path.exists() {
-            let content = fs::read_to_string(&index_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
-            serde_json::from_str(&content).unwrap_or_default()
-        } else {
-            HashMap::new()
+        // Compressed data also moves to the current codec and level. Files compressed before
+        // the codec was recorded are re-stored once so that it gets recorded.
+        let compressed = metadata.pipeline.contains(&PipelineStage::Compress)
+            && (metadata.chunk_compressed.is_empty() || metadata.chunk_compressed.contains(&true));
+        match self.compression.as_ref().filter(|_| compressed) {
+            Some(compression) => {
+                metadata.compression_algorithm != Some(compression.algorithm())
+                    || compression.expected_level().is_some_and(|level| metadata.compression_level != Some(level))
+            }
+            None => false,
+        }
+    }
+
+    async fn prepare_file(&self, id: &FileId, data: &[u8], chunk_size: Option<usize>, encrypt: bool) -> Result<ProcessedFile> {
+        let file_type = FileTypeDetector::detect_with_fallback(data, &self.unknown_file_type);
+        let custom_chunker = chunk_size
+            .or_else(|| self.type_chunk_sizes.get(&file_type).copied())
+            .map(|chunk_size| FileChunker::new(ChunkManager::new(chunk_size)));
+        let chunker = custom_chunker.as_ref().unwrap_or(&self.chunker);
+        let compress = is_compressible_type(&file_type);
+        // Binding ciphertext to the file id stops chunks being swapped between files
+        let aad = Some(id.0.as_bytes().as_slice());
+        let level = self.compression_level(compress, data)?;
+
+        // Encrypted files are always processed per chunk, so every chunk decrypts on its own
+        let encrypting = self.write_encryption(encrypt).is_some();
+        let per_chunk = encrypting || (self.chunk_compression && compress);
+        let per_chunk_keys = self.per_chunk_keys && encrypting && per_chunk;
+        let (chunks, pipeline, chunk_compressed, chunk_sizes, chunk_unpadded_sizes) = if per_chunk {
+            let mut chunks = Vec::new();
+            let mut chunk_compressed = Vec::new();
+            let mut chunk_sizes = Vec::new();
+            let mut chunk_unpadded_sizes = Vec::new();
+            for chunk in chunker.chunk_data(data) {
+                chunk_sizes.push(chunk.size as u64);
+                let (chunk, compressed, unpadded) = self.processed_chunk(&chunk, aad, per_chunk_keys, level, encrypt).await?;
+                chunk_unpadded_sizes.extend(unpadded);
+                chunks.push(chunk);
+                chunk_compressed.push(compressed);
+            }
+            // With no chunks there is nothing to decode, and an empty flag list would read as whole-file mode
+            let pipeline = if chunks.is_empty() { Vec::new() } else { self.configured_stages(compress, encrypt) };
+            (chunks, pipeline, chunk_compressed, chunk_sizes, chunk_unpadded_sizes)
+        } else {
+            let (final_data, pipeline) = self.process_file_by_type(file_type.clone(), data, aad, level, encrypt).await?;
+            let chunks = chunker.chunk_data(&final_data);
+            // Stored bytes are the decoded bytes only when nothing was applied to the whole file
+            let chunk_sizes = if pipeline.is_empty() {
+                chunks.iter().map(|c| c.size as u64).collect()
+            } else {
+                Vec::new()
+            };
+            (chunks, pipeline, Vec::new(), chunk_sizes, Vec::new())
         };
 
-        index.insert(name.to_string(), *id);
-        let updated_index = serde_json::to_string(&index).unwrap();
-        fs::write(index_path, updated_index).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
-        Ok(())
+        let mut hasher = Sha256::new();
+        for chunk in &chunks {
+            hasher.update(&chunk.data);
+        }
+        let chunk_checksums = chunks.iter().map(|chunk| chunk.checksum.clone()).collect();
+
+        let compressed = pipeline.contains(&PipelineStage::Compress)
+            && (chunk_compressed.is_empty() || chunk_compressed.contains(&true));
+        let compression_algorithm = self.compression.as_ref().map(|c| c.algorithm()).filter(|_| compressed);
+        let size: u64 = chunks.iter().map(|c| c.size as u64).sum();
+        Ok(ProcessedFile {
+            compression_level: level.filter(|_| compression_algorithm.is_some_and(|a| a.uses_level())),
+            compression_algorithm,
+            file_type,
+            size,
+            compression_ratio: if size == 0 { 1.0 } else { data.len() as f64 / size as f64 },
+            checksum: format!("{:x}", hasher.finalize()),
+            content_checksum: Self::calculate_checksum(data),
+            chunks,
+            pipeline,
+            chunk_compressed,
+            chunk_sizes,
+            chunk_size: chunker.chunk_size(),
+            per_chunk_keys,
+            chunk_unpadded_sizes,
+            chunk_checksums,
+        })
     }
 
-    pub async fn list_files(&self) -> Result<Vec<FileMetadata>> {
-        let metadata_dir = self.base_path.join("metadata");
-        let mut files = Vec::new();
+    // Runs the pipeline over one chunk and names the result after its processed bytes
+    async fn processed_chunk(&self, chunk: &Chunk, aad: Option<&[u8]>, per_chunk_keys: bool, level: Option<u32>, encrypt: bool) -> Result<(Chunk, bool, Option<u64>)> {
+        // A chunk's own key is derived from its id, which has to exist before the ciphertext does
+        let key_chunk = per_chunk_keys.then(ChunkId::random);
+        let (chunk_data, compressed, unpadded) = self.process_chunk(&chunk.data, aad, key_chunk.as_ref(), level, encrypt).await?;
+        let checksum = Self::calculate_checksum(&chunk_data);
+        let chunk = Chunk {
+            id: key_chunk.unwrap_or_else(|| ChunkId(checksum.clone())),
+            checksum,
+            size: chunk_data.len(),
+            data: chunk_data,
+        };
+        Ok((chunk, compressed, unpadded))
+    }
 
-        let mut entries = fs::read_dir(&metadata_dir).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+    // Level a file of this type is compressed with, None when it isn't compressed. Only
+    // data worth compressing is sampled by adaptive tuning.
+    fn compression_level(&self, compress: bool, data: &[u8]) -> Result<Option<u32>> {
+        match self.compression.as_ref().filter(|c| compress && c.is_enabled()) {
+            Some(compression) if compression.is_worth_compressing(data) => Ok(Some(compression.tune(data)?)),
+            Some(compression) => Ok(Some(compression.current_level())),
+            None => Ok(None),
+        }
+    }
+
+    async fn is_chunk_used_by_others(
+        &self,
+        chunk_id: &ChunkId,
+        current_file_id: &FileId,
+    ) -> Result<bool> {
+        let mut entries = fs::read_dir(&self.metadata_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
         while let Some(entry) = entries.next_entry().await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))? {
             if entry.file_type().await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?.is_file() {
                 if let Some(ext) = entry.path().extension() {
@@ -280,88 +981,354 @@ impl DiskStorage {
                         let metadata_content = fs::read_to_string(entry.path()).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
                         let metadata: FileMetadata = serde_json::from_str(&metadata_content)
                             .map_err(|e| {
-                                StorageError::Storage(format!("Failed to parse metadata: {}", e))
+                                StorageError::IntegrityError(format!("Failed to parse metadata: {}", e))
                             })?;
-                        files.push(metadata);
+
+                        // Skip the current file being deleted
+                        if metadata.id != *current_file_id && metadata.chunk_ids.contains(chunk_id)
+                        {
+                            return Ok(true);
+                        }
                     }
                 }
             }
         }
+        Ok(false)
+    }
 
-        Ok(files)
+    async fn is_chunk_in_flight(&self, chunk_file: &str, chunk_path: &Path) -> bool {
+        if self.in_flight_chunks.contains(&ChunkId(chunk_file.to_string())) {
+            return true;
+        }
+
+        let Some(grace_period) = self.orphan_grace_period else {
+            return false;
+        };
+        match fs::metadata(chunk_path).await.and_then(|m| m.modified()) {
+            Ok(modified) => modified.elapsed().map_or(true, |age| age < grace_period),
+            // Unknown age, keep it for the next run
+            Err(_) => true,
+        }
     }
-}
 
-#[async_trait]
-impl StorageBackend for DiskStorage {
-    async fn store_file(&self, name: &str, data: &[u8]) -> Result<FileMetadata> {
-        let operation_id = self.progress_tracker.start_operation(data.len() as u64).await;
-
-        with_retry(&self.retry_config, || async {
-            let id = Uuid::new_v4();
-            let file_type = FileTypeDetector::detect(data);
-
-            let final_data = self.process_file_by_type(file_type.clone(), data).await?;
-            // self.progress_tracker.update_progress(&operation_id, final_data.len() as u64).await;
-            // if let Some(progress) = self.progress_tracker.get_progress(&operation_id).await {
-            //     println!(
-            //         "Progress: {}, Speed: {}, Time remaining: {}",
-            //         progress.format_progress(),
-            //         progress.format_speed(),
-            //         progress.format_time_remaining()
-            //     );
-            // };
-
-            let chunks = self.chunker.chunk_data(&final_data);
-            let chunk_ids = self.store_chunks(chunks).await?;
+    async fn cleanup_orphaned_chunks(&self) -> Result<()> {
+        // Get all existing chunk files. Ones still being written are named `.tmp` until renamed in.
+        let mut chunk_files = HashSet::new();
+        let mut entries = fs::read_dir(&self.chunks_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+        while let Some(entry) = entries.next_entry().await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))? {
+            if let Some(file_name) = entry.file_name().to_str().filter(|name| !name.ends_with(".tmp")) {
+                chunk_files.insert(file_name.to_string());
+            }
+        }
+
+        // Taken before reading metadata: an upload can write its metadata and stop being in
+        // flight while the metadata is read, and its chunks must not look orphaned then
+        let in_flight = self.in_flight_chunks.snapshot();
+
+        // Get all chunks referenced in metadata. A file that can't be read may reference any
+        // of them, so nothing counts as orphaned until it is fixed.
+        let (files, unreadable) = self.list_files_with_errors().await?;
+        if let Some(entry) = unreadable.first() {
+            warn!(path = %entry.path.display(), error = %entry.error, "Skipping orphaned chunk cleanup: unreadable metadata");
+            return Ok(());
+        }
+        let referenced_chunks: HashSet<String> = files.into_iter().flat_map(|metadata| metadata.chunk_ids).map(|chunk_id| chunk_id.0).collect();
+
+        // Delete orphaned chunks
+        for chunk_file in chunk_files {
+            if !referenced_chunks.contains(&chunk_file) {
+                let chunk_path = self.chunks_path.join(&chunk_file);
+                if in_flight.contains(&ChunkId(chunk_file.clone())) || self.is_chunk_in_flight(&chunk_file, &chunk_path).await {
+                    continue;
+                }
+                if let Err(e) = fs::remove_file(&chunk_path).await {
+                    eprintln!("Failed to delete orphaned chunk {}: {}", chunk_file, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn invalidate_caches(&self, id: &FileId) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(id).await;
+        }
+
+        if let Some(persistent_cache) = &self.persistent_cache {
+            persistent_cache.invalidate(id).await;
+        }
+    }
+
+    fn get_metadata_path(&self, id: &FileId) -> PathBuf {
+        self.metadata_path.join(format!("{}.json", id))
+    }
+
+    pub fn calculate_checksum(data: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        format!("{:x}", hasher.finalize())
+    }
+
+    async fn update_name_index(&self, name: &str, id: &FileId) -> Result<()> {
+        self.name_index.insert(name, id).await
+    }
+
+    /// Id of the file currently stored under `name`, if any.
+    pub async fn lookup_name(&self, name: &str) -> Result<Option<FileId>> {
+        self.name_index.get(name).await
+    }
+
+    /// Rebuilds the name index from file metadata. See `ValidationManager::rebuild_name_index`.
+    pub async fn rebuild_name_index(&self) -> Result<usize> {
+        let count = ValidationManager::new(self.base_path.clone()).rebuild_name_index(self.name_index.as_ref()).await?;
+        info!(count, "Rebuilt name index");
+        Ok(count)
+    }
+
+    /// Stored files whose names start with `prefix`, sorted by name. Index entries
+    /// left behind by deleted files are skipped.
+    pub async fn search_by_prefix(&self, prefix: &str) -> Result<Vec<(String, FileId)>> {
+        let matches = self.name_index.search_prefix(prefix).await?;
+        Ok(matches.into_iter().filter(|(_, id)| self.get_metadata_path(id).exists()).collect())
+    }
+
+    pub async fn get_progress(&self, operation_id: &Uuid) -> Option<ProgressStats> {
+        self.progress_tracker.get_progress(operation_id).await
+    }
+
+    pub async fn subscribe_progress(&self, operation_id: &Uuid) -> Option<watch::Receiver<ProgressStats>> {
+        self.progress_tracker.subscribe(operation_id).await
+    }
+
+    /// Handle on the tracker behind `get_progress`, sharing its operations. Lets callers
+    /// start and follow operations without going through the storage.
+    pub fn progress_tracker(&self) -> ProgressTracker {
+        self.progress_tracker.clone()
+    }
+
+    /// Reads a file's metadata without touching its chunks.
+    pub async fn get_metadata(&self, id: &FileId) -> Result<FileMetadata> {
+        let metadata_path = self.get_metadata_path(id);
+
+        if !metadata_path.exists() {
+            return Err(AppError::Storage(StorageError::NotFound(id.to_string())));
+        }
+
+        let metadata_content = fs::read_to_string(&metadata_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+        let metadata: FileMetadata = serde_json::from_str(&metadata_content)
+            .map_err(|e| StorageError::IntegrityError(format!("Failed to parse metadata: {}", e)))?;
+        Ok(metadata)
+    }
+
+    /// Metadata of the file currently stored under `name`.
+    pub async fn get_metadata_by_name(&self, name: &str) -> Result<FileMetadata> {
+        let id = self
+            .lookup_name(name)
+            .await?
+            .ok_or_else(|| AppError::Storage(StorageError::NotFound(name.to_string())))?;
+        self.get_metadata(&id).await
+    }
+
+    /// Reports for each id whether a file with that id is stored.
+    pub async fn exists_many(&self, ids: &[FileId]) -> Result<HashMap<FileId, bool>> {
+        let mut found = HashMap::with_capacity(ids.len());
+        for id in ids {
+            let exists = fs::try_exists(self.get_metadata_path(id)).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+            found.insert(*id, exists);
+        }
+        Ok(found)
+    }
+
+    /// Reports for each plaintext checksum whether a stored file has that content.
+    /// Files written before content checksums were recorded never match.
+    pub async fn exists_many_by_checksum(&self, checksums: &[String]) -> Result<HashMap<String, bool>> {
+        let stored: HashSet<String> = self
+            .list_files()
+            .await?
+            .into_iter()
+            .map(|metadata| metadata.content_checksum)
+            .filter(|checksum| !checksum.is_empty())
+            .collect();
+        Ok(checksums.iter().map(|checksum| (checksum.clone(), stored.contains(checksum))).collect())
+    }
+
+    /// Compares each `(name, checksum)` pair with the plaintext checksum of the file stored
+    /// under that name, reading only metadata. Files written before content checksums were
+    /// recorded have nothing to compare against and report `Differs`.
+    pub async fn verify_checksums(&self, expected: &[(String, String)]) -> Result<Vec<ChecksumStatus>> {
+        let mut statuses = Vec::with_capacity(expected.len());
+        for (name, checksum) in expected {
+            let status = match self.lookup_name(name).await? {
+                Some(id) => match self.get_metadata(&id).await {
+                    Ok(metadata) if !metadata.content_checksum.is_empty() && metadata.content_checksum.eq_ignore_ascii_case(checksum) => ChecksumStatus::Match,
+                    Ok(_) => ChecksumStatus::Differs,
+                    Err(AppError::Storage(StorageError::NotFound(_))) => ChecksumStatus::Missing,
+                    Err(e) => return Err(e),
+                },
+                None => ChecksumStatus::Missing,
+            };
+            statuses.push(status);
+        }
+        Ok(statuses)
+    }
+
+    // Written aside and renamed in, so a crash never leaves truncated metadata behind
+    async fn swap_metadata(&self, metadata: &FileMetadata) -> Result<()> {
+        let metadata_json = serde_json::to_string(metadata)
+            .map_err(|e| StorageError::Storage(e.to_string()))?;
+        let final_path = self.get_metadata_path(&metadata.id);
+        let tmp_path = final_path.with_extension("json.tmp");
+
+        fs::write(&tmp_path, metadata_json).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+        fs::rename(&tmp_path, &final_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+        Ok(())
+    }
+
+    /// Replaces a file's contents copy-on-write. The new chunk set is written first and
+    /// the metadata is swapped in with a rename, so concurrent readers see either the old
+    /// or the new version. Old chunks are removed once in-flight reads have finished.
+    #[instrument(skip(self, data), fields(size = data.len()))]
+    pub async fn update_file(&self, id: &FileId, data: &[u8]) -> Result<FileMetadata> {
+        let existing = self.get_metadata(id).await?;
+        self.ensure_not_empty(&existing.name, data)?;
+        self.replace_contents(&existing, data, None, Utc::now(), existing.keep_plaintext).await
+    }
+
+    /// Replaces the contents of the file stored under `name`, keeping its id. Fails with
+    /// `NotFound` when no file has that name; use `store_file` to create one.
+    pub async fn update_file_by_name(&self, name: &str, data: &[u8]) -> Result<FileMetadata> {
+        let id = self
+            .lookup_name(name)
+            .await?
+            .ok_or_else(|| AppError::Storage(StorageError::NotFound(name.to_string())))?;
+        self.update_file(&id, data).await
+    }
+
+    // Progress is reported under `operation_id` when given, otherwise under an operation of
+    // its own. Either way the operation is completed before returning.
+    async fn store_tagged(&self, name: &str, data: &[u8], tags: &HashMap<String, String>, operation_id: Option<&Uuid>) -> Result<FileMetadata> {
+        let operation_id = match operation_id {
+            Some(operation_id) => *operation_id,
+            None => self.progress_tracker.start_operation(data.len() as u64).await,
+        };
+        let result = self.store_with_progress(name, data, tags, &operation_id).await;
+        self.progress_tracker.complete_operation(&operation_id).await;
+        result
+    }
+
+    // Body of `store_file`. Non-empty `tags` replace those of an existing file the upload
+    // resolves to; empty ones leave them alone.
+    async fn store_with_progress(&self, name: &str, data: &[u8], tags: &HashMap<String, String>, operation_id: &Uuid) -> Result<FileMetadata> {
+        self.ensure_not_empty(name, data)?;
+        if self.idempotent_uploads {
+            if let Some(existing) = self.find_identical(name, data).await? {
+                if tags.is_empty() || existing.tags == *tags {
+                    return Ok(existing);
+                }
+                return self.set_tags(&existing.id, tags.clone()).await;
+            }
+        }
+        if self.overwrite_by_name && !self.versioning {
+            if let Some(id) = self.lookup_name(name).await? {
+                if self.get_metadata_path(&id).exists() {
+                    let updated = self.update_file(&id, data).await?;
+                    if tags.is_empty() {
+                        return Ok(updated);
+                    }
+                    return self.set_tags(&id, tags.clone()).await;
+                }
+            }
+        }
+        let previous = if self.versioning { self.lookup_name(name).await? } else { None };
+        // One budget for every attempt, so retried stores don't start over with a full one
+        let budget = RetryBudget::new(self.retry_budget);
+        let metadata = with_retry(&self.retry_config, || async {
+            let id = FileId::new();
+
+            let processed = self.prepare_file(&id, data, None, true).await?;
+            self.ensure_free_space(processed.size)?;
+            let _in_flight = self.in_flight_chunks.track(processed.chunks.iter().map(|c| c.id.clone()).collect());
+
+            let chunk_ids = self.store_chunks(processed.chunks, Some((operation_id, data.len() as u64)), &budget).await?;
 
             // Create and store metadata
             let metadata = FileMetadata {
                 id,
                 name: name.to_string(),
-                size: final_data.len() as u64,
+                size: processed.size,
+                original_size: data.len() as u64,
+                stored_size: processed.size,
                 created_at: Utc::now(),
                 modified_at: Utc::now(),
-                checksum: Self::calculate_checksum(&final_data),
-                file_type,
+                checksum: processed.checksum,
+                content_checksum: processed.content_checksum,
+                compression_ratio: processed.compression_ratio,
+                file_type: processed.file_type,
                 chunk_ids,
+                pipeline: processed.pipeline,
+                chunk_compressed: processed.chunk_compressed,
+                chunk_sizes: processed.chunk_sizes,
+                chunk_size: processed.chunk_size,
+                id_bound: true,
+                compression_level: processed.compression_level,
+                compression_algorithm: processed.compression_algorithm,
+                per_chunk_keys: processed.per_chunk_keys,
+                chunk_unpadded_sizes: processed.chunk_unpadded_sizes,
+                chunk_checksums: processed.chunk_checksums,
+                keep_plaintext: false,
+                tags: tags.clone(),
             };
 
             let validation = ValidationManager::new(self.base_path.clone());
             validation.validate_file(&metadata).await?;
 
-            // Write metadata to file
-            let metadata_json = serde_json::to_string(&metadata)
-                .map_err(|e| StorageError::Storage(e.to_string()))?;
-            fs::write(self.get_metadata_path(&id), metadata_json).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+            self.swap_metadata(&metadata).await?;
 
             self.update_name_index(name, &id).await?;
 
             if let Some(cache) = &self.cache {
-                cache.put(id, final_data.clone()).await;
+                cache.put(id, data.to_vec()).await;
             }
 
-            self.progress_tracker.complete_operation(&operation_id).await;
+            info!(id = %metadata.id, "Stored file");
             Ok(metadata)
         })
-        .await
+        .await?;
+
+        if self.versioning {
+            self.record_version(&metadata, previous).await?;
+        }
+        Ok(metadata)
     }
 
-    async fn get_file(&self, id: &Uuid) -> Result<Vec<u8>> {
+    // Body of `get_file`, advancing `operation_id` as chunks are read when given
+    async fn load_file(&self, id: &FileId, operation_id: Option<&Uuid>) -> Result<Vec<u8>> {
+        // Both cache tiers hold decoded data, so a hit only needs the file to still exist
         if let Some(cache) = &self.cache {
             if let Some(data) = cache.get(id).await {
-                let metadata_path = self.get_metadata_path(id);
-                if !metadata_path.exists() {
+                if !self.get_metadata_path(id).exists() {
+                    return Err(AppError::Storage(StorageError::NotFound(id.to_string())));
+                }
+                return Ok(data);
+            }
+        }
+
+        if let Some(persistent_cache) = &self.persistent_cache {
+            if let Some(data) = persistent_cache.get(id).await {
+                if !self.get_metadata_path(id).exists() {
+                    persistent_cache.invalidate(id).await;
                     return Err(AppError::Storage(StorageError::NotFound(id.to_string())));
                 }
-                let metadata_content = fs::read_to_string(&metadata_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
-                let metadata: FileMetadata = serde_json::from_str(&metadata_content)
-                .map_err(|e| StorageError::Storage(format!("Failed to parse metadata: {}", e)))?;
-                return self.deprocess_file_by_type(metadata.file_type, &data).await;
+                if let Some(cache) = &self.cache {
+                    cache.put(*id, data.clone()).await;
+                }
+                return Ok(data);
             }
         }
 
-        with_retry(&self.retry_config, || async {
+        let (final_data, stale) = with_retry(&self.retry_config, || async {
+            let _guard = self.chunk_gc_lock.read().await;
             let metadata_path = self.get_metadata_path(id);
 
             if !metadata_path.exists() {
@@ -370,68 +1337,2411 @@ impl StorageBackend for DiskStorage {
 
             let metadata_content = fs::read_to_string(&metadata_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
             let metadata: FileMetadata = serde_json::from_str(&metadata_content)
-                .map_err(|e| StorageError::Storage(format!("Failed to parse metadata: {}", e)))?;
-
+                .map_err(|e| StorageError::IntegrityError(format!("Failed to parse metadata: {}", e)))?;
 
+            // Chunks are checked against their checksums as they're read, so the layout is enough here
             let validation = ValidationManager::new(self.base_path.clone());
-            validation.validate_file(&metadata).await?;
+            validation.validate_layout(&metadata).await?;
 
             // Read and combine chunks
             let mut data = Vec::new();
-            for chunk_id in metadata.chunk_ids {
-                let chunk_path = self.get_chunk_path(&chunk_id);
-                let chunk_data = fs::read(&chunk_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
-                data.extend(chunk_data);
+            let mut stored_len = 0;
+            let mut hasher = Sha256::new();
+            for (index, chunk_id) in metadata.chunk_ids.iter().enumerate() {
+                let chunk_data = self.read_chunk(chunk_id, metadata.chunk_checksums.get(index).map(String::as_str)).await?;
+                stored_len += chunk_data.len() as u64;
+                hasher.update(&chunk_data);
+                match metadata.chunk_compressed.get(index) {
+                    Some(compressed) => data.extend(self.deprocess_chunk(&chunk_data, &metadata.pipeline, *compressed, metadata.encryption_aad(), metadata.per_chunk_keys.then_some(chunk_id), metadata.chunk_unpadded_sizes.get(index).copied()).await?),
+                    None => data.extend(chunk_data),
+                }
+                if let Some(operation_id) = operation_id {
+                    let done = metadata.original_len() * (index as u64 + 1) / metadata.chunk_ids.len() as u64;
+                    self.progress_tracker.update_progress(operation_id, done).await;
+                }
             }
 
-            let final_data = self
-                .deprocess_file_by_type(metadata.file_type, &data)
-                .await?;
+            validation.validate_length(&metadata, stored_len)?;
+            validation.validate_checksum(&metadata, &format!("{:x}", hasher.finalize()))?;
+
+            let final_data = if metadata.chunk_compressed.is_empty() {
+                self.deprocess_file_by_type(metadata.file_type.clone(), &metadata.pipeline, &data, metadata.encryption_aad())
+                    .await?
+            } else {
+                data
+            };
+            validation.validate_original_length(&metadata, final_data.len() as u64)?;
+            let stale = self.lazy_recompress && self.needs_reprocessing(&metadata, &final_data);
 
             if let Some(cache) = &self.cache {
                 cache.put(*id, final_data.clone()).await; // Store the data in cache
             }
 
-            Ok(final_data)
+            if let Some(persistent_cache) = &self.persistent_cache {
+                if let Err(e) = persistent_cache.put(*id, &final_data).await {
+                    warn!(%id, error = %e, "Failed to write persistent cache entry");
+                }
+            }
+
+            Ok((final_data, stale))
         })
-        .await
+        .await?;
+
+        // Runs after the read guard is released since update_file reclaims the old chunks
+        if stale {
+            if let Err(e) = self.update_file(id, &final_data).await {
+                warn!(%id, error = %e, "Failed to migrate file");
+            }
+        }
+
+        Ok(final_data)
     }
 
-    async fn delete_file(&self, id: &Uuid) -> Result<()> {
-        let metadata_path = self.get_metadata_path(id);
+    /// Stores a file like `store_file`, attaching application-defined tags to it.
+    #[instrument(skip(self, data, tags), fields(size = data.len()))]
+    pub async fn store_file_with_tags(&self, name: &str, data: &[u8], tags: HashMap<String, String>) -> Result<FileMetadata> {
+        self.store_tagged(name, data, &tags, None).await
+    }
 
-        // Check if file exists
-        if !metadata_path.exists() {
-            return Err(AppError::Storage(StorageError::NotFound(id.to_string())));
+    /// Stores a file like `store_file`, reporting progress under `operation_id`, which the
+    /// caller started on the progress tracker with the data's size.
+    #[instrument(skip(self, data), fields(size = data.len()))]
+    pub async fn store_file_tracked(&self, name: &str, data: &[u8], operation_id: &Uuid) -> Result<FileMetadata> {
+        self.store_tagged(name, data, &HashMap::new(), Some(operation_id)).await
+    }
+
+    /// Reads a file like `get_file`, reporting progress under `operation_id`, which the
+    /// caller started on the progress tracker with the file's size. The operation is
+    /// completed once the read finishes.
+    #[instrument(skip(self))]
+    pub async fn get_file_tracked(&self, id: &FileId, operation_id: &Uuid) -> Result<Vec<u8>> {
+        let result = self.load_file(id, Some(operation_id)).await;
+        if let Ok(data) = &result {
+            self.progress_tracker.update_progress(operation_id, data.len() as u64).await;
         }
+        self.progress_tracker.complete_operation(operation_id).await;
+        result
+    }
 
-        // Read metadata to get chunk information
-        let metadata_content = fs::read_to_string(&metadata_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
-        let metadata: FileMetadata = serde_json::from_str(&metadata_content)
-            .map_err(|e| StorageError::Storage(format!("Failed to parse metadata: {}", e)))?;
+    /// Replaces a file's tags. Only the metadata is rewritten; contents and chunks are left alone.
+    pub async fn set_tags(&self, id: &FileId, tags: HashMap<String, String>) -> Result<FileMetadata> {
+        let mut metadata = self.get_metadata(id).await?;
+        metadata.tags = tags;
+        self.swap_metadata(&metadata).await?;
+        Ok(metadata)
+    }
 
-        // Delete chunks that aren't used by other files
-        for chunk_id in &metadata.chunk_ids {
-            if !self.is_chunk_used_by_others(chunk_id, id).await? {
-                let chunk_path = self.get_chunk_path(chunk_id);
-                if chunk_path.exists() {
-                    if let Err(e) = fs::remove_file(&chunk_path).await {
-                        eprintln!("Failed to delete chunk {}: {}", chunk_id.0, e);
+    pub async fn get_tags(&self, id: &FileId) -> Result<HashMap<String, String>> {
+        Ok(self.get_metadata(id).await?.tags)
+    }
+
+    /// Gives a file a new name, keeping its id and contents. Fails when another file is
+    /// stored under `new_name`. With versioning on, the history stays with the old name.
+    pub async fn rename_file(&self, id: &FileId, new_name: &str) -> Result<()> {
+        let mut metadata = self.get_metadata(id).await?;
+        if metadata.name == new_name {
+            return Ok(());
+        }
+        if let Some(other) = self.lookup_name(new_name).await?.filter(|other| other != id) {
+            // Entries left behind by deleted files don't block the name
+            if self.get_metadata_path(&other).exists() {
+                return Err(AppError::Storage(StorageError::InvalidInput(format!("Cannot rename {} to {}: the name is used by {}", id, new_name, other))));
+            }
+        }
+
+        let old_name = std::mem::replace(&mut metadata.name, new_name.to_string());
+        metadata.modified_at = Utc::now();
+        self.swap_metadata(&metadata).await?;
+        self.update_name_index(new_name, id).await?;
+        // The old name may already point at a newer upload
+        if self.lookup_name(&old_name).await? == Some(*id) {
+            self.name_index.remove(&old_name).await?;
+        }
+
+        info!(%id, from = %old_name, to = %new_name, "Renamed file");
+        Ok(())
+    }
+
+    /// Versions of the file stored under `name`, oldest first. A file stored before
+    /// versioning was turned on is listed as version 1, and files removed with
+    /// `delete_file` stay listed with the time they were deleted.
+    pub async fn list_versions(&self, name: &str) -> Result<Vec<FileVersion>> {
+        Ok(self.versions_of(name).await?.versions)
+    }
+
+    pub async fn get_file_version(&self, name: &str, version: u32) -> Result<Vec<u8>> {
+        let history = self.versions_of(name).await?;
+        let entry = history
+            .get(version)
+            .ok_or_else(|| AppError::Storage(StorageError::NotFound(format!("{} version {}", name, version))))?;
+        self.get_file(&entry.id).await
+    }
+
+    /// Deletes one version of a file and drops it from the history. Chunks it shares with
+    /// other versions are kept, and deleting the newest version points the name back at
+    /// the one before it.
+    pub async fn delete_version(&self, name: &str, version: u32) -> Result<()> {
+        let removed = {
+            let _lock = self.versions_lock.lock().await;
+            let mut history = self.versions_of(name).await?;
+            let index = history
+                .versions
+                .iter()
+                .position(|entry| entry.version == version)
+                .ok_or_else(|| AppError::Storage(StorageError::NotFound(format!("{} version {}", name, version))))?;
+            let removed = history.versions.remove(index);
+            self.save_versions(&history).await?;
+            removed
+        };
+
+        match self.delete_file(&removed.id).await {
+            Ok(()) => Ok(()),
+            // Already deleted, so only the name can still point at it
+            Err(AppError::Storage(StorageError::NotFound(_))) => self.repoint_name(name, &removed.id).await,
+            Err(e) => Err(e),
+        }
+    }
+
+    // Marks a deleted file's version as deleted and moves its name off it
+    async fn record_deletion(&self, metadata: &FileMetadata) -> Result<()> {
+        {
+            let _lock = self.versions_lock.lock().await;
+            if let Some(mut history) = self.load_versions(&metadata.name).await? {
+                if let Some(entry) = history.versions.iter_mut().find(|entry| entry.id == metadata.id) {
+                    entry.deleted_at = Some(Utc::now());
+                    self.save_versions(&history).await?;
+                }
+            }
+        }
+        self.repoint_name(&metadata.name, &metadata.id).await
+    }
+
+    // Points `name` at its newest remaining version if it pointed at `removed`, or drops it
+    async fn repoint_name(&self, name: &str, removed: &FileId) -> Result<()> {
+        if self.lookup_name(name).await? != Some(*removed) {
+            return Ok(());
+        }
+        let latest = self.load_versions(name).await?.and_then(|history| history.latest().map(|entry| entry.id)).filter(|id| id != removed);
+        match latest {
+            Some(latest) => self.update_name_index(name, &latest).await,
+            None => self.name_index.remove(name).await,
+        }
+    }
+
+    fn versions_path(&self, name: &str) -> PathBuf {
+        self.base_path.join("versions").join(format!("{}.json", Self::calculate_checksum(name.as_bytes())))
+    }
+
+    async fn load_versions(&self, name: &str) -> Result<Option<VersionedMetadata>> {
+        let path = self.versions_path(name);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+        let history = serde_json::from_str(&content).map_err(|e| StorageError::IntegrityError(format!("Failed to parse version history: {}", e)))?;
+        Ok(Some(history))
+    }
+
+    async fn save_versions(&self, history: &VersionedMetadata) -> Result<()> {
+        let path = self.versions_path(&history.name);
+        if history.versions.is_empty() {
+            if path.exists() {
+                fs::remove_file(&path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+            }
+            return Ok(());
+        }
+
+        fs::create_dir_all(self.base_path.join("versions")).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+        let json = serde_json::to_string(history).map_err(|e| StorageError::Storage(e.to_string()))?;
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, json).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+        fs::rename(&tmp_path, &path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+        Ok(())
+    }
+
+    // History of `name`, or the file currently under it as version 1 when there is none
+    async fn versions_of(&self, name: &str) -> Result<VersionedMetadata> {
+        if let Some(history) = self.load_versions(name).await? {
+            return Ok(history);
+        }
+        let id = self
+            .lookup_name(name)
+            .await?
+            .ok_or_else(|| AppError::Storage(StorageError::NotFound(name.to_string())))?;
+        let mut history = VersionedMetadata::new(name);
+        history.push(&self.get_metadata(&id).await?);
+        Ok(history)
+    }
+
+    // Appends a newly stored file to its name's history. `previous` is the file the name
+    // pointed at before, which starts the history if versioning was only just turned on.
+    async fn record_version(&self, metadata: &FileMetadata, previous: Option<FileId>) -> Result<()> {
+        let _lock = self.versions_lock.lock().await;
+        let mut history = match self.load_versions(&metadata.name).await? {
+            Some(history) => history,
+            None => {
+                let mut history = VersionedMetadata::new(&metadata.name);
+                if let Some(previous) = previous.filter(|id| *id != metadata.id) {
+                    match self.get_metadata(&previous).await {
+                        Ok(previous) => history.push(&previous),
+                        Err(AppError::Storage(StorageError::NotFound(_))) => {}
+                        Err(e) => return Err(e),
                     }
                 }
+                history
             }
+        };
+        history.push(metadata);
+        self.save_versions(&history).await
+    }
+
+    /// Splits an existing file into chunks of `new_chunk_size`, keeping its id and name.
+    pub async fn rechunk(&self, id: &FileId, new_chunk_size: usize) -> Result<FileMetadata> {
+        if new_chunk_size == 0 {
+            return Err(AppError::Storage(StorageError::InvalidInput("Chunk size must be greater than zero".to_string())));
         }
 
-        // Delete metadata file
-        fs::remove_file(&metadata_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+        let existing = self.get_metadata(id).await?;
+        let data = self.get_file(id).await?;
+        self.replace_contents(&existing, &data, Some(new_chunk_size), existing.modified_at, existing.keep_plaintext).await
+    }
 
-        // Clean up any orphaned chunks
-        self.cleanup_orphaned_chunks().await?;
+    pub async fn rechunk_many(&self, ids: &[FileId], new_chunk_size: usize) -> Result<Vec<FileMetadata>> {
+        let mut rechunked = Vec::with_capacity(ids.len());
+        for id in ids {
+            rechunked.push(self.rechunk(id, new_chunk_size).await?);
+        }
+        Ok(rechunked)
+    }
 
-        if let Some(cache) = &self.cache {
-            cache.invalidate(id).await; // Invalidate cache entry
+    async fn replace_contents(&self, existing: &FileMetadata, data: &[u8], chunk_size: Option<usize>, modified_at: DateTime<Utc>, keep_plaintext: bool) -> Result<FileMetadata> {
+        let id = &existing.id;
+        let processed = self.prepare_file(id, data, chunk_size, !keep_plaintext).await?;
+        self.ensure_free_space(processed.size)?;
+        let _in_flight = self.in_flight_chunks.track(processed.chunks.iter().map(|c| c.id.clone()).collect());
+        let chunk_ids = self.store_chunks(processed.chunks, None, &RetryBudget::new(self.retry_budget)).await?;
+
+        let metadata = FileMetadata {
+            id: *id,
+            name: existing.name.clone(),
+            size: processed.size,
+            original_size: data.len() as u64,
+            stored_size: processed.size,
+            created_at: existing.created_at,
+            modified_at,
+            checksum: processed.checksum,
+            content_checksum: processed.content_checksum,
+            compression_ratio: processed.compression_ratio,
+            file_type: processed.file_type,
+            chunk_ids,
+            pipeline: processed.pipeline,
+            chunk_compressed: processed.chunk_compressed,
+            chunk_sizes: processed.chunk_sizes,
+            chunk_size: processed.chunk_size,
+            id_bound: true,
+            compression_level: processed.compression_level,
+            compression_algorithm: processed.compression_algorithm,
+            per_chunk_keys: processed.per_chunk_keys,
+            chunk_unpadded_sizes: processed.chunk_unpadded_sizes,
+            chunk_checksums: processed.chunk_checksums,
+            keep_plaintext,
+            tags: existing.tags.clone(),
+        };
+
+        let validation = ValidationManager::new(self.base_path.clone());
+        validation.validate_file(&metadata).await?;
+
+        self.swap_metadata(&metadata).await?;
+
+        let _guard = self.chunk_gc_lock.write().await;
+
+        self.invalidate_caches(id).await;
+
+        if self.auto_gc {
+            self.remove_replaced_chunks(existing, &metadata).await?;
         }
 
+        Ok(metadata)
+    }
+
+    // Deletes the chunks of `old` that `new` no longer uses and nothing else references.
+    // Callers hold the chunk GC lock for writing.
+    async fn remove_replaced_chunks(&self, old: &FileMetadata, new: &FileMetadata) -> Result<()> {
+        // Repeated content appears more than once in a file's chunk list
+        let old_chunks: HashSet<&ChunkId> = old.chunk_ids.iter().collect();
+        for chunk_id in old_chunks {
+            if !new.chunk_ids.contains(chunk_id)
+                && !self.in_flight_chunks.contains(chunk_id)
+                && !self.is_chunk_used_by_others(chunk_id, &old.id).await?
+            {
+                if let Err(e) = fs::remove_file(self.get_chunk_path(chunk_id)).await {
+                    eprintln!("Failed to delete chunk {}: {}", chunk_id.0, e);
+                }
+            }
+        }
         Ok(())
     }
+
+    /// Rewrites a file without encryption, e.g. before moving it to a public store, and
+    /// removes its encrypted chunks. It stays unencrypted through later updates and
+    /// migrations until `encrypt_file` is called.
+    pub async fn decrypt_file(&self, id: &FileId) -> Result<FileMetadata> {
+        let existing = self.get_metadata(id).await?;
+        let data = self.get_file(id).await?;
+        self.replace_contents(&existing, &data, None, existing.modified_at, true).await
+    }
+
+    /// Rewrites a file with the configured encryption, undoing `decrypt_file` or migrating
+    /// a file stored before encryption was enabled. The plaintext chunks it replaces are
+    /// removed even when automatic chunk GC is off; with it off, chunks of older versions
+    /// still wait for `gc`.
+    pub async fn encrypt_file(&self, id: &FileId) -> Result<FileMetadata> {
+        if self.write_encryption(true).is_none() {
+            return Err(AppError::Storage(StorageError::InvalidInput("Encryption is not configured".to_string())));
+        }
+
+        let existing = self.get_metadata(id).await?;
+        let data = self.get_file(id).await?;
+        let encrypted = self.replace_contents(&existing, &data, None, existing.modified_at, false).await?;
+        if !self.auto_gc {
+            let _guard = self.chunk_gc_lock.write().await;
+            self.remove_replaced_chunks(&existing, &encrypted).await?;
+        }
+        Ok(encrypted)
+    }
+
+    /// Reads the inclusive byte range `[start, end]` of a file, clamping `end` to the file size.
+    /// Only the chunks overlapping the range are read when each chunk can be decoded on its
+    /// own; files processed as a whole are decoded in full and then sliced.
+    pub async fn get_file_range(&self, id: &FileId, start: u64, end: u64) -> Result<Vec<u8>> {
+        if start > end {
+            return Err(AppError::Storage(StorageError::InvalidInput(format!("Invalid range: start {} is after end {}", start, end))));
+        }
+
+        let guard = self.chunk_gc_lock.read().await;
+        let metadata = self.get_metadata(id).await?;
+
+        if metadata.chunk_sizes.len() != metadata.chunk_ids.len() {
+            drop(guard);
+            let data = self.get_file(id).await?;
+            let size = data.len() as u64;
+            if start >= size {
+                return Err(AppError::Storage(StorageError::InvalidInput(format!("Range start {} is beyond file size {}", start, size))));
+            }
+            return Ok(data[start as usize..=end.min(size - 1) as usize].to_vec());
+        }
+
+        let size: u64 = metadata.chunk_sizes.iter().sum();
+        if start >= size {
+            return Err(AppError::Storage(StorageError::InvalidInput(format!("Range start {} is beyond file size {}", start, size))));
+        }
+        let end = end.min(size - 1);
+
+        let validation = ValidationManager::new(self.base_path.clone());
+        let mut data = Vec::new();
+        let mut chunk_start = 0;
+        for (index, chunk_size) in metadata.chunk_sizes.iter().enumerate() {
+            let chunk_end = chunk_start + chunk_size;
+            if chunk_end <= start {
+                chunk_start = chunk_end;
+                continue;
+            }
+            if chunk_start > end {
+                break;
+            }
+
+            let chunk_data = self.read_decoded_chunk(&metadata, index, &validation).await?;
+            let from = start.saturating_sub(chunk_start) as usize;
+            let to = ((end + 1).min(chunk_end) - chunk_start) as usize;
+            data.extend_from_slice(&chunk_data[from..to]);
+            chunk_start = chunk_end;
+        }
+
+        Ok(data)
+    }
+
+    /// Reads a file as a stream, decoding each chunk only when the consumer gets to it, so
+    /// memory use stays around one chunk however large the file is. Files processed as a
+    /// whole rather than per chunk can only be decoded in full, so those are read into
+    /// memory first. Chunk removal isn't held off while the stream is idle, so a file
+    /// replaced or deleted partway through ends the stream with an error.
+    pub async fn get_file_stream(&self, id: &FileId) -> Result<impl AsyncRead + Send + Unpin + '_> {
+        let metadata = {
+            let _guard = self.chunk_gc_lock.read().await;
+            let metadata = self.get_metadata(id).await?;
+            ValidationManager::new(self.base_path.clone()).validate_layout(&metadata).await?;
+            metadata
+        };
+
+        let chunks: ChunkStream<'_> = if metadata.chunk_compressed.is_empty() && !metadata.pipeline.is_empty() {
+            let data = self.get_file(id).await?;
+            Box::pin(stream::once(async { Ok(io::Cursor::new(data)) }))
+        } else {
+            let validation = ValidationManager::new(self.base_path.clone());
+            Box::pin(stream::try_unfold((metadata, validation, 0), move |(metadata, validation, index)| async move {
+                if index == metadata.chunk_ids.len() {
+                    return Ok(None);
+                }
+                let _guard = self.chunk_gc_lock.read().await;
+                let data = self.read_decoded_chunk(&metadata, index, &validation).await?;
+                Ok(Some((io::Cursor::new(data), (metadata, validation, index + 1))))
+            }))
+        };
+
+        Ok(StreamReader::new(chunks.map_err(io::Error::other)))
+    }
+
+    // Reads one chunk of a file stored per chunk (or unprocessed) and decodes it.
+    // Callers hold the chunk GC lock for reading.
+    async fn read_decoded_chunk(&self, metadata: &FileMetadata, index: usize, validation: &ValidationManager) -> Result<Vec<u8>> {
+        let chunk_id = &metadata.chunk_ids[index];
+        let chunk_data = self.read_chunk(chunk_id, metadata.chunk_checksums.get(index).map(String::as_str)).await?;
+        let chunk_data = match metadata.chunk_compressed.get(index) {
+            Some(compressed) => self.deprocess_chunk(&chunk_data, &metadata.pipeline, *compressed, metadata.encryption_aad(), metadata.per_chunk_keys.then_some(chunk_id), metadata.chunk_unpadded_sizes.get(index).copied()).await?,
+            None => chunk_data,
+        };
+        if let Some(chunk_size) = metadata.chunk_sizes.get(index) {
+            validation.validate_chunk_length(chunk_id, *chunk_size, chunk_data.len() as u64)?;
+        }
+        Ok(chunk_data)
+    }
+
+    /// Counts stored files by whether their recorded pipeline included encryption.
+    pub async fn encryption_audit(&self) -> Result<EncryptionAudit> {
+        let mut audit = EncryptionAudit::default();
+
+        for metadata in self.list_files().await? {
+            if metadata.pipeline.contains(&PipelineStage::Encrypt) {
+                audit.encrypted += 1;
+            } else {
+                audit.plaintext += 1;
+                audit.plaintext_ids.push(metadata.id);
+            }
+        }
+
+        Ok(audit)
+    }
+
+    fn chunk_size_for(&self, file_type: &FileType) -> usize {
+        self.type_chunk_sizes.get(file_type).copied().unwrap_or_else(|| self.chunker.min_chunk_size())
+    }
+
+    /// Rewrites files split into more chunks than their configured chunk size needs,
+    /// removes unreferenced chunks and drops index entries for files that no longer
+    /// exist. Safe to run periodically.
+    pub async fn compact(&self) -> Result<CompactionReport> {
+        let mut report = CompactionReport::default();
+
+        for metadata in self.list_files().await? {
+            let chunk_size = self.chunk_size_for(&metadata.file_type) as u64;
+            // Chunks hold decoded bytes when their sizes were recorded, otherwise the stored bytes
+            let size = if metadata.chunk_sizes.len() == metadata.chunk_ids.len() {
+                metadata.chunk_sizes.iter().sum()
+            } else {
+                metadata.size
+            };
+            if chunk_size == 0 || metadata.chunk_ids.len() <= size.div_ceil(chunk_size) as usize {
+                continue;
+            }
+
+            let data = self.get_file(&metadata.id).await?;
+            let compacted = self.replace_contents(&metadata, &data, None, metadata.modified_at, metadata.keep_plaintext).await?;
+            report.files_compacted += 1;
+            report.chunks_removed += metadata.chunk_ids.len().saturating_sub(compacted.chunk_ids.len());
+        }
+
+        self.gc().await?;
+
+        for (name, id) in self.name_index.entries().await? {
+            if !self.get_metadata_path(&id).exists() {
+                self.name_index.remove(&name).await?;
+                report.index_entries_removed += 1;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Averages the recorded compression ratio per file type. Files stored before
+    /// the ratio was recorded are left out.
+    pub async fn compression_report(&self) -> Result<CompressionReport> {
+        let mut totals: HashMap<FileType, (usize, f64)> = HashMap::new();
+
+        for metadata in self.list_files().await? {
+            if metadata.compression_ratio > 0.0 {
+                let entry = totals.entry(metadata.file_type).or_default();
+                entry.0 += 1;
+                entry.1 += metadata.compression_ratio;
+            }
+        }
+
+        let by_type = totals
+            .into_iter()
+            .map(|(file_type, (files, sum))| (file_type, CompressionStats { files, average_ratio: sum / files as f64 }))
+            .collect();
+        Ok(CompressionReport { by_type })
+    }
+
+    /// Stores a file read from `reader` without holding all of it in memory. Data is read a
+    /// chunk at a time and written in batches of up to `write_concurrency` chunks, with the
+    /// checksums accumulated along the way. The file type, compressibility and compression
+    /// level are decided on the first megabyte, which is all `store_file` looks at either,
+    /// so the metadata matches what `store_file` records for the same bytes. A file
+    /// compressed as a whole is compressed as it is read and its output chunked.
+    #[instrument(skip(self, reader))]
+    pub async fn store_file_stream<R: AsyncRead + Unpin>(&self, name: &str, mut reader: R) -> Result<FileMetadata> {
+        let existing = if self.idempotent_uploads || self.overwrite_by_name || self.versioning {
+            match self.lookup_name(name).await? {
+                Some(id) => match self.get_metadata(&id).await {
+                    Ok(metadata) => Some(metadata),
+                    Err(AppError::Storage(StorageError::NotFound(_))) => None,
+                    Err(e) => return Err(e),
+                },
+                None => None,
+            }
+        } else {
+            None
+        };
+        let target = existing.as_ref().filter(|_| self.overwrite_by_name && !self.versioning);
+        let id = target.map_or_else(FileId::new, |existing| existing.id);
+        let encrypt = !target.is_some_and(|existing| existing.keep_plaintext);
+
+        let mut buffer = Vec::new();
+        let prefix_len = DECISION_PREFIX_SIZE.max(self.chunker.max_chunk_size());
+        let mut eof = fill_buffer(&mut reader, &mut buffer, prefix_len).await?;
+        if buffer.is_empty() {
+            self.ensure_not_empty(name, &buffer)?;
+        }
+
+        let file_type = FileTypeDetector::detect_with_fallback(&buffer, &self.unknown_file_type);
+        let custom_chunker = self
+            .type_chunk_sizes
+            .get(&file_type)
+            .map(|chunk_size| FileChunker::new(ChunkManager::new(*chunk_size)));
+        let chunker = custom_chunker.as_ref().unwrap_or(&self.chunker);
+        let compress = is_compressible_type(&file_type);
+        let aad = Some(id.0.as_bytes().as_slice());
+        let compressible = self.compression.as_ref().filter(|c| compress && c.is_worth_compressing(&buffer));
+        let level = self.compression_level(compress, &buffer)?;
+        let encrypting = self.write_encryption(encrypt).is_some();
+        let per_chunk = encrypting || (self.chunk_compression && compress);
+        let per_chunk_keys = self.per_chunk_keys && encrypting && per_chunk;
+        // Same decision `process_data` makes for the whole file
+        let mut encoder = match (compressible, level) {
+            (Some(compression), Some(level)) if !per_chunk && self.pipeline.stages().contains(&PipelineStage::Compress) => {
+                Some(compression.stream_encoder(level)?)
+            }
+            _ => None,
+        };
+        let whole_file_compressed = encoder.is_some();
+
+        let mut in_flight = self.in_flight_chunks.track(Vec::new());
+        let budget = RetryBudget::new(self.retry_budget);
+        let mut content_hasher = Sha256::new();
+        let mut hasher = Sha256::new();
+        let mut original_size = 0u64;
+        let mut size = 0u64;
+        let mut chunk_ids = Vec::new();
+        let mut chunk_compressed = Vec::new();
+        let mut chunk_sizes = Vec::new();
+        let mut chunk_unpadded_sizes = Vec::new();
+        let mut chunk_checksums = Vec::new();
+        let mut batch: Vec<Chunk> = Vec::new();
+        // `buffer` holds the bytes to chunk, which are the compressed bytes when compressing
+        // the whole file, so what has been read goes through the encoder first
+        let mut input = std::mem::take(&mut buffer);
+        loop {
+            loop {
+                content_hasher.update(&input);
+                original_size += input.len() as u64;
+                match encoder.as_mut() {
+                    Some(encoder) => {
+                        encoder.write(&input)?;
+                        buffer.extend(encoder.take_output());
+                    }
+                    None => buffer.extend_from_slice(&input),
+                }
+                input.clear();
+                if eof {
+                    if let Some(encoder) = encoder.take() {
+                        buffer.extend(encoder.finish()?);
+                    }
+                    break;
+                }
+                if buffer.len() >= chunker.max_chunk_size() {
+                    break;
+                }
+                eof = fill_buffer(&mut reader, &mut input, chunker.max_chunk_size() - buffer.len()).await?;
+            }
+            // An empty buffer means the input is exhausted, so whatever is left in the batch goes
+            // out now; input that ends exactly on a chunk boundary reaches this with a full batch
+            if batch.len() >= self.write_concurrency || (buffer.is_empty() && !batch.is_empty()) {
+                let batch = std::mem::take(&mut batch);
+                self.ensure_free_space(batch.iter().map(|c| c.size as u64).sum())?;
+                in_flight.extend(&batch.iter().map(|c| c.id.clone()).collect::<Vec<_>>());
+                chunk_ids.extend(self.store_chunks(batch, None, &budget).await?);
+            }
+            if buffer.is_empty() {
+                break;
+            }
+
+            let chunk = chunker.next_chunk(&buffer);
+            buffer.drain(..chunk.size);
+            if !whole_file_compressed {
+                chunk_sizes.push(chunk.size as u64);
+            }
+            let chunk = if per_chunk {
+                let (chunk, compressed, unpadded) = self.processed_chunk(&chunk, aad, per_chunk_keys, level, encrypt).await?;
+                chunk_compressed.push(compressed);
+                chunk_unpadded_sizes.extend(unpadded);
+                chunk
+            } else {
+                chunk
+            };
+            hasher.update(&chunk.data);
+            size += chunk.size as u64;
+            chunk_checksums.push(chunk.checksum.clone());
+            batch.push(chunk);
+        }
+
+        // Mirrors `prepare_file`: no chunks means nothing to decode, and raw chunks decode to themselves
+        let pipeline = if whole_file_compressed {
+            vec![PipelineStage::Compress]
+        } else if per_chunk && !chunk_ids.is_empty() {
+            self.configured_stages(compress, encrypt)
+        } else {
+            Vec::new()
+        };
+        let compressed = pipeline.contains(&PipelineStage::Compress) && (whole_file_compressed || chunk_compressed.contains(&true));
+        let now = Utc::now();
+        let metadata = FileMetadata {
+            id,
+            name: name.to_string(),
+            size,
+            original_size,
+            stored_size: size,
+            created_at: target.map_or(now, |existing| existing.created_at),
+            modified_at: now,
+            checksum: format!("{:x}", hasher.finalize()),
+            content_checksum: format!("{:x}", content_hasher.finalize()),
+            compression_ratio: if size == 0 { 1.0 } else { original_size as f64 / size as f64 },
+            file_type,
+            chunk_ids,
+            pipeline,
+            chunk_compressed,
+            chunk_sizes,
+            chunk_size: chunker.chunk_size(),
+            id_bound: true,
+            compression_level: level.filter(|_| compressed && self.compression.as_ref().is_some_and(|c| c.algorithm().uses_level())),
+            compression_algorithm: self.compression.as_ref().map(|c| c.algorithm()).filter(|_| compressed),
+            per_chunk_keys,
+            chunk_unpadded_sizes,
+            chunk_checksums,
+            keep_plaintext: !encrypt,
+            tags: target.map(|existing| existing.tags.clone()).unwrap_or_default(),
+        };
+
+        // The upload was only known to be a duplicate once it had been read, so drop what it wrote
+        if let Some(identical) = existing.as_ref().filter(|e| self.idempotent_uploads && e.content_checksum == metadata.content_checksum) {
+            drop(in_flight);
+            let _guard = self.chunk_gc_lock.write().await;
+            self.remove_replaced_chunks(&metadata, identical).await?;
+            return Ok(identical.clone());
+        }
+
+        let validation = ValidationManager::new(self.base_path.clone());
+        validation.validate_file(&metadata).await?;
+
+        self.swap_metadata(&metadata).await?;
+        match target {
+            Some(existing) => {
+                let _guard = self.chunk_gc_lock.write().await;
+                self.invalidate_caches(&id).await;
+                if self.auto_gc {
+                    self.remove_replaced_chunks(existing, &metadata).await?;
+                }
+            }
+            None => {
+                self.update_name_index(name, &id).await?;
+                if self.versioning {
+                    self.record_version(&metadata, existing.map(|existing| existing.id)).await?;
+                }
+            }
+        }
+
+        info!(id = %metadata.id, size = metadata.original_size, "Stored streamed file");
+        Ok(metadata)
+    }
+
+    /// Adopts an existing file by hard-linking it into the store as a single chunk, so
+    /// its bytes aren't duplicated. When linking fails, e.g. because the file is on another
+    /// filesystem, it is copied in instead. Nothing is kept when a chunk with the same
+    /// content is already stored, and an empty file is stored without chunks, like
+    /// `store_file` would. Ingested files are stored uncompressed and unencrypted. A
+    /// linked chunk shares the source's bytes, so editing the source in place corrupts
+    /// the stored file until it is rewritten.
+    pub async fn ingest_path(&self, path: &Path) -> Result<FileMetadata> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| AppError::Storage(StorageError::InvalidInput(format!("Cannot ingest {}: no usable file name", path.display()))))?;
+
+        // The chunk is named after its checksum, so it is placed aside and renamed in once hashed
+        let tmp_path = self.chunks_path.join(format!("{}.tmp", Uuid::new_v4()));
+        let linked = fs::hard_link(path, &tmp_path).await.is_ok();
+        if !linked {
+            let source_len = fs::metadata(path).await.map_err(|e| AppError::Storage(StorageError::Io(e)))?.len();
+            self.ensure_free_space(source_len)?;
+        }
+        let hashed = if linked { hash_file(&tmp_path, None).await } else { hash_file(path, Some(&tmp_path)).await };
+        let (checksum, len, prefix) = match hashed {
+            Ok(hashed) => hashed,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_path).await;
+                return Err(e);
+            }
+        };
+        if len == 0 {
+            let _ = fs::remove_file(&tmp_path).await;
+            self.ensure_not_empty(name, &[])?;
+        }
+
+        let id = FileId::new();
+        let chunk_id = ChunkId(checksum.clone());
+        let chunk_path = self.get_chunk_path(&chunk_id);
+        let _in_flight = self.in_flight_chunks.track(vec![chunk_id.clone()]);
+        if len > 0 {
+            let _guard = self.chunk_gc_lock.read().await;
+            let exists = fs::try_exists(&chunk_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+            let placed = if exists { fs::remove_file(&tmp_path).await } else { fs::rename(&tmp_path, &chunk_path).await };
+            placed.map_err(|e| AppError::Storage(StorageError::Io(e)))?;
+        }
+        let (chunk_ids, chunk_sizes, chunk_checksums) = if len == 0 {
+            (Vec::new(), Vec::new(), Vec::new())
+        } else {
+            (vec![chunk_id], vec![len], vec![checksum.clone()])
+        };
+
+        let now = Utc::now();
+        let metadata = FileMetadata {
+            id,
+            name: name.to_string(),
+            size: len,
+            original_size: len,
+            stored_size: len,
+            created_at: now,
+            modified_at: now,
+            checksum: checksum.clone(),
+            content_checksum: checksum.clone(),
+            compression_ratio: 1.0,
+            file_type: FileTypeDetector::detect_with_fallback(&prefix, &self.unknown_file_type),
+            chunk_ids,
+            pipeline: Vec::new(),
+            chunk_compressed: Vec::new(),
+            chunk_sizes,
+            chunk_size: if len == 0 { self.chunker.chunk_size() } else { len as usize },
+            id_bound: false,
+            compression_level: None,
+            compression_algorithm: None,
+            per_chunk_keys: false,
+            chunk_unpadded_sizes: Vec::new(),
+            chunk_checksums,
+            keep_plaintext: false,
+            tags: HashMap::new(),
+        };
+
+        let validation = ValidationManager::new(self.base_path.clone());
+        validation.validate_file(&metadata).await?;
+
+        let previous = if self.versioning { self.lookup_name(name).await? } else { None };
+        self.swap_metadata(&metadata).await?;
+        self.update_name_index(name, &id).await?;
+        if self.versioning {
+            self.record_version(&metadata, previous).await?;
+        }
+
+        info!(id = %metadata.id, path = %path.display(), "Ingested file");
+        Ok(metadata)
+    }
+
+    /// Metadata of every stored file. Metadata files that can't be read or parsed are
+    /// logged and skipped, so one corrupt file doesn't hide the rest.
+    pub async fn list_files(&self) -> Result<Vec<FileMetadata>> {
+        let (files, unreadable) = self.list_files_with_errors().await?;
+        for entry in unreadable {
+            warn!(path = %entry.path.display(), error = %entry.error, "Skipping unreadable metadata");
+        }
+        Ok(files)
+    }
+
+    /// Like `list_files`, but also returns the metadata files that were skipped.
+    pub async fn list_files_with_errors(&self) -> Result<(Vec<FileMetadata>, Vec<UnreadableMetadata>)> {
+        let metadata_dir = self.base_path.join("metadata");
+        let mut files = Vec::new();
+        let mut unreadable = Vec::new();
+
+        let mut entries = fs::read_dir(&metadata_dir).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+        while let Some(entry) = entries.next_entry().await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))? {
+            if entry.file_type().await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?.is_file() {
+                if let Some(ext) = entry.path().extension() {
+                    if ext == "json" {
+                        let parsed = match fs::read_to_string(entry.path()).await {
+                            Ok(metadata_content) => serde_json::from_str::<FileMetadata>(&metadata_content)
+                                .map_err(|e| format!("Failed to parse metadata: {}", e)),
+                            Err(e) => Err(e.to_string()),
+                        };
+                        match parsed {
+                            Ok(metadata) => files.push(metadata),
+                            Err(error) => unreadable.push(UnreadableMetadata { path: entry.path(), error }),
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok((files, unreadable))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for DiskStorage {
+    #[instrument(skip(self, data), fields(size = data.len()))]
+    async fn store_file(&self, name: &str, data: &[u8]) -> Result<FileMetadata> {
+        self.store_tagged(name, data, &HashMap::new(), None).await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_file(&self, id: &FileId) -> Result<Vec<u8>> {
+        self.load_file(id, None).await
+    }
+
+    #[instrument(skip(self))]
+    async fn delete_file(&self, id: &FileId) -> Result<()> {
+        let metadata_path = self.get_metadata_path(id);
+
+        // Check if file exists
+        if !metadata_path.exists() {
+            return Err(AppError::Storage(StorageError::NotFound(id.to_string())));
+        }
+
+        // Read metadata to get chunk information
+        let metadata_content = fs::read_to_string(&metadata_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+        let metadata: FileMetadata = serde_json::from_str(&metadata_content)
+            .map_err(|e| StorageError::IntegrityError(format!("Failed to parse metadata: {}", e)))?;
+
+        let _guard = self.chunk_gc_lock.write().await;
+
+        if !self.auto_gc {
+            fs::remove_file(&metadata_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+            self.invalidate_caches(id).await;
+            self.unpin_file(id).await;
+            self.record_deletion(&metadata).await?;
+            info!("Deleted file");
+            return Ok(());
+        }
+
+        // Delete chunks that aren't used by other files or by an upload still in flight
+        for chunk_id in &metadata.chunk_ids {
+            if !self.in_flight_chunks.contains(chunk_id) && !self.is_chunk_used_by_others(chunk_id, id).await? {
+                let chunk_path = self.get_chunk_path(chunk_id);
+                if chunk_path.exists() {
+                    if let Err(e) = fs::remove_file(&chunk_path).await {
+                        eprintln!("Failed to delete chunk {}: {}", chunk_id.0, e);
+                    }
+                }
+            }
+        }
+
+        // Delete metadata file
+        fs::remove_file(&metadata_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+
+        // Clean up any orphaned chunks
+        self.cleanup_orphaned_chunks().await?;
+
+        self.invalidate_caches(id).await;
+        self.unpin_file(id).await;
+        self.record_deletion(&metadata).await?;
+
+        info!("Deleted file");
+        Ok(())
+    }
+}
+
+// Reads `from`, copying it to `copy_to` when given, and returns its checksum, its length,
+// and the first `DECISION_PREFIX_SIZE` bytes for type detection
+async fn hash_file(from: &Path, copy_to: Option<&Path>) -> Result<(String, u64, Vec<u8>)> {
+    let mut source = fs::File::open(from).await.map_err(|e| AppError::Storage(StorageError::Io(e)))?;
+    let mut target = match copy_to {
+        Some(to) => Some(fs::File::create(to).await.map_err(|e| AppError::Storage(StorageError::Io(e)))?),
+        None => None,
+    };
+    let mut hasher = Sha256::new();
+    let mut prefix = Vec::new();
+    let mut len = 0u64;
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = source.read(&mut buffer).await.map_err(|e| AppError::Storage(StorageError::Io(e)))?;
+        if read == 0 {
+            break;
+        }
+        let data = &buffer[..read];
+        hasher.update(data);
+        let wanted = DECISION_PREFIX_SIZE.saturating_sub(prefix.len()).min(read);
+        prefix.extend_from_slice(&data[..wanted]);
+        if let Some(target) = &mut target {
+            target.write_all(data).await.map_err(|e| AppError::Storage(StorageError::Io(e)))?;
+        }
+        len += read as u64;
+    }
+    if let Some(target) = target {
+        target.sync_all().await.map_err(|e| AppError::Storage(StorageError::Io(e)))?;
+    }
+    Ok((format!("{:x}", hasher.finalize()), len, prefix))
+}
+
+// Media formats are compressed already, so only documents and unrecognised data are worth compressing
+fn is_compressible_type(file_type: &FileType) -> bool {
+    matches!(file_type, FileType::Document(_) | FileType::Unknown)
+}
+
+// Reads until `buffer` holds `len` bytes or the reader runs out; returns whether it ran out
+async fn fill_buffer<R: AsyncRead + Unpin>(reader: &mut R, buffer: &mut Vec<u8>, len: usize) -> Result<bool> {
+    let wanted = len.saturating_sub(buffer.len());
+    let read = (&mut *reader)
+        .take(wanted as u64)
+        .read_to_end(buffer)
+        .await
+        .map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
+    Ok(read < wanted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FixedSpace(u64);
+
+    impl DiskSpaceProbe for FixedSpace {
+        fn available_space(&self, _path: &Path) -> Result<u64> {
+            Ok(self.0)
+        }
+    }
+
+    fn text(len: usize) -> Vec<u8> {
+        b"the quick brown fox jumps over the lazy dog\n".iter().copied().cycle().take(len).collect()
+    }
+
+    // Letters in no particular order, which compress to a few chunks rather than one
+    fn varied_text(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491u32;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                b'a' + (state >> 16) as u8 % 26
+            })
+            .collect()
+    }
+
+    // Contents of every file under `dir`, chunks and metadata alike
+    fn files_under(dir: &Path) -> Vec<Vec<u8>> {
+        let mut contents = Vec::new();
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                contents.extend(files_under(&path));
+            } else {
+                contents.push(std::fs::read(&path).unwrap());
+            }
+        }
+        contents
+    }
+
+    #[tokio::test]
+    async fn lazy_recompress_migrates_gzip_files_to_zstd() {
+        let dir = tempfile::tempdir().unwrap();
+        let gzip = DiskStorage::new(dir.path()).await.unwrap().with_compression(true);
+        let data = text(100_000);
+        let stored = gzip.store_file("notes.txt", &data).await.unwrap();
+        assert_eq!(stored.compression_algorithm, Some(CompressionAlgorithm::Gzip));
+
+        let zstd = DiskStorage::new(dir.path())
+            .await
+            .unwrap()
+            .with_compression(true)
+            .with_compression_algorithm(CompressionAlgorithm::Zstd)
+            .with_lazy_recompress(true);
+        assert_eq!(zstd.get_file(&stored.id).await.unwrap(), data);
+
+        let migrated = zstd.get_metadata(&stored.id).await.unwrap();
+        assert_eq!(migrated.compression_algorithm, Some(CompressionAlgorithm::Zstd));
+        assert_eq!(zstd.get_file(&stored.id).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn lazy_recompress_leaves_current_files_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_compression(true).with_lazy_recompress(true);
+        let stored = storage.store_file("notes.txt", &text(100_000)).await.unwrap();
+
+        storage.get_file(&stored.id).await.unwrap();
+        assert_eq!(storage.get_metadata(&stored.id).await.unwrap().modified_at, stored.modified_at);
+    }
+
+    #[tokio::test]
+    async fn lazy_recompress_applies_a_new_level() {
+        let dir = tempfile::tempdir().unwrap();
+        let fast = DiskStorage::new(dir.path()).await.unwrap().with_compression(true).with_compression_level(1);
+        let stored = fast.store_file("notes.txt", &text(100_000)).await.unwrap();
+        assert_eq!(stored.compression_level, Some(1));
+
+        let best = DiskStorage::new(dir.path())
+            .await
+            .unwrap()
+            .with_compression(true)
+            .with_compression_level(9)
+            .with_lazy_recompress(true);
+        best.get_file(&stored.id).await.unwrap();
+        assert_eq!(best.get_metadata(&stored.id).await.unwrap().compression_level, Some(9));
+    }
+
+    #[tokio::test]
+    async fn min_free_space_rejects_writes_that_would_cross_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path())
+            .await
+            .unwrap()
+            .with_min_free_space(1000)
+            .with_disk_space_probe(FixedSpace(1500));
+
+        storage.store_file("small.txt", &text(400)).await.unwrap();
+        let err = storage.store_file("large.txt", &text(600)).await.unwrap_err();
+        assert!(matches!(err, AppError::Storage(StorageError::InsufficientSpace { available: 1500, required: 1600 })));
+    }
+
+    #[tokio::test]
+    async fn media_files_are_encrypted_but_not_compressed() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_compression(true).with_encryption([7; 32]);
+        let mut image = b"\x89PNG\r\n\x1a\n".to_vec();
+        image.extend(text(50_000));
+
+        let stored = storage.store_file("photo.png", &image).await.unwrap();
+        assert!(matches!(stored.file_type, FileType::Image(_)));
+        assert!(stored.pipeline.contains(&PipelineStage::Encrypt));
+        assert!(!stored.pipeline.contains(&PipelineStage::Compress));
+
+        let plaintext = &image[..64];
+        assert!(!files_under(dir.path()).iter().any(|file| file.windows(plaintext.len()).any(|window| window == plaintext)));
+        assert_eq!(storage.get_file(&stored.id).await.unwrap(), image);
+    }
+
+    #[tokio::test]
+    async fn adaptive_compression_records_the_level_it_settled_on() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path())
+            .await
+            .unwrap()
+            .with_compression(true)
+            .with_compression_level(9)
+            .with_adaptive_compression(AdaptiveCompression { sample_files: 2, min_throughput: f64::INFINITY });
+
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            let stored = storage.store_file(name, &text(100_000)).await.unwrap();
+            assert_eq!(stored.compression_level, Some(1));
+        }
+        assert_eq!(storage.compression.as_ref().unwrap().tuned_level(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn ingested_file_is_linked_to_the_source() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("report.txt");
+        let data = text(10_000);
+        std::fs::write(&source, &data).unwrap();
+
+        let storage = DiskStorage::new(dir.path().join("store")).await.unwrap();
+        let ingested = storage.ingest_path(&source).await.unwrap();
+        assert_eq!(ingested.name, "report.txt");
+        assert_eq!(ingested.original_size, data.len() as u64);
+        assert_eq!(ingested.content_checksum, DiskStorage::calculate_checksum(&data));
+
+        let chunk = std::fs::metadata(storage.get_chunk_path(&ingested.chunk_ids[0])).unwrap();
+        let source = std::fs::metadata(&source).unwrap();
+        assert_eq!(chunk.ino(), source.ino());
+        assert_eq!(chunk.nlink(), 2);
+        assert_eq!(storage.get_file(&ingested.id).await.unwrap(), data);
+        assert_eq!(storage.lookup_name("report.txt").await.unwrap(), Some(ingested.id));
+    }
+
+    #[tokio::test]
+    async fn ingested_empty_file_has_no_chunks_and_is_left_alone_by_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("empty.txt");
+        std::fs::write(&source, b"").unwrap();
+
+        let storage = DiskStorage::new(dir.path().join("store")).await.unwrap();
+        let ingested = storage.ingest_path(&source).await.unwrap();
+        assert!(ingested.chunk_ids.is_empty());
+        assert_eq!(ingested.chunk_size, storage.store_file("stored.txt", b"").await.unwrap().chunk_size);
+        assert_eq!(storage.get_file(&ingested.id).await.unwrap(), b"");
+        assert_eq!(storage.compact().await.unwrap().files_compacted, 0);
+    }
+
+    async fn stored_both_ways(storage: &DiskStorage, data: &[u8]) -> (FileMetadata, FileMetadata) {
+        let in_memory = storage.store_file("memory.txt", data).await.unwrap();
+        let reader = tokio::io::BufReader::with_capacity(10_000, std::io::Cursor::new(data.to_vec()));
+        let streamed = storage.store_file_stream("stream.txt", reader).await.unwrap();
+        assert_eq!(storage.get_file(&streamed.id).await.unwrap(), data);
+        (in_memory, streamed)
+    }
+
+    #[tokio::test]
+    async fn streamed_files_are_stored_like_in_memory_ones() {
+        let data = varied_text(2_000_000);
+        for algorithm in [CompressionAlgorithm::Gzip, CompressionAlgorithm::Zstd, CompressionAlgorithm::Lz4] {
+            for chunk_compression in [false, true] {
+                let dir = tempfile::tempdir().unwrap();
+                let storage = DiskStorage::new(dir.path())
+                    .await
+                    .unwrap()
+                    .with_compression(true)
+                    .with_compression_algorithm(algorithm)
+                    .with_chunk_compression(chunk_compression);
+                let (in_memory, streamed) = stored_both_ways(&storage, &data).await;
+                assert!(in_memory.chunk_ids.len() > 1);
+
+                assert_eq!(streamed.checksum, in_memory.checksum, "{:?}, chunk compression {}", algorithm, chunk_compression);
+                assert_eq!(streamed.content_checksum, in_memory.content_checksum);
+                assert_eq!(streamed.size, in_memory.size);
+                assert_eq!(streamed.original_size, in_memory.original_size);
+                assert_eq!(streamed.file_type, in_memory.file_type);
+                assert_eq!(streamed.pipeline, in_memory.pipeline);
+                assert_eq!(streamed.chunk_ids, in_memory.chunk_ids);
+                assert_eq!(streamed.chunk_compressed, in_memory.chunk_compressed);
+                assert_eq!(streamed.chunk_sizes, in_memory.chunk_sizes);
+                assert_eq!(streamed.chunk_size, in_memory.chunk_size);
+                assert_eq!(streamed.compression_level, in_memory.compression_level);
+                assert_eq!(streamed.compression_algorithm, in_memory.compression_algorithm);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn streamed_files_ending_on_a_chunk_boundary_keep_their_last_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let chunk_size = crate::chunk::DEFAULT_CHUNK_SIZE;
+        for len in [chunk_size, 3 * chunk_size] {
+            let data = varied_text(len);
+            let (in_memory, streamed) = stored_both_ways(&storage, &data).await;
+            assert_eq!(streamed.chunk_ids, in_memory.chunk_ids);
+            assert_eq!(streamed.chunk_ids.len(), len / chunk_size);
+        }
+    }
+
+    #[tokio::test]
+    async fn streamed_encrypted_files_are_laid_out_like_in_memory_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_compression(true).with_encryption([7; 32]);
+        let (in_memory, streamed) = stored_both_ways(&storage, &text(3_000_000)).await;
+
+        // Ciphertext differs between writes, so only the layout can match
+        assert_eq!(streamed.content_checksum, in_memory.content_checksum);
+        assert_eq!(streamed.pipeline, in_memory.pipeline);
+        assert_eq!(streamed.chunk_compressed, in_memory.chunk_compressed);
+        assert_eq!(streamed.chunk_sizes, in_memory.chunk_sizes);
+        assert_eq!(streamed.chunk_ids.len(), in_memory.chunk_ids.len());
+        assert_eq!(streamed.compression_level, in_memory.compression_level);
+    }
+
+    #[tokio::test]
+    async fn range_reads_span_chunk_boundaries() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_chunking(ChunkManager::new(1000));
+        let data = varied_text(3500);
+        let stored = storage.store_file("notes.txt", &data).await.unwrap();
+        assert_eq!(stored.chunk_ids.len(), 4);
+
+        assert_eq!(storage.get_file_range(&stored.id, 900, 2100).await.unwrap(), &data[900..=2100]);
+        assert_eq!(storage.get_file_range(&stored.id, 1200, 1300).await.unwrap(), &data[1200..=1300]);
+        assert_eq!(storage.get_file_range(&stored.id, 3000, 10_000).await.unwrap(), &data[3000..]);
+        assert!(storage.get_file_range(&stored.id, 20, 10).await.is_err());
+        assert!(storage.get_file_range(&stored.id, 3500, 3600).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn range_reads_of_compressed_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path())
+            .await
+            .unwrap()
+            .with_chunking(ChunkManager::new(1000))
+            .with_compression(true)
+            .with_chunk_compression(true);
+        let data = text(3500);
+        let stored = storage.store_file("notes.txt", &data).await.unwrap();
+        assert!(stored.chunk_compressed.contains(&true));
+
+        assert_eq!(storage.get_file_range(&stored.id, 999, 1000).await.unwrap(), &data[999..=1000]);
+        assert_eq!(storage.get_file_range(&stored.id, 2001, 2998).await.unwrap(), &data[2001..=2998]);
+    }
+
+    #[tokio::test]
+    async fn deleting_a_file_frees_its_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let stored = storage.store_file("notes.txt", &text(100)).await.unwrap();
+
+        storage.delete_file(&stored.id).await.unwrap();
+        assert_eq!(storage.lookup_name("notes.txt").await.unwrap(), None);
+        assert!(storage.search_by_prefix("notes").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn versions_are_read_back_independently() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_versioning(true);
+        let contents: Vec<Vec<u8>> = (1..=3).map(|version| text(1000 * version)).collect();
+        for data in &contents {
+            storage.store_file("notes.txt", data).await.unwrap();
+        }
+
+        let versions = storage.list_versions("notes.txt").await.unwrap();
+        assert_eq!(versions.iter().map(|entry| entry.version).collect::<Vec<_>>(), [1, 2, 3]);
+        for (version, data) in versions.iter().zip(&contents) {
+            assert_eq!(&storage.get_file_version("notes.txt", version.version).await.unwrap(), data);
+        }
+
+        storage.delete_version("notes.txt", 2).await.unwrap();
+        assert_eq!(storage.get_file_version("notes.txt", 1).await.unwrap(), contents[0]);
+        assert_eq!(storage.get_file_version("notes.txt", 3).await.unwrap(), contents[2]);
+    }
+
+    #[tokio::test]
+    async fn deleting_the_newest_version_is_recorded_in_its_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_versioning(true);
+        let first = storage.store_file("notes.txt", &text(1000)).await.unwrap();
+        let second = storage.store_file("notes.txt", &text(2000)).await.unwrap();
+
+        storage.delete_file(&second.id).await.unwrap();
+        let versions = storage.list_versions("notes.txt").await.unwrap();
+        assert_eq!(versions.len(), 2);
+        assert!(versions[0].deleted_at.is_none());
+        assert!(versions[1].deleted_at.is_some());
+        assert_eq!(storage.lookup_name("notes.txt").await.unwrap(), Some(first.id));
+        assert!(matches!(storage.get_file_version("notes.txt", 2).await, Err(AppError::Storage(StorageError::NotFound(_)))));
+
+        storage.delete_file(&first.id).await.unwrap();
+        assert_eq!(storage.lookup_name("notes.txt").await.unwrap(), None);
+        assert!(storage.list_versions("notes.txt").await.unwrap().iter().all(|entry| entry.deleted_at.is_some()));
+    }
+
+    #[tokio::test]
+    async fn cleanup_leaves_chunks_being_written_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let stored = storage.store_file("notes.txt", &text(100)).await.unwrap();
+        let partial = storage.chunks_path.join("ingest.tmp");
+        std::fs::write(&partial, b"partial copy").unwrap();
+
+        storage.delete_file(&stored.id).await.unwrap();
+        assert!(partial.exists());
+    }
+
+    #[tokio::test]
+    async fn gc_keeps_chunks_while_metadata_is_unreadable() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_auto_gc(false);
+        let deleted = storage.store_file("old.txt", &text(100)).await.unwrap();
+        let kept = storage.store_file("kept.txt", &varied_text(100)).await.unwrap();
+        storage.delete_file(&deleted.id).await.unwrap();
+
+        // Stands in for a metadata file that got cut off mid-write
+        let kept_path = storage.get_metadata_path(&kept.id);
+        let kept_json = std::fs::read_to_string(&kept_path).unwrap();
+        std::fs::write(&kept_path, &kept_json[..kept_json.len() / 2]).unwrap();
+        storage.gc().await.unwrap();
+        assert!(storage.get_chunk_path(&kept.chunk_ids[0]).exists());
+        assert!(storage.get_chunk_path(&deleted.chunk_ids[0]).exists());
+
+        std::fs::write(&kept_path, kept_json).unwrap();
+        storage.gc().await.unwrap();
+        assert!(storage.get_chunk_path(&kept.chunk_ids[0]).exists());
+        assert!(!storage.get_chunk_path(&deleted.chunk_ids[0]).exists());
+    }
+
+    #[tokio::test]
+    async fn listing_skips_corrupt_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let stored = storage.store_file("notes.txt", &text(100)).await.unwrap();
+        std::fs::write(storage.metadata_path.join("garbage.json"), b"{not json").unwrap();
+
+        let (files, unreadable) = storage.list_files_with_errors().await.unwrap();
+        assert_eq!(files.iter().map(|file| file.id).collect::<Vec<_>>(), [stored.id]);
+        assert_eq!(unreadable.len(), 1);
+        assert_eq!(storage.list_files().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn corrupted_chunk_fails_on_the_first_attempt() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path())
+            .await
+            .unwrap()
+            .with_retry_config(RetryConfig::new(5, std::time::Duration::from_secs(10)));
+        let stored = storage.store_file("notes.txt", &text(1000)).await.unwrap();
+        let chunk_path = storage.get_chunk_path(&stored.chunk_ids[0]);
+        let mut chunk = std::fs::read(&chunk_path).unwrap();
+        chunk[0] ^= 0xff;
+        std::fs::write(&chunk_path, chunk).unwrap();
+
+        // A retry would sleep for ten seconds first
+        let started = std::time::Instant::now();
+        let err = storage.get_file(&stored.id).await.unwrap_err();
+        assert!(matches!(err, AppError::Storage(StorageError::IntegrityError(_))), "{:?}", err);
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn files_round_trip_under_the_default_pipeline() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_compression(true).with_encryption([7; 32]);
+        let data = text(100_000);
+
+        let stored = storage.store_file("notes.txt", &data).await.unwrap();
+        assert_eq!(stored.pipeline, [PipelineStage::Compress, PipelineStage::Encrypt]);
+        assert_eq!(storage.get_file(&stored.id).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn encrypt_only_pipeline_is_recorded_and_reversed() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path())
+            .await
+            .unwrap()
+            .with_compression(true)
+            .with_encryption([7; 32])
+            .with_pipeline(vec![PipelineStage::Encrypt])
+            .unwrap();
+        let data = text(100_000);
+
+        let stored = storage.store_file("notes.txt", &data).await.unwrap();
+        assert_eq!(stored.pipeline, [PipelineStage::Encrypt]);
+        assert!(stored.stored_size >= data.len() as u64);
+        assert_eq!(storage.get_file(&stored.id).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn persistent_cache_serves_reads_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = text(10_000);
+        let stored = {
+            let storage = DiskStorage::new(dir.path()).await.unwrap().with_persistent_cache(1 << 20).unwrap();
+            let stored = storage.store_file("notes.txt", &data).await.unwrap();
+            storage.get_file(&stored.id).await.unwrap();
+            stored
+        };
+
+        // Without its chunks the file can only come from the cache
+        std::fs::remove_dir_all(dir.path().join("chunks")).unwrap();
+        let restarted = DiskStorage::new(dir.path()).await.unwrap().with_persistent_cache(1 << 20).unwrap();
+        assert_eq!(restarted.get_file(&stored.id).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn deleting_a_file_drops_its_persistent_cache_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_persistent_cache(1 << 20).unwrap();
+        let stored = storage.store_file("notes.txt", &text(10_000)).await.unwrap();
+        storage.get_file(&stored.id).await.unwrap();
+
+        storage.delete_file(&stored.id).await.unwrap();
+        assert!(!dir.path().join("cache").join(stored.id.to_string()).exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn reads_during_updates_see_a_whole_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = std::sync::Arc::new(DiskStorage::new(dir.path()).await.unwrap().with_chunking(ChunkManager::new(1024)));
+        let old = vec![b'a'; 50_000];
+        let new = vec![b'b'; 60_000];
+        let stored = storage.store_file("notes.txt", &old).await.unwrap();
+
+        let writer = {
+            let storage = storage.clone();
+            let (old, new) = (old.clone(), new.clone());
+            tokio::spawn(async move {
+                for round in 0..20 {
+                    let data = if round % 2 == 0 { &new } else { &old };
+                    storage.update_file(&stored.id, data).await.unwrap();
+                }
+            })
+        };
+
+        while !writer.is_finished() {
+            let data = storage.get_file(&stored.id).await.unwrap();
+            assert!(data == old || data == new, "read a torn version of {} bytes", data.len());
+        }
+        writer.await.unwrap();
+        assert_eq!(storage.get_file(&stored.id).await.unwrap(), old);
+    }
+
+    #[tokio::test]
+    async fn chunks_are_only_kept_compressed_where_it_helped() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path())
+            .await
+            .unwrap()
+            .with_compression(true)
+            .with_chunk_compression(true)
+            .with_chunking(ChunkManager::new(4096));
+        let mut state = 0x9e37_79b9u32;
+        let mut data = text(8192);
+        data.extend((0..8192).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }));
+
+        let stored = storage.store_file("mixed.bin", &data).await.unwrap();
+        assert_eq!(stored.chunk_compressed, [true, true, false, false]);
+        assert_eq!(storage.get_file(&stored.id).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn rechunking_replaces_the_chunks_and_keeps_the_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let data = varied_text(3 * 1024 * 1024);
+        let stored = storage.store_file("big.txt", &data).await.unwrap();
+        assert_eq!(stored.chunk_ids.len(), 3);
+
+        let rechunked = storage.rechunk(&stored.id, 256 * 1024).await.unwrap();
+        assert_eq!((rechunked.id, rechunked.name.as_str()), (stored.id, "big.txt"));
+        assert_eq!(rechunked.chunk_ids.len(), 12);
+        assert_eq!(files_under(&dir.path().join("chunks")).len(), 12);
+        assert_eq!(storage.get_file(&stored.id).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn rechunking_to_zero_bytes_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let stored = storage.store_file("notes.txt", &text(1000)).await.unwrap();
+
+        let err = storage.rechunk(&stored.id, 0).await.unwrap_err();
+        assert!(matches!(err, AppError::Storage(StorageError::InvalidInput(_))));
+    }
+
+    #[tokio::test]
+    async fn encryption_audit_lists_the_files_left_in_plaintext() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = DiskStorage::new(dir.path()).await.unwrap();
+        let first = plain.store_file("old.txt", &text(1000)).await.unwrap();
+        let second = plain.store_file("older.txt", &text(2000)).await.unwrap();
+
+        let encrypted = DiskStorage::new(dir.path()).await.unwrap().with_encryption([7; 32]);
+        encrypted.store_file("new.txt", &text(3000)).await.unwrap();
+
+        let mut audit = encrypted.encryption_audit().await.unwrap();
+        audit.plaintext_ids.sort_by_key(|id| id.0);
+        let mut expected = vec![first.id, second.id];
+        expected.sort_by_key(|id| id.0);
+        assert_eq!((audit.encrypted, audit.plaintext), (1, 2));
+        assert_eq!(audit.plaintext_ids, expected);
+    }
+
+    #[tokio::test]
+    async fn range_reads_only_touch_the_chunks_they_overlap() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_chunking(ChunkManager::new(1000));
+        let data = varied_text(4000);
+        let stored = storage.store_file("notes.txt", &data).await.unwrap();
+
+        // A read of any other chunk would fail now
+        for index in [0, 3] {
+            std::fs::remove_file(storage.get_chunk_path(&stored.chunk_ids[index])).unwrap();
+        }
+        assert_eq!(storage.get_file_range(&stored.id, 1500, 2500).await.unwrap(), &data[1500..=2500]);
+        assert!(storage.get_file(&stored.id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unidentifiable_data_is_stored_as_the_configured_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let fallback = FileType::Video(crate::VideoType::Other("video/raw".to_string()));
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_compression(true).with_unknown_file_type(fallback.clone());
+
+        let stored = storage.store_file("capture.raw", &text(50_000)).await.unwrap();
+        assert_eq!(stored.file_type, fallback);
+        assert!(!stored.pipeline.contains(&PipelineStage::Compress));
+    }
+
+    #[tokio::test]
+    async fn empty_files_are_stored_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+
+        let stored = storage.store_file("empty.txt", b"").await.unwrap();
+        assert_eq!(stored.original_size, 0);
+        assert!(storage.get_file(&stored.id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn empty_files_can_be_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_reject_empty_files(true);
+
+        let err = storage.store_file("empty.txt", b"").await.unwrap_err();
+        assert!(matches!(err, AppError::Storage(StorageError::InvalidInput(_))));
+        assert!(storage.list_files().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn chunk_size_follows_the_file_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let video_type = FileType::Video(crate::VideoType::Mp4);
+        let storage = DiskStorage::new(dir.path())
+            .await
+            .unwrap()
+            .with_chunk_size_for(FileType::Unknown, 1000)
+            .unwrap()
+            .with_chunk_size_for(video_type.clone(), 4000)
+            .unwrap();
+        let mut video = b"\0\0\0\x18ftypisom\0\0\0\0isomiso2".to_vec();
+        video.extend(varied_text(8000));
+
+        let notes = storage.store_file("notes.txt", &varied_text(8000)).await.unwrap();
+        let clip = storage.store_file("clip.mp4", &video).await.unwrap();
+        assert_eq!(clip.file_type, video_type);
+        assert_eq!((notes.chunk_size, notes.chunk_ids.len()), (1000, 8));
+        assert_eq!((clip.chunk_size, clip.chunk_ids.len()), (4000, 3));
+        assert_eq!(storage.get_file(&clip.id).await.unwrap(), video);
+    }
+
+    #[tokio::test]
+    async fn zero_chunk_size_for_a_file_type_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let err = storage.with_chunk_size_for(FileType::Unknown, 0).err().unwrap();
+        assert!(matches!(err, AppError::Storage(StorageError::InvalidInput(_))));
+    }
+
+    #[tokio::test]
+    async fn identical_reupload_returns_the_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_idempotent_uploads(true);
+        let data = varied_text(5000);
+        let first = storage.store_file("notes.txt", &data).await.unwrap();
+        let chunks = files_under(&dir.path().join("chunks"));
+
+        let second = storage.store_file("notes.txt", &data).await.unwrap();
+        assert_eq!(second.id, first.id);
+        assert_eq!(second.modified_at, first.modified_at);
+        assert_eq!(files_under(&dir.path().join("chunks")), chunks);
+        assert_eq!(storage.list_files().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reupload_under_another_name_is_a_new_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_idempotent_uploads(true);
+        let data = varied_text(5000);
+        let first = storage.store_file("notes.txt", &data).await.unwrap();
+
+        let second = storage.store_file("copy.txt", &data).await.unwrap();
+        assert_ne!(second.id, first.id);
+    }
+
+    #[tokio::test]
+    async fn chunks_of_deleted_files_linger_until_gc_without_auto_gc() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_auto_gc(false);
+        let kept = storage.store_file("kept.txt", &varied_text(3000)).await.unwrap();
+        let mut deleted = Vec::new();
+        for index in 0..3 {
+            deleted.push(storage.store_file(&format!("gone{}.txt", index), &varied_text(4000 + index)).await.unwrap());
+        }
+
+        for metadata in &deleted {
+            storage.delete_file(&metadata.id).await.unwrap();
+        }
+        assert_eq!(files_under(&dir.path().join("chunks")).len(), 4);
+
+        storage.gc().await.unwrap();
+        assert_eq!(files_under(&dir.path().join("chunks")).len(), 1);
+        assert_eq!(storage.get_file(&kept.id).await.unwrap(), varied_text(3000));
+    }
+
+    #[tokio::test]
+    async fn auto_gc_removes_chunks_on_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let stored = storage.store_file("gone.txt", &varied_text(3000)).await.unwrap();
+
+        storage.delete_file(&stored.id).await.unwrap();
+        assert!(files_under(&dir.path().join("chunks")).is_empty());
+    }
+
+    #[tokio::test]
+    async fn content_checksum_ignores_how_the_file_was_stored() {
+        let data = text(100_000);
+        let mut checksums = Vec::new();
+        for (compress, level) in [(false, 6), (true, 1), (true, 9)] {
+            let dir = tempfile::tempdir().unwrap();
+            let storage = DiskStorage::new(dir.path()).await.unwrap().with_compression(compress).with_compression_level(level);
+            let stored = storage.store_file("notes.txt", &data).await.unwrap();
+            checksums.push((stored.checksum, stored.content_checksum));
+        }
+
+        assert!(checksums.iter().all(|(_, content)| *content == DiskStorage::calculate_checksum(&data)));
+        assert_ne!(checksums[0].0, checksums[1].0);
+    }
+
+    #[tokio::test]
+    async fn compression_report_averages_each_file_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let document = FileType::Document(crate::DocumentType::Other("text/plain".to_string()));
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_compression(true).with_unknown_file_type(document.clone());
+        for index in 0..3 {
+            storage.store_file(&format!("notes{}.txt", index), &text(20_000 + index)).await.unwrap();
+            let mut image = b"\x89PNG\r\n\x1a\n".to_vec();
+            image.extend(varied_text(20_000 + index));
+            storage.store_file(&format!("photo{}.png", index), &image).await.unwrap();
+        }
+
+        let report = storage.compression_report().await.unwrap();
+        let documents = &report.by_type[&document];
+        let images = &report.by_type[&FileType::Image(crate::ImageType::Png)];
+        assert_eq!((documents.files, images.files), (3, 3));
+        assert!(documents.average_ratio > images.average_ratio, "{} vs {}", documents.average_ratio, images.average_ratio);
+    }
+
+    #[tokio::test]
+    async fn cached_chunks_are_not_read_from_disk_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_chunking(ChunkManager::new(1000)).with_chunk_cache(16);
+        let data = varied_text(4000);
+        let stored = storage.store_file("notes.txt", &data).await.unwrap();
+
+        // Warm the first half, then corrupt it on disk so a read from there would fail
+        storage.get_file_range(&stored.id, 0, 1999).await.unwrap();
+        for chunk_id in &stored.chunk_ids[..2] {
+            std::fs::write(storage.get_chunk_path(chunk_id), vec![0; 1000]).unwrap();
+        }
+        assert_eq!(storage.get_file(&stored.id).await.unwrap(), data);
+
+        // The misses were cached on the way
+        std::fs::write(storage.get_chunk_path(&stored.chunk_ids[2]), vec![0; 1000]).unwrap();
+        storage.get_file_range(&stored.id, 2000, 2999).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn zero_capacity_turns_the_chunk_cache_off() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_chunk_cache(16).with_chunk_cache(0);
+        let stored = storage.store_file("notes.txt", &varied_text(4000)).await.unwrap();
+
+        assert_eq!(storage.get_file(&stored.id).await.unwrap(), varied_text(4000));
+        assert!(storage.chunk_cache.is_none());
+    }
+
+    #[tokio::test]
+    async fn compaction_merges_tiny_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiny = DiskStorage::new(dir.path()).await.unwrap().with_chunking(ChunkManager::new(100));
+        let data = varied_text(3000);
+        let stored = tiny.store_file("notes.txt", &data).await.unwrap();
+        let gone = tiny.store_file("gone.txt", &varied_text(50)).await.unwrap();
+        std::fs::remove_file(tiny.get_metadata_path(&gone.id)).unwrap();
+        assert_eq!(files_under(&dir.path().join("chunks")).len(), 31);
+
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_chunking(ChunkManager::new(1000));
+        let report = storage.compact().await.unwrap();
+        assert_eq!((report.files_compacted, report.chunks_removed, report.index_entries_removed), (1, 27, 1));
+        assert_eq!(files_under(&dir.path().join("chunks")).len(), 3);
+        assert_eq!(storage.get_file(&stored.id).await.unwrap(), data);
+        assert_eq!(storage.lookup_name("gone.txt").await.unwrap(), None);
+    }
+
+    // A file stored under `policy` whose only chunk has since gone missing
+    async fn file_missing_its_chunk(dir: &Path, policy: OrphanedMetadataPolicy) -> (DiskStorage, FileMetadata) {
+        let storage = DiskStorage::new(dir).await.unwrap().with_orphaned_metadata_policy(policy);
+        let stored = storage.store_file("notes.txt", &text(1000)).await.unwrap();
+        std::fs::remove_file(storage.get_chunk_path(&stored.chunk_ids[0])).unwrap();
+        (storage, stored)
+    }
+
+    #[tokio::test]
+    async fn report_policy_leaves_orphaned_metadata_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let (storage, stored) = file_missing_its_chunk(dir.path(), OrphanedMetadataPolicy::Report).await;
+
+        assert_eq!(storage.scan().await.unwrap().orphaned, [stored.id]);
+        assert!(storage.get_metadata_path(&stored.id).exists());
+        assert_eq!(storage.lookup_name("notes.txt").await.unwrap(), Some(stored.id));
+    }
+
+    #[tokio::test]
+    async fn quarantine_policy_moves_orphaned_metadata_aside() {
+        let dir = tempfile::tempdir().unwrap();
+        let (storage, stored) = file_missing_its_chunk(dir.path(), OrphanedMetadataPolicy::Quarantine).await;
+
+        assert_eq!(storage.scan().await.unwrap().orphaned, [stored.id]);
+        assert!(!storage.get_metadata_path(&stored.id).exists());
+        assert!(dir.path().join("corrupt").join(format!("{}.json", stored.id)).exists());
+        assert_eq!(storage.lookup_name("notes.txt").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn delete_policy_removes_orphaned_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let (storage, stored) = file_missing_its_chunk(dir.path(), OrphanedMetadataPolicy::Delete).await;
+
+        assert_eq!(storage.scan().await.unwrap().orphaned, [stored.id]);
+        assert!(!storage.get_metadata_path(&stored.id).exists());
+        assert!(!dir.path().join("corrupt").exists());
+        assert_eq!(storage.lookup_name("notes.txt").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn pinned_files_stay_cached_while_others_are_evicted() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_cache(4);
+        let hot = storage.store_file("hot.txt", &text(1000)).await.unwrap();
+        storage.pin_file(&hot.id).await.unwrap();
+
+        for index in 0..10 {
+            let cold = storage.store_file(&format!("cold{}.txt", index), &varied_text(1000 + index)).await.unwrap();
+            storage.get_file(&cold.id).await.unwrap();
+        }
+
+        // Served from the cache, so the overwritten chunk goes unnoticed
+        std::fs::write(storage.get_chunk_path(&hot.chunk_ids[0]), b"overwritten").unwrap();
+        assert_eq!(storage.get_file(&hot.id).await.unwrap(), text(1000));
+    }
+
+    #[tokio::test]
+    async fn pinning_needs_a_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let stored = storage.store_file("hot.txt", &text(1000)).await.unwrap();
+
+        assert!(storage.pin_file(&stored.id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn uploading_an_existing_name_overwrites_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_overwrite_by_name(true);
+        let first = storage.store_file("foo.txt", b"first draft").await.unwrap();
+
+        let second = storage.store_file("foo.txt", b"second draft").await.unwrap();
+        assert_eq!(second.id, first.id);
+        assert_eq!(storage.list_files().await.unwrap().len(), 1);
+        assert_eq!(storage.get_file(&first.id).await.unwrap(), b"second draft");
+    }
+
+    #[tokio::test]
+    async fn uploading_an_existing_name_adds_a_file_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let first = storage.store_file("foo.txt", b"first draft").await.unwrap();
+
+        let second = storage.store_file("foo.txt", b"second draft").await.unwrap();
+        assert_ne!(second.id, first.id);
+        assert_eq!(storage.lookup_name("foo.txt").await.unwrap(), Some(second.id));
+    }
+
+    #[tokio::test]
+    async fn passphrase_keyed_stores_reopen_with_the_same_passphrase() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = text(1000);
+        let stored = {
+            let storage = DiskStorage::new(dir.path()).await.unwrap().with_passphrase("correct horse").unwrap();
+            storage.store_file("notes.txt", &data).await.unwrap()
+        };
+
+        let reopened = DiskStorage::new(dir.path()).await.unwrap().with_passphrase("correct horse").unwrap();
+        assert_eq!(reopened.get_file(&stored.id).await.unwrap(), data);
+        let wrong = DiskStorage::new(dir.path()).await.unwrap().with_passphrase("wrong horse").unwrap();
+        assert!(wrong.get_file(&stored.id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn cleanup_spares_chunks_of_uploads_in_flight() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        // Written, but the upload hasn't got to its metadata yet
+        let chunk_id = ChunkId(DiskStorage::calculate_checksum(b"half an upload"));
+        std::fs::write(storage.get_chunk_path(&chunk_id), b"half an upload").unwrap();
+
+        let in_flight = storage.in_flight_chunks.track(vec![chunk_id.clone()]);
+        storage.gc().await.unwrap();
+        assert!(storage.get_chunk_path(&chunk_id).exists());
+
+        drop(in_flight);
+        storage.gc().await.unwrap();
+        assert!(!storage.get_chunk_path(&chunk_id).exists());
+    }
+
+    #[tokio::test]
+    async fn cleanup_spares_chunks_younger_than_the_grace_period() {
+        let dir = tempfile::tempdir().unwrap();
+        let chunk_id = ChunkId(DiskStorage::calculate_checksum(b"from another process"));
+        let patient = DiskStorage::new(dir.path()).await.unwrap().with_orphan_grace_period(std::time::Duration::from_secs(3600));
+        std::fs::write(patient.get_chunk_path(&chunk_id), b"from another process").unwrap();
+
+        patient.gc().await.unwrap();
+        assert!(patient.get_chunk_path(&chunk_id).exists());
+
+        let eager = DiskStorage::new(dir.path()).await.unwrap();
+        eager.gc().await.unwrap();
+        assert!(!eager.get_chunk_path(&chunk_id).exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_cleanup_does_not_break_uploads() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = std::sync::Arc::new(DiskStorage::new(dir.path()).await.unwrap().with_chunking(ChunkManager::new(1000)));
+
+        let uploads = {
+            let storage = storage.clone();
+            tokio::spawn(async move {
+                let mut stored = Vec::new();
+                for index in 0..20 {
+                    stored.push(storage.store_file(&format!("notes{}.txt", index), &varied_text(5000 + index)).await.unwrap());
+                }
+                stored
+            })
+        };
+        while !uploads.is_finished() {
+            storage.gc().await.unwrap();
+        }
+
+        for (index, metadata) in uploads.await.unwrap().into_iter().enumerate() {
+            assert_eq!(storage.get_file(&metadata.id).await.unwrap(), varied_text(5000 + index));
+        }
+    }
+
+    #[tokio::test]
+    async fn disabled_encryption_stores_plaintext_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path())
+            .await
+            .unwrap()
+            .with_chunking(ChunkManager::new(1000))
+            .with_encryption([7; 32])
+            .with_encryption_enabled(false);
+        let data = varied_text(2500);
+
+        let stored = storage.store_file("notes.txt", &data).await.unwrap();
+        assert!(stored.pipeline.is_empty());
+        for (chunk_id, plaintext) in stored.chunk_ids.iter().zip(data.chunks(1000)) {
+            assert_eq!(std::fs::read(storage.get_chunk_path(chunk_id)).unwrap(), plaintext);
+        }
+        assert_eq!(storage.get_file(&stored.id).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn files_written_while_encryption_was_off_read_back_once_it_is_on() {
+        let dir = tempfile::tempdir().unwrap();
+        let off = DiskStorage::new(dir.path()).await.unwrap().with_encryption([7; 32]).with_encryption_enabled(false);
+        let stored = off.store_file("notes.txt", &text(1000)).await.unwrap();
+
+        let on = DiskStorage::new(dir.path()).await.unwrap().with_encryption([7; 32]);
+        assert_eq!(on.get_file(&stored.id).await.unwrap(), text(1000));
+    }
+
+    #[tokio::test]
+    async fn encrypted_files_are_decrypted_after_encryption_is_turned_off() {
+        let dir = tempfile::tempdir().unwrap();
+        let on = DiskStorage::new(dir.path()).await.unwrap().with_encryption([7; 32]);
+        let stored = on.store_file("notes.txt", &varied_text(2600)).await.unwrap();
+        assert!(stored.pipeline.contains(&PipelineStage::Encrypt));
+
+        let off = DiskStorage::new(dir.path()).await.unwrap().with_encryption([7; 32]).with_encryption_enabled(false);
+        assert_eq!(off.get_file(&stored.id).await.unwrap(), varied_text(2600));
+
+        let keyless = DiskStorage::new(dir.path()).await.unwrap();
+        assert!(keyless.get_file(&stored.id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn compressed_files_are_decompressed_after_compression_is_turned_off() {
+        let dir = tempfile::tempdir().unwrap();
+        for chunk_compression in [false, true] {
+            let on = DiskStorage::new(dir.path()).await.unwrap().with_compression(true).with_chunk_compression(chunk_compression);
+            let stored = on.store_file("notes.txt", &text(3200)).await.unwrap();
+            assert!(stored.pipeline.contains(&PipelineStage::Compress));
+            assert!(stored.size < stored.original_size);
+
+            let off = DiskStorage::new(dir.path()).await.unwrap().with_compression(false);
+            assert_eq!(off.get_file(&stored.id).await.unwrap(), text(3200), "chunk compression {}", chunk_compression);
+            let unconfigured = DiskStorage::new(dir.path()).await.unwrap();
+            assert_eq!(unconfigured.get_file(&stored.id).await.unwrap(), text(3200));
+        }
+    }
+
+    #[tokio::test]
+    async fn exists_many_reports_each_id_and_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let stored = storage.store_file("kept.txt", b"still here").await.unwrap();
+        let missing = FileId::new();
+
+        let found = storage.exists_many(&[stored.id, missing]).await.unwrap();
+        assert_eq!(found.get(&stored.id), Some(&true));
+        assert_eq!(found.get(&missing), Some(&false));
+
+        let present = DiskStorage::calculate_checksum(b"still here");
+        let absent = DiskStorage::calculate_checksum(b"never stored");
+        let found = storage.exists_many_by_checksum(&[present.clone(), absent.clone()]).await.unwrap();
+        assert_eq!(found.get(&present), Some(&true));
+        assert_eq!(found.get(&absent), Some(&false));
+    }
+
+    #[tokio::test]
+    async fn chunks_swapped_between_files_fail_to_decrypt() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_encryption([7; 32]);
+        let first = storage.store_file("first.txt", b"contents of the first file").await.unwrap();
+        let second = storage.store_file("second.txt", b"contents of the other file").await.unwrap();
+        assert!(first.id_bound && second.id_bound);
+
+        let first_chunk = storage.get_chunk_path(&first.chunk_ids[0]);
+        let second_chunk = storage.get_chunk_path(&second.chunk_ids[0]);
+        let first_bytes = std::fs::read(&first_chunk).unwrap();
+        std::fs::write(&first_chunk, std::fs::read(&second_chunk).unwrap()).unwrap();
+        std::fs::write(&second_chunk, first_bytes).unwrap();
+
+        for id in [first.id, second.id] {
+            let err = storage.get_file(&id).await.unwrap_err();
+            assert!(matches!(err, AppError::Storage(StorageError::IntegrityError(_))), "{:?}", err);
+        }
+    }
+
+    #[tokio::test]
+    async fn bytes_appended_to_a_plaintext_chunk_fail_the_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let stored = storage.store_file("notes.txt", &text(1000)).await.unwrap();
+
+        let chunk_path = storage.get_chunk_path(&stored.chunk_ids[0]);
+        let mut chunk = std::fs::read(&chunk_path).unwrap();
+        chunk.extend_from_slice(b"appended garbage");
+        std::fs::write(&chunk_path, chunk).unwrap();
+
+        let err = storage.get_file(&stored.id).await.unwrap_err();
+        assert!(err.to_string().contains("size mismatch"), "{}", err);
+        let err = storage.get_file_range(&stored.id, 0, 99).await.unwrap_err();
+        assert!(matches!(err, AppError::Storage(StorageError::IntegrityError(_))), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn per_chunk_keys_differ_between_chunks_and_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path())
+            .await
+            .unwrap()
+            .with_chunking(ChunkManager::new(1000))
+            .with_encryption([7; 32])
+            .with_per_chunk_keys(true);
+        let data = varied_text(3000);
+        let stored = storage.store_file("notes.txt", &data).await.unwrap();
+        assert!(stored.per_chunk_keys);
+        assert_eq!(stored.chunk_ids.len(), 3);
+
+        let master = storage.encryption.as_ref().unwrap();
+        let aad = stored.encryption_aad();
+        let keys: Vec<EncryptionConfig> = stored.chunk_ids.iter().map(|chunk_id| master.derive(&chunk_id.key_context()).unwrap()).collect();
+        for (index, (chunk_id, plaintext)) in stored.chunk_ids.iter().zip(data.chunks(1000)).enumerate() {
+            let ciphertext = std::fs::read(storage.get_chunk_path(chunk_id)).unwrap();
+            assert!(master.decrypt(&ciphertext, aad).is_err());
+            for (other, key) in keys.iter().enumerate() {
+                assert_eq!(key.decrypt(&ciphertext, aad).ok().as_deref() == Some(plaintext), other == index);
+            }
+        }
+        assert_eq!(storage.get_file(&stored.id).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn original_size_is_the_input_length_with_or_without_compression() {
+        let data = text(100_000);
+        for compress in [false, true] {
+            let dir = tempfile::tempdir().unwrap();
+            let storage = DiskStorage::new(dir.path()).await.unwrap().with_compression(compress);
+            let stored = storage.store_file("notes.txt", &data).await.unwrap();
+
+            assert_eq!(stored.original_size, data.len() as u64);
+            assert_eq!(stored.original_len(), data.len() as u64);
+            assert_eq!(stored.stored_size, files_under(&dir.path().join("chunks")).iter().map(|chunk| chunk.len() as u64).sum::<u64>());
+            assert_eq!(stored.stored_size < stored.original_size, compress);
+            let listed = storage.list_files().await.unwrap();
+            assert_eq!(listed[0].original_len(), data.len() as u64);
+        }
+    }
+
+    #[tokio::test]
+    async fn identical_content_under_two_names_shares_its_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_chunking(ChunkManager::new(512 * 1024));
+        let data = varied_text(2 * 1024 * 1024);
+        let first = storage.store_file("first.txt", &data).await.unwrap();
+        let second = storage.store_file("second.txt", &data).await.unwrap();
+
+        assert_ne!(first.id, second.id);
+        assert_eq!(first.chunk_ids, second.chunk_ids);
+        assert_eq!(files_under(&dir.path().join("chunks")).len(), 4);
+
+        // Deleting one copy keeps the chunks the other still uses
+        storage.delete_file(&first.id).await.unwrap();
+        assert_eq!(storage.get_file(&second.id).await.unwrap(), data);
+        storage.delete_file(&second.id).await.unwrap();
+        assert!(files_under(&dir.path().join("chunks")).is_empty());
+    }
+
+    #[tokio::test]
+    async fn checksums_are_classified_per_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        storage.store_file("same.txt", b"unchanged").await.unwrap();
+        storage.store_file("edited.txt", b"edited remotely").await.unwrap();
+
+        let expected = [
+            ("same.txt".to_string(), DiskStorage::calculate_checksum(b"unchanged").to_uppercase()),
+            ("edited.txt".to_string(), DiskStorage::calculate_checksum(b"edited locally")),
+            ("gone.txt".to_string(), DiskStorage::calculate_checksum(b"never stored")),
+        ];
+        assert_eq!(
+            storage.verify_checksums(&expected).await.unwrap(),
+            [ChecksumStatus::Match, ChecksumStatus::Differs, ChecksumStatus::Missing]
+        );
+    }
+
+    #[tokio::test]
+    async fn padded_chunks_hide_sizes_below_the_block_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_encryption([7; 32]).with_padding(4096);
+        // Algorithm byte, nonce and tag around the padded plaintext
+        let overhead = 1 + crate::crypto::encryption::NONCE_LEN as u64 + 16;
+
+        for (len, padded) in [(1, 4096), (100, 4096), (4096, 4096), (4097, 8192)] {
+            let data = varied_text(len);
+            let stored = storage.store_file(&format!("{}.txt", len), &data).await.unwrap();
+            assert_eq!(stored.stored_size, padded + overhead, "{} bytes", len);
+            assert_eq!(stored.chunk_unpadded_sizes, [len as u64]);
+            assert_eq!(storage.get_file(&stored.id).await.unwrap(), data);
+        }
+    }
+
+    #[tokio::test]
+    async fn tampered_chunk_is_reported_as_an_integrity_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_chunking(ChunkManager::new(1000));
+        let stored = storage.store_file("notes.txt", &varied_text(3000)).await.unwrap();
+        assert_eq!(stored.chunk_checksums.len(), 3);
+
+        let tampered = &stored.chunk_ids[1];
+        let chunk_path = storage.get_chunk_path(tampered);
+        let mut chunk = std::fs::read(&chunk_path).unwrap();
+        chunk[10] = chunk[10].wrapping_add(1);
+        std::fs::write(&chunk_path, chunk).unwrap();
+
+        let err = storage.get_file(&stored.id).await.unwrap_err();
+        assert!(matches!(&err, AppError::Storage(StorageError::IntegrityError(message)) if message.contains(&tampered.0)), "{:?}", err);
+        let err = storage.get_file_range(&stored.id, 1000, 1999).await.unwrap_err();
+        assert!(matches!(&err, AppError::Storage(StorageError::IntegrityError(message)) if message.contains(&tampered.0)), "{:?}", err);
+        // Chunks that weren't touched still read
+        assert_eq!(storage.get_file_range(&stored.id, 0, 999).await.unwrap(), varied_text(3000)[..1000]);
+    }
+
+    #[tokio::test]
+    async fn decrypted_file_is_stored_as_plaintext_until_encrypted_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_chunking(ChunkManager::new(1000)).with_encryption([7; 32]);
+        let data = varied_text(2500);
+        let encrypted = storage.store_file("notes.txt", &data).await.unwrap();
+        let chunks = || files_under(&dir.path().join("chunks"));
+
+        let decrypted = storage.decrypt_file(&encrypted.id).await.unwrap();
+        assert!(decrypted.keep_plaintext);
+        assert_eq!(chunks().len(), 3);
+        for (chunk_id, plaintext) in decrypted.chunk_ids.iter().zip(data.chunks(1000)) {
+            assert_eq!(std::fs::read(storage.get_chunk_path(chunk_id)).unwrap(), plaintext);
+        }
+        assert_eq!(storage.get_file(&encrypted.id).await.unwrap(), data);
+
+        // Updates keep it unencrypted
+        let updated = storage.update_file(&encrypted.id, &data[..1500]).await.unwrap();
+        assert_eq!(std::fs::read(storage.get_chunk_path(&updated.chunk_ids[0])).unwrap(), &data[..1000]);
+
+        let reencrypted = storage.encrypt_file(&encrypted.id).await.unwrap();
+        assert!(!reencrypted.keep_plaintext);
+        assert!(!chunks().iter().any(|chunk| chunk.windows(100).any(|window| window == &data[..100])));
+        assert_eq!(storage.get_file(&encrypted.id).await.unwrap(), &data[..1500]);
+    }
+
+    #[tokio::test]
+    async fn encrypting_without_a_key_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let stored = storage.store_file("notes.txt", b"plain").await.unwrap();
+
+        let err = storage.encrypt_file(&stored.id).await.unwrap_err();
+        assert!(matches!(err, AppError::Storage(StorageError::InvalidInput(_))), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn concurrently_written_chunks_keep_their_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_chunking(ChunkManager::new(100)).with_write_concurrency(16);
+        let data = varied_text(20_000);
+        let stored = storage.store_file("notes.txt", &data).await.unwrap();
+
+        let expected: Vec<ChunkId> = data.chunks(100).map(|chunk| ChunkId(DiskStorage::calculate_checksum(chunk))).collect();
+        assert_eq!(stored.chunk_ids, expected);
+        assert_eq!(storage.get_file(&stored.id).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn a_failed_chunk_write_fails_the_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path())
+            .await
+            .unwrap()
+            .with_chunking(ChunkManager::new(100))
+            .with_write_concurrency(16)
+            .with_retry_config(RetryConfig::new(1, std::time::Duration::ZERO));
+        // Nothing can be written under a file
+        std::fs::remove_dir_all(&storage.chunks_path).unwrap();
+        std::fs::write(&storage.chunks_path, b"not a directory").unwrap();
+
+        assert!(storage.store_file("notes.txt", &varied_text(20_000)).await.is_err());
+        assert_eq!(storage.lookup_name("notes.txt").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn streamed_reads_match_the_stored_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path())
+            .await
+            .unwrap()
+            .with_chunking(ChunkManager::new(1000))
+            .with_compression(true)
+            .with_encryption([7; 32]);
+        let data = varied_text(20_000);
+        let stored = storage.store_file("notes.txt", &data).await.unwrap();
+
+        let mut streamed = Vec::new();
+        storage.get_file_stream(&stored.id).await.unwrap().read_to_end(&mut streamed).await.unwrap();
+        assert_eq!(streamed, data);
+    }
+
+    #[tokio::test]
+    async fn updating_by_name_keeps_the_id_and_drops_the_old_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap().with_chunking(ChunkManager::new(1000));
+        let original = storage.store_file("a.txt", &varied_text(5000)).await.unwrap();
+
+        let data = text(3000);
+        let updated = storage.update_file_by_name("a.txt", &data).await.unwrap();
+        assert_eq!(updated.id, original.id);
+        assert!(updated.modified_at > original.modified_at);
+        assert_eq!(storage.get_file(&original.id).await.unwrap(), data);
+
+        assert_eq!(std::fs::read_dir(&storage.metadata_path).unwrap().count(), 1);
+        assert!(original.chunk_ids.iter().all(|chunk_id| !storage.get_chunk_path(chunk_id).exists()));
+    }
+
+    #[tokio::test]
+    async fn updating_a_missing_name_is_not_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+
+        let err = storage.update_file_by_name("a.txt", &text(100)).await.unwrap_err();
+        assert!(matches!(err, AppError::Storage(StorageError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn renaming_moves_the_name_in_metadata_and_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let stored = storage.store_file("draft.txt", &text(100)).await.unwrap();
+
+        storage.rename_file(&stored.id, "final.txt").await.unwrap();
+        let renamed = storage.get_metadata(&stored.id).await.unwrap();
+        assert_eq!(renamed.name, "final.txt");
+        assert!(renamed.modified_at > stored.modified_at);
+        assert_eq!(storage.lookup_name("final.txt").await.unwrap(), Some(stored.id));
+        assert_eq!(storage.lookup_name("draft.txt").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn renaming_onto_another_files_name_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let first = storage.store_file("a.txt", &text(100)).await.unwrap();
+        let second = storage.store_file("b.txt", &text(200)).await.unwrap();
+
+        let err = storage.rename_file(&first.id, "b.txt").await.unwrap_err();
+        assert!(matches!(err, AppError::Storage(StorageError::InvalidInput(_))));
+        assert_eq!(storage.lookup_name("b.txt").await.unwrap(), Some(second.id));
+        assert_eq!(storage.get_metadata(&first.id).await.unwrap().name, "a.txt");
+    }
+
+    #[tokio::test]
+    async fn metadata_loads_without_the_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let stored = storage.store_file("notes.txt", &text(5000)).await.unwrap();
+        std::fs::remove_dir_all(&storage.chunks_path).unwrap();
+
+        let metadata = storage.get_metadata(&stored.id).await.unwrap();
+        assert_eq!(metadata.name, "notes.txt");
+        assert_eq!(metadata.original_size, 5000);
+        assert_eq!(metadata.chunk_ids, stored.chunk_ids);
+        assert_eq!(storage.get_metadata_by_name("notes.txt").await.unwrap().id, stored.id);
+        assert!(matches!(storage.get_metadata_by_name("other.txt").await, Err(AppError::Storage(StorageError::NotFound(_)))));
+    }
+
+    #[tokio::test]
+    async fn interrupted_metadata_writes_leave_listing_intact() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let stored = storage.store_file("notes.txt", &text(100)).await.unwrap();
+        // What a crash halfway through the next write of this file would leave behind
+        std::fs::write(storage.get_metadata_path(&stored.id).with_extension("json.tmp"), b"{\"id\": \"trunc").unwrap();
+
+        let (files, unreadable) = storage.list_files_with_errors().await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert!(unreadable.is_empty());
+        assert_eq!(storage.get_file(&stored.id).await.unwrap(), text(100));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_uploads_all_land_in_the_name_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = std::sync::Arc::new(DiskStorage::new(dir.path()).await.unwrap());
+
+        let uploads: Vec<_> = (0..32)
+            .map(|index| {
+                let storage = storage.clone();
+                tokio::spawn(async move { storage.store_file(&format!("notes{}.txt", index), &text(100 + index)).await.unwrap() })
+            })
+            .collect();
+        let mut stored = Vec::new();
+        for upload in uploads {
+            stored.push(upload.await.unwrap());
+        }
+
+        for metadata in stored {
+            assert_eq!(storage.lookup_name(&metadata.name).await.unwrap(), Some(metadata.id));
+        }
+    }
+
+    #[tokio::test]
+    async fn names_are_found_exactly_and_by_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let mut ids = HashMap::new();
+        for name in ["report-2024.txt", "report-2025.txt", "notes.txt"] {
+            ids.insert(name, storage.store_file(name, &text(100)).await.unwrap().id);
+        }
+
+        assert_eq!(storage.lookup_name("notes.txt").await.unwrap(), Some(ids["notes.txt"]));
+        assert_eq!(storage.lookup_name("missing.txt").await.unwrap(), None);
+
+        let mut hits = storage.search_by_prefix("report-").await.unwrap();
+        hits.sort();
+        assert_eq!(
+            hits,
+            vec![("report-2024.txt".to_string(), ids["report-2024.txt"]), ("report-2025.txt".to_string(), ids["report-2025.txt"])]
+        );
+    }
+
+    #[tokio::test]
+    async fn tags_persist_and_update_without_rewriting_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let tags = HashMap::from([("owner".to_string(), "alice".to_string())]);
+        let stored = storage.store_file_with_tags("notes.txt", &text(5000), tags.clone()).await.unwrap();
+        assert_eq!(storage.get_tags(&stored.id).await.unwrap(), tags);
+
+        let chunk_path = storage.get_chunk_path(&stored.chunk_ids[0]);
+        let written_at = std::fs::metadata(&chunk_path).unwrap().modified().unwrap();
+        let retagged = HashMap::from([("content-type".to_string(), "text/plain".to_string())]);
+        storage.set_tags(&stored.id, retagged.clone()).await.unwrap();
+
+        assert_eq!(storage.get_metadata(&stored.id).await.unwrap().tags, retagged);
+        assert_eq!(std::fs::metadata(&chunk_path).unwrap().modified().unwrap(), written_at);
+        assert_eq!(storage.get_file(&stored.id).await.unwrap(), text(5000));
+    }
+
+    #[tokio::test]
+    async fn chunk_reads_are_retried_until_the_chunk_is_readable() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path())
+            .await
+            .unwrap()
+            .with_retry_config(RetryConfig::new(5, std::time::Duration::from_millis(20)));
+        let stored = storage.store_file("notes.txt", &text(100)).await.unwrap();
+        let chunk_id = &stored.chunk_ids[0];
+        let chunk_path = storage.get_chunk_path(chunk_id);
+        let aside = dir.path().join("aside");
+        // Reading a directory fails with an error worth retrying, unlike a missing file
+        std::fs::rename(&chunk_path, &aside).unwrap();
+        std::fs::create_dir(&chunk_path).unwrap();
+
+        let restore = {
+            let chunk_path = chunk_path.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+                std::fs::remove_dir(&chunk_path).unwrap();
+                std::fs::rename(&aside, &chunk_path).unwrap();
+            })
+        };
+        let data = storage.read_chunk(chunk_id, stored.chunk_checksums.first().map(String::as_str)).await.unwrap();
+        restore.await.unwrap();
+        assert_eq!(data, std::fs::read(&chunk_path).unwrap());
+    }
+
+    #[tokio::test]
+    async fn missing_chunks_are_not_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path())
+            .await
+            .unwrap()
+            .with_retry_config(RetryConfig::new(3, std::time::Duration::from_secs(60)));
+        let stored = storage.store_file("notes.txt", &text(100)).await.unwrap();
+        std::fs::remove_file(storage.get_chunk_path(&stored.chunk_ids[0])).unwrap();
+
+        let started = std::time::Instant::now();
+        let err = storage.read_chunk(&stored.chunk_ids[0], None).await.unwrap_err();
+        assert!(matches!(err, AppError::Storage(StorageError::Io(ref e)) if e.kind() == std::io::ErrorKind::NotFound), "{:?}", err);
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn a_lost_name_index_is_rebuilt_from_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        storage.store_file("a.txt", &text(100)).await.unwrap();
+        let newer = storage.store_file("a.txt", &text(200)).await.unwrap();
+        let other = storage.store_file("b.txt", &text(300)).await.unwrap();
+
+        std::fs::remove_file(dir.path().join("name_to_id.json")).unwrap();
+        assert_eq!(storage.lookup_name("a.txt").await.unwrap(), None);
+
+        assert_eq!(storage.rebuild_name_index().await.unwrap(), 2);
+        let id = storage.lookup_name("a.txt").await.unwrap().unwrap();
+        assert_eq!(id, newer.id);
+        assert_eq!(storage.get_file(&id).await.unwrap(), text(200));
+        assert_eq!(storage.lookup_name("b.txt").await.unwrap(), Some(other.id));
+    }
 }
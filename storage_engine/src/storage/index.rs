@@ -0,0 +1,245 @@
+use crate::{AppError, FileId, Result, StorageError};
+use async_trait::async_trait;
+use fs2::FileExt;
+use redb::{Database, ReadableTable, TableDefinition};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+use tokio::{fs, sync::Mutex};
+use uuid::Uuid;
+
+/// Maps file names to the id of the file currently stored under that name.
+#[async_trait]
+pub trait NameIndex: Send + Sync {
+    async fn get(&self, name: &str) -> Result<Option<FileId>>;
+    async fn insert(&self, name: &str, id: &FileId) -> Result<()>;
+    async fn remove(&self, name: &str) -> Result<()>;
+    async fn entries(&self) -> Result<HashMap<String, FileId>>;
+
+    /// Makes `entries` the whole index, dropping any name not in it.
+    async fn replace_all(&self, entries: HashMap<String, FileId>) -> Result<()> {
+        for name in self.entries().await?.into_keys() {
+            if !entries.contains_key(&name) {
+                self.remove(&name).await?;
+            }
+        }
+        for (name, id) in &entries {
+            self.insert(name, id).await?;
+        }
+        Ok(())
+    }
+
+    /// Entries whose names start with `prefix`, sorted by name.
+    async fn search_prefix(&self, prefix: &str) -> Result<Vec<(String, FileId)>> {
+        let mut matches: Vec<_> = self.entries().await?.into_iter().filter(|(name, _)| name.starts_with(prefix)).collect();
+        matches.sort();
+        Ok(matches)
+    }
+}
+
+/// Default index, kept as a single JSON object rewritten on every change.
+pub struct JsonNameIndex {
+    path: PathBuf,
+    write_lock: Mutex<()>,
+}
+
+impl JsonNameIndex {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    async fn load(&self) -> Result<HashMap<String, FileId>> {
+        if !self.path.exists() {
+            return Ok(HashMap::new());
+        }
+
+        let content = fs::read_to_string(&self.path).await.map_err(|e| AppError::Storage(StorageError::Storage(e.to_string())))?;
+        Ok(serde_json::from_str(&content).unwrap_or_default())
+    }
+
+    // Advisory lock on a file beside the index, so other processes and other instances
+    // over the same directory take turns too. Released when the file is dropped.
+    async fn lock_file(&self) -> Result<std::fs::File> {
+        let mut lock_path = self.path.clone().into_os_string();
+        lock_path.push(".lock");
+        tokio::task::spawn_blocking(move || {
+            let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)?;
+            file.lock_exclusive()?;
+            Ok(file)
+        })
+        .await
+        .map_err(|e| AppError::Storage(StorageError::Storage(e.to_string())))?
+        .map_err(|e: std::io::Error| AppError::Storage(StorageError::Storage(e.to_string())))
+    }
+
+    async fn save(&self, index: &HashMap<String, FileId>) -> Result<()> {
+        let content = serde_json::to_string(index).map_err(|e| AppError::Storage(StorageError::Storage(e.to_string())))?;
+        // Renamed into place so a crash mid-write can't truncate the index
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, content).await.map_err(|e| AppError::Storage(StorageError::Storage(e.to_string())))?;
+        fs::rename(&tmp_path, &self.path).await.map_err(|e| AppError::Storage(StorageError::Storage(e.to_string())))?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl NameIndex for JsonNameIndex {
+    async fn get(&self, name: &str) -> Result<Option<FileId>> {
+        Ok(self.load().await?.get(name).copied())
+    }
+
+    async fn insert(&self, name: &str, id: &FileId) -> Result<()> {
+        let _guard = self.write_lock.lock().await;
+        let _lock = self.lock_file().await?;
+        let mut index = self.load().await?;
+        index.insert(name.to_string(), *id);
+        self.save(&index).await
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        let _guard = self.write_lock.lock().await;
+        let _lock = self.lock_file().await?;
+        let mut index = self.load().await?;
+        if index.remove(name).is_some() {
+            self.save(&index).await?;
+        }
+        Ok(())
+    }
+
+    async fn entries(&self) -> Result<HashMap<String, FileId>> {
+        self.load().await
+    }
+
+    // One rewrite instead of one per name
+    async fn replace_all(&self, entries: HashMap<String, FileId>) -> Result<()> {
+        let _guard = self.write_lock.lock().await;
+        let _lock = self.lock_file().await?;
+        self.save(&entries).await
+    }
+}
+
+const NAMES: TableDefinition<&str, u128> = TableDefinition::new("name_to_id");
+
+/// Index backed by an embedded redb database, updated one key at a time.
+pub struct RedbNameIndex {
+    db: Database,
+}
+
+fn redb_error(e: impl std::fmt::Display) -> AppError {
+    AppError::Storage(StorageError::Storage(format!("Name index error: {}", e)))
+}
+
+impl RedbNameIndex {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let db = Database::create(path.as_ref()).map_err(redb_error)?;
+
+        // Create the table up front so reads never see it missing
+        let txn = db.begin_write().map_err(redb_error)?;
+        txn.open_table(NAMES).map_err(redb_error)?;
+        txn.commit().map_err(redb_error)?;
+
+        Ok(Self { db })
+    }
+}
+
+#[async_trait]
+impl NameIndex for RedbNameIndex {
+    async fn get(&self, name: &str) -> Result<Option<FileId>> {
+        let txn = self.db.begin_read().map_err(redb_error)?;
+        let table = txn.open_table(NAMES).map_err(redb_error)?;
+        let id = table.get(name).map_err(redb_error)?.map(|id| FileId(Uuid::from_u128(id.value())));
+        Ok(id)
+    }
+
+    async fn insert(&self, name: &str, id: &FileId) -> Result<()> {
+        let txn = self.db.begin_write().map_err(redb_error)?;
+        {
+            let mut table = txn.open_table(NAMES).map_err(redb_error)?;
+            table.insert(name, id.0.as_u128()).map_err(redb_error)?;
+        }
+        txn.commit().map_err(redb_error)
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        let txn = self.db.begin_write().map_err(redb_error)?;
+        {
+            let mut table = txn.open_table(NAMES).map_err(redb_error)?;
+            table.remove(name).map_err(redb_error)?;
+        }
+        txn.commit().map_err(redb_error)
+    }
+
+    async fn entries(&self) -> Result<HashMap<String, FileId>> {
+        let txn = self.db.begin_read().map_err(redb_error)?;
+        let table = txn.open_table(NAMES).map_err(redb_error)?;
+        let mut entries = HashMap::new();
+        for entry in table.iter().map_err(redb_error)? {
+            let (name, id) = entry.map_err(redb_error)?;
+            entries.insert(name.value().to_string(), FileId(Uuid::from_u128(id.value())));
+        }
+        Ok(entries)
+    }
+
+    // Keys are ordered, so the matches are one contiguous range starting at the prefix
+    async fn search_prefix(&self, prefix: &str) -> Result<Vec<(String, FileId)>> {
+        let txn = self.db.begin_read().map_err(redb_error)?;
+        let table = txn.open_table(NAMES).map_err(redb_error)?;
+        let mut matches = Vec::new();
+        for entry in table.range(prefix..).map_err(redb_error)? {
+            let (name, id) = entry.map_err(redb_error)?;
+            if !name.value().starts_with(prefix) {
+                break;
+            }
+            matches.push((name.value().to_string(), FileId(Uuid::from_u128(id.value()))));
+        }
+        Ok(matches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the same changes against `index` and returns what it holds afterwards
+    async fn exercise(index: &dyn NameIndex, ids: &[FileId; 3]) -> (Option<FileId>, Option<FileId>, HashMap<String, FileId>) {
+        index.insert("a.txt", &ids[0]).await.unwrap();
+        index.insert("b.txt", &ids[1]).await.unwrap();
+        index.insert("a.txt", &ids[2]).await.unwrap();
+        index.remove("b.txt").await.unwrap();
+        index.remove("missing.txt").await.unwrap();
+        (index.get("a.txt").await.unwrap(), index.get("b.txt").await.unwrap(), index.entries().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn json_and_redb_indexes_agree() {
+        let dir = tempfile::tempdir().unwrap();
+        let ids = [FileId::new(), FileId::new(), FileId::new()];
+        let json = JsonNameIndex::new(dir.path().join("names.json"));
+        let redb = RedbNameIndex::open(dir.path().join("names.redb")).unwrap();
+
+        let from_json = exercise(&json, &ids).await;
+        assert_eq!(from_json, exercise(&redb, &ids).await);
+        assert_eq!(from_json.0, Some(ids[2]));
+        assert_eq!(from_json.1, None);
+        assert_eq!(from_json.2, HashMap::from([("a.txt".to_string(), ids[2])]));
+    }
+
+    #[tokio::test]
+    async fn replace_all_drops_names_not_given() {
+        let dir = tempfile::tempdir().unwrap();
+        let json = JsonNameIndex::new(dir.path().join("names.json"));
+        let redb = RedbNameIndex::open(dir.path().join("names.redb")).unwrap();
+        let (old, new) = (FileId::new(), FileId::new());
+
+        for index in [&json as &dyn NameIndex, &redb] {
+            index.insert("old.txt", &old).await.unwrap();
+            index.replace_all(HashMap::from([("new.txt".to_string(), new)])).await.unwrap();
+            assert_eq!(index.entries().await.unwrap(), HashMap::from([("new.txt".to_string(), new)]));
+        }
+    }
+}
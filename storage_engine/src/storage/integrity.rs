@@ -0,0 +1,123 @@
+use crate::Result;
+use std::{sync::Arc, time::Duration};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use super::{
+    disk::{DiskStorage, ScanReport},
+    validation::ValidationManager,
+};
+
+/// Periodically runs `ValidationManager::validate_file` over every stored file, reporting
+/// files whose chunks are missing or no longer match their checksums. Nothing is repaired.
+pub struct IntegrityScanner {
+    storage: Arc<DiskStorage>,
+    interval: Duration,
+    cancel: CancellationToken,
+}
+
+impl IntegrityScanner {
+    pub fn new(storage: Arc<DiskStorage>, interval: Duration) -> Self {
+        Self {
+            storage,
+            interval,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// Stops the scanner when `cancel` fires, between files in the middle of a pass.
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Validates every file once. A cancelled pass returns what it checked so far.
+    pub async fn scan_once(&self) -> Result<ScanReport> {
+        let validation = ValidationManager::new(self.storage.base_path().to_path_buf());
+        let mut report = ScanReport::default();
+
+        for metadata in self.storage.list_files().await? {
+            if self.cancel.is_cancelled() {
+                break;
+            }
+            report.checked += 1;
+            if let Err(e) = validation.validate_file(&metadata).await {
+                // A file updated or deleted since it was listed can lose chunks legitimately
+                let unchanged = self
+                    .storage
+                    .get_metadata(&metadata.id)
+                    .await
+                    .is_ok_and(|current| current.chunk_ids == metadata.chunk_ids);
+                if unchanged {
+                    report.corrupted.push((metadata.id, e.to_string()));
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Scans every `interval`, logging each report, until cancelled. The first pass runs
+    /// one interval after starting.
+    pub async fn run(&self) {
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                _ = self.cancel.cancelled() => return,
+                _ = ticker.tick() => {}
+            }
+
+            match self.scan_once().await {
+                Ok(report) => {
+                    for (id, reason) in &report.corrupted {
+                        warn!(%id, %reason, "Integrity scan found a damaged file");
+                    }
+                    info!(checked = report.checked, corrupted = report.corrupted.len(), "Integrity scan finished");
+                }
+                Err(e) => warn!("Integrity scan failed: {}", e),
+            }
+        }
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move { self.run().await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::disk::StorageBackend;
+
+    #[tokio::test]
+    async fn a_scan_flags_only_the_corrupted_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(DiskStorage::new(dir.path()).await.unwrap());
+        let mut stored = Vec::new();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            stored.push(storage.store_file(name, format!("contents of {}", name).as_bytes()).await.unwrap());
+        }
+        let chunk_path = dir.path().join("chunks").join(&stored[1].chunk_ids[0].0);
+        let mut data = std::fs::read(&chunk_path).unwrap();
+        data[0] ^= 1;
+        std::fs::write(&chunk_path, data).unwrap();
+
+        let report = IntegrityScanner::new(storage, Duration::from_secs(3600)).scan_once().await.unwrap();
+        assert_eq!(report.checked, 3);
+        assert_eq!(report.corrupted.len(), 1);
+        assert_eq!(report.corrupted[0].0, stored[1].id);
+    }
+
+    #[tokio::test]
+    async fn cancelling_stops_a_running_scanner() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(DiskStorage::new(dir.path()).await.unwrap());
+        let cancel = CancellationToken::new();
+        let handle = IntegrityScanner::new(storage, Duration::from_secs(3600)).with_cancellation(cancel.clone()).spawn();
+
+        cancel.cancel();
+        tokio::time::timeout(Duration::from_secs(1), handle).await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+use crate::{
+    chunk::{ChunkManager, FileChunker},
+    crypto::encryption::EncryptionConfig,
+    AppError, ChunkId, FileId, FileMetadata, FileType, FileTypeDetector, Result, StorageError,
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use tokio::sync::RwLock;
+use tracing::{info, instrument};
+
+use super::{
+    compression::{CompressionAlgorithm, CompressionManager},
+    disk::{DiskStorage, StorageBackend},
+    pipeline::{PipelineStage, ProcessingPipeline},
+};
+
+#[derive(Default)]
+struct MemoryState {
+    files: HashMap<FileId, FileMetadata>,
+    chunks: HashMap<ChunkId, Vec<u8>>,
+}
+
+/// Keeps files in memory instead of on disk, for tests that want the storage pipeline
+/// without a temporary directory. Data goes through the same chunker, compression and
+/// encryption as `DiskStorage`, always per chunk, and chunks are named by checksum so
+/// identical chunks are kept once. Nothing outlives the value.
+pub struct MemoryStorage {
+    chunker: FileChunker,
+    encryption: Option<EncryptionConfig>,
+    compression: Option<CompressionManager>,
+    pipeline: ProcessingPipeline,
+    state: RwLock<MemoryState>,
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self {
+            chunker: FileChunker::new(ChunkManager::default()),
+            encryption: None,
+            compression: None,
+            pipeline: ProcessingPipeline::default(),
+            state: RwLock::new(MemoryState::default()),
+        }
+    }
+
+    pub fn with_chunking(mut self, config: ChunkManager) -> Self {
+        self.chunker = FileChunker::new(config);
+        self
+    }
+
+    pub fn with_encryption(mut self, key: [u8; 32]) -> Self {
+        self.encryption = Some(EncryptionConfig::new(key));
+        self
+    }
+
+    pub fn with_compression(mut self, enabled: bool) -> Self {
+        self.compression = Some(CompressionManager::new(enabled));
+        self
+    }
+
+    /// Codec for newly compressed data. Only applies after compression is configured.
+    pub fn with_compression_algorithm(mut self, algorithm: CompressionAlgorithm) -> Self {
+        self.compression = self.compression.map(|compression| compression.with_algorithm(algorithm));
+        self
+    }
+
+    pub fn with_pipeline(mut self, stages: Vec<PipelineStage>) -> Result<Self> {
+        self.pipeline = ProcessingPipeline::new(stages)?;
+        Ok(self)
+    }
+
+    pub async fn list_files(&self) -> Result<Vec<FileMetadata>> {
+        Ok(self.state.read().await.files.values().cloned().collect())
+    }
+
+    fn configured_stages(&self, compress: bool) -> Vec<PipelineStage> {
+        self.pipeline
+            .stages()
+            .iter()
+            .copied()
+            .filter(|stage| match stage {
+                PipelineStage::Compress => compress && self.compression.as_ref().is_some_and(|c| c.is_enabled()),
+                PipelineStage::Encrypt => self.encryption.as_ref().is_some_and(|e| e.is_enabled()),
+            })
+            .collect()
+    }
+
+    // Same per-chunk transform as `DiskStorage`: compression is kept only when it shrinks the chunk
+    fn process_chunk(&self, data: &[u8], aad: &[u8], compress: bool) -> Result<(Vec<u8>, bool)> {
+        let mut processed = data.to_vec();
+        let mut compressed = false;
+
+        for stage in self.pipeline.stages() {
+            match stage {
+                PipelineStage::Compress => {
+                    if let Some(compression) = self.compression.as_ref().filter(|c| compress && c.is_worth_compressing(&processed)) {
+                        let candidate = compression.compress(&processed)?;
+                        if candidate.len() < processed.len() {
+                            processed = candidate;
+                            compressed = true;
+                        }
+                    }
+                }
+                PipelineStage::Encrypt => {
+                    if let Some(encryption) = self.encryption.as_ref().filter(|e| e.is_enabled()) {
+                        processed = encryption.encrypt(&processed, Some(aad))?;
+                    }
+                }
+            }
+        }
+
+        Ok((processed, compressed))
+    }
+
+    fn deprocess_chunk(&self, data: &[u8], pipeline: &[PipelineStage], compressed: bool, aad: &[u8]) -> Result<Vec<u8>> {
+        let mut processed = data.to_vec();
+
+        for stage in pipeline.iter().rev() {
+            match stage {
+                PipelineStage::Compress => {
+                    if compressed {
+                        processed = match &self.compression {
+                            Some(compression) => compression.decompress(&processed)?,
+                            None => CompressionManager::new(false).decompress(&processed)?,
+                        };
+                    }
+                }
+                PipelineStage::Encrypt => {
+                    let encryption = self.encryption.as_ref().ok_or_else(|| {
+                        AppError::Storage(StorageError::Storage("Data is encrypted but no encryption key is configured".to_string()))
+                    })?;
+                    processed = encryption.decrypt(&processed, Some(aad))?;
+                }
+            }
+        }
+
+        Ok(processed)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryStorage {
+    #[instrument(skip(self, data), fields(size = data.len()))]
+    async fn store_file(&self, name: &str, data: &[u8]) -> Result<FileMetadata> {
+        let id = FileId::new();
+        let file_type = FileTypeDetector::detect_with_fallback(data, &FileType::Unknown);
+        // Like `DiskStorage`, only documents and unrecognised data are compressed
+        let compress = matches!(file_type, FileType::Document(_) | FileType::Unknown);
+
+        let mut chunks = Vec::new();
+        let mut chunk_compressed = Vec::new();
+        let mut chunk_sizes = Vec::new();
+        for chunk in self.chunker.chunk_data(data) {
+            chunk_sizes.push(chunk.size as u64);
+            let (processed, compressed) = self.process_chunk(&chunk.data, id.0.as_bytes(), compress)?;
+            chunks.push(processed);
+            chunk_compressed.push(compressed);
+        }
+        let pipeline = if chunks.is_empty() { Vec::new() } else { self.configured_stages(compress) };
+
+        let mut hasher = Sha256::new();
+        for chunk in &chunks {
+            hasher.update(chunk);
+        }
+        let chunk_checksums: Vec<String> = chunks.iter().map(|chunk| DiskStorage::calculate_checksum(chunk)).collect();
+        let chunk_ids: Vec<ChunkId> = chunk_checksums.iter().cloned().map(ChunkId).collect();
+        let size: u64 = chunks.iter().map(|chunk| chunk.len() as u64).sum();
+        let compression_level = self
+            .compression
+            .as_ref()
+            .filter(|c| c.algorithm().uses_level() && chunk_compressed.contains(&true))
+            .map(|c| c.level());
+        let compression_algorithm = self
+            .compression
+            .as_ref()
+            .filter(|_| chunk_compressed.contains(&true))
+            .map(|c| c.algorithm());
+
+        let now = Utc::now();
+        let metadata = FileMetadata {
+            id,
+            name: name.to_string(),
+            size,
+            original_size: data.len() as u64,
+            stored_size: size,
+            created_at: now,
+            modified_at: now,
+            checksum: format!("{:x}", hasher.finalize()),
+            content_checksum: DiskStorage::calculate_checksum(data),
+            compression_ratio: if size == 0 { 1.0 } else { data.len() as f64 / size as f64 },
+            file_type,
+            chunk_ids: chunk_ids.clone(),
+            pipeline,
+            chunk_compressed,
+            chunk_sizes,
+            chunk_size: self.chunker.chunk_size(),
+            id_bound: true,
+            compression_level,
+            compression_algorithm,
+            per_chunk_keys: false,
+            chunk_unpadded_sizes: Vec::new(),
+            chunk_checksums,
+            keep_plaintext: false,
+            tags: HashMap::new(),
+        };
+
+        let mut state = self.state.write().await;
+        state.chunks.extend(chunk_ids.into_iter().zip(chunks));
+        state.files.insert(id, metadata.clone());
+
+        info!(id = %metadata.id, "Stored file in memory");
+        Ok(metadata)
+    }
+
+    #[instrument(skip(self))]
+    async fn get_file(&self, id: &FileId) -> Result<Vec<u8>> {
+        let state = self.state.read().await;
+        let metadata = state.files.get(id).ok_or_else(|| AppError::Storage(StorageError::NotFound(id.to_string())))?;
+
+        let mut data = Vec::with_capacity(metadata.original_size as usize);
+        for (index, chunk_id) in metadata.chunk_ids.iter().enumerate() {
+            let chunk_data = state
+                .chunks
+                .get(chunk_id)
+                .ok_or_else(|| AppError::Storage(StorageError::NotFound(format!("Chunk {} missing", chunk_id.0))))?;
+            if metadata.chunk_checksums.get(index).is_some_and(|checksum| DiskStorage::calculate_checksum(chunk_data) != *checksum) {
+                return Err(AppError::Storage(StorageError::IntegrityError(format!("chunk {} corrupted", chunk_id.0))));
+            }
+            match metadata.chunk_compressed.get(index) {
+                Some(compressed) => data.extend(self.deprocess_chunk(chunk_data, &metadata.pipeline, *compressed, id.0.as_bytes())?),
+                None => data.extend_from_slice(chunk_data),
+            }
+        }
+
+        if data.len() as u64 != metadata.original_size {
+            return Err(AppError::Storage(StorageError::IntegrityError(format!(
+                "File {} length mismatch. Expected: {}, Got: {}",
+                id,
+                metadata.original_size,
+                data.len()
+            ))));
+        }
+        Ok(data)
+    }
+
+    #[instrument(skip(self))]
+    async fn delete_file(&self, id: &FileId) -> Result<()> {
+        let mut state = self.state.write().await;
+        let metadata = state.files.remove(id).ok_or_else(|| AppError::Storage(StorageError::NotFound(id.to_string())))?;
+
+        // Chunks are shared between files with the same content
+        let in_use: HashSet<&ChunkId> = state.files.values().flat_map(|file| &file.chunk_ids).collect();
+        let unused: Vec<ChunkId> = metadata.chunk_ids.into_iter().filter(|chunk_id| !in_use.contains(chunk_id)).collect();
+        for chunk_id in unused {
+            state.chunks.remove(&chunk_id);
+        }
+
+        info!(%id, "Deleted file from memory");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(len: usize) -> Vec<u8> {
+        b"the quick brown fox jumps over the lazy dog\n".iter().copied().cycle().take(len).collect()
+    }
+
+    #[tokio::test]
+    async fn files_round_trip_through_compression_and_encryption() {
+        let storage = MemoryStorage::new().with_chunking(ChunkManager::new(1000)).with_compression(true).with_encryption([7; 32]);
+        let data = text(10_000);
+
+        let stored = storage.store_file("notes.txt", &data).await.unwrap();
+        assert_eq!(stored.pipeline, vec![PipelineStage::Compress, PipelineStage::Encrypt]);
+        assert_eq!(stored.chunk_ids.len(), 10);
+        assert_eq!(storage.get_file(&stored.id).await.unwrap(), data);
+        assert_eq!(storage.list_files().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn deleted_files_are_gone_but_shared_chunks_stay() {
+        let storage = MemoryStorage::new().with_chunking(ChunkManager::new(1000));
+        let data = text(5000);
+        let first = storage.store_file("a.txt", &data).await.unwrap();
+        let second = storage.store_file("b.txt", &data).await.unwrap();
+        assert_eq!(first.chunk_ids, second.chunk_ids);
+
+        storage.delete_file(&first.id).await.unwrap();
+        assert!(matches!(storage.get_file(&first.id).await, Err(AppError::Storage(StorageError::NotFound(_)))));
+        assert!(matches!(storage.delete_file(&first.id).await, Err(AppError::Storage(StorageError::NotFound(_)))));
+        assert_eq!(storage.get_file(&second.id).await.unwrap(), data);
+    }
+}
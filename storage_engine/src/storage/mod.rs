@@ -3,4 +3,9 @@ pub mod cache;
 pub mod compression;
 pub mod retry;
 pub mod validation;
-pub mod progress;
\ No newline at end of file
+pub mod integrity;
+pub mod progress;
+pub mod pipeline;
+pub mod space;
+pub mod index;
+pub mod memory;
\ No newline at end of file
@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+use crate::{AppError, Result, StorageError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PipelineStage {
+    Compress,
+    Encrypt,
+}
+
+/// Ordered list of transforms applied to file data before chunking.
+/// Reads undo the stages in reverse order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessingPipeline {
+    stages: Vec<PipelineStage>,
+}
+
+impl Default for ProcessingPipeline {
+    fn default() -> Self {
+        Self {
+            stages: vec![PipelineStage::Compress, PipelineStage::Encrypt],
+        }
+    }
+}
+
+impl ProcessingPipeline {
+    pub fn new(stages: Vec<PipelineStage>) -> Result<Self> {
+        Self::validate(&stages)?;
+        Ok(Self { stages })
+    }
+
+    pub fn stages(&self) -> &[PipelineStage] {
+        &self.stages
+    }
+
+    pub fn validate(stages: &[PipelineStage]) -> Result<()> {
+        for (i, stage) in stages.iter().enumerate() {
+            if stages[..i].contains(stage) {
+                return Err(AppError::Storage(StorageError::InvalidInput(format!(
+                    "Pipeline stage {:?} is listed more than once",
+                    stage
+                ))));
+            }
+        }
+
+        // Ciphertext is indistinguishable from random data, so compressing it only costs CPU.
+        let encrypt = stages.iter().position(|s| *s == PipelineStage::Encrypt);
+        let compress = stages.iter().position(|s| *s == PipelineStage::Compress);
+        if let (Some(encrypt), Some(compress)) = (encrypt, compress) {
+            if encrypt < compress {
+                return Err(AppError::Storage(StorageError::InvalidInput(
+                    "Invalid pipeline order: compressing after encryption has no effect".to_string(),
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_order_compresses_before_encrypting() {
+        assert_eq!(ProcessingPipeline::default().stages(), [PipelineStage::Compress, PipelineStage::Encrypt]);
+    }
+
+    #[test]
+    fn encrypting_before_compressing_is_rejected() {
+        let err = ProcessingPipeline::new(vec![PipelineStage::Encrypt, PipelineStage::Compress]).unwrap_err();
+        assert!(matches!(err, AppError::Storage(StorageError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn repeated_stages_are_rejected() {
+        let err = ProcessingPipeline::new(vec![PipelineStage::Compress, PipelineStage::Compress]).unwrap_err();
+        assert!(matches!(err, AppError::Storage(StorageError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn single_stage_pipelines_are_allowed() {
+        assert_eq!(ProcessingPipeline::new(vec![PipelineStage::Encrypt]).unwrap().stages(), [PipelineStage::Encrypt]);
+    }
+}
@@ -1,5 +1,5 @@
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex};
 use std::collections::HashMap;
 use uuid::Uuid;
 use std::time::{Duration, Instant};
@@ -14,9 +14,17 @@ pub struct ProgressStats {
     pub estimated_time_remaining: Duration,
 }
 
-#[derive(Debug)]
+// Each operation's latest stats live in its watch channel, so subscribers are pushed every
+// update and see the channel close once the operation completes.
+#[derive(Debug, Clone)]
 pub struct ProgressTracker {
-    operation: Arc<Mutex<HashMap<Uuid, ProgressStats>>>,
+    operation: Arc<Mutex<HashMap<Uuid, watch::Sender<ProgressStats>>>>,
+}
+
+impl Default for ProgressTracker {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ProgressTracker {
@@ -36,14 +44,15 @@ impl ProgressTracker {
         };
 
         let mut operations = self.operation.lock().await;
-        operations.insert(operation_id, stats);
+        operations.insert(operation_id, watch::Sender::new(stats));
         operation_id
     }
 
     pub async fn update_progress(&self, operation_id: &Uuid, processed_bytes: u64) -> Option<ProgressStats> {
-        let mut operations = self.operation.lock().await;
+        let operations = self.operation.lock().await;
 
-        if let Some(stats) = operations.get_mut(operation_id) {
+        let sender = operations.get(operation_id)?;
+        sender.send_modify(|stats| {
             let elapsed = stats.start_time.elapsed();
             let elapsed_secs = elapsed.as_secs_f64();
 
@@ -54,19 +63,22 @@ impl ProgressTracker {
                 0.0
             };
 
-            stats.percent_complete = (processed_bytes as f32 / stats.total_bytes as f32) * 100.0;
-            let remaining_bytes = stats.total_bytes - processed_bytes;
+            // Nothing to transfer counts as done; over-reported bytes are capped at the total
+            stats.percent_complete = if stats.total_bytes == 0 {
+                100.0
+            } else {
+                (processed_bytes as f32 / stats.total_bytes as f32 * 100.0).min(100.0)
+            };
+            let remaining_bytes = stats.total_bytes.saturating_sub(processed_bytes);
             stats.estimated_time_remaining  = if stats.current_speed > 0.0 {
                 Duration::from_secs_f64(remaining_bytes as f64 / stats.current_speed)
             }else {
                 Duration::from_secs(0)
             };
+        });
 
-            Some(stats.clone())
-        }else {
-            None
-        }
-
+        let stats = sender.borrow().clone();
+        Some(stats)
     }
 
     pub async fn complete_operation(&self, operation_id: &Uuid) {
@@ -76,7 +88,14 @@ impl ProgressTracker {
 
     pub async fn get_progress(&self, operation_id: &Uuid) -> Option<ProgressStats> {
         let operations = self.operation.lock().await;
-        operations.get(operation_id).cloned()
+        operations.get(operation_id).map(|sender| sender.borrow().clone())
+    }
+
+    /// Receiver that is notified on every `update_progress` for the operation. `changed`
+    /// returns an error once the operation completes.
+    pub async fn subscribe(&self, operation_id: &Uuid) -> Option<watch::Receiver<ProgressStats>> {
+        let operations = self.operation.lock().await;
+        operations.get(operation_id).map(|sender| sender.subscribe())
     }
 }
 
@@ -115,4 +134,47 @@ impl ProgressFormatter for ProgressStats {
             format!("{}s remaining", secs)
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn subscribers_see_every_update_and_the_end() {
+        let tracker = ProgressTracker::new();
+        let operation_id = tracker.start_operation(300).await;
+        let mut updates = tracker.subscribe(&operation_id).await.unwrap();
+
+        let mut seen = Vec::new();
+        for processed in [100, 200, 300] {
+            tracker.update_progress(&operation_id, processed).await;
+            updates.changed().await.unwrap();
+            seen.push(updates.borrow_and_update().processed_bytes);
+        }
+        assert_eq!(seen, vec![100, 200, 300]);
+
+        tracker.complete_operation(&operation_id).await;
+        assert!(updates.changed().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn zero_byte_operations_are_complete() {
+        let tracker = ProgressTracker::new();
+        let operation_id = tracker.start_operation(0).await;
+
+        let stats = tracker.update_progress(&operation_id, 0).await.unwrap();
+        assert_eq!(stats.percent_complete, 100.0);
+        assert_eq!(stats.estimated_time_remaining, Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn over_reported_bytes_are_capped_at_the_total() {
+        let tracker = ProgressTracker::new();
+        let operation_id = tracker.start_operation(100).await;
+
+        let stats = tracker.update_progress(&operation_id, 250).await.unwrap();
+        assert_eq!(stats.percent_complete, 100.0);
+        assert_eq!(stats.estimated_time_remaining, Duration::ZERO);
+    }
+}
@@ -1,17 +1,27 @@
-use crate::Result;
-use tokio::time::{sleep, Duration};
+use crate::{AppError, Result};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use tokio::time::{sleep, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(30);
 
 pub struct RetryConfig {
     max_retries: u32,
     initial_delay: Duration,
+    // No single backoff sleeps longer than this
+    max_delay: Duration,
+    cancel: Option<CancellationToken>,
+    // Fraction of each delay that may be added or taken away at random; 0 keeps delays exact
+    jitter: f64,
+    jitter_state: AtomicU64,
 }
 
 impl Default for RetryConfig {
     fn default() -> Self {
-        Self {
-            max_retries: 3,
-            initial_delay: Duration::from_secs(1),
-        }
+        Self::new(3, Duration::from_secs(1))
     }
 }
 
@@ -20,7 +30,83 @@ impl RetryConfig {
         Self {
             max_retries,
             initial_delay,
+            max_delay: DEFAULT_MAX_DELAY,
+            cancel: None,
+            jitter: 0.0,
+            jitter_state: AtomicU64::new(RandomState::new().build_hasher().finish()),
+        }
+    }
+
+    /// Caps each backoff delay, jitter included. Defaults to 30 seconds.
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Spreads each delay uniformly over `delay * (1 ± fraction)`, so clients that failed
+    /// together don't all retry at the same moment. `fraction` is clamped to `0.0..=1.0`.
+    pub fn with_jitter(mut self, fraction: f64) -> Self {
+        self.jitter = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Fixes the sequence of jittered delays, which are otherwise seeded randomly.
+    pub fn with_jitter_seed(mut self, seed: u64) -> Self {
+        self.jitter_state = AtomicU64::new(seed);
+        self
+    }
+
+    /// Delay before retry number `attempt`, counting from 1: `initial_delay * 2^(attempt - 1)`,
+    /// jittered when enabled and capped at `max_delay`.
+    pub fn backoff_delay(&self, attempt: u32) -> Duration {
+        // Growth past the cap would overflow for high attempt counts
+        let delay = 2u32
+            .checked_pow(attempt.saturating_sub(1))
+            .and_then(|factor| self.initial_delay.checked_mul(factor))
+            .unwrap_or(self.max_delay);
+        if self.jitter <= 0.0 {
+            return delay.min(self.max_delay);
         }
+
+        // splitmix64 over a shared counter, so concurrent retries still draw distinct values
+        const GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+        let mut z = self.jitter_state.fetch_add(GAMMA, Ordering::Relaxed).wrapping_add(GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        let unit = (z >> 11) as f64 / (1u64 << 53) as f64;
+        delay.mul_f64(1.0 - self.jitter + 2.0 * self.jitter * unit).min(self.max_delay)
+    }
+
+    /// Stops retrying as soon as `cancel` fires, including mid-backoff, so shutdown
+    /// doesn't wait out the remaining sleeps.
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+}
+
+/// Retries shared by every sub-step of one composite operation, so the total
+/// number of retries stays bounded however many steps fail.
+pub struct RetryBudget {
+    remaining: AtomicU32,
+}
+
+impl RetryBudget {
+    pub fn new(retries: u32) -> Self {
+        Self {
+            remaining: AtomicU32::new(retries),
+        }
+    }
+
+    pub fn remaining(&self) -> u32 {
+        self.remaining.load(Ordering::SeqCst)
+    }
+
+    pub fn try_consume(&self) -> bool {
+        self.remaining
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |remaining| remaining.checked_sub(1))
+            .is_ok()
     }
 }
 
@@ -29,21 +115,214 @@ where
     F: Fn() -> Fut,
     Fut: std::future::Future<Output = Result<T>>,
 {
+    retry_loop(config, None, operation).await
+}
+
+/// Like `with_retry`, but each retry also spends a token from `budget` and the
+/// operation gives up early once the budget is exhausted.
+pub async fn with_retry_budget<F, Fut, T>(config: &RetryConfig, budget: &RetryBudget, operation: F) -> Result<T>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    retry_loop(config, Some(budget), operation).await
+}
+
+async fn retry_loop<F, Fut, T>(config: &RetryConfig, budget: Option<&RetryBudget>, operation: F) -> Result<T>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let started = Instant::now();
     let mut attempts = 0;
     let mut last_error = None;
     while attempts < config.max_retries {
+        if config.cancel.as_ref().is_some_and(CancellationToken::is_cancelled) {
+            return Err(AppError::Cancelled { attempts });
+        }
         match operation().await {
             Ok(result) => return Ok(result),
+            // Permanent failures come back as they are, without spending the backoff
+            Err(e) if !e.is_retriable() => return Err(e),
             Err(e) => {
                 last_error = Some(e);
                 attempts += 1;
-                if attempts <= config.max_retries {
-                    sleep(config.initial_delay * 2u32.pow(attempts - 1)).await;
-                    println!("{:?}", config.initial_delay * 2u32.pow(attempts - 1));
+                // Nothing follows the last attempt, so there's nothing to wait for
+                if attempts < config.max_retries {
+                    if budget.is_some_and(|budget| !budget.try_consume()) {
+                        break;
+                    }
+                    let delay = config.backoff_delay(attempts);
+                    debug!(attempts, ?delay, "Retrying after backoff");
+                    match &config.cancel {
+                        Some(cancel) => tokio::select! {
+                            _ = cancel.cancelled() => return Err(AppError::Cancelled { attempts }),
+                            _ = sleep(delay) => {}
+                        },
+                        None => sleep(delay).await,
+                    }
                 }
             }
         }
     }
 
-    Err(last_error.unwrap())
+    match last_error {
+        Some(e) => Err(AppError::RetriesExhausted {
+            attempts,
+            elapsed: started.elapsed(),
+            source: Box::new(e),
+        }),
+        None => Err(AppError::Other("Operation was not attempted: max_retries is 0".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StorageError;
+    use std::sync::atomic::AtomicUsize;
+
+    fn failing() -> Result<()> {
+        Err(AppError::Other("transient".to_string()))
+    }
+
+    #[test]
+    fn seeded_jitter_stays_within_its_window() {
+        let config = RetryConfig::new(10, Duration::from_millis(100)).with_jitter(0.25).with_jitter_seed(42);
+        let delays: Vec<Duration> = (1..=5).map(|attempt| config.backoff_delay(attempt)).collect();
+        for (attempt, delay) in (1..=5).zip(&delays) {
+            let base = Duration::from_millis(100) * 2u32.pow(attempt - 1);
+            assert!(*delay >= base.mul_f64(0.75) && *delay <= base.mul_f64(1.25), "attempt {}: {:?}", attempt, delay);
+        }
+
+        let replayed = RetryConfig::new(10, Duration::from_millis(100)).with_jitter(0.25).with_jitter_seed(42);
+        assert_eq!((1..=5).map(|attempt| replayed.backoff_delay(attempt)).collect::<Vec<_>>(), delays);
+    }
+
+    #[test]
+    fn delays_are_exact_without_jitter() {
+        let config = RetryConfig::new(3, Duration::from_millis(10));
+        assert_eq!(config.backoff_delay(1), Duration::from_millis(10));
+        assert_eq!(config.backoff_delay(3), Duration::from_millis(40));
+    }
+
+    #[tokio::test]
+    async fn long_retry_runs_are_bounded_by_max_delay() {
+        let config = RetryConfig::new(20, Duration::from_millis(1)).with_max_delay(Duration::from_millis(5));
+        let started = std::time::Instant::now();
+        let result = with_retry(&config, || async { failing() }).await;
+
+        // 19 sleeps of at most 5ms; uncapped, the last one alone would be over four minutes
+        assert!(matches!(result, Err(AppError::RetriesExhausted { attempts: 20, .. })));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn no_backoff_after_the_last_attempt() {
+        let config = RetryConfig::new(2, Duration::from_millis(300));
+        let result = with_retry(&config, || async { failing() }).await;
+
+        let Err(AppError::RetriesExhausted { attempts, elapsed, .. }) = result else {
+            panic!("expected exhausted retries, got {:?}", result);
+        };
+        assert_eq!(attempts, 2);
+        assert!(elapsed >= Duration::from_millis(300) && elapsed < Duration::from_millis(600), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn budget_is_shared_across_operations() {
+        let config = RetryConfig::new(5, Duration::ZERO);
+        let budget = RetryBudget::new(3);
+        let calls = AtomicUsize::new(0);
+
+        for _ in 0..2 {
+            let result = with_retry_budget(&config, &budget, || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                failing()
+            })
+            .await;
+            assert!(matches!(result, Err(AppError::RetriesExhausted { .. })));
+        }
+
+        // Three retries in all: the first operation spends them, the second gets one try
+        assert_eq!(budget.remaining(), 0);
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn permanent_errors_are_not_retried() {
+        let config = RetryConfig::new(3, Duration::ZERO);
+        let calls = AtomicUsize::new(0);
+        let result: Result<()> = with_retry(&config, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(AppError::Storage(StorageError::NotFound("notes.txt".to_string())))
+        })
+        .await;
+
+        assert!(matches!(result, Err(AppError::Storage(StorageError::NotFound(_)))));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn timeouts_are_retried_until_exhausted() {
+        let config = RetryConfig::new(3, Duration::ZERO);
+        let calls = AtomicUsize::new(0);
+        let result: Result<()> = with_retry(&config, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(AppError::Storage(StorageError::Io(std::io::Error::from(std::io::ErrorKind::TimedOut))))
+        })
+        .await;
+
+        assert!(matches!(result, Err(AppError::RetriesExhausted { attempts: 3, .. })));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn exhausted_retries_report_the_attempts_and_last_error() {
+        let config = RetryConfig::new(3, Duration::from_millis(1));
+        let result = with_retry(&config, || async { failing() }).await;
+
+        let message = result.unwrap_err().to_string();
+        assert!(message.contains("3 attempts"), "{}", message);
+        assert!(message.contains("transient"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn cancelling_mid_backoff_returns_promptly() {
+        let cancel = CancellationToken::new();
+        let config = RetryConfig::new(5, Duration::from_secs(10)).with_cancellation(cancel.clone());
+        let calls = AtomicUsize::new(0);
+
+        let started = std::time::Instant::now();
+        let canceller = tokio::spawn(async move {
+            sleep(Duration::from_millis(50)).await;
+            cancel.cancel();
+        });
+        let result = with_retry(&config, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            failing()
+        })
+        .await;
+        canceller.await.unwrap();
+
+        assert!(matches!(result, Err(AppError::Cancelled { attempts: 1 })), "{:?}", result);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn cancelled_token_stops_before_the_first_attempt() {
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let config = RetryConfig::new(3, Duration::ZERO).with_cancellation(cancel);
+        let calls = AtomicUsize::new(0);
+        let result: Result<()> = with_retry(&config, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        })
+        .await;
+
+        assert!(matches!(result, Err(AppError::Cancelled { attempts: 0 })), "{:?}", result);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
 }
@@ -0,0 +1,15 @@
+use std::path::Path;
+use crate::{AppError, Result, StorageError};
+
+/// Reports how much space is left on the volume holding the store.
+pub trait DiskSpaceProbe: Send + Sync {
+    fn available_space(&self, path: &Path) -> Result<u64>;
+}
+
+pub struct SystemDiskSpace;
+
+impl DiskSpaceProbe for SystemDiskSpace {
+    fn available_space(&self, path: &Path) -> Result<u64> {
+        fs2::available_space(path).map_err(|e| AppError::Storage(StorageError::Storage(e.to_string())))
+    }
+}
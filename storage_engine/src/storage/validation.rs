@@ -1,7 +1,23 @@
-use crate::{AppError, FileMetadata, Result, StorageError};
+use crate::{AppError, ChunkId, FileId, FileMetadata, Result, StorageError};
+use sha2::{Digest, Sha256};
 use tokio::fs;
+use std::collections::HashMap;
 use std::path::PathBuf;
 
+use super::index::NameIndex;
+
+/// What `scan` does with metadata whose chunks are missing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OrphanedMetadataPolicy {
+    /// Only list the file in the scan report.
+    #[default]
+    Report,
+    /// Move the metadata into `corrupt/` so the file disappears from listings.
+    Quarantine,
+    /// Remove the metadata.
+    Delete,
+}
+
 pub struct ValidationManager {
     base_path: PathBuf,
 }
@@ -11,25 +27,166 @@ impl ValidationManager {
         Self {base_path}
     }
 
+    pub fn missing_chunks(&self, metadata: &FileMetadata) -> Vec<ChunkId> {
+        metadata
+            .chunk_ids
+            .iter()
+            .filter(|chunk_id| !self.base_path.join("chunks").join(&chunk_id.0).exists())
+            .cloned()
+            .collect()
+    }
+
+    /// Checks a file's chunks against its metadata: every chunk exists and hashes to its
+    /// recorded checksum, and together they have the file's size and checksum. Reads
+    /// every chunk.
     pub async fn validate_file(&self, metadata: &FileMetadata) -> Result<()> {
+        self.validate_layout(metadata).await?;
+
+        let mut hasher = Sha256::new();
+        for (index, chunk_id) in metadata.chunk_ids.iter().enumerate() {
+            let chunk_path = self.base_path.join("chunks").join(&chunk_id.0);
+            let data = fs::read(chunk_path).await.map_err(|e| AppError::Storage(StorageError::Io(e)))?;
+            // Files stored before per-chunk checksums only have the whole-file one
+            if let Some(expected) = metadata.chunk_checksums.get(index) {
+                let actual = format!("{:x}", Sha256::digest(&data));
+                if actual != *expected {
+                    return Err(AppError::Storage(StorageError::IntegrityError(format!("Chunk {} checksum mismatch. Expected: {}, Got: {}", chunk_id.0, expected, actual))));
+                }
+            }
+            hasher.update(&data);
+        }
+
+        self.validate_checksum(metadata, &format!("{:x}", hasher.finalize()))
+    }
+
+    /// Cheap part of `validate_file`: every chunk exists and together they have the
+    /// recorded size. Enough for reads that verify checksums as they go.
+    pub async fn validate_layout(&self, metadata: &FileMetadata) -> Result<()> {
         for chunk_id in &metadata.chunk_ids {
-            let chunk_path = self.base_path.join("chunks").join(chunk_id.0.to_string());
+            let chunk_path = self.base_path.join("chunks").join(&chunk_id.0);
             if !chunk_path.exists() {
-                return Err(AppError::Storage(StorageError::Storage(format!("chunk {} is missing", chunk_id.0))));
+                return Err(AppError::Storage(StorageError::NotFound(format!("chunk {} is missing", chunk_id.0))));
             }
         }
 
         let mut total_size = 0;
         for chunk_id in &metadata.chunk_ids {
-            let chunk_path = self.base_path.join("chunks").join(chunk_id.0.to_string());
+            let chunk_path = self.base_path.join("chunks").join(&chunk_id.0);
             let metadata = fs::metadata(chunk_path).await.map_err(|e| AppError::Storage(crate::StorageError::Storage(e.to_string())))?;
             total_size += metadata.len();
         }
 
-        if total_size as u64 != metadata.size {
-            return Err(AppError::Storage(StorageError::Storage(format!("File size mismatch. Expected: {}, Got: {}", metadata.size, total_size))));
+        if total_size != metadata.size {
+            return Err(AppError::Storage(StorageError::IntegrityError(format!("File size mismatch. Expected: {}, Got: {}", metadata.size, total_size))));
         }
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Rejects reassembled chunk data whose length differs from the recorded size. Catches
+    /// bytes appended to a chunk after validation, which nothing else notices when the
+    /// store isn't encrypted.
+    pub fn validate_length(&self, metadata: &FileMetadata, stored_len: u64) -> Result<()> {
+        if stored_len != metadata.size {
+            return Err(AppError::Storage(StorageError::IntegrityError(format!("Stored length mismatch for {}. Expected: {}, Got: {}", metadata.id, metadata.size, stored_len))));
+        }
+        Ok(())
+    }
+
+    /// Rejects decoded file data whose length differs from the recorded upload length.
+    /// Older files that recorded no length are let through.
+    pub fn validate_original_length(&self, metadata: &FileMetadata, decoded_len: u64) -> Result<()> {
+        if metadata.original_size > 0 && decoded_len != metadata.original_size {
+            return Err(AppError::Storage(StorageError::IntegrityError(format!("Decoded length mismatch for {}. Expected: {}, Got: {}", metadata.id, metadata.original_size, decoded_len))));
+        }
+        Ok(())
+    }
+
+    /// Rejects stored data whose SHA-256, `actual`, differs from the file's checksum.
+    pub fn validate_checksum(&self, metadata: &FileMetadata, actual: &str) -> Result<()> {
+        if !metadata.checksum.is_empty() && actual != metadata.checksum {
+            return Err(AppError::Storage(StorageError::IntegrityError(format!("Checksum mismatch for {}. Expected: {}, Got: {}", metadata.id, metadata.checksum, actual))));
+        }
+        Ok(())
+    }
+
+    /// Per-chunk form of `validate_length`, for reads that only touch some chunks.
+    pub fn validate_chunk_length(&self, chunk_id: &ChunkId, expected: u64, actual: u64) -> Result<()> {
+        if actual != expected {
+            return Err(AppError::Storage(StorageError::IntegrityError(format!("Chunk {} length mismatch. Expected: {}, Got: {}", chunk_id.0, expected, actual))));
+        }
+        Ok(())
+    }
+
+    /// Regenerates `index` from the names recorded in file metadata, for when the index
+    /// was lost or corrupted. A name shared by several files goes to the most recently
+    /// modified one; unreadable metadata is skipped. Returns the number of names indexed.
+    pub async fn rebuild_name_index(&self, index: &dyn NameIndex) -> Result<usize> {
+        let mut latest: HashMap<String, FileMetadata> = HashMap::new();
+        let mut entries = fs::read_dir(self.base_path.join("metadata")).await.map_err(|e| AppError::Storage(StorageError::Io(e)))?;
+        while let Some(entry) = entries.next_entry().await.map_err(|e| AppError::Storage(StorageError::Io(e)))? {
+            let path = entry.path();
+            // Skips the temporary files metadata is written through
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let Ok(content) = fs::read_to_string(&path).await else {
+                continue;
+            };
+            let Ok(metadata) = serde_json::from_str::<FileMetadata>(&content) else {
+                continue;
+            };
+            if latest.get(&metadata.name).is_none_or(|current| metadata.modified_at > current.modified_at) {
+                latest.insert(metadata.name.clone(), metadata);
+            }
+        }
+
+        let rebuilt: HashMap<String, FileId> = latest.into_iter().map(|(name, metadata)| (name, metadata.id)).collect();
+        let count = rebuilt.len();
+        index.replace_all(rebuilt).await?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::disk::{DiskStorage, StorageBackend};
+
+    #[tokio::test]
+    async fn reassembled_length_must_match_the_recorded_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let metadata = storage.store_file("notes.txt", b"twenty-four bytes of it!").await.unwrap();
+        let validation = ValidationManager::new(dir.path().to_path_buf());
+
+        assert!(validation.validate_length(&metadata, metadata.size).is_ok());
+        let err = validation.validate_length(&metadata, metadata.size + 16).unwrap_err();
+        assert!(matches!(err, AppError::Storage(StorageError::IntegrityError(_))), "{:?}", err);
+
+        assert!(validation.validate_original_length(&metadata, 24).is_ok());
+        assert!(validation.validate_original_length(&metadata, 53).is_err());
+
+        let chunk_id = &metadata.chunk_ids[0];
+        assert!(validation.validate_chunk_length(chunk_id, 24, 24).is_ok());
+        assert!(validation.validate_chunk_length(chunk_id, 24, 40).is_err());
+    }
+
+    #[tokio::test]
+    async fn a_flipped_byte_fails_validation_but_not_the_size_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let metadata = storage.store_file("notes.txt", b"twenty-four bytes of it!").await.unwrap();
+        let validation = ValidationManager::new(dir.path().to_path_buf());
+        validation.validate_file(&metadata).await.unwrap();
+
+        let chunk_path = dir.path().join("chunks").join(&metadata.chunk_ids[0].0);
+        let mut data = std::fs::read(&chunk_path).unwrap();
+        data[0] ^= 1;
+        std::fs::write(&chunk_path, data).unwrap();
+
+        validation.validate_layout(&metadata).await.unwrap();
+        let err = validation.validate_file(&metadata).await.unwrap_err();
+        assert!(matches!(err, AppError::Storage(StorageError::IntegrityError(_))), "{:?}", err);
+    }
+}
@@ -1,8 +1,27 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+/// Names a chunk file. New chunks are named by the SHA-256 of their stored bytes, so
+/// identical chunks share one file; older chunks and chunks with their own key use a
+/// random UUID. Both serialize as a plain string.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
-pub struct ChunkId(pub Uuid);
+#[serde(transparent)]
+pub struct ChunkId(pub String);
+
+impl ChunkId {
+    pub fn random() -> Self {
+        Self(Uuid::new_v4().to_string())
+    }
+
+    /// Bytes a per-chunk key is derived from. UUID ids use their raw bytes, so keys
+    /// derived before ids could be checksums still match.
+    pub fn key_context(&self) -> Vec<u8> {
+        match Uuid::parse_str(&self.0) {
+            Ok(id) => id.as_bytes().to_vec(),
+            Err(_) => self.0.as_bytes().to_vec(),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize, )]
 pub struct Chunk {
@@ -10,4 +29,4 @@ pub struct Chunk {
     pub data: Vec<u8>,
     pub checksum: String,
     pub size: usize,
-}
\ No newline at end of file
+}
@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, PartialEq , Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum FileType {
     Image(ImageType),
     Document(DocumentType),
@@ -9,7 +9,7 @@ pub enum FileType {
     Unknown,
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum ImageType {
     Jpeg,
     Png,
@@ -18,7 +18,7 @@ pub enum ImageType {
     Other(String),
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum DocumentType {
     Pdf,
     Doc,
@@ -26,7 +26,7 @@ pub enum DocumentType {
     Other(String),
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum VideoType {
     Mp4,
     Mkv,
@@ -34,7 +34,7 @@ pub enum VideoType {
     Other(String),
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum AudioType {
     Mp3,
     Wav,
@@ -42,10 +42,42 @@ pub enum AudioType {
     Other(String),
 }
 
+impl FileType {
+    /// MIME type to serve the file as. Falls back to `application/octet-stream` when
+    /// the type wasn't detected.
+    pub fn mime_type(&self) -> &str {
+        match self {
+            FileType::Image(ImageType::Jpeg) => "image/jpeg",
+            FileType::Image(ImageType::Png) => "image/png",
+            FileType::Image(ImageType::Gif) => "image/gif",
+            FileType::Image(ImageType::Webp) => "image/webp",
+            FileType::Document(DocumentType::Pdf) => "application/pdf",
+            FileType::Document(DocumentType::Doc) => "application/msword",
+            FileType::Document(DocumentType::Docx) => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+            FileType::Video(VideoType::Mp4) => "video/mp4",
+            FileType::Video(VideoType::Mkv) => "video/x-matroska",
+            FileType::Video(VideoType::Avi) => "video/x-msvideo",
+            FileType::Audio(AudioType::Mp3) => "audio/mpeg",
+            FileType::Audio(AudioType::Wav) => "audio/wav",
+            FileType::Audio(AudioType::Flac) => "audio/flac",
+            FileType::Image(ImageType::Other(mime))
+            | FileType::Document(DocumentType::Other(mime))
+            | FileType::Video(VideoType::Other(mime))
+            | FileType::Audio(AudioType::Other(mime)) => mime,
+            FileType::Unknown => "application/octet-stream",
+        }
+    }
+}
+
 pub struct FileTypeDetector;
 
 impl FileTypeDetector {
     pub fn detect(data: &[u8]) -> FileType {
+        Self::detect_with_fallback(data, &FileType::Unknown)
+    }
+
+    /// Like `detect`, but returns `fallback` for data that can't be classified.
+    pub fn detect_with_fallback(data: &[u8], fallback: &FileType) -> FileType {
         if let Some(kind) = infer::get(data) {
             match kind.mime_type() {
                 // Image types
@@ -79,10 +111,28 @@ impl FileTypeDetector {
                     FileType::Audio(AudioType::Other(mime.to_string())),
                 mime if mime.starts_with("application/") => 
                     FileType::Document(DocumentType::Other(mime.to_string())),
-                _ => FileType::Unknown,
+                _ => fallback.clone(),
             }
         } else {
-            FileType::Unknown
+            fallback.clone()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unidentifiable_data_gets_the_fallback_type() {
+        let fallback = FileType::Document(DocumentType::Other("text/plain".to_string()));
+        assert_eq!(FileTypeDetector::detect_with_fallback(b"plain words", &fallback), fallback);
+        assert_eq!(FileTypeDetector::detect(b"plain words"), FileType::Unknown);
+    }
+
+    #[test]
+    fn recognised_data_ignores_the_fallback() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(FileTypeDetector::detect_with_fallback(png, &FileType::Unknown), FileType::Image(ImageType::Png));
+    }
+}
@@ -2,15 +2,218 @@ use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
 use super::{ChunkId, FileType};
+use crate::storage::{compression::CompressionAlgorithm, pipeline::PipelineStage};
+use std::{collections::HashMap, fmt, str::FromStr};
+
+/// Identifies a stored file. Serializes as the bare UUID, so existing metadata parses unchanged.
+/// Chunk ids are a different type, so one can't be passed where the other is expected:
+///
+/// ```compile_fail
+/// use storage_engine::{ChunkId, FileId};
+///
+/// let file: FileId = ChunkId("0f3a".to_string());
+/// ```
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(transparent)]
+pub struct FileId(pub Uuid);
+
+impl FileId {
+    pub fn new() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+impl Default for FileId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<Uuid> for FileId {
+    fn from(id: Uuid) -> Self {
+        Self(id)
+    }
+}
+
+impl From<FileId> for Uuid {
+    fn from(id: FileId) -> Self {
+        id.0
+    }
+}
+
+impl fmt::Display for FileId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for FileId {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Uuid::parse_str(s).map(Self)
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileMetadata {
-    pub id: Uuid,
+    pub id: FileId,
     pub name: String,
+    // Bytes on disk across all chunks; kept alongside `stored_size` for older readers
     pub size: u64,
+    // Length of the data as uploaded; 0 for files written before it was recorded
+    #[serde(default)]
+    pub original_size: u64,
+    // Bytes on disk across all chunks; 0 for files written before it was recorded
+    #[serde(default)]
+    pub stored_size: u64,
     pub created_at: DateTime<Utc>,
     pub modified_at: DateTime<Utc>,
     pub checksum: String,
+    // Hash of the original plaintext, stable across processing settings; empty for older files
+    #[serde(default)]
+    pub content_checksum: String,
     pub file_type: FileType,
     pub chunk_ids: Vec<ChunkId>,
+    // Files written before the pipeline was recorded used compress-then-encrypt
+    #[serde(default = "default_pipeline")]
+    pub pipeline: Vec<PipelineStage>,
+    // Set when the pipeline ran per chunk; one flag per entry in `chunk_ids`
+    #[serde(default)]
+    pub chunk_compressed: Vec<bool>,
+    // Decoded length of each chunk; empty when chunks can't be decoded on their own
+    #[serde(default)]
+    pub chunk_sizes: Vec<u64>,
+    // Original size over stored size, so higher means better compression; 0 for older files
+    #[serde(default)]
+    pub compression_ratio: f64,
+    // Size the data was split at; 0 for files written before it was recorded
+    #[serde(default)]
+    pub chunk_size: usize,
+    // Set when ciphertext was authenticated against `id`; older files weren't
+    #[serde(default)]
+    pub id_bound: bool,
+    // Level the data was compressed at; None when nothing was compressed, the codec has no levels, or for older files
+    #[serde(default)]
+    pub compression_level: Option<u32>,
+    // Codec the data was compressed with; None when nothing was compressed or for older files
+    #[serde(default)]
+    pub compression_algorithm: Option<CompressionAlgorithm>,
+    // Set when each chunk was encrypted with a key derived from its chunk id
+    #[serde(default)]
+    pub per_chunk_keys: bool,
+    // Length of each chunk before it was padded for encryption; empty when chunks weren't padded
+    #[serde(default)]
+    pub chunk_unpadded_sizes: Vec<u64>,
+    // SHA-256 of each chunk's stored bytes, checked on read; empty for files written before they were recorded
+    #[serde(default)]
+    pub chunk_checksums: Vec<String>,
+    // Set when the file was decrypted on purpose, so rewrites leave it unencrypted
+    #[serde(default)]
+    pub keep_plaintext: bool,
+    // Application-defined key/value pairs, such as an owner id; kept across content updates
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+impl FileMetadata {
+    /// Length of the file as uploaded. Older files fall back to their decoded chunk
+    /// lengths when recorded, and to the stored size otherwise.
+    pub fn original_len(&self) -> u64 {
+        if self.original_size > 0 {
+            self.original_size
+        } else if !self.chunk_sizes.is_empty() && self.chunk_sizes.len() == self.chunk_ids.len() {
+            self.chunk_sizes.iter().sum()
+        } else {
+            self.size
+        }
+    }
+
+    /// Associated data the file's ciphertext was bound to, if any.
+    pub fn encryption_aad(&self) -> Option<&[u8]> {
+        self.id_bound.then(|| self.id.0.as_bytes().as_slice())
+    }
+
+    /// Whether each chunk was encrypted on its own, rather than the file as a whole.
+    pub fn chunks_encrypted(&self) -> bool {
+        !self.chunk_compressed.is_empty() && self.pipeline.contains(&PipelineStage::Encrypt)
+    }
+}
+
+fn default_pipeline() -> Vec<PipelineStage> {
+    vec![PipelineStage::Compress, PipelineStage::Encrypt]
+}
+
+/// One version of a file kept under versioning. Each version is a stored file of its own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileVersion {
+    pub version: u32,
+    pub id: FileId,
+    pub created_at: DateTime<Utc>,
+    pub size: u64,
+    /// When the file was deleted; deleted versions stay listed but can't be read.
+    #[serde(default)]
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+/// Versions stored under one name, oldest first. The name resolves to the last one
+/// that hasn't been deleted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionedMetadata {
+    pub name: String,
+    pub versions: Vec<FileVersion>,
+    // Highest number handed out, so deleted versions' numbers aren't reused
+    pub last_version: u32,
+}
+
+impl VersionedMetadata {
+    pub fn new(name: &str) -> Self {
+        Self { name: name.to_string(), versions: Vec::new(), last_version: 0 }
+    }
+
+    /// Records `metadata` as the newest version. Numbers keep counting up after deletions.
+    pub fn push(&mut self, metadata: &FileMetadata) {
+        self.last_version += 1;
+        self.versions.push(FileVersion {
+            version: self.last_version,
+            id: metadata.id,
+            created_at: metadata.created_at,
+            size: metadata.original_len(),
+            deleted_at: None,
+        });
+    }
+
+    pub fn get(&self, version: u32) -> Option<&FileVersion> {
+        self.versions.iter().find(|entry| entry.version == version)
+    }
+
+    /// Newest version that hasn't been deleted, which the name resolves to.
+    pub fn latest(&self) -> Option<&FileVersion> {
+        self.versions.iter().rev().find(|entry| entry.deleted_at.is_none())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_written_before_file_ids_still_parses() {
+        let id = Uuid::new_v4();
+        let json = format!(
+            r#"{{"id":"{}","name":"notes.txt","size":5,"created_at":"2024-01-01T00:00:00Z","modified_at":"2024-01-01T00:00:00Z","checksum":"abc","file_type":"Unknown","chunk_ids":["abc"]}}"#,
+            id
+        );
+
+        let metadata: FileMetadata = serde_json::from_str(&json).unwrap();
+        assert_eq!(metadata.id, FileId(id));
+        assert_eq!(serde_json::to_value(metadata.id).unwrap(), serde_json::Value::String(id.to_string()));
+    }
+
+    #[test]
+    fn file_ids_parse_from_their_display_form() {
+        let id = FileId::new();
+        assert_eq!(id.to_string().parse::<FileId>().unwrap(), id);
+        assert!("not-a-uuid".parse::<FileId>().is_err());
+    }
 }
@@ -4,5 +4,5 @@ mod file;
 
 pub use chunk::{Chunk, ChunkId};
 pub use file::{FileType, FileTypeDetector, ImageType, DocumentType, VideoType, AudioType};
-pub use metadata::FileMetadata;
+pub use metadata::{FileId, FileMetadata, FileVersion, VersionedMetadata};
 